@@ -32,7 +32,6 @@ pub async fn broadcast_identity(
     known_nodes: &[String],
 ) -> Result<()> {
     let client = Client::new();
-    let payload = serde_json::to_string(identity)?;
 
     for node_url in known_nodes {
         let url = format!("{}/sync", node_url.trim_end_matches('/'));
@@ -1,6 +1,6 @@
-use sled::{Db, IVec};
+use sled::Db;
 use anyhow::{Result, Context};
-use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use serde::{Serialize, de::DeserializeOwned};
 
 #[derive(Debug)]
 pub struct NodeDatabase {
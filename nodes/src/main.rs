@@ -1,8 +1,15 @@
 mod server;
+// Not wired into the server yet
+#[allow(dead_code)]
 mod consensus;
+#[allow(dead_code)]
 mod db;
+#[cfg(test)]
+mod server_tests;
+#[cfg(test)]
+mod consensus_tests;
 
-#[tokio::main]
+#[actix_web::main]
 async fn main() {
     server::start_server().await;
 }
@@ -1,32 +1,47 @@
 use actix_web::{web, App, HttpServer, HttpResponse, Responder};
+use ed25519_dalek::{PublicKey, Signature, Verifier};
 use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
 use std::collections::{HashMap, HashSet};
 use std::sync::Mutex;
 use std::fs;
-use tokio::time::{interval, Duration};
+use actix_rt::time::interval;
+use std::time::Duration;
 
 const PRESENCE_TTL_SECS: u64 = 300; // 5 minutes
 
+/// Domain separation prefix of the signed identity encoding (shared with the client)
+const IDENTITY_DOMAIN: &[u8] = b"enigma-identity-v1";
+
+/// Domain separation prefix of the signed device list encoding (shared with the client)
+const DEVICE_LIST_DOMAIN: &[u8] = b"enigma-devices-v1";
+
+/// Device holding the account key, whose bundle is published before any device list
+const PRIMARY_DEVICE: u32 = 1;
+
 // ===================== Configuration structures =====================
 
-#[derive(Debug, Deserialize)]
-struct Config {
-    node: NodeConfig,
-    sync: SyncConfig,
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    pub(crate) node: NodeConfig,
+    pub(crate) sync: SyncConfig,
 }
 
-#[derive(Debug, Deserialize)]
-struct NodeConfig {
-    mode: String,
-    bind_address: String,
-    bind_port: u16,
-    max_users: usize,
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct NodeConfig {
+    #[allow(dead_code)]
+    pub(crate) mode: String,
+    pub(crate) bind_address: String,
+    pub(crate) bind_port: u16,
+    #[allow(dead_code)]
+    pub(crate) max_users: usize,
 }
 
-#[derive(Debug, Deserialize)]
-struct SyncConfig {
-    enabled: bool,
-    initial_nodes: Vec<String>,
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct SyncConfig {
+    #[allow(dead_code)]
+    pub(crate) enabled: bool,
+    pub(crate) initial_nodes: Vec<String>,
 }
 
 // ===================== Runtime data structures =====================
@@ -34,11 +49,39 @@ struct SyncConfig {
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct PublicIdentity {
     pub username: String,
-    pub public_key: String,
-    pub signature: String,
+    pub public_key: String, // Hex Ed25519 signing key
+    #[serde(default)]
+    pub encryption_public_key: String, // Hex X25519 key
+    pub signature: String,  // Hex self-signature over username and both keys
     pub timestamp: u64,
 }
 
+impl PublicIdentity {
+    /// Checks the self-signature, with the same length-prefixed encoding as the client
+    pub fn verify(&self) -> bool {
+        let (Ok(public_key), Ok(encryption_key), Ok(signature)) = (
+            hex::decode(&self.public_key),
+            hex::decode(&self.encryption_public_key),
+            hex::decode(&self.signature),
+        ) else {
+            return false;
+        };
+        let (Ok(public_key), Ok(signature)) = (
+            PublicKey::from_bytes(&public_key),
+            Signature::try_from(signature.as_slice()),
+        ) else {
+            return false;
+        };
+
+        let mut payload = Vec::new();
+        for field in [IDENTITY_DOMAIN, self.username.as_bytes(), &public_key.as_bytes()[..], &encryption_key[..]] {
+            payload.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            payload.extend_from_slice(field);
+        }
+        public_key.verify(&payload, &signature).is_ok()
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct PeerPresence {
     pub ip: String,
@@ -46,28 +89,153 @@ pub struct PeerPresence {
     pub timestamp: u64,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct OneTimePreKey {
+    pub id: u32,
+    pub public: Vec<u8>,
+}
+
+/// X3DH prekey bundle published by a user; one-time prekeys are handed out once each
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PreKeyBundle {
+    pub identity_pub: Vec<u8>,
+    pub identity_dh_pub: Vec<u8>,
+    pub identity_dh_signature: Vec<u8>,
+    #[serde(default)]
+    pub spk_id: u32,
+    pub spk_pub: Vec<u8>,
+    pub spk_signature: Vec<u8>,
+    pub one_time_prekeys: Vec<OneTimePreKey>,
+    #[serde(default)]
+    pub key_packages: Vec<KeyPackage>,
+}
+
+impl PreKeyBundle {
+    /// Checks that the identity DH key and the signed prekey are signed by `identity_pub`,
+    /// and that one-time prekey ids are unique
+    pub fn verify(&self) -> bool {
+        let Ok(identity) = PublicKey::from_bytes(&self.identity_pub) else {
+            return false;
+        };
+        let signed = [(&self.identity_dh_pub, &self.identity_dh_signature), (&self.spk_pub, &self.spk_signature)]
+            .into_iter()
+            .all(|(key, signature)| {
+                Signature::try_from(signature.as_slice()).is_ok_and(|s| identity.verify(key, &s).is_ok())
+            });
+        let ids: HashSet<u32> = self.one_time_prekeys.iter().map(|k| k.id).collect();
+        signed && ids.len() == self.one_time_prekeys.len()
+    }
+}
+
+/// MLS key package published with a bundle; like one-time prekeys, each is handed out once
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct KeyPackage {
+    pub init_key: Vec<u8>,
+    #[serde(flatten)]
+    pub rest: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Account identity embedded in a device list (other fields are passed through)
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DeviceListOwner {
+    pub username: String,
+    pub signing_public_key: Vec<u8>,
+    #[serde(flatten)]
+    pub rest: serde_json::Map<String, serde_json::Value>,
+}
+
+/// One device of a device list, with its own identity keys
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DeviceEntry {
+    pub id: u32,
+    pub name: String,
+    pub signing_public_key: Vec<u8>,
+    pub encryption_public_key: Vec<u8>,
+    #[serde(flatten)]
+    pub rest: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Device list of a user, signed by its account key; nodes check the owner, the signature
+/// and the version
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DeviceList {
+    pub identity: DeviceListOwner,
+    pub version: u64,
+    #[serde(default)]
+    pub devices: Vec<DeviceEntry>,
+    pub signature: Vec<u8>,
+    #[serde(flatten)]
+    pub rest: serde_json::Map<String, serde_json::Value>,
+}
+
+impl DeviceList {
+    /// Checks the signature by the account key, over the same length-prefixed encoding
+    /// as the client: username, account key, version, then every device
+    pub fn verify(&self) -> bool {
+        let (Ok(account_key), Ok(signature)) = (
+            PublicKey::from_bytes(&self.identity.signing_public_key),
+            Signature::try_from(self.signature.as_slice()),
+        ) else {
+            return false;
+        };
+
+        let put_field = |payload: &mut Vec<u8>, field: &[u8]| {
+            payload.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            payload.extend_from_slice(field);
+        };
+        let mut payload = Vec::new();
+        put_field(&mut payload, DEVICE_LIST_DOMAIN);
+        put_field(&mut payload, self.identity.username.as_bytes());
+        put_field(&mut payload, &self.identity.signing_public_key);
+        payload.extend_from_slice(&self.version.to_be_bytes());
+        payload.extend_from_slice(&(self.devices.len() as u32).to_be_bytes());
+        for device in &self.devices {
+            payload.extend_from_slice(&device.id.to_be_bytes());
+            put_field(&mut payload, device.name.as_bytes());
+            put_field(&mut payload, &device.signing_public_key);
+            put_field(&mut payload, &device.encryption_public_key);
+        }
+        account_key.verify(&payload, &signature).is_ok()
+    }
+
+    /// Identity key of one of the listed devices
+    pub fn device_key(&self, id: u32) -> Option<&[u8]> {
+        self.devices.iter().find(|d| d.id == id).map(|d| d.signing_public_key.as_slice())
+    }
+}
+
 pub struct AppState {
     pub known_users: Mutex<HashMap<String, PublicIdentity>>,
+    pub prekey_bundles: Mutex<HashMap<(String, u32), PreKeyBundle>>, // Keyed by (username, device)
+    pub device_lists: Mutex<HashMap<String, DeviceList>>,
     pub active_peers: Mutex<HashMap<String, PeerPresence>>,
     pub known_nodes: Mutex<HashSet<String>>,
+    #[allow(dead_code)]
     pub config: Config,
 }
 
 // ===================== Handlers =====================
 
-async fn register(
+pub(crate) async fn register(
     data: web::Data<AppState>,
     info: web::Json<PublicIdentity>,
 ) -> impl Responder {
+    if !info.verify() {
+        return HttpResponse::BadRequest().body("Invalid identity signature");
+    }
     let mut users = data.known_users.lock().unwrap();
-    if users.contains_key(&info.username) {
-        return HttpResponse::Conflict().body("Username already exists");
+    match users.get(&info.username) {
+        // Re-registering the same key refreshes the entry, another key cannot take the name
+        Some(existing) if existing.public_key != info.public_key => {
+            return HttpResponse::Conflict().body("Username already exists");
+        }
+        _ => {}
     }
     users.insert(info.username.clone(), info.into_inner());
     HttpResponse::Ok().body("User registered")
 }
 
-async fn resolve(
+pub(crate) async fn resolve(
     data: web::Data<AppState>,
     web::Path(username): web::Path<String>,
 ) -> impl Responder {
@@ -79,7 +247,7 @@ async fn resolve(
     }
 }
 
-async fn announce(
+pub(crate) async fn announce(
     data: web::Data<AppState>,
     info: web::Json<PeerPresence>,
 ) -> impl Responder {
@@ -88,24 +256,140 @@ async fn announce(
     HttpResponse::Ok().body("Peer announced")
 }
 
-async fn sync(
+pub(crate) async fn sync(
     data: web::Data<AppState>,
     info: web::Json<HashMap<String, PublicIdentity>>,
 ) -> impl Responder {
     let mut users = data.known_users.lock().unwrap();
     for (username, identity) in info.into_inner() {
+        // Other nodes are not trusted: only keep identities signed by their owner
+        if identity.username != username || !identity.verify() {
+            continue;
+        }
         users.entry(username).or_insert(identity);
     }
     HttpResponse::Ok().body("Sync completed")
 }
 
-async fn nodes(data: web::Data<AppState>) -> impl Responder {
+pub(crate) async fn publish_prekeys(
+    data: web::Data<AppState>,
+    web::Path((username, device)): web::Path<(String, u32)>,
+    info: web::Json<PreKeyBundle>,
+) -> impl Responder {
+    let registered_key = match data.known_users.lock().unwrap().get(&username) {
+        Some(identity) => identity.public_key.clone(),
+        None => return HttpResponse::NotFound().body("User not found"),
+    };
+    let incoming = info.into_inner();
+    // The bundle must come from a device of the account: the registered key for the primary
+    // device, or the key the published device list gives to this device
+    let owned = match data.device_lists.lock().unwrap().get(&username).and_then(|list| list.device_key(device)) {
+        Some(key) => key == incoming.identity_pub.as_slice(),
+        None => device == PRIMARY_DEVICE && hex::encode(&incoming.identity_pub) == registered_key,
+    };
+    if !owned {
+        return HttpResponse::Forbidden().body("Bundle not owned by this device");
+    }
+    if !incoming.verify() {
+        return HttpResponse::BadRequest().body("Invalid prekey signature");
+    }
+
+    let mut bundles = data.prekey_bundles.lock().unwrap();
+    match bundles.get_mut(&(username.clone(), device)) {
+        Some(existing) => {
+            // Replenish: keep unspent one-time prekeys and key packages, refresh the (possibly
+            // rotated) signed prekey
+            let mut opks = std::mem::take(&mut existing.one_time_prekeys);
+            for opk in incoming.one_time_prekeys.iter() {
+                if !opks.iter().any(|k| k.id == opk.id) {
+                    opks.push(opk.clone());
+                }
+            }
+            let mut packages = std::mem::take(&mut existing.key_packages);
+            for package in incoming.key_packages.iter() {
+                if !packages.iter().any(|p| p.init_key == package.init_key) {
+                    packages.push(package.clone());
+                }
+            }
+            *existing = PreKeyBundle { one_time_prekeys: opks, key_packages: packages, ..incoming };
+        }
+        None => {
+            bundles.insert((username, device), incoming);
+        }
+    }
+    HttpResponse::Ok().body("Prekeys published")
+}
+
+pub(crate) async fn fetch_bundle(
+    data: web::Data<AppState>,
+    web::Path((username, device)): web::Path<(String, u32)>,
+) -> impl Responder {
+    let mut bundles = data.prekey_bundles.lock().unwrap();
+    if let Some(bundle) = bundles.get_mut(&(username, device)) {
+        // Hand out at most one one-time prekey and mark it spent by removing it
+        let mut response = bundle.clone();
+        response.one_time_prekeys = if bundle.one_time_prekeys.is_empty() {
+            vec![]
+        } else {
+            vec![bundle.one_time_prekeys.remove(0)]
+        };
+        response.key_packages = if bundle.key_packages.is_empty() {
+            vec![]
+        } else {
+            vec![bundle.key_packages.remove(0)]
+        };
+        HttpResponse::Ok().json(response)
+    } else {
+        HttpResponse::NotFound().body("Bundle not found")
+    }
+}
+
+pub(crate) async fn publish_devices(
+    data: web::Data<AppState>,
+    web::Path(username): web::Path<String>,
+    info: web::Json<DeviceList>,
+) -> impl Responder {
+    let incoming = info.into_inner();
+    let registered_key = match data.known_users.lock().unwrap().get(&username) {
+        Some(identity) => identity.public_key.clone(),
+        None => return HttpResponse::NotFound().body("User not found"),
+    };
+    // Only the registered account key may publish the list of this user
+    if incoming.identity.username != username || hex::encode(&incoming.identity.signing_public_key) != registered_key {
+        return HttpResponse::Forbidden().body("Device list not owned by this user");
+    }
+    // Checked before the version, so nobody can lock the user out with a huge version
+    if !incoming.verify() {
+        return HttpResponse::BadRequest().body("Invalid device list signature");
+    }
+
+    let mut lists = data.device_lists.lock().unwrap();
+    if lists.get(&username).is_some_and(|current| current.version >= incoming.version) {
+        return HttpResponse::Conflict().body("A newer device list is already published");
+    }
+    lists.insert(username, incoming);
+    HttpResponse::Ok().body("Device list published")
+}
+
+pub(crate) async fn fetch_devices(
+    data: web::Data<AppState>,
+    web::Path(username): web::Path<String>,
+) -> impl Responder {
+    let lists = data.device_lists.lock().unwrap();
+    if let Some(list) = lists.get(&username) {
+        HttpResponse::Ok().json(list)
+    } else {
+        HttpResponse::NotFound().body("Device list not found")
+    }
+}
+
+pub(crate) async fn nodes(data: web::Data<AppState>) -> impl Responder {
     let nodes = data.known_nodes.lock().unwrap();
     let list: Vec<String> = nodes.iter().cloned().collect();
     HttpResponse::Ok().json(list)
 }
 
-async fn check_user(
+pub(crate) async fn check_user(
     data: web::Data<AppState>,
     web::Path(username): web::Path<String>,
 ) -> impl Responder {
@@ -125,6 +409,8 @@ pub async fn start_server() {
 
     let state = web::Data::new(AppState {
         known_users: Mutex::new(HashMap::new()),
+        prekey_bundles: Mutex::new(HashMap::new()),
+        device_lists: Mutex::new(HashMap::new()),
         active_peers: Mutex::new(HashMap::new()),
         known_nodes: Mutex::new(config.sync.initial_nodes.iter().cloned().collect()),
         config: config.clone(),
@@ -132,7 +418,7 @@ pub async fn start_server() {
 
     // Spawn cleanup task for peer TTL
     let state_clone = state.clone();
-    tokio::spawn(async move {
+    actix_rt::spawn(async move {
         let mut cleaner = interval(Duration::from_secs(60));
         loop {
             cleaner.tick().await;
@@ -151,6 +437,10 @@ pub async fn start_server() {
             .route("/announce", web::post().to(announce))
             .route("/sync", web::post().to(sync))
             .route("/nodes", web::get().to(nodes))
+            .route("/prekeys/{username}/{device}", web::post().to(publish_prekeys))
+            .route("/bundle/{username}/{device}", web::get().to(fetch_bundle))
+            .route("/devices/{username}", web::post().to(publish_devices))
+            .route("/devices/{username}", web::get().to(fetch_devices))
             .route("/check_user/{username}", web::get().to(check_user))
     })
     .bind((config.node.bind_address.as_str(), config.node.bind_port))
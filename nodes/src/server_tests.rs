@@ -1,11 +1,39 @@
 #[cfg(test)]
 mod tests {
-    use super::*;
-    use actix_web::{test, App};
-    use crate::server::{AppState, PublicIdentity, PeerPresence};
+    use actix_web::{test, web, App};
+    use crate::server::{AppState, PublicIdentity, PeerPresence, PreKeyBundle, OneTimePreKey, KeyPackage};
+    use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signer};
     use std::collections::{HashMap, HashSet};
     use std::sync::Mutex;
 
+    /// Ed25519 key pair derived from `seed`
+    fn keypair(seed: u8) -> Keypair {
+        let secret = SecretKey::from_bytes(&[seed; 32]).unwrap();
+        let public = PublicKey::from(&secret);
+        Keypair { secret, public }
+    }
+
+    /// Identity self-signed the way the client does it, with a key derived from `seed`
+    fn signed_identity(username: &str, seed: u8) -> PublicIdentity {
+        let keypair = keypair(seed);
+        let public = keypair.public;
+        let encryption_key = [seed.wrapping_add(1); 32];
+
+        let mut payload = Vec::new();
+        for field in [&b"enigma-identity-v1"[..], username.as_bytes(), &public.as_bytes()[..], &encryption_key[..]] {
+            payload.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            payload.extend_from_slice(field);
+        }
+
+        PublicIdentity {
+            username: username.to_string(),
+            public_key: hex::encode(public.as_bytes()),
+            encryption_public_key: hex::encode(encryption_key),
+            signature: hex::encode(keypair.sign(&payload).to_bytes()),
+            timestamp: chrono::Utc::now().timestamp() as u64,
+        }
+    }
+
     fn test_state() -> web::Data<AppState> {
         let dummy_config = crate::server::Config {
             node: crate::server::NodeConfig {
@@ -22,6 +50,8 @@ mod tests {
 
         web::Data::new(AppState {
             known_users: Mutex::new(HashMap::new()),
+            prekey_bundles: Mutex::new(HashMap::new()),
+            device_lists: Mutex::new(HashMap::new()),
             active_peers: Mutex::new(HashMap::new()),
             known_nodes: Mutex::new(HashSet::new()),
             config: dummy_config,
@@ -31,16 +61,11 @@ mod tests {
     #[actix_rt::test]
     async fn test_check_user() {
         let state = test_state();
-        let identity = PublicIdentity {
-            username: "testuser".to_string(),
-            public_key: "key".to_string(),
-            signature: "sig".to_string(),
-            timestamp: 0,
-        };
+        let identity = signed_identity("testuser", 1);
 
         state.known_users.lock().unwrap().insert("testuser".to_string(), identity);
 
-        let app = test::init_service(
+        let mut app = test::init_service(
             App::new()
                 .app_data(state.clone())
                 .route("/check_user/{username}", web::get().to(crate::server::check_user))
@@ -50,14 +75,14 @@ mod tests {
             .uri("/check_user/testuser")
             .to_request();
 
-        let resp = test::call_service(&app, req).await;
+        let resp = test::call_service(&mut app, req).await;
         assert_eq!(resp.status(), 200);
 
         let req_not_found = test::TestRequest::get()
             .uri("/check_user/unknown")
             .to_request();
 
-        let resp_nf = test::call_service(&app, req_not_found).await;
+        let resp_nf = test::call_service(&mut app, req_not_found).await;
         assert_eq!(resp_nf.status(), 404);
     }
 
@@ -65,7 +90,7 @@ mod tests {
     async fn test_announce() {
         let state = test_state();
 
-        let app = test::init_service(
+        let mut app = test::init_service(
             App::new()
                 .app_data(state.clone())
                 .route("/announce", web::post().to(crate::server::announce))
@@ -82,31 +107,25 @@ mod tests {
             .set_json(&peer)
             .to_request();
 
-        let resp = test::call_service(&app, req).await;
+        let resp = test::call_service(&mut app, req).await;
         assert_eq!(resp.status(), 200);
 
         let peers = state.active_peers.lock().unwrap();
         assert!(peers.contains_key("1.2.3.4"));
     }
-}
 
     #[actix_rt::test]
     async fn test_register_and_resolve() {
         let state = test_state();
 
-        let app = test::init_service(
+        let mut app = test::init_service(
             App::new()
                 .app_data(state.clone())
                 .route("/register", web::post().to(crate::server::register))
                 .route("/resolve/{username}", web::get().to(crate::server::resolve))
         ).await;
 
-        let identity = PublicIdentity {
-            username: "john_doe".to_string(),
-            public_key: "pubkey123".to_string(),
-            signature: "sigXYZ".to_string(),
-            timestamp: chrono::Utc::now().timestamp() as u64,
-        };
+        let identity = signed_identity("john_doe", 1);
 
         // Register the identity
         let req = test::TestRequest::post()
@@ -114,7 +133,7 @@ mod tests {
             .set_json(&identity)
             .to_request();
 
-        let resp = test::call_service(&app, req).await;
+        let resp = test::call_service(&mut app, req).await;
         assert_eq!(resp.status(), 200);
 
         // Resolve the identity
@@ -122,7 +141,7 @@ mod tests {
             .uri("/resolve/john_doe")
             .to_request();
 
-        let resolve_resp = test::call_service(&app, resolve_req).await;
+        let resolve_resp = test::call_service(&mut app, resolve_req).await;
         assert_eq!(resolve_resp.status(), 200);
 
         // Try resolving unknown user
@@ -130,40 +149,89 @@ mod tests {
             .uri("/resolve/unknown_user")
             .to_request();
 
-        let unknown_resp = test::call_service(&app, unknown).await;
+        let unknown_resp = test::call_service(&mut app, unknown).await;
         assert_eq!(unknown_resp.status(), 404);
+
+        // Registering again with the same key is accepted, another key is not
+        let req = test::TestRequest::post()
+            .uri("/register")
+            .set_json(&identity)
+            .to_request();
+        assert_eq!(test::call_service(&mut app, req).await.status(), 200);
+
+        let req = test::TestRequest::post()
+            .uri("/register")
+            .set_json(&signed_identity("john_doe", 2))
+            .to_request();
+        assert_eq!(test::call_service(&mut app, req).await.status(), 409);
+    }
+
+    #[actix_rt::test]
+    async fn test_register_rejects_forged_identity() {
+        let state = test_state();
+
+        let mut app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .route("/register", web::post().to(crate::server::register))
+        ).await;
+
+        // A signature made for another username
+        let mut renamed = signed_identity("alice", 1);
+        renamed.username = "mallory".to_string();
+
+        // Someone else's encryption key under a valid signing key
+        let mut swapped = signed_identity("bob", 2);
+        swapped.encryption_public_key = signed_identity("eve", 3).encryption_public_key;
+
+        let mut garbage = signed_identity("carol", 4);
+        garbage.signature = "sigXYZ".to_string();
+
+        for forged in [renamed, swapped, garbage] {
+            let req = test::TestRequest::post()
+                .uri("/register")
+                .set_json(&forged)
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), 400);
+        }
+        assert!(state.known_users.lock().unwrap().is_empty());
     }
 
     #[actix_rt::test]
     async fn test_sync() {
         let state = test_state();
 
-        let app = test::init_service(
+        let mut app = test::init_service(
             App::new()
                 .app_data(state.clone())
                 .route("/sync", web::post().to(crate::server::sync))
         ).await;
 
         let mut identities = HashMap::new();
-        let user = PublicIdentity {
-            username: "sync_user".to_string(),
-            public_key: "abc123".to_string(),
-            signature: "sig".to_string(),
-            timestamp: 123456789,
-        };
+        let user = signed_identity("sync_user", 1);
         identities.insert("sync_user".to_string(), user.clone());
 
+        // Unsigned entries, or entries filed under another name, are dropped
+        let mut forged = signed_identity("forged_user", 2);
+        forged.signature = "sig".to_string();
+        identities.insert("forged_user".to_string(), forged);
+        identities.insert("squatted_user".to_string(), signed_identity("other_user", 3));
+
         let req = test::TestRequest::post()
             .uri("/sync")
             .set_json(&identities)
             .to_request();
 
-        let resp = test::call_service(&app, req).await;
+        let resp = test::call_service(&mut app, req).await;
         assert_eq!(resp.status(), 200);
 
         let users = state.known_users.lock().unwrap();
         assert!(users.contains_key("sync_user"));
-        assert_eq!(users.get("sync_user").unwrap().public_key, "abc123");
+        assert_eq!(users.get("sync_user").unwrap().public_key, user.public_key);
+        assert!(!users.contains_key("forged_user"));
+        assert!(!users.contains_key("squatted_user"));
+        assert_eq!(users.len(), 1);
     }
 
     #[actix_rt::test]
@@ -175,7 +243,7 @@ mod tests {
             nodes.insert("https://node2.test:1488".to_string());
         }
 
-        let app = test::init_service(
+        let mut app = test::init_service(
             App::new()
                 .app_data(state.clone())
                 .route("/nodes", web::get().to(crate::server::nodes))
@@ -185,10 +253,155 @@ mod tests {
             .uri("/nodes")
             .to_request();
 
-        let resp = test::call_service(&app, req).await;
+        let resp = test::call_service(&mut app, req).await;
         assert_eq!(resp.status(), 200);
 
         let body: Vec<String> = test::read_body_json(resp).await;
         assert!(body.contains(&"https://node1.test:1488".to_string()));
         assert!(body.contains(&"https://node2.test:1488".to_string()));
     }
+
+    #[actix_rt::test]
+    async fn test_publish_and_fetch_bundle() {
+        let state = test_state();
+        state.known_users.lock().unwrap().insert("bob".to_string(), signed_identity("bob", 1));
+
+        let mut app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .route("/prekeys/{username}/{device}", web::post().to(crate::server::publish_prekeys))
+                .route("/bundle/{username}/{device}", web::get().to(crate::server::fetch_bundle))
+        ).await;
+
+        let bob = keypair(1);
+        let bundle = PreKeyBundle {
+            identity_pub: bob.public.as_bytes().to_vec(),
+            identity_dh_pub: vec![2; 32],
+            identity_dh_signature: bob.sign(&[2; 32]).to_bytes().to_vec(),
+            spk_id: 0,
+            spk_pub: vec![4; 32],
+            spk_signature: bob.sign(&[4; 32]).to_bytes().to_vec(),
+            one_time_prekeys: vec![
+                OneTimePreKey { id: 0, public: vec![6; 32] },
+                OneTimePreKey { id: 1, public: vec![7; 32] },
+            ],
+            key_packages: vec![KeyPackage { init_key: vec![8; 32], rest: serde_json::Map::new() }],
+        };
+
+        // Only the registered key publishes the bundle of the primary device, and its
+        // prekeys must be signed
+        let mallory = keypair(9);
+        let forged = PreKeyBundle {
+            identity_pub: mallory.public.as_bytes().to_vec(),
+            identity_dh_signature: mallory.sign(&[2; 32]).to_bytes().to_vec(),
+            spk_signature: mallory.sign(&[4; 32]).to_bytes().to_vec(),
+            ..bundle.clone()
+        };
+        let unsigned = PreKeyBundle { spk_signature: vec![5; 64], ..bundle.clone() };
+        let colliding = PreKeyBundle {
+            one_time_prekeys: vec![OneTimePreKey { id: 3, public: vec![6; 32] }; 2],
+            ..bundle.clone()
+        };
+        for (uri, body, status) in [
+            ("/prekeys/bob/1", &forged, 403),
+            ("/prekeys/bob/2", &bundle, 403),
+            ("/prekeys/bob/1", &unsigned, 400),
+            ("/prekeys/bob/1", &colliding, 400),
+            ("/prekeys/bob/1", &bundle, 200),
+        ] {
+            let req = test::TestRequest::post().uri(uri).set_json(body).to_request();
+            assert_eq!(test::call_service(&mut app, req).await.status(), status, "{}", uri);
+        }
+
+        // Each device has its own bundle
+        let req = test::TestRequest::get().uri("/bundle/bob/2").to_request();
+        assert_eq!(test::call_service(&mut app, req).await.status(), 404);
+
+        // Each fetch hands out a different one-time prekey, then none once exhausted; key
+        // packages likewise
+        for (expected, package) in [(Some(0), true), (Some(1), false), (None, false)] {
+            let req = test::TestRequest::get().uri("/bundle/bob/1").to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), 200);
+
+            let fetched: PreKeyBundle = test::read_body_json(resp).await;
+            assert_eq!(fetched.one_time_prekeys.first().map(|k| k.id), expected);
+            assert!(fetched.one_time_prekeys.len() <= 1);
+            assert_eq!(fetched.key_packages.len(), package as usize);
+        }
+
+        // Publishing for an unregistered user is refused
+        let req = test::TestRequest::post()
+            .uri("/prekeys/unknown/1")
+            .set_json(&bundle)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), 404);
+    }
+
+    #[actix_rt::test]
+    async fn test_publish_and_fetch_devices() {
+        let state = test_state();
+        let bob = signed_identity("bob", 1);
+        state.known_users.lock().unwrap().insert("bob".to_string(), bob.clone());
+
+        let mut app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .route("/devices/{username}", web::post().to(crate::server::publish_devices))
+                .route("/devices/{username}", web::get().to(crate::server::fetch_devices))
+        ).await;
+
+        // Single-device list signed the way the client does it, with the key of `seed`
+        let list = |version: u64, seed: u8| {
+            let keypair = keypair(seed);
+            let key = keypair.public.as_bytes().to_vec();
+            let mut payload = Vec::new();
+            let put_field = |payload: &mut Vec<u8>, field: &[u8]| {
+                payload.extend_from_slice(&(field.len() as u32).to_be_bytes());
+                payload.extend_from_slice(field);
+            };
+            put_field(&mut payload, b"enigma-devices-v1");
+            put_field(&mut payload, b"bob");
+            put_field(&mut payload, &key);
+            payload.extend_from_slice(&version.to_be_bytes());
+            payload.extend_from_slice(&1u32.to_be_bytes());
+            payload.extend_from_slice(&1u32.to_be_bytes());
+            put_field(&mut payload, b"phone");
+            put_field(&mut payload, &key);
+            let encryption_key = vec![2u8; 32];
+            put_field(&mut payload, &encryption_key);
+            serde_json::json!({
+                "identity": { "username": "bob", "signing_public_key": key, "signature": [1, 2] },
+                "version": version,
+                "devices": [{ "id": 1, "name": "phone", "signing_public_key": key, "encryption_public_key": encryption_key }],
+                "signature": keypair.sign(&payload).to_bytes().to_vec(),
+            })
+        };
+
+        let published = list(2, 1);
+        let req = test::TestRequest::post().uri("/devices/bob").set_json(&published).to_request();
+        assert_eq!(test::call_service(&mut app, req).await.status(), 200);
+
+        // Older or replayed lists, lists under another account key and unsigned lists are refused
+        let req = test::TestRequest::post().uri("/devices/bob").set_json(&list(1, 1)).to_request();
+        assert_eq!(test::call_service(&mut app, req).await.status(), 409);
+        let req = test::TestRequest::post().uri("/devices/bob").set_json(&list(3, 9)).to_request();
+        assert_eq!(test::call_service(&mut app, req).await.status(), 403);
+        let req = test::TestRequest::post().uri("/devices/carol").set_json(&list(1, 1)).to_request();
+        assert_eq!(test::call_service(&mut app, req).await.status(), 404);
+        let mut forged = list(3, 1);
+        forged["version"] = serde_json::json!(u64::MAX);
+        let req = test::TestRequest::post().uri("/devices/bob").set_json(&forged).to_request();
+        assert_eq!(test::call_service(&mut app, req).await.status(), 400);
+
+        // The list is served back untouched
+        let req = test::TestRequest::get().uri("/devices/bob").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), 200);
+        let fetched: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(fetched["version"], 2);
+        assert_eq!(fetched["signature"], published["signature"]);
+        assert_eq!(fetched["identity"]["signature"], serde_json::json!([1, 2]));
+    }
+}
@@ -1,81 +1,924 @@
-use crate::crypto::encryption::EncryptionEngine;
-use crate::crypto::ratchet::Ratchet;
-use crate::crypto::signature::{SigningKey, verify_signature};
-use crate::crypto::handshake::{generate_identity_bundle, x3dh_initiate};
-use crate::network::webrtc_client::WebRTCClient;
-use crate::network::signaling::{SignalMessage, SignalingSession};
+use crate::crypto::signature::SigningKey;
+use crate::crypto::fingerprint::SafetyNumber;
+use crate::crypto::handshake::{
+    generate_identity_bundle, IdentityKey, KeyMaterial, OneTimePreKeyStore, SignedPreKeyStore,
+    ONE_TIME_PREKEY_BATCH,
+};
+use crate::crypto::padding::{PaddingPolicy, PaddingScheme};
+use crate::crypto::sealed::SealedEnvelope;
+use crate::crypto::session::{SessionEnvelope, SessionManager};
+use crate::network::directory::{NodeDirectory, PreKeyDirectory};
+use crate::network::webrtc_client::{WebRTC, WebRTCClient};
+use crate::storage::account::{AccountStore, PendingDevice};
 use crate::storage::db::Storage;
+use crate::storage::contacts::{ContactStore, IdentityObservation};
+use crate::storage::groups::GroupStore;
+use crate::storage::conversations::ConversationStore;
+use crate::storage::messages::{MessageStore, StoredMessage};
+use crate::storage::sessions::SessionStore;
+use crate::storage::vault::RotationProgress;
 use crate::models::user::{LocalUser, PublicIdentity};
-use crate::models::message::{Message, MessageType};
+use crate::models::message::{Message, MessageError, MessageType};
+use crate::models::contact::Contact;
+use crate::models::conversation::Conversation;
+use crate::models::group::Group;
+use crate::models::device::{DeviceError, DeviceId, DeviceLinkApproval, DeviceLinkRequest, DeviceList, PRIMARY_DEVICE};
+use crate::models::invite::GroupInvite;
+use crate::groups::GroupService;
 
-use anyhow::Result;
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use anyhow::{Result, anyhow};
+use ed25519_dalek::PublicKey as EdPublicKey;
+use ring::aead::NONCE_LEN;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use uuid::Uuid;
+use tokio::sync::{broadcast, mpsc, Mutex};
+
+/// Capacity of the event channel towards the UI layer
+const EVENT_CHANNEL_CAPACITY: usize = 256;
+
+/// Name of the device that creates an account
+const PRIMARY_DEVICE_NAME: &str = "Primary";
+
+/// MLS key packages published by `announce`
+const KEY_PACKAGE_BATCH: usize = 10;
+
+/// Events emitted by the core towards the UI layer
+#[derive(Debug, Clone)]
+pub enum AppEvent {
+    /// A message was received, verified, decrypted and stored
+    MessageReceived { message: Message, plaintext: Vec<u8> },
+    /// Raw inbound data was rejected
+    InboundRejected { reason: String },
+    /// A conversation of the list changed (new message, read, flags)
+    ConversationUpdated { conversation: Conversation },
+    /// A known contact presented another identity key: the user must be warned, and its
+    /// messages are refused until it is approved with `approve_identity_change`
+    IdentityKeyChanged { contact: Contact, previous_key: Vec<u8> },
+    /// A data key rotation advanced (`done == total` when finished)
+    DataKeyRotation { progress: RotationProgress },
+    /// A group was created, joined or changed
+    GroupUpdated { group: Group },
+    /// We left a group or were removed from it
+    GroupRemoved { group_id: Uuid },
+    /// An invite to a group was received (see `pending_group_invites`)
+    GroupInviteReceived { invite: GroupInvite },
+    /// An invitee declined one of our invites
+    GroupInviteDeclined { group_id: Uuid, username: String },
+    /// An owner or admin refused our acceptance of an invite
+    GroupInviteRejected { group_id: Uuid, reason: String },
+}
 
 /// Global state of the Enigma client
 pub struct EnigmaApp {
     pub user: LocalUser,
     pub storage: Arc<Storage>,
-    pub webrtc: Arc<WebRTCClient>,
-    pub encryption: Mutex<EncryptionEngine>,
-    pub ratchet: Mutex<Ratchet>,
+    pub webrtc: Arc<dyn WebRTC>,
+    pub sessions: Mutex<SessionManager>,
+    pub messages: Arc<MessageStore>,
+    pub conversations: Arc<ConversationStore>,
+    pub contacts: Arc<ContactStore>,
+    pub groups: Arc<GroupStore>,
     pub signing: Arc<SigningKey>,
+    pub events: broadcast::Sender<AppEvent>,
+    pub sealed_sender: AtomicBool, // Hide the sender from transport metadata
+    pub padding: RwLock<PaddingPolicy>,
 }
 
 impl EnigmaApp {
-    /// Initializes a new EnigmaApp context with full X3DH key derivation
-    pub async fn init(storage_path: &str, username: &str) -> Result<Self> {
-        let storage = Arc::new(Storage::open(storage_path)?);
+    /// Opens the account stored at `storage_path`, or creates it on first run,
+    /// using the default signaling nodes. `passphrase` unlocks the encrypted storage.
+    pub async fn init(storage_path: &str, username: &str, passphrase: &str) -> Result<Self> {
+        let directory = Arc::new(NodeDirectory::with_default_nodes()?);
+        Self::init_with_directory(storage_path, username, passphrase, directory).await
+    }
 
+    /// Same as `init`, fetching prekey bundles from `directory`
+    pub async fn init_with_directory(
+        storage_path: &str,
+        username: &str,
+        passphrase: &str,
+        directory: Arc<dyn PreKeyDirectory>,
+    ) -> Result<Self> {
+        let storage = Self::unlock_storage(storage_path, passphrase)?;
+        let account = AccountStore::new(Arc::new(storage.persistence()));
+
+        match account.load_user()? {
+            Some(user) if user.username != username => Err(anyhow!(
+                "Storage belongs to {}, not {}",
+                user.username,
+                username
+            )),
+            Some(_) => Self::load(storage, directory).await,
+            None if account.load_pending_link()?.is_some() => Self::load(storage, directory).await,
+            None => Self::register(storage, username, directory).await,
+        }
+    }
+
+    /// First-run account creation: generates and persists the identity and prekeys
+    pub async fn create(
+        storage_path: &str,
+        username: &str,
+        passphrase: &str,
+        directory: Arc<dyn PreKeyDirectory>,
+    ) -> Result<Self> {
+        let storage = Self::unlock_storage(storage_path, passphrase)?;
+        if AccountStore::new(Arc::new(storage.persistence())).exists()? {
+            return Err(anyhow!("An account already exists in {}", storage_path));
+        }
+        Self::register(storage, username, directory).await
+    }
+
+    /// Opens an existing account, loading its identity and prekeys back
+    pub async fn open(
+        storage_path: &str,
+        passphrase: &str,
+        directory: Arc<dyn PreKeyDirectory>,
+    ) -> Result<Self> {
+        let storage = Self::unlock_storage(storage_path, passphrase)?;
+        Self::load(storage, directory).await
+    }
+
+    /// Opens the storage and unlocks it: nothing can be read before this step
+    fn unlock_storage(storage_path: &str, passphrase: &str) -> Result<Arc<Storage>> {
+        let storage = Storage::open(storage_path)?;
+        storage.unlock(passphrase)?;
+        Ok(Arc::new(storage))
+    }
+
+    async fn register(
+        storage: Arc<Storage>,
+        username: &str,
+        directory: Arc<dyn PreKeyDirectory>,
+    ) -> Result<Self> {
         // Generate keys (X3DH)
-        let (identity_key, signed_prekey, bundle) = generate_identity_bundle()?;
-        let x3dh = x3dh_initiate(&bundle)?; // derive key from own bundle (loopback for now)
+        let (identity_key, signed_prekey, one_time_prekeys, _bundle) = generate_identity_bundle()?;
 
-        // Use derived key for encryption/ratchet
-        let ratchet = Ratchet::new(&x3dh.shared_secret);
-        let encryption = EncryptionEngine::new(&x3dh.shared_secret)?;
+        // The primary device's identity key is the account key: it signs the device list
+        let devices = DeviceList::new(
+            identity_key.public_identity(username)?,
+            PRIMARY_DEVICE_NAME,
+            &identity_key.signing_key()?,
+        );
 
         // Build local user
         let user = LocalUser {
             uuid: uuid::Uuid::new_v4(),
             username: username.to_owned(),
+            device_id: PRIMARY_DEVICE,
+            device_name: PRIMARY_DEVICE_NAME.to_owned(),
             signing_private_key: identity_key.keypair.secret.to_bytes().to_vec(),
-            encryption_private_key: signed_prekey.secret.to_bytes().to_vec(),
-            encryption_public_key: signed_prekey.public.as_bytes().to_vec(),
         };
 
+        let signed_prekeys = SignedPreKeyStore::new(signed_prekey);
+        let account = AccountStore::new(Arc::new(storage.persistence()));
+        account.save_keys(&KeyMaterial::export(&identity_key, &signed_prekeys, &one_time_prekeys))?;
+        account.save_devices(&devices)?;
+        account.save_user(&user)?;
+
+        Self::assemble(storage, user, (identity_key, signed_prekeys, one_time_prekeys), devices, directory).await
+    }
+
+    /// Starts adding this new device to an existing account: generates its keys, saves them
+    /// and returns the request to show as a QR code to the primary device. A link started
+    /// before a restart is resumed with the same keys.
+    pub fn begin_link(
+        storage_path: &str,
+        username: &str,
+        passphrase: &str,
+        device_name: &str,
+        directory: Arc<dyn PreKeyDirectory>,
+    ) -> Result<PendingLink> {
+        let storage = Self::unlock_storage(storage_path, passphrase)?;
+        let account = AccountStore::new(Arc::new(storage.persistence()));
+        if account.exists()? {
+            return Err(anyhow!("An account already exists in {}", storage_path));
+        }
+        if let Some(pending) = PendingLink::restore(storage.clone(), directory.clone())? {
+            if pending.username == username {
+                return Ok(pending);
+            }
+        }
+
+        let (identity_key, signed_prekey, one_time_prekeys, _bundle) = generate_identity_bundle()?;
+        let pending = PendingLink {
+            storage,
+            username: username.to_owned(),
+            device_name: device_name.to_owned(),
+            keys: (identity_key, SignedPreKeyStore::new(signed_prekey), one_time_prekeys),
+            account_key: None,
+            directory,
+        };
+        pending.save()?;
+        Ok(pending)
+    }
+
+    async fn load(storage: Arc<Storage>, directory: Arc<dyn PreKeyDirectory>) -> Result<Self> {
+        let account = AccountStore::new(Arc::new(storage.persistence()));
+        let user = match account.load_user()? {
+            Some(user) => user,
+            // A device linked while this one was closed finishes the link with its saved keys
+            None => match PendingLink::restore(storage.clone(), directory.clone())? {
+                Some(pending) => return pending.complete().await,
+                None => return Err(anyhow!("No account in this storage")),
+            },
+        };
+        let keys = account
+            .load_keys()?
+            .ok_or_else(|| anyhow!("Account keys are missing"))?
+            .restore()?;
+        let devices = account
+            .load_devices()?
+            .ok_or_else(|| anyhow!("Account device list is missing"))?;
+
+        Self::assemble(storage, user, keys, devices, directory).await
+    }
+
+    async fn assemble(
+        storage: Arc<Storage>,
+        user: LocalUser,
+        (identity_key, signed_prekeys, one_time_prekeys): (IdentityKey, SignedPreKeyStore, OneTimePreKeyStore),
+        devices: DeviceList,
+        directory: Arc<dyn PreKeyDirectory>,
+    ) -> Result<Self> {
         let webrtc = Arc::new(WebRTCClient::new().await?);
 
+        // Per-peer ratchet sessions, restored from a previous run when present
+        let persistence = Arc::new(storage.persistence());
+        let session_store = Arc::new(SessionStore::new(persistence.clone()));
+        let account = Arc::new(AccountStore::new(persistence.clone()));
+        let messages = Arc::new(MessageStore::new(persistence.clone()));
+        let conversations = Arc::new(ConversationStore::new(persistence.clone()));
+        let contacts = Arc::new(ContactStore::new(persistence.clone()));
+        let groups = Arc::new(GroupStore::new(persistence));
+        let signing = identity_key.signing_key()?;
+        let sessions = SessionManager::new(
+            user.device_id,
+            devices,
+            identity_key,
+            signed_prekeys,
+            one_time_prekeys,
+            session_store,
+            account,
+            directory,
+        )?;
+
         Ok(Self {
             user,
             storage,
             webrtc,
-            encryption: Mutex::new(encryption),
-            ratchet: Mutex::new(ratchet),
-            signing: Arc::new(SigningKey {
-                key_pair: identity_key.keypair,
-            }),
+            sessions: Mutex::new(sessions),
+            messages,
+            conversations,
+            contacts,
+            groups,
+            signing: Arc::new(signing),
+            events: broadcast::channel(EVENT_CHANNEL_CAPACITY).0,
+            sealed_sender: AtomicBool::new(false),
+            padding: RwLock::new(PaddingPolicy::default()),
+        })
+    }
+
+    /// Changes the passphrase unlocking the local storage
+    pub fn change_passphrase(&self, old: &str, new: &str) -> Result<()> {
+        self.storage.change_passphrase(old, new)
+    }
+
+    /// Re-encrypts the local storage with a fresh data key in a background task,
+    /// reporting progress as `AppEvent::DataKeyRotation`
+    pub fn rotate_data_key(&self) -> tokio::task::JoinHandle<Result<()>> {
+        let storage = self.storage.clone();
+        let events = self.events.clone();
+        tokio::task::spawn_blocking(move || {
+            storage.rotate_data_key(|progress| {
+                let _ = events.send(AppEvent::DataKeyRotation { progress });
+            })
         })
     }
 
-    /// Sends a message to a peer
+    /// Enables or disables sealed sender: outgoing messages are wrapped so that only the
+    /// recipient device learns who sent them
+    pub fn set_sealed_sender(&self, enabled: bool) {
+        self.sealed_sender.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Replaces the padding scheme applied to each message type
+    pub fn set_padding_policy(&self, policy: PaddingPolicy) {
+        if let Ok(mut padding) = self.padding.write() {
+            *padding = policy;
+        }
+    }
+
+    /// Padding scheme currently applied to a message type
+    pub fn padding_scheme(&self, msg_type: &MessageType) -> PaddingScheme {
+        self.padding
+            .read()
+            .map(|policy| policy.scheme_for(msg_type))
+            .unwrap_or_default()
+    }
+
+    /// Sends a message to every device of a peer
     pub async fn send_message(&self, to: &str, plaintext: &[u8]) -> Result<Message> {
-        let mut encryption = self.encryption.lock().await;
-        let encrypted = encryption.encrypt(plaintext, b"message")?;
-        let nonce = &encrypted[encrypted.len() - 12..];
-
-        let msg = Message {
-            id: uuid::Uuid::new_v4(),
-            sender: self.user.username.clone(),
-            receiver: to.to_owned(),
-            timestamp: chrono::Utc::now(),
-            msg_type: MessageType::Text,
-            encrypted_payload: encrypted,
-            nonce: nonce.to_vec(),
-            signature: None,
+        let msg = self.send_direct(to, MessageType::Text, plaintext).await?;
+        self.record_message(
+            to,
+            StoredMessage { message: msg.clone(), plaintext: plaintext.to_vec(), outgoing: true },
+        )?;
+        Ok(msg)
+    }
+
+    /// Encrypts a payload for every device of a peer and sends one signed copy per device,
+    /// without recording it in the history
+    pub(crate) async fn send_direct(&self, to: &str, msg_type: MessageType, plaintext: &[u8]) -> Result<Message> {
+        let (envelopes, peer_identity) = {
+            let mut sessions = self.sessions.lock().await;
+            let padding = self.padding_scheme(&msg_type);
+            let envelopes = sessions.encrypt_padded(to, plaintext, padding).await?;
+            (envelopes, sessions.peer_public_identity(to))
         };
+        // New session: the peer's identity comes from its freshly fetched device list
+        if let (true, Some(identity)) = (envelopes.iter().any(|e| e.prekey.is_some()), peer_identity) {
+            self.observe_identity(&identity)?;
+        }
 
-        self.webrtc.send_message(&bincode::serialize(&msg)?).await?;
-        Ok(msg)
+        // One copy per device, sharing the id and timestamp of the logical message
+        let id = uuid::Uuid::new_v4();
+        let timestamp = chrono::Utc::now();
+        let mut sent = None;
+        for envelope in envelopes {
+            let mut msg = Message {
+                id,
+                sender: self.user.username.clone(),
+                receiver: to.to_owned(),
+                timestamp,
+                msg_type: msg_type.clone(),
+                nonce: envelope.message.ciphertext[..NONCE_LEN].to_vec(),
+                encrypted_payload: bincode::serialize(&envelope)?,
+                signature: None,
+            };
+            msg.sign(&self.signing);
+
+            let mut wire = bincode::serialize(&msg)?;
+            if self.sealed_sender.load(Ordering::Relaxed) {
+                wire = self
+                    .sessions
+                    .lock()
+                    .await
+                    .seal(to, envelope.receiver_device, &wire)?
+                    .to_bytes()?;
+            }
+            self.webrtc.send_message(&wire).await?;
+            sent.get_or_insert(msg);
+        }
+
+        sent.ok_or_else(|| anyhow!("{} has no device", to))
+    }
+
+    /// Decrypts a received message with the session of its sender
+    pub async fn decrypt_message(&self, msg: &Message) -> Result<Vec<u8>> {
+        let envelope: SessionEnvelope = bincode::deserialize(&msg.encrypted_payload)?;
+        self.sessions.lock().await.decrypt(&msg.sender, &envelope).await
+    }
+
+    /// Subscribes to the events emitted by the core
+    pub fn subscribe(&self) -> broadcast::Receiver<AppEvent> {
+        self.events.subscribe()
+    }
+
+    /// Registers the data-channel handler and returns the queue of raw inbound messages,
+    /// to be fed to `handle_incoming`
+    pub fn inbound_channel(&self) -> mpsc::UnboundedReceiver<Vec<u8>> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.webrtc.on_message(tx);
+        rx
+    }
+
+    /// Inbound pipeline: unseal, deserialize, verify the sender, decrypt, store and dispatch
+    pub async fn handle_incoming(&self, raw: &[u8]) -> Result<(Message, Vec<u8>)> {
+        match self.process_incoming(raw).await {
+            Ok((message, plaintext)) => {
+                if !message.msg_type.is_control() {
+                    let _ = self.events.send(AppEvent::MessageReceived {
+                        message: message.clone(),
+                        plaintext: plaintext.clone(),
+                    });
+                }
+                Ok((message, plaintext))
+            }
+            Err(e) => {
+                let _ = self.events.send(AppEvent::InboundRejected {
+                    reason: e.to_string(),
+                });
+                Err(e)
+            }
+        }
+    }
+
+    async fn process_incoming(&self, raw: &[u8]) -> Result<(Message, Vec<u8>)> {
+        let msg: Message = if SealedEnvelope::is_sealed(raw) {
+            let sealed = SealedEnvelope::from_bytes(raw)?;
+            let inner = self.sessions.lock().await.open_sealed(&sealed)?;
+            bincode::deserialize(&inner)?
+        } else {
+            bincode::deserialize(raw)?
+        };
+        // Group messages are addressed to the group id and encrypted with sender keys
+        if let Ok(group_id) = msg.receiver.parse::<Uuid>() {
+            return self.group_service().process_group_message(group_id, msg).await;
+        }
+        if msg.receiver != self.user.username {
+            return Err(anyhow!("Message addressed to {}, not to us", msg.receiver));
+        }
+
+        let envelope: SessionEnvelope = bincode::deserialize(&msg.encrypted_payload)?;
+        let mut sessions = self.sessions.lock().await;
+
+        // A key we already know for the sender wins over the one of an X3DH header, which
+        // is only used on first contact
+        let contact = self.contacts.get(&msg.sender)?;
+        let known_key = sessions.device_key(&msg.sender, envelope.sender_device).or_else(|| {
+            contact
+                .as_ref()
+                .filter(|_| envelope.sender_device == PRIMARY_DEVICE)
+                .and_then(|c| EdPublicKey::from_bytes(&c.identity.signing_public_key).ok())
+        });
+        let presented_key = envelope.prekey.as_ref().map(|h| h.identity_pub);
+        let presented_account = envelope.sender_devices.as_ref().map(|devices| &devices.identity);
+        let mismatch = matches!((known_key, presented_key), (Some(known), Some(presented)) if known != presented)
+            || matches!((&contact, presented_account), (Some(c), Some(account)) if c.identity.signing_public_key != account.signing_public_key);
+        if mismatch {
+            drop(sessions);
+            if let Some(account) = presented_account {
+                self.hold_identity_change(account);
+            }
+            return Err(MessageError::IdentityMismatch(msg.sender.clone()).into());
+        }
+        self.verify_sender(&msg, known_key.or(presented_key))?;
+
+        // A device list signed by another account key than the pinned one is held as well
+        let plaintext = match sessions.decrypt(&msg.sender, &envelope).await {
+            Err(e) if matches!(e.downcast_ref::<DeviceError>(), Some(DeviceError::AccountKeyMismatch(_))) => {
+                drop(sessions);
+                if let Some(account) = presented_account {
+                    self.hold_identity_change(account);
+                }
+                return Err(MessageError::IdentityMismatch(msg.sender.clone()).into());
+            }
+            result => result?,
+        };
+        // A new session carries the sender's identity, verified by the session manager
+        let new_identity = envelope
+            .prekey
+            .as_ref()
+            .and_then(|_| sessions.peer_public_identity(&msg.sender));
+        drop(sessions);
+
+        if let Some(identity) = new_identity {
+            self.observe_identity(&identity)?;
+        }
+
+        if msg.msg_type.is_control() {
+            self.group_service().process_control(&msg, envelope.sender_device, &plaintext).await?;
+            return Ok((msg, plaintext));
+        }
+        if matches!(msg.msg_type, MessageType::GroupInvite) {
+            self.group_service()
+                .receive_invite(&msg.sender, envelope.sender_device, bincode::deserialize(&plaintext)?)
+                .await?;
+        }
+        self.store_message(&msg, &plaintext)?;
+        Ok((msg, plaintext))
+    }
+
+    /// Our self-signed public identity, as recorded by our contacts
+    pub async fn public_identity(&self) -> PublicIdentity {
+        self.sessions.lock().await.local_identity().clone()
+    }
+
+    /// Registers our identity and device list, and publishes a fresh batch of one-time
+    /// prekeys and MLS key packages for this device on the nodes
+    pub async fn announce(&self) -> Result<()> {
+        let key_packages = self.group_service().new_key_packages(KEY_PACKAGE_BATCH)?;
+        let mut sessions = self.sessions.lock().await;
+        sessions.publish_identity().await?;
+        sessions.publish_devices().await?;
+        sessions
+            .publish_prekeys_with(&self.user.username, ONE_TIME_PREKEY_BATCH, key_packages)
+            .await
+    }
+
+    /// Devices of our account
+    pub async fn devices(&self) -> DeviceList {
+        self.sessions.lock().await.local_devices().clone()
+    }
+
+    /// Adds the device whose link QR code was scanned (primary device only). Returns the
+    /// approval to show as a QR code to the new device, which pins the account key with it.
+    pub async fn link_device(&self, scanned: &[u8]) -> Result<DeviceLinkApproval> {
+        let request = DeviceLinkRequest::from_payload(scanned)?;
+        if request.username != self.user.username {
+            return Err(anyhow!("Link request is for {}, not {}", request.username, self.user.username));
+        }
+        let device = self.edit_devices(|devices, key| {
+            if devices.devices.iter().any(|d| d.signing_public_key == request.signing_public_key) {
+                return Err(anyhow!("Device {} is already linked", request.name));
+            }
+            Ok(devices.add(&request.name, request.signing_public_key, request.encryption_public_key, key))
+        })
+        .await?;
+        Ok(DeviceLinkApproval {
+            username: self.user.username.clone(),
+            device,
+            account_key: self.signing.public_key_bytes().to_vec(),
+        })
+    }
+
+    /// Removes a device from the account (primary device only). Peers drop their sessions
+    /// with it when they fetch the new list.
+    pub async fn revoke_device(&self, device: DeviceId) -> Result<()> {
+        self.edit_devices(|devices, key| Ok(devices.revoke(device, key)?)).await
+    }
+
+    /// Applies a change to our device list, signs it with the account key and publishes it
+    async fn edit_devices<T>(&self, change: impl FnOnce(&mut DeviceList, &SigningKey) -> Result<T>) -> Result<T> {
+        if self.user.device_id != PRIMARY_DEVICE {
+            return Err(anyhow!("Only the primary device can change the device list"));
+        }
+        let mut sessions = self.sessions.lock().await;
+        let mut devices = sessions.local_devices().clone();
+        let result = change(&mut devices, &self.signing)?;
+        sessions.update_local_devices(devices)?;
+        sessions.publish_devices().await?;
+        Ok(result)
+    }
+
+    /// Fetches the device list of a peer again, e.g. after it linked or revoked a device
+    pub async fn refresh_devices(&self, username: &str) -> Result<()> {
+        self.sessions.lock().await.refresh_devices(username).await
+    }
+
+    /// Rotates the signed prekey once it is due and publishes the new one (the nodes keep
+    /// the unspent one-time prekeys). Returns whether a new signed prekey was published.
+    pub async fn rotate_signed_prekey_if_due(&self) -> Result<bool> {
+        let mut sessions = self.sessions.lock().await;
+        if !sessions.rotate_signed_prekey_if_due(chrono::Utc::now().timestamp())? {
+            return Ok(false);
+        }
+        sessions.publish_prekeys(&self.user.username, 0).await?;
+        Ok(true)
+    }
+
+    /// Runs `rotate_signed_prekey_if_due` every `check_every` in a background task
+    pub fn schedule_prekey_rotation(self: &Arc<Self>, check_every: Duration) -> tokio::task::JoinHandle<()> {
+        let app = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(check_every);
+            loop {
+                ticker.tick().await;
+                let Some(app) = app.upgrade() else { break };
+                // Nodes may be unreachable: the next tick tries again
+                let _ = app.rotate_signed_prekey_if_due().await;
+            }
+        })
+    }
+
+    /// Safety number of the conversation with a contact, to compare out of band
+    pub async fn safety_number(&self, username: &str) -> Result<SafetyNumber> {
+        let contact = self
+            .contacts
+            .get(username)?
+            .ok_or_else(|| anyhow!("Unknown contact {}", username))?;
+        Ok(SafetyNumber::from_identities(&self.public_identity().await, &contact.identity))
+    }
+
+    /// Checks the QR code scanned on a contact's device and marks the contact verified
+    pub async fn verify_scanned_safety_number(&self, username: &str, payload: &[u8]) -> Result<Contact> {
+        self.safety_number(username).await?.verify_scanned(payload)?;
+        self.contacts.mark_verified(username)
+    }
+
+    /// Records a peer identity in the contact book, raising an alert if its key changed.
+    /// Our own account, met through our other devices, is not a contact.
+    fn observe_identity(&self, identity: &PublicIdentity) -> Result<()> {
+        if identity.username == self.user.username {
+            return Ok(());
+        }
+        if let IdentityObservation::KeyChanged { contact, previous_key } = self.contacts.observe(identity)? {
+            let _ = self.events.send(AppEvent::IdentityKeyChanged { contact, previous_key });
+        }
+        Ok(())
+    }
+
+    /// Keeps a different identity presented by a contact until the user approves it with
+    /// `approve_identity_change`, and warns the user. Identities that are not validly
+    /// self-signed are ignored.
+    fn hold_identity_change(&self, identity: &PublicIdentity) {
+        if let Ok(contact) = self.contacts.hold_changed(identity) {
+            let previous_key = contact.identity.signing_public_key.clone();
+            let _ = self.events.send(AppEvent::IdentityKeyChanged { contact, previous_key });
+        }
+    }
+
+    /// Trusts the new identity key a contact presented (see AppEvent::IdentityKeyChanged):
+    /// until then its messages are refused. Our sessions with its former keys are dropped.
+    pub async fn approve_identity_change(&self, username: &str) -> Result<Contact> {
+        let contact = self.contacts.approve_pending(username)?;
+        self.sessions
+            .lock()
+            .await
+            .approve_account_key(username, &contact.identity.signing_public_key)?;
+        Ok(contact)
+    }
+
+    /// Checks the envelope signature against the sender's known identity key
+    pub(crate) fn verify_sender(&self, msg: &Message, sender_key: Option<EdPublicKey>) -> Result<(), MessageError> {
+        let key = sender_key.ok_or_else(|| MessageError::UnknownSender(msg.sender.clone()))?;
+        msg.verify(key.as_bytes())
+    }
+
+    /// Stores a received message with its decrypted content in the sender's conversation
+    fn store_message(&self, msg: &Message, plaintext: &[u8]) -> Result<()> {
+        self.record_message(
+            &msg.sender,
+            StoredMessage { message: msg.clone(), plaintext: plaintext.to_vec(), outgoing: false },
+        )
+    }
+
+    /// Adds a message to the history and refreshes its entry in the conversation list
+    pub(crate) fn record_message(&self, conversation_id: &str, stored: StoredMessage) -> Result<()> {
+        self.messages.insert(conversation_id, &stored)?;
+
+        let mut conversation = self
+            .conversations
+            .get_or(conversation_id, || Conversation::direct(conversation_id))?;
+        conversation.record(&stored.message, &stored.plaintext);
+        conversation.unread = self.messages.unread_count(conversation_id)?;
+        // New activity brings an archived conversation back, unless it is muted
+        if !stored.outgoing && !conversation.muted {
+            conversation.archived = false;
+        }
+        self.conversations.save(&conversation)?;
+
+        let _ = self.events.send(AppEvent::ConversationUpdated { conversation });
+        Ok(())
+    }
+
+    /// Conversation list sorted by activity (pinned first), without archived ones
+    pub fn conversation_list(&self) -> Result<Vec<Conversation>> {
+        self.conversations.list(false)
+    }
+
+    /// Marks a conversation as read
+    pub fn mark_read(&self, conversation_id: &str) -> Result<()> {
+        self.messages.mark_read(conversation_id)?;
+        self.update_conversation(conversation_id, |c| c.unread = 0)
+    }
+
+    /// Pins or unpins a conversation
+    pub fn set_pinned(&self, conversation_id: &str, pinned: bool) -> Result<()> {
+        self.update_conversation(conversation_id, |c| c.pinned = pinned)
+    }
+
+    /// Mutes or unmutes a conversation
+    pub fn set_muted(&self, conversation_id: &str, muted: bool) -> Result<()> {
+        self.update_conversation(conversation_id, |c| c.muted = muted)
+    }
+
+    /// Archives or restores a conversation
+    pub fn set_archived(&self, conversation_id: &str, archived: bool) -> Result<()> {
+        self.update_conversation(conversation_id, |c| c.archived = archived)
+    }
+
+    fn update_conversation(&self, conversation_id: &str, change: impl FnOnce(&mut Conversation)) -> Result<()> {
+        let conversation = self.conversations.update(conversation_id, change)?;
+        let _ = self.events.send(AppEvent::ConversationUpdated { conversation });
+        Ok(())
+    }
+
+    /// Groups, channels and invites of this client
+    pub(crate) fn group_service(&self) -> GroupService<'_> {
+        GroupService::new(self)
+    }
+
+    /// Groups we belong to
+    pub fn group_list(&self) -> Result<Vec<Group>> {
+        self.group_service().list()
+    }
+
+    /// Creates a group with `members`
+    pub async fn create_group(&self, name: &str, members: &[&str]) -> Result<Group> {
+        self.group_service().create_group(name, members).await
+    }
+
+    /// Creates a channel with `subscribers`
+    pub async fn create_channel(&self, name: &str, subscribers: &[&str]) -> Result<Group> {
+        self.group_service().create_channel(name, subscribers).await
+    }
+
+    /// Creates an MLS group with `members`
+    pub async fn create_mls_group(&self, name: &str, members: &[&str]) -> Result<Group> {
+        self.group_service().create_mls_group(name, members).await
+    }
+
+    /// Adds a member to a group (owners and admins only)
+    pub async fn add_group_member(&self, group_id: &Uuid, username: &str) -> Result<Group> {
+        self.group_service().add_group_member(group_id, username).await
+    }
+
+    /// Adds a subscriber to a channel with up to `backlog` of the latest posts
+    pub async fn add_channel_subscriber(&self, channel_id: &Uuid, username: &str, backlog: usize) -> Result<Group> {
+        self.group_service().add_channel_subscriber(channel_id, username, backlog).await
+    }
+
+    /// Removes a member ranked below us (owners and admins only)
+    pub async fn remove_group_member(&self, group_id: &Uuid, username: &str) -> Result<Group> {
+        self.group_service().remove_group_member(group_id, username).await
+    }
+
+    /// Leaves a group or unsubscribes from a channel
+    pub async fn leave_group(&self, group_id: &Uuid) -> Result<()> {
+        self.group_service().leave_group(group_id).await
+    }
+
+    /// Moves a member one role up
+    pub async fn promote_group_member(&self, group_id: &Uuid, username: &str) -> Result<Group> {
+        self.group_service().promote_group_member(group_id, username).await
+    }
+
+    /// Moves a member one role down
+    pub async fn demote_group_member(&self, group_id: &Uuid, username: &str) -> Result<Group> {
+        self.group_service().demote_group_member(group_id, username).await
+    }
+
+    /// Renames a group (owners and admins only)
+    pub async fn rename_group(&self, group_id: &Uuid, name: &str) -> Result<Group> {
+        self.group_service().rename_group(group_id, name).await
+    }
+
+    /// Makes another member the owner of a group (owner only)
+    pub async fn transfer_group_ownership(&self, group_id: &Uuid, username: &str) -> Result<Group> {
+        self.group_service().transfer_group_ownership(group_id, username).await
+    }
+
+    /// Dissolves a group (owner only)
+    pub async fn dissolve_group(&self, group_id: &Uuid) -> Result<()> {
+        self.group_service().dissolve_group(group_id).await
+    }
+
+    /// Signs an invite to a group, sent to `invitee` or shared as a link
+    pub async fn create_group_invite(
+        &self,
+        group_id: &Uuid,
+        invitee: Option<&str>,
+        valid_for: Duration,
+        backlog: usize,
+    ) -> Result<GroupInvite> {
+        self.group_service().create_group_invite(group_id, invitee, valid_for, backlog).await
+    }
+
+    /// Invites we received and did not answer yet
+    pub fn pending_group_invites(&self) -> Result<Vec<GroupInvite>> {
+        self.group_service().pending_group_invites()
+    }
+
+    /// Accepts an invite, received or read from a link or QR code
+    pub async fn accept_group_invite(&self, invite: &GroupInvite) -> Result<()> {
+        self.group_service().accept_group_invite(invite).await
+    }
+
+    /// Declines an invite we received
+    pub async fn decline_group_invite(&self, invite: &GroupInvite) -> Result<()> {
+        self.group_service().decline_group_invite(invite).await
+    }
+
+    /// Revokes an invite to a group (owners and admins only)
+    pub async fn revoke_group_invite(&self, group_id: &Uuid, token: &[u8]) -> Result<Group> {
+        self.group_service().revoke_group_invite(group_id, token).await
+    }
+
+    /// Replaces our keys in a group for post-compromise security
+    pub async fn refresh_group_keys(&self, group_id: &Uuid) -> Result<()> {
+        self.group_service().refresh_group_keys(group_id).await
+    }
+
+    /// Sends a message to a group (read-only members cannot post)
+    pub async fn send_group_message(&self, group_id: &Uuid, plaintext: &[u8]) -> Result<Message> {
+        self.group_service().send_group_message(group_id, plaintext).await
+    }
+}
+
+/// A new device waiting to be added to its account by the primary device
+pub struct PendingLink {
+    storage: Arc<Storage>,
+    username: String,
+    device_name: String,
+    keys: (IdentityKey, SignedPreKeyStore, OneTimePreKeyStore),
+    account_key: Option<Vec<u8>>, // Pinned by `approve`
+    directory: Arc<dyn PreKeyDirectory>,
+}
+
+impl PendingLink {
+    /// Restores the link saved by `EnigmaApp::begin_link`, if any
+    fn restore(storage: Arc<Storage>, directory: Arc<dyn PreKeyDirectory>) -> Result<Option<Self>> {
+        let account = AccountStore::new(Arc::new(storage.persistence()));
+        let Some(pending) = account.load_pending_link()? else {
+            return Ok(None);
+        };
+        Ok(Some(Self {
+            storage,
+            username: pending.username,
+            device_name: pending.device_name,
+            keys: pending.keys.restore()?,
+            account_key: pending.account_key,
+            directory,
+        }))
+    }
+
+    fn save(&self) -> Result<()> {
+        let (identity_key, signed_prekeys, one_time_prekeys) = &self.keys;
+        AccountStore::new(Arc::new(self.storage.persistence())).save_pending_link(&PendingDevice {
+            username: self.username.clone(),
+            device_name: self.device_name.clone(),
+            keys: KeyMaterial::export(identity_key, signed_prekeys, one_time_prekeys),
+            account_key: self.account_key.clone(),
+        })
+    }
+
+    /// Public keys of this device, to be shown as a QR code (`to_qr_svg`) and scanned by the
+    /// primary device
+    pub fn request(&self) -> DeviceLinkRequest {
+        DeviceLinkRequest {
+            username: self.username.clone(),
+            name: self.device_name.clone(),
+            signing_public_key: self.keys.0.keypair.public.as_bytes().to_vec(),
+            encryption_public_key: self.keys.0.dh_public.as_bytes().to_vec(),
+        }
+    }
+
+    /// Pins the account key from the approval QR code shown by the primary device once it
+    /// linked this device. The pin is saved with the pending link.
+    pub fn approve(&mut self, scanned: &[u8]) -> Result<()> {
+        let approval = DeviceLinkApproval::from_payload(scanned)?;
+        if approval.username != self.username {
+            return Err(anyhow!("Link approval is for {}, not {}", approval.username, self.username));
+        }
+        if approval.device.signing_public_key[..] != self.keys.0.keypair.public.as_bytes()[..] {
+            return Err(anyhow!("Link approval is for another device"));
+        }
+        self.account_key = Some(approval.account_key);
+        self.save()
+    }
+
+    /// Finishes the link once the primary device published a device list containing this
+    /// device, signed by the account key pinned by `approve`: saves the account and
+    /// publishes this device's prekeys
+    pub async fn complete(&self) -> Result<EnigmaApp> {
+        let account_key = self
+            .account_key
+            .as_ref()
+            .ok_or_else(|| anyhow!("Scan the link approval of the primary device of {} first", self.username))?;
+        let devices = self.directory.fetch_devices(&self.username).await?;
+        devices.verify()?;
+        if devices.username() != self.username {
+            return Err(anyhow!("Device list of {} returned for {}", devices.username(), self.username));
+        }
+        if devices.identity.signing_public_key != *account_key {
+            return Err(DeviceError::AccountKeyMismatch(self.username.clone()).into());
+        }
+        let (identity_key, signed_prekeys, one_time_prekeys) = self.keys.clone();
+        let device = devices
+            .devices
+            .iter()
+            .find(|d| d.signing_public_key[..] == identity_key.keypair.public.as_bytes()[..])
+            .ok_or_else(|| anyhow!("This device was not linked to {} yet", self.username))?;
+
+        let user = LocalUser {
+            uuid: uuid::Uuid::new_v4(),
+            username: self.username.clone(),
+            device_id: device.id,
+            device_name: device.name.clone(),
+            signing_private_key: identity_key.keypair.secret.to_bytes().to_vec(),
+        };
+        let account = AccountStore::new(Arc::new(self.storage.persistence()));
+        account.save_keys(&KeyMaterial::export(&identity_key, &signed_prekeys, &one_time_prekeys))?;
+        account.save_devices(&devices)?;
+        account.save_user(&user)?;
+        account.delete_pending_link()?;
+
+        let app = EnigmaApp::assemble(
+            self.storage.clone(),
+            user,
+            (identity_key, signed_prekeys, one_time_prekeys),
+            devices,
+            self.directory.clone(),
+        )
+        .await?;
+        app.sessions
+            .lock()
+            .await
+            .publish_prekeys(&self.username, ONE_TIME_PREKEY_BATCH)
+            .await?;
+        Ok(app)
     }
 }
@@ -1,33 +1,44 @@
 #[cfg(test)]
 mod e2e {
     use crate::crypto::encryption::EncryptionEngine;
-    use crate::crypto::handshake::{generate_identity_bundle, x3dh_initiate, verify_signed_prekey};
+    use crate::crypto::handshake::{generate_identity_bundle, x3dh_initiate, x3dh_respond, verify_signed_prekey};
     use crate::crypto::ratchet::Ratchet;
 
     #[tokio::test]
     async fn test_end_to_end_encryption_between_two_clients() {
         // Step 1: Generate identity and bundle for Bob
-        let (bob_identity, bob_spk, bob_bundle) = generate_identity_bundle().expect("Bob bundle gen failed");
+        let (bob_identity, bob_spk, mut bob_opks, bob_bundle) = generate_identity_bundle().expect("Bob bundle gen failed");
 
         // Step 2: Alice verifies Bob's bundle and initiates X3DH
+        let (alice_identity, _alice_spk, _alice_opks, _alice_bundle) = generate_identity_bundle().expect("Alice bundle gen failed");
         verify_signed_prekey(&bob_bundle).expect("Invalid signature");
-        let x3dh_result = x3dh_initiate(&bob_bundle).expect("X3DH initiation failed");
+        let x3dh_result = x3dh_initiate(&alice_identity, &bob_bundle).expect("X3DH initiation failed");
 
         // Step 3: Alice sets up Ratchet + EncryptionEngine with derived key
-        let mut alice_ratchet = Ratchet::new(&x3dh_result.shared_secret);
+        let mut alice_ratchet = Ratchet::new_initiator(&x3dh_result.shared_secret, bob_bundle.spk_pub.as_bytes()).expect("ratchet");
         let mut alice_enc = EncryptionEngine::new(&x3dh_result.shared_secret).expect("engine");
 
-        // Step 4: Bob reconstructs the shared secret (mocked same key here)
-        let mut bob_ratchet = Ratchet::new(&x3dh_result.shared_secret);
-        let mut bob_enc = EncryptionEngine::new(&x3dh_result.shared_secret).expect("engine");
+        // Step 4: Bob reconstructs the shared secret from Alice's initial header
+        let bob_secret = x3dh_respond(&bob_identity, &bob_spk, &mut bob_opks, &x3dh_result.header).expect("X3DH response failed");
+        assert_eq!(bob_secret, x3dh_result.shared_secret, "Both sides must derive the same secret");
+
+        let mut bob_ratchet = Ratchet::new_responder(&bob_secret, bob_spk.secret.clone()).expect("ratchet");
+        let bob_enc = EncryptionEngine::new(&bob_secret).expect("engine");
 
         // Step 5: Alice encrypts a message
         let msg = b"hello Bob!";
-        let encrypted = alice_enc.encrypt(msg, b"context").expect("Alice encrypt");
+        let (encrypted, nonce) = alice_enc.encrypt(msg, b"context").expect("Alice encrypt");
 
         // Step 6: Bob decrypts it
-        let decrypted = bob_enc.decrypt(&encrypted, b"context").expect("Bob decrypt");
+        let decrypted = bob_enc.decrypt(&encrypted, &nonce, b"context").expect("Bob decrypt");
 
         assert_eq!(decrypted, msg, "E2E encrypted-decrypted message should match");
+
+        // Step 7: The ratchet sessions exchange messages in both directions
+        let ratchet_msg = alice_ratchet.encrypt(msg).expect("Alice ratchet encrypt");
+        assert_eq!(bob_ratchet.decrypt(&ratchet_msg).expect("Bob ratchet decrypt"), msg);
+
+        let reply = bob_ratchet.encrypt(b"hello Alice!").expect("Bob ratchet encrypt");
+        assert_eq!(alice_ratchet.decrypt(&reply).expect("Alice ratchet decrypt"), b"hello Alice!");
     }
 }
@@ -1,11 +1,18 @@
 #[cfg(test)]
 mod tests {
-    use super::*;
+    use crate::app::*;
     use std::fs;
     use std::path::Path;
     use std::sync::Arc;
     use tokio::sync::Mutex;
     use async_trait::async_trait;
+    use crate::models::device::{DeviceError, DeviceId, DeviceList, PRIMARY_DEVICE};
+    use crate::crypto::sender_key::SenderKey;
+    use crate::models::group::{ChannelBacklog, ChannelPost, GroupAction, GroupOperation, GroupProtocol, GroupRole};
+    use crate::models::invite::GroupInvite;
+    use crate::models::message::MessageType;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
 
     // Test EnigmaApp initialization with X3DH key derivation
     #[tokio::test]
@@ -15,7 +22,7 @@ mod tests {
             fs::remove_dir_all(test_path).unwrap();
         }
 
-        let result = EnigmaApp::init(test_path, "@testuser").await;
+        let result = EnigmaApp::init(test_path, "@testuser", PASSPHRASE).await;
         assert!(result.is_ok(), "Failed to initialize EnigmaApp");
 
         let app = result.unwrap();
@@ -23,24 +30,75 @@ mod tests {
         assert_eq!(app.user.username, "@testuser");
 
         // Validate signature key length
-        assert_eq!(app.signing.key_pair.public.as_bytes().len(), 32);
+        assert_eq!(app.signing.public_key_bytes().len(), 32);
         assert_eq!(app.user.signing_private_key.len(), 32);
 
-        // Validate encryption key pair (X25519)
-        assert_eq!(app.user.encryption_private_key.len(), 32);
-        assert_eq!(app.user.encryption_public_key.len(), 32);
-
-        // Ensure encryption works
-        let message = b"hello world!";
-        let ciphertext = {
-            let enc = app.encryption.lock().await;
-            enc.encrypt(message, b"test").expect("Encryption failed")
-        };
-        assert!(ciphertext.len() > message.len());
+        // No peer session exists before the first contact
+        assert_eq!(app.sessions.lock().await.session_count(), 0);
 
         fs::remove_dir_all(test_path).unwrap();
     }
 
+    // Mock directory serving a single pre-generated bundle and the last published device list
+    // for every username, and the identities registered with it
+    struct MockDirectory {
+        bundle: crate::crypto::handshake::X3DHBundle,
+        devices: std::sync::Mutex<DeviceList>,
+        identities: std::sync::Mutex<std::collections::HashMap<String, crate::models::user::PublicIdentity>>,
+    }
+
+    impl MockDirectory {
+        // Directory serving the keys of a freshly generated single-device peer
+        fn with_peer(username: &str) -> Self {
+            let (identity_key, _, _, bundle) = crate::crypto::handshake::generate_identity_bundle().unwrap();
+            let devices = DeviceList::new(
+                identity_key.public_identity(username).unwrap(),
+                "phone",
+                &identity_key.signing_key().unwrap(),
+            );
+            Self::serving(bundle, devices)
+        }
+
+        fn serving(bundle: crate::crypto::handshake::X3DHBundle, devices: DeviceList) -> Self {
+            Self { bundle, devices: std::sync::Mutex::new(devices), identities: Default::default() }
+        }
+
+        // Registers the identity of a user, as signing up on the nodes does
+        fn register(&self, identity: crate::models::user::PublicIdentity) {
+            self.identities.lock().unwrap().insert(identity.username.clone(), identity);
+        }
+    }
+
+    #[async_trait]
+    impl crate::network::directory::PreKeyDirectory for MockDirectory {
+        async fn fetch_bundle(&self, _username: &str, _device: DeviceId) -> anyhow::Result<crate::crypto::handshake::X3DHBundle> {
+            Ok(self.bundle.clone())
+        }
+
+        async fn publish_bundle(&self, _username: &str, _device: DeviceId, _bundle: &crate::crypto::handshake::X3DHBundle) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        async fn fetch_devices(&self, _username: &str) -> anyhow::Result<DeviceList> {
+            Ok(self.devices.lock().unwrap().clone())
+        }
+
+        async fn publish_devices(&self, devices: &DeviceList) -> anyhow::Result<()> {
+            *self.devices.lock().unwrap() = devices.clone();
+            Ok(())
+        }
+
+        async fn fetch_identity(&self, username: &str) -> anyhow::Result<crate::models::user::PublicIdentity> {
+            let registered = self.identities.lock().unwrap().get(username).cloned();
+            Ok(registered.unwrap_or_else(|| self.devices.lock().unwrap().identity.clone()))
+        }
+
+        async fn publish_identity(&self, identity: &crate::models::user::PublicIdentity) -> anyhow::Result<()> {
+            self.register(identity.clone());
+            Ok(())
+        }
+    }
+
     // Mock implementation of WebRTC to capture outgoing data
     struct MockWebRTCClient {
         pub last_sent: Arc<Mutex<Option<Vec<u8>>>>,
@@ -53,6 +111,131 @@ mod tests {
             *lock = Some(data.to_vec());
             Ok(())
         }
+
+        fn on_message(&self, _inbound: tokio::sync::mpsc::UnboundedSender<Vec<u8>>) {}
+    }
+
+    // Directory shared by several in-process apps: what one publishes, the others fetch.
+    // Each fetched bundle hands out one one-time prekey and one key package, like the nodes.
+    #[derive(Default)]
+    struct SharedDirectory {
+        bundles: std::sync::Mutex<std::collections::HashMap<(String, DeviceId), crate::crypto::handshake::X3DHBundle>>,
+        devices: std::sync::Mutex<std::collections::HashMap<String, DeviceList>>,
+    }
+
+    #[async_trait]
+    impl crate::network::directory::PreKeyDirectory for SharedDirectory {
+        async fn fetch_bundle(&self, username: &str, device: DeviceId) -> anyhow::Result<crate::crypto::handshake::X3DHBundle> {
+            let mut bundles = self.bundles.lock().unwrap();
+            let stored = bundles
+                .get_mut(&(username.to_owned(), device))
+                .ok_or_else(|| anyhow::anyhow!("No bundle for {}", username))?;
+            let mut bundle = stored.clone();
+            bundle.one_time_prekeys = stored.one_time_prekeys.drain(..1.min(stored.one_time_prekeys.len())).collect();
+            bundle.key_packages = stored.key_packages.drain(..1.min(stored.key_packages.len())).collect();
+            Ok(bundle)
+        }
+
+        async fn publish_bundle(&self, username: &str, device: DeviceId, bundle: &crate::crypto::handshake::X3DHBundle) -> anyhow::Result<()> {
+            let mut bundles = self.bundles.lock().unwrap();
+            let mut merged = bundle.clone();
+            if let Some(old) = bundles.remove(&(username.to_owned(), device)) {
+                merged.one_time_prekeys.splice(0..0, old.one_time_prekeys);
+                merged.key_packages.splice(0..0, old.key_packages);
+            }
+            bundles.insert((username.to_owned(), device), merged);
+            Ok(())
+        }
+
+        async fn fetch_devices(&self, username: &str) -> anyhow::Result<DeviceList> {
+            self.devices
+                .lock()
+                .unwrap()
+                .get(username)
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("Unknown user {}", username))
+        }
+
+        async fn publish_devices(&self, devices: &DeviceList) -> anyhow::Result<()> {
+            self.devices.lock().unwrap().insert(devices.username().to_owned(), devices.clone());
+            Ok(())
+        }
+
+        async fn fetch_identity(&self, username: &str) -> anyhow::Result<crate::models::user::PublicIdentity> {
+            Ok(self.fetch_devices(username).await?.identity)
+        }
+
+        async fn publish_identity(&self, _identity: &crate::models::user::PublicIdentity) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    type Outbox = Arc<Mutex<Vec<Vec<u8>>>>;
+
+    // Transport appending everything sent to a shared outbox
+    struct OutboxWebRTC {
+        outbox: Outbox,
+    }
+
+    #[async_trait]
+    impl crate::network::webrtc_client::WebRTC for OutboxWebRTC {
+        async fn send_message(&self, data: &[u8]) -> anyhow::Result<()> {
+            self.outbox.lock().await.push(data.to_vec());
+            Ok(())
+        }
+
+        fn on_message(&self, _inbound: tokio::sync::mpsc::UnboundedSender<Vec<u8>>) {}
+    }
+
+    // Registered app whose messages go to the shared outbox
+    async fn member(path: &str, username: &str, directory: &Arc<SharedDirectory>, outbox: &Outbox) -> EnigmaApp {
+        if Path::new(path).exists() {
+            fs::remove_dir_all(path).unwrap();
+        }
+        let app = EnigmaApp::init_with_directory(path, username, PASSPHRASE, directory.clone()).await.unwrap();
+        app.announce().await.unwrap();
+        EnigmaApp { webrtc: Arc::new(OutboxWebRTC { outbox: outbox.clone() }), ..app }
+    }
+
+    // Delivers pairwise messages to their receiver until the outbox is empty (replies
+    // included), and returns the group messages for the test to deliver
+    async fn deliver(outbox: &Outbox, apps: &[&EnigmaApp]) -> Vec<Vec<u8>> {
+        let mut held = Vec::new();
+        let group_messages = deliver_online(outbox, apps, &mut held).await;
+        assert!(held.is_empty(), "Unknown receiver");
+        group_messages
+    }
+
+    // Same as deliver, keeping in `held` the pairwise messages of the members missing from
+    // `apps`, who are offline
+    async fn deliver_online(outbox: &Outbox, apps: &[&EnigmaApp], held: &mut Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        let mut group_messages = Vec::new();
+        loop {
+            let pending: Vec<Vec<u8>> = outbox.lock().await.drain(..).collect();
+            if pending.is_empty() {
+                return group_messages;
+            }
+            for raw in pending {
+                let msg: crate::models::message::Message = bincode::deserialize(&raw).unwrap();
+                if msg.receiver.parse::<uuid::Uuid>().is_ok() {
+                    group_messages.push(raw);
+                    continue;
+                }
+                // Pairwise messages go to one device of the receiver
+                let device = bincode::deserialize::<crate::crypto::session::SessionEnvelope>(&msg.encrypted_payload)
+                    .map(|envelope| envelope.receiver_device)
+                    .ok();
+                let receiver = apps
+                    .iter()
+                    .find(|a| a.user.username == msg.receiver && device.is_none_or(|d| d == a.user.device_id));
+                match receiver {
+                    Some(app) => {
+                        app.handle_incoming(&raw).await.unwrap();
+                    }
+                    None => held.push(raw),
+                }
+            }
+        }
     }
 
     // Test that send_message encrypts and emits a message correctly
@@ -63,7 +246,8 @@ mod tests {
             fs::remove_dir_all(test_path).unwrap();
         }
 
-        let app = EnigmaApp::init(test_path, "@sender").await.unwrap();
+        let directory = Arc::new(MockDirectory::with_peer("@recipient"));
+        let app = EnigmaApp::init_with_directory(test_path, "@sender", PASSPHRASE, directory).await.unwrap();
 
         let mock_sent = Arc::new(Mutex::new(None));
         let mock_webrtc = Arc::new(MockWebRTCClient {
@@ -78,13 +262,804 @@ mod tests {
         let msg = app.send_message("@recipient", b"Secret!").await.unwrap();
         assert_eq!(msg.sender, "@sender");
         assert_eq!(msg.receiver, "@recipient");
-        assert!(msg.encrypted_payload.len() > 0);
+        assert!(!msg.encrypted_payload.is_empty());
         assert_eq!(msg.nonce.len(), 12);
+        assert!(app.sessions.lock().await.has_session("@recipient"));
+        assert!(msg.verify(app.signing.public_key_bytes()).is_ok(), "Outgoing messages are signed");
 
         let sent_data = mock_sent.lock().await.clone();
         assert!(sent_data.is_some());
 
         fs::remove_dir_all(test_path).unwrap();
     }
-}
 
+    // Test that a message sent by one app goes through the inbound pipeline of the other
+    #[tokio::test]
+    async fn test_inbound_pipeline_decrypts_and_dispatches() {
+        let bob_path = "test_data/enigma_inbound_bob";
+        let alice_path = "test_data/enigma_inbound_alice";
+        for path in [bob_path, alice_path] {
+            if Path::new(path).exists() {
+                fs::remove_dir_all(path).unwrap();
+            }
+        }
+
+        let bob_directory = Arc::new(MockDirectory::with_peer("@unused"));
+        let bob = EnigmaApp::init_with_directory(bob_path, "@bob", PASSPHRASE, bob_directory.clone())
+            .await
+            .unwrap();
+        let bundle = bob.sessions.lock().await.refill_bundle(1).unwrap();
+        let bob_keys = MockDirectory::serving(bundle, bob.devices().await);
+
+        let alice_sent = Arc::new(Mutex::new(None));
+        let alice = EnigmaApp::init_with_directory(alice_path, "@alice", PASSPHRASE, Arc::new(bob_keys))
+            .await
+            .unwrap();
+        bob_directory.register(alice.public_identity().await);
+        let alice = EnigmaApp {
+            webrtc: Arc::new(MockWebRTCClient { last_sent: Arc::clone(&alice_sent) }),
+            ..alice
+        };
+
+        let mut events = bob.subscribe();
+        alice.send_message("@bob", b"Inbound!").await.unwrap();
+        let raw = alice_sent.lock().await.clone().expect("Alice sent nothing");
+
+        // A forged copy (signature stripped, then timestamp changed) is rejected before decryption
+        let mut unsigned: crate::models::message::Message = bincode::deserialize(&raw).unwrap();
+        unsigned.signature = None;
+        assert!(bob.handle_incoming(&bincode::serialize(&unsigned).unwrap()).await.is_err());
+        let mut forged: crate::models::message::Message = bincode::deserialize(&raw).unwrap();
+        forged.timestamp += chrono::Duration::seconds(5);
+        assert!(bob.handle_incoming(&bincode::serialize(&forged).unwrap()).await.is_err());
+        for _ in 0..2 {
+            assert!(matches!(events.recv().await.unwrap(), crate::app::AppEvent::InboundRejected { .. }));
+        }
+
+        let (msg, plaintext) = bob.handle_incoming(&raw).await.expect("Inbound pipeline failed");
+        assert_eq!(msg.sender, "@alice");
+        assert_eq!(plaintext, b"Inbound!");
+        let history = bob.messages.latest("@alice", 10).unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].message.id, msg.id);
+        assert_eq!(bob.messages.unread_count("@alice").unwrap(), 1);
+        assert!(alice.messages.get(&msg.id).unwrap().unwrap().outgoing);
+        assert_eq!(alice.conversation_list().unwrap()[0].unread, 0, "Sent messages are not unread");
+
+        match events.recv().await.unwrap() {
+            crate::app::AppEvent::ConversationUpdated { conversation } => {
+                assert_eq!(conversation.id, "@alice");
+                assert_eq!(conversation.unread, 1);
+                assert_eq!(conversation.last_message_preview.as_deref(), Some("Inbound!"));
+            }
+            other => panic!("Unexpected event {:?}", other),
+        }
+        match events.recv().await.unwrap() {
+            crate::app::AppEvent::MessageReceived { plaintext, .. } => assert_eq!(plaintext, b"Inbound!"),
+            other => panic!("Unexpected event {:?}", other),
+        }
+
+        // Both sides compute the same safety number; scanning Alice's code verifies her
+        let bob_view = bob.safety_number("@alice").await.unwrap();
+        let alice_view = alice.safety_number("@bob").await.unwrap();
+        assert_eq!(bob_view.digits(), alice_view.digits());
+        let contact = bob
+            .verify_scanned_safety_number("@alice", &alice_view.scannable_payload())
+            .await
+            .unwrap();
+        assert_eq!(contact.trust, crate::models::contact::TrustState::Verified);
+
+        // Garbage is rejected and reported
+        assert!(bob.handle_incoming(b"not a message").await.is_err());
+        assert!(matches!(events.recv().await.unwrap(), crate::app::AppEvent::InboundRejected { .. }));
+
+        fs::remove_dir_all(bob_path).unwrap();
+        fs::remove_dir_all(alice_path).unwrap();
+    }
+
+    // Sealed-sender messages hide the sender on the wire and still go through the pipeline
+    #[tokio::test]
+    async fn test_sealed_sender_round_trip() {
+        let bob_path = "test_data/enigma_sealed_bob";
+        let alice_path = "test_data/enigma_sealed_alice";
+        for path in [bob_path, alice_path] {
+            if Path::new(path).exists() {
+                fs::remove_dir_all(path).unwrap();
+            }
+        }
+
+        let bob_directory = Arc::new(MockDirectory::with_peer("@unused"));
+        let bob = EnigmaApp::init_with_directory(bob_path, "@bob", PASSPHRASE, bob_directory.clone())
+            .await
+            .unwrap();
+        let bundle = bob.sessions.lock().await.refill_bundle(1).unwrap();
+        let bob_keys = MockDirectory::serving(bundle, bob.devices().await);
+
+        let alice_sent = Arc::new(Mutex::new(None));
+        let alice = EnigmaApp::init_with_directory(alice_path, "@alice", PASSPHRASE, Arc::new(bob_keys))
+            .await
+            .unwrap();
+        bob_directory.register(alice.public_identity().await);
+        let alice = EnigmaApp {
+            webrtc: Arc::new(MockWebRTCClient { last_sent: Arc::clone(&alice_sent) }),
+            ..alice
+        };
+        alice.set_sealed_sender(true);
+
+        let sent = alice.send_message("@bob", b"Who am I?").await.unwrap();
+        let raw = alice_sent.lock().await.clone().expect("Alice sent nothing");
+        assert!(crate::crypto::sealed::SealedEnvelope::is_sealed(&raw));
+        assert!(!raw.windows(b"@alice".len()).any(|w| w == b"@alice"), "The sender is hidden");
+        assert!(bincode::deserialize::<crate::models::message::Message>(&raw).is_err());
+
+        // Another device cannot open it
+        let carol_path = "test_data/enigma_sealed_carol";
+        if Path::new(carol_path).exists() {
+            fs::remove_dir_all(carol_path).unwrap();
+        }
+        let carol = EnigmaApp::init_with_directory(carol_path, "@carol", PASSPHRASE, Arc::new(MockDirectory::with_peer("@unused")))
+            .await
+            .unwrap();
+        assert!(carol.handle_incoming(&raw).await.is_err());
+
+        let (msg, plaintext) = bob.handle_incoming(&raw).await.expect("Sealed message rejected");
+        assert_eq!(msg.sender, "@alice");
+        assert_eq!(msg.id, sent.id);
+        assert_eq!(plaintext, b"Who am I?");
+
+        drop((alice, bob, carol));
+        for path in [bob_path, alice_path, carol_path] {
+            fs::remove_dir_all(path).unwrap();
+        }
+    }
+
+    // Sealed messages to our own account reach our other devices
+    #[tokio::test]
+    async fn test_sealed_sender_to_own_device() {
+        let paths = ["test_data/enigma_sealed_phone", "test_data/enigma_sealed_tablet"];
+        let directory = Arc::new(SharedDirectory::default());
+        let outbox: Outbox = Arc::new(Mutex::new(Vec::new()));
+        let phone = member(paths[0], "@bob", &directory, &outbox).await;
+        if Path::new(paths[1]).exists() {
+            fs::remove_dir_all(paths[1]).unwrap();
+        }
+        let mut pending = EnigmaApp::begin_link(paths[1], "@bob", PASSPHRASE, "Tablet", directory.clone()).unwrap();
+        let approval = phone.link_device(&pending.request().to_payload().unwrap()).await.unwrap();
+        pending.approve(&approval.to_payload().unwrap()).unwrap();
+        let tablet = pending.complete().await.unwrap();
+
+        phone.set_sealed_sender(true);
+        let sent = phone.send_message("@bob", b"Note to self").await.unwrap();
+        let raw: Vec<Vec<u8>> = outbox.lock().await.drain(..).collect();
+        assert_eq!(raw.len(), 1, "One copy, for the tablet");
+        assert!(crate::crypto::sealed::SealedEnvelope::is_sealed(&raw[0]));
+        assert!(phone.handle_incoming(&raw[0]).await.is_err(), "Addressed to the tablet");
+
+        let (msg, plaintext) = tablet.handle_incoming(&raw[0]).await.expect("Sealed message rejected");
+        assert_eq!((msg.id, msg.sender.as_str()), (sent.id, "@bob"));
+        assert_eq!(plaintext, b"Note to self");
+
+        drop((phone, tablet));
+        for path in paths {
+            fs::remove_dir_all(path).unwrap();
+        }
+    }
+
+    // Group messages are encrypted once with sender keys; removed members lose access
+    #[tokio::test]
+    async fn test_group_sender_keys() {
+        let paths = ["test_data/enigma_group_alice", "test_data/enigma_group_bob", "test_data/enigma_group_carol", "test_data/enigma_group_dave"];
+        let directory = Arc::new(SharedDirectory::default());
+        let outbox: Outbox = Arc::new(Mutex::new(Vec::new()));
+        let alice = member(paths[0], "@alice", &directory, &outbox).await;
+        let bob = member(paths[1], "@bob", &directory, &outbox).await;
+        let carol = member(paths[2], "@carol", &directory, &outbox).await;
+        let dave = member(paths[3], "@dave", &directory, &outbox).await;
+        let apps = [&alice, &bob, &carol, &dave];
+
+        let group = alice.create_group("Friends", &["@bob", "@carol"]).await.unwrap();
+        assert!(deliver(&outbox, &apps).await.is_empty());
+        assert_eq!(bob.group_list().unwrap()[0].id, group.id);
+        assert_eq!(carol.group_list().unwrap()[0].members.len(), 3);
+        assert!(dave.group_list().unwrap().is_empty());
+
+        // Bob's first message sends his key to Carol, then goes out once for everybody
+        let sent = bob.send_group_message(&group.id, b"Hi all").await.unwrap();
+        let broadcast = deliver(&outbox, &apps).await;
+        assert_eq!(broadcast.len(), 1, "Encrypted once");
+        assert_eq!(alice.handle_incoming(&broadcast[0]).await.unwrap().1, b"Hi all");
+        assert_eq!(carol.handle_incoming(&broadcast[0]).await.unwrap().1, b"Hi all");
+        assert!(dave.handle_incoming(&broadcast[0]).await.is_err());
+        assert!(bob.handle_incoming(&broadcast[0]).await.is_err());
+        assert!(alice.handle_incoming(&broadcast[0]).await.is_err(), "Replays are rejected");
+        assert_eq!(alice.messages.latest(&group.id.to_string(), 10).unwrap()[0].message.id, sent.id);
+
+        // Only owners and admins change the members
+        assert!(carol.add_group_member(&group.id, "@dave").await.is_err());
+
+        // Removing Carol rotates the keys of Alice, then of Bob
+        alice.remove_group_member(&group.id, "@carol").await.unwrap();
+        assert!(deliver(&outbox, &apps).await.is_empty());
+        assert_eq!(bob.group_list().unwrap()[0].members.len(), 2);
+
+        alice.send_group_message(&group.id, b"Carol is gone").await.unwrap();
+        bob.send_group_message(&group.id, b"Indeed").await.unwrap();
+        let broadcast = deliver(&outbox, &apps).await;
+        assert_eq!(broadcast.len(), 2);
+        assert_eq!(bob.handle_incoming(&broadcast[0]).await.unwrap().1, b"Carol is gone");
+        assert_eq!(alice.handle_incoming(&broadcast[1]).await.unwrap().1, b"Indeed");
+        for raw in &broadcast {
+            assert!(carol.handle_incoming(raw).await.is_err(), "Carol only has the old keys");
+        }
+
+        // Bob leaves: Alice drops his key and stays alone
+        bob.leave_group(&group.id).await.unwrap();
+        assert!(deliver(&outbox, &apps).await.is_empty());
+        assert!(bob.group_list().unwrap().is_empty());
+        assert_eq!(alice.group_list().unwrap()[0].members.len(), 1);
+
+        drop((alice, bob, carol, dave));
+        for path in paths {
+            fs::remove_dir_all(path).unwrap();
+        }
+    }
+
+    // Our sender key also reaches our other devices, which accept what this one posts
+    #[tokio::test]
+    async fn test_group_own_devices() {
+        let paths = ["test_data/enigma_own_alice", "test_data/enigma_own_phone", "test_data/enigma_own_tablet"];
+        let directory = Arc::new(SharedDirectory::default());
+        let outbox: Outbox = Arc::new(Mutex::new(Vec::new()));
+        let alice = member(paths[0], "@alice", &directory, &outbox).await;
+        let phone = member(paths[1], "@bob", &directory, &outbox).await;
+        if Path::new(paths[2]).exists() {
+            fs::remove_dir_all(paths[2]).unwrap();
+        }
+        let mut pending = EnigmaApp::begin_link(paths[2], "@bob", PASSPHRASE, "Tablet", directory.clone()).unwrap();
+        let approval = phone.link_device(&pending.request().to_payload().unwrap()).await.unwrap();
+        pending.approve(&approval.to_payload().unwrap()).unwrap();
+        let tablet = pending.complete().await.unwrap();
+        let tablet = EnigmaApp { webrtc: Arc::new(OutboxWebRTC { outbox: outbox.clone() }), ..tablet };
+        let apps = [&alice, &phone, &tablet];
+
+        let group = alice.create_group("Friends", &["@bob"]).await.unwrap();
+        assert!(deliver(&outbox, &apps).await.is_empty());
+        assert_eq!(tablet.group_list().unwrap()[0].id, group.id);
+
+        // The phone's key goes to Alice and to the tablet
+        phone.send_group_message(&group.id, b"From the phone").await.unwrap();
+        let broadcast = deliver(&outbox, &apps).await;
+        assert_eq!(alice.handle_incoming(&broadcast[0]).await.unwrap().1, b"From the phone");
+        assert_eq!(tablet.handle_incoming(&broadcast[0]).await.unwrap().1, b"From the phone");
+        assert!(phone.handle_incoming(&broadcast[0]).await.is_err(), "Not from another device");
+
+        tablet.send_group_message(&group.id, b"From the tablet").await.unwrap();
+        let broadcast = deliver(&outbox, &apps).await;
+        assert_eq!(phone.handle_incoming(&broadcast[0]).await.unwrap().1, b"From the tablet");
+        assert_eq!(alice.handle_incoming(&broadcast[0]).await.unwrap().1, b"From the tablet");
+        assert!(alice.contacts.get("@bob").unwrap().is_some());
+        assert!(phone.contacts.get("@bob").unwrap().is_none(), "Our account is not a contact");
+
+        drop((alice, phone, tablet));
+        for path in paths {
+            fs::remove_dir_all(path).unwrap();
+        }
+    }
+
+    // MLS groups: members join from Welcomes, follow commits for additions, key updates
+    // and removals, and a member who leaves is removed by the owner's commit
+    #[tokio::test]
+    async fn test_group_mls() {
+        let paths = ["test_data/enigma_mls_alice", "test_data/enigma_mls_bob", "test_data/enigma_mls_carol", "test_data/enigma_mls_dave"];
+        let directory = Arc::new(SharedDirectory::default());
+        let outbox: Outbox = Arc::new(Mutex::new(Vec::new()));
+        let alice = member(paths[0], "@alice", &directory, &outbox).await;
+        let bob = member(paths[1], "@bob", &directory, &outbox).await;
+        let carol = member(paths[2], "@carol", &directory, &outbox).await;
+        let dave = member(paths[3], "@dave", &directory, &outbox).await;
+        let apps = [&alice, &bob, &carol, &dave];
+
+        // The commit goes to the group, the Welcome to Bob and Carol
+        let group = alice.create_mls_group("Team", &["@bob", "@carol"]).await.unwrap();
+        assert_eq!(deliver(&outbox, &apps).await.len(), 1);
+        let joined = &bob.group_list().unwrap()[0];
+        assert_eq!((joined.id, joined.protocol), (group.id, GroupProtocol::Mls));
+        assert_eq!(carol.group_list().unwrap()[0].members.len(), 3);
+
+        carol.send_group_message(&group.id, b"Hi team").await.unwrap();
+        let broadcast = deliver(&outbox, &apps).await;
+        assert_eq!(alice.handle_incoming(&broadcast[0]).await.unwrap().1, b"Hi team");
+        assert_eq!(bob.handle_incoming(&broadcast[0]).await.unwrap().1, b"Hi team");
+        assert!(dave.handle_incoming(&broadcast[0]).await.is_err());
+
+        // Add: only owners and admins; Dave joins while the others apply the commit
+        assert!(carol.add_group_member(&group.id, "@dave").await.is_err());
+        alice.add_group_member(&group.id, "@dave").await.unwrap();
+        let commits = deliver(&outbox, &apps).await;
+        for app in [&bob, &carol] {
+            app.handle_incoming(&commits[0]).await.unwrap();
+            assert_eq!(app.group_list().unwrap()[0].members.len(), 4);
+        }
+        dave.send_group_message(&group.id, b"Joined").await.unwrap();
+        let broadcast = deliver(&outbox, &apps).await;
+        for app in [&alice, &bob, &carol] {
+            assert_eq!(app.handle_incoming(&broadcast[0]).await.unwrap().1, b"Joined");
+        }
+
+        // Update: a member proposes new keys, which Alice (the committer) commits; a message
+        // sent just before is still read after the commit
+        dave.send_group_message(&group.id, b"Before the commit").await.unwrap();
+        let late = deliver(&outbox, &apps).await;
+        bob.refresh_group_keys(&group.id).await.unwrap();
+        let proposals = deliver(&outbox, &apps).await;
+        carol.handle_incoming(&proposals[0]).await.unwrap();
+        dave.handle_incoming(&proposals[0]).await.unwrap();
+        alice.handle_incoming(&proposals[0]).await.unwrap();
+        let commits = deliver(&outbox, &apps).await;
+        for app in [&bob, &carol, &dave] {
+            app.handle_incoming(&commits[0]).await.unwrap();
+        }
+        assert_eq!(carol.handle_incoming(&late[0]).await.unwrap().1, b"Before the commit");
+
+        // Only the committer commits
+        let service = bob.group_service();
+        let mut state = service.mls_state(&service.group(&group.id).unwrap()).unwrap();
+        let (forged, _) = state.commit(Vec::new(), &bob.signing).unwrap();
+        let payload = bincode::serialize(&forged).unwrap();
+        service.broadcast(&service.group(&group.id).unwrap(), MessageType::MlsCommit, Vec::new(), payload).await.unwrap();
+        let forged = deliver(&outbox, &apps).await;
+        assert!(carol.handle_incoming(&forged[0]).await.is_err());
+
+        // Remove: Carol applies her removal and cannot read what follows
+        alice.remove_group_member(&group.id, "@carol").await.unwrap();
+        let commits = deliver(&outbox, &apps).await;
+        for app in [&bob, &carol, &dave] {
+            app.handle_incoming(&commits[0]).await.unwrap();
+        }
+        assert!(carol.group_list().unwrap().is_empty());
+        alice.send_group_message(&group.id, b"Carol is gone").await.unwrap();
+        let broadcast = deliver(&outbox, &apps).await;
+        assert_eq!(dave.handle_incoming(&broadcast[0]).await.unwrap().1, b"Carol is gone");
+        assert!(carol.handle_incoming(&broadcast[0]).await.is_err());
+
+        // Leave: Dave proposes his removal, Alice (the owner) commits it
+        dave.leave_group(&group.id).await.unwrap();
+        assert!(dave.group_list().unwrap().is_empty());
+        let proposals = deliver(&outbox, &apps).await;
+        alice.handle_incoming(&proposals[0]).await.unwrap();
+        bob.handle_incoming(&proposals[0]).await.unwrap();
+        let commits = deliver(&outbox, &apps).await;
+        bob.handle_incoming(&commits[0]).await.unwrap();
+        assert_eq!(bob.group_list().unwrap()[0].members.len(), 2);
+        assert_eq!(alice.group_list().unwrap()[0].members.len(), 2);
+
+        drop((alice, bob, carol, dave));
+        for path in paths {
+            fs::remove_dir_all(path).unwrap();
+        }
+    }
+
+    // Signed administration operations reach every member, who checks them against the
+    // roles of the actor before applying them
+    #[tokio::test]
+    async fn test_group_administration() {
+        let paths = ["test_data/enigma_admin_alice", "test_data/enigma_admin_bob", "test_data/enigma_admin_carol", "test_data/enigma_admin_dave"];
+        let directory = Arc::new(SharedDirectory::default());
+        let outbox: Outbox = Arc::new(Mutex::new(Vec::new()));
+        let alice = member(paths[0], "@alice", &directory, &outbox).await;
+        let bob = member(paths[1], "@bob", &directory, &outbox).await;
+        let carol = member(paths[2], "@carol", &directory, &outbox).await;
+        let dave = member(paths[3], "@dave", &directory, &outbox).await;
+        let apps = [&alice, &bob, &carol, &dave];
+
+        let group = alice.create_group("Friends", &["@bob", "@carol"]).await.unwrap();
+        deliver(&outbox, &apps).await;
+
+        // Bob becomes an admin and renames the group; Carol, a member, cannot
+        alice.promote_group_member(&group.id, "@bob").await.unwrap();
+        deliver(&outbox, &apps).await;
+        bob.rename_group(&group.id, "Crew").await.unwrap();
+        assert!(carol.rename_group(&group.id, "Mine").await.is_err());
+        deliver(&outbox, &apps).await;
+        for app in [&alice, &bob, &carol] {
+            let state = &app.group_list().unwrap()[0];
+            assert_eq!((state.name.as_str(), state.role_of("@bob")), ("Crew", Some(GroupRole::Admin)));
+        }
+
+        // Read-only members receive messages but cannot post
+        bob.demote_group_member(&group.id, "@carol").await.unwrap();
+        deliver(&outbox, &apps).await;
+        assert!(carol.send_group_message(&group.id, b"Hello?").await.is_err());
+
+        // Bob invites Dave: every member sends its key to him
+        bob.add_group_member(&group.id, "@dave").await.unwrap();
+        deliver(&outbox, &apps).await;
+        assert_eq!(dave.group_list().unwrap()[0].members.len(), 4);
+        alice.send_group_message(&group.id, b"Welcome Dave").await.unwrap();
+        let broadcast = deliver(&outbox, &apps).await;
+        assert_eq!(dave.handle_incoming(&broadcast[0]).await.unwrap().1, b"Welcome Dave");
+
+        // Alice hands the group over to Bob, who dissolves it
+        alice.transfer_group_ownership(&group.id, "@bob").await.unwrap();
+        deliver(&outbox, &apps).await;
+        assert_eq!(dave.group_list().unwrap()[0].role_of("@bob"), Some(GroupRole::Owner));
+        assert!(alice.dissolve_group(&group.id).await.is_err());
+        bob.dissolve_group(&group.id).await.unwrap();
+        deliver(&outbox, &apps).await;
+        for app in apps {
+            assert!(app.group_list().unwrap().is_empty());
+        }
+
+        drop((alice, bob, carol, dave));
+        for path in paths {
+            fs::remove_dir_all(path).unwrap();
+        }
+    }
+
+    // Channels: subscribers only see the admins, receive a backlog when they join, and
+    // the admins rotate their keys when one unsubscribes
+    #[tokio::test]
+    async fn test_channel_subscribers() {
+        let paths = ["test_data/enigma_channel_alice", "test_data/enigma_channel_bob", "test_data/enigma_channel_carol", "test_data/enigma_channel_dave"];
+        let directory = Arc::new(SharedDirectory::default());
+        let outbox: Outbox = Arc::new(Mutex::new(Vec::new()));
+        let alice = member(paths[0], "@alice", &directory, &outbox).await;
+        let bob = member(paths[1], "@bob", &directory, &outbox).await;
+        let carol = member(paths[2], "@carol", &directory, &outbox).await;
+        let dave = member(paths[3], "@dave", &directory, &outbox).await;
+        let apps = [&alice, &bob, &carol, &dave];
+
+        let channel = alice.create_channel("News", &["@bob", "@carol"]).await.unwrap();
+        deliver(&outbox, &apps).await;
+        assert_eq!(bob.group_list().unwrap()[0].members.len(), 2, "Bob only sees Alice");
+        assert!(carol.send_group_message(&channel.id, b"Hello?").await.is_err());
+
+        for post in [b"First".as_slice(), b"Second".as_slice()] {
+            alice.send_group_message(&channel.id, post).await.unwrap();
+            let broadcast = deliver(&outbox, &apps).await;
+            for app in [&bob, &carol] {
+                assert_eq!(app.handle_incoming(&broadcast[0]).await.unwrap().1, post);
+            }
+        }
+
+        // Dave subscribes with a backlog of one post
+        alice.add_channel_subscriber(&channel.id, "@dave", 1).await.unwrap();
+        deliver(&outbox, &apps).await;
+        assert_eq!(dave.group_list().unwrap()[0].members.len(), 2);
+        let history = dave.messages.latest(&channel.id.to_string(), 10).unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].plaintext, b"Second");
+
+        // Bob becomes an admin: he sees every subscriber and posts
+        alice.promote_group_member(&channel.id, "@bob").await.unwrap();
+        deliver(&outbox, &apps).await;
+        assert_eq!(bob.group_list().unwrap()[0].members.len(), 4);
+        assert_eq!(carol.group_list().unwrap()[0].members.len(), 3);
+        bob.send_group_message(&channel.id, b"From Bob").await.unwrap();
+        let broadcast = deliver(&outbox, &apps).await;
+        for app in [&alice, &carol, &dave] {
+            assert_eq!(app.handle_incoming(&broadcast[0]).await.unwrap().1, b"From Bob");
+        }
+
+        // A view raising a role needs the signed operation giving it: Bob, an admin, can
+        // neither make Carol an admin on his own nor with a promotion he signs himself
+        let mut view = bob.group_list().unwrap()[0].view_for("@carol");
+        view.members.iter_mut().find(|m| m.username == "@carol").unwrap().role = GroupRole::Admin;
+        let promotion = GroupOperation::new(
+            &bob.group_list().unwrap()[0],
+            "@bob",
+            bob.user.device_id,
+            GroupAction::Promote { username: "@carol".to_owned() },
+            &bob.signing,
+        )
+        .unwrap();
+        for operations in [Vec::new(), vec![promotion]] {
+            let distribution = SenderKey::generate(7).unwrap().distribution(&view, operations, bob.user.device_id).unwrap();
+            let payload = bincode::serialize(&distribution).unwrap();
+            bob.send_direct("@carol", MessageType::SenderKeyDistribution, &payload).await.unwrap();
+        }
+        let sent: Vec<Vec<u8>> = outbox.lock().await.drain(..).collect();
+        for raw in &sent {
+            assert!(carol.handle_incoming(raw).await.is_err());
+        }
+        assert_eq!(carol.group_list().unwrap()[0].role_of("@carol"), Some(GroupRole::Member));
+
+        // A backlog post keeps the signature of its author, who must be able to post: Bob
+        // cannot pass his post off as Alice's, and Carol's posts are left out
+        let stored = bob.messages.latest(&channel.id.to_string(), 1).unwrap().remove(0);
+        let mut forged = ChannelPost { message: stored.message, plaintext: b"From Alice".to_vec() };
+        forged.message.sender = "@alice".to_owned();
+        let mut by_carol = forged.clone();
+        by_carol.message.sender = "@carol".to_owned();
+        for post in [forged, by_carol] {
+            let backlog = ChannelBacklog { channel_id: channel.id, posts: vec![post] };
+            bob.send_direct("@dave", MessageType::ChannelBacklog, &bincode::serialize(&backlog).unwrap()).await.unwrap();
+        }
+        let sent: Vec<Vec<u8>> = outbox.lock().await.drain(..).collect();
+        assert!(dave.handle_incoming(&sent[0]).await.is_err());
+        dave.handle_incoming(&sent[1]).await.unwrap();
+        assert_eq!(dave.messages.latest(&channel.id.to_string(), 10).unwrap().len(), 2);
+
+        // Carol unsubscribes: Alice and Bob rotate their keys
+        carol.leave_group(&channel.id).await.unwrap();
+        deliver(&outbox, &apps).await;
+        assert!(carol.group_list().unwrap().is_empty());
+        assert_eq!(alice.group_list().unwrap()[0].members.len(), 3);
+        alice.send_group_message(&channel.id, b"Without Carol").await.unwrap();
+        let broadcast = deliver(&outbox, &apps).await;
+        assert_eq!(dave.handle_incoming(&broadcast[0]).await.unwrap().1, b"Without Carol");
+        assert!(carol.handle_incoming(&broadcast[0]).await.is_err());
+
+        drop((alice, bob, carol, dave));
+        for path in paths {
+            fs::remove_dir_all(path).unwrap();
+        }
+    }
+
+    // Invites: a direct invite is declined, then accepted; forged and revoked invites are
+    // rejected by their inviter; a link is used while another admin is offline
+    #[tokio::test]
+    async fn test_group_invites() {
+        let paths = ["test_data/enigma_invite_alice", "test_data/enigma_invite_bob", "test_data/enigma_invite_carol", "test_data/enigma_invite_dave"];
+        let directory = Arc::new(SharedDirectory::default());
+        let outbox: Outbox = Arc::new(Mutex::new(Vec::new()));
+        let alice = member(paths[0], "@alice", &directory, &outbox).await;
+        let bob = member(paths[1], "@bob", &directory, &outbox).await;
+        let carol = member(paths[2], "@carol", &directory, &outbox).await;
+        let dave = member(paths[3], "@dave", &directory, &outbox).await;
+        let apps = [&alice, &bob, &carol, &dave];
+        let hour = std::time::Duration::from_secs(3600);
+
+        let group = alice.create_group("Friends", &["@bob"]).await.unwrap();
+        deliver(&outbox, &apps).await;
+
+        // Carol declines a first invite, then accepts a second one
+        let mut alice_events = alice.subscribe();
+        alice.create_group_invite(&group.id, Some("@carol"), hour, 0).await.unwrap();
+        deliver(&outbox, &apps).await;
+        assert_eq!(carol.conversation_list().unwrap()[0].last_message_preview.as_deref(), Some("[Group invite]"));
+        let pending = carol.pending_group_invites().unwrap();
+        assert_eq!(pending.len(), 1);
+        carol.decline_group_invite(&pending[0]).await.unwrap();
+        deliver(&outbox, &apps).await;
+        assert!(carol.pending_group_invites().unwrap().is_empty());
+        let mut declined = false;
+        while let Ok(event) = alice_events.try_recv() {
+            declined |= matches!(event, crate::app::AppEvent::GroupInviteDeclined { ref username, .. } if username == "@carol");
+        }
+        assert!(declined);
+
+        let invite = alice.create_group_invite(&group.id, Some("@carol"), hour, 0).await.unwrap();
+        assert!(dave.accept_group_invite(&invite).await.is_err(), "Meant for Carol");
+        deliver(&outbox, &apps).await;
+        carol.accept_group_invite(&carol.pending_group_invites().unwrap()[0]).await.unwrap();
+        deliver(&outbox, &apps).await;
+        for app in [&alice, &bob, &carol] {
+            assert_eq!(app.group_list().unwrap()[0].members.len(), 3);
+        }
+
+        // Links are signed by owners and admins only
+        assert!(carol.create_group_invite(&group.id, None, hour, 0).await.is_err());
+        alice.promote_group_member(&group.id, "@bob").await.unwrap();
+        deliver(&outbox, &apps).await;
+        let link = alice.create_group_invite(&group.id, None, hour, 0).await.unwrap().to_link().unwrap();
+        let invite = GroupInvite::from_link(&link).unwrap();
+
+        // A tampered invite is rejected by its inviter, and so is a revoked one
+        let mut dave_events = dave.subscribe();
+        let mut forged = invite.clone();
+        forged.expires_at += chrono::Duration::days(365);
+        dave.accept_group_invite(&forged).await.unwrap();
+        deliver(&outbox, &apps).await;
+        bob.revoke_group_invite(&group.id, &invite.token).await.unwrap();
+        deliver(&outbox, &apps).await;
+        dave.accept_group_invite(&invite).await.unwrap();
+        deliver(&outbox, &apps).await;
+        assert!(dave.group_list().unwrap().is_empty());
+        let mut rejections = 0;
+        while let Ok(event) = dave_events.try_recv() {
+            if matches!(event, crate::app::AppEvent::GroupInviteRejected { .. }) {
+                rejections += 1;
+            }
+        }
+        assert_eq!(rejections, 2);
+
+        // Alice is offline: Bob lets Dave in with his link, and Dave posts to the others
+        let invite = bob.create_group_invite(&group.id, None, hour, 0).await.unwrap();
+        dave.accept_group_invite(&invite).await.unwrap();
+        let online = [&bob, &carol, &dave];
+        let mut held = Vec::new();
+        deliver_online(&outbox, &online, &mut held).await;
+        assert_eq!(dave.group_list().unwrap()[0].members.len(), 4);
+        dave.send_group_message(&group.id, b"Hi all").await.unwrap();
+        let broadcast = deliver_online(&outbox, &online, &mut held).await;
+        for app in [&bob, &carol] {
+            assert_eq!(app.handle_incoming(&broadcast[0]).await.unwrap().1, b"Hi all");
+        }
+
+        // Back online, Alice catches up
+        outbox.lock().await.extend(held);
+        deliver(&outbox, &apps).await;
+        assert_eq!(alice.group_list().unwrap()[0].members.len(), 4);
+        assert_eq!(alice.handle_incoming(&broadcast[0]).await.unwrap().1, b"Hi all");
+
+        drop((alice, bob, carol, dave));
+        for path in paths {
+            fs::remove_dir_all(path).unwrap();
+        }
+    }
+
+    // A known contact coming back with another identity key triggers a warning
+    #[tokio::test]
+    async fn test_identity_key_change_raises_alert() {
+        let paths = ["test_data/enigma_tofu_bob", "test_data/enigma_tofu_alice", "test_data/enigma_tofu_alice2"];
+        for path in paths {
+            if Path::new(path).exists() {
+                fs::remove_dir_all(path).unwrap();
+            }
+        }
+
+        let bob_directory = Arc::new(MockDirectory::with_peer("@unused"));
+        let bob = EnigmaApp::init_with_directory(paths[0], "@bob", PASSPHRASE, bob_directory.clone())
+            .await
+            .unwrap();
+        let mut events = bob.subscribe();
+
+        // Same username, two different installs (identity keys)
+        let mut installs = Vec::new();
+        for path in &paths[1..] {
+            let bundle = bob.sessions.lock().await.refill_bundle(1).unwrap();
+            let bob_keys = MockDirectory::serving(bundle, bob.devices().await);
+            let sent = Arc::new(Mutex::new(None));
+            let alice = EnigmaApp::init_with_directory(path, "@alice", PASSPHRASE, Arc::new(bob_keys))
+                .await
+                .unwrap();
+            let alice = EnigmaApp { webrtc: Arc::new(MockWebRTCClient { last_sent: Arc::clone(&sent) }), ..alice };
+            if installs.is_empty() {
+                // The nodes know the first install; the second one was never registered
+                bob_directory.register(alice.public_identity().await);
+            }
+            alice.send_message("@bob", b"hello").await.unwrap();
+            let raw = sent.lock().await.clone().unwrap();
+            installs.push((alice, sent, raw));
+        }
+        bob.handle_incoming(&installs[0].2).await.expect("First session accepted");
+
+        // The second key never overrides the known one: its message is refused and held
+        let err = bob.handle_incoming(&installs[1].2).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<crate::models::message::MessageError>(),
+            Some(crate::models::message::MessageError::IdentityMismatch(_))
+        ));
+        assert_eq!(bob.messages.latest("@alice", 10).unwrap().len(), 1);
+        assert!(bob.contacts.get("@alice").unwrap().unwrap().pending_identity.is_some());
+
+        let mut alerts = 0;
+        while let Ok(event) = events.try_recv() {
+            if let crate::app::AppEvent::IdentityKeyChanged { contact, .. } = event {
+                assert_eq!(contact.username, "@alice");
+                assert_eq!(contact.trust, crate::models::contact::TrustState::Changed);
+                alerts += 1;
+            }
+        }
+        assert_eq!(alerts, 1);
+        assert!(bob.contacts.get("@alice").unwrap().unwrap().previous_keys.is_empty());
+
+        // Once the user approves the new key, the second install is accepted
+        let contact = bob.approve_identity_change("@alice").await.unwrap();
+        assert_eq!(contact.previous_keys.len(), 1);
+        assert!(contact.pending_identity.is_none());
+        let (alice, sent, _) = &installs[1];
+        alice.send_message("@bob", b"hello again").await.unwrap();
+        let raw = sent.lock().await.clone().unwrap();
+        let (_, plaintext) = bob.handle_incoming(&raw).await.expect("Approved key accepted");
+        assert_eq!(plaintext, b"hello again");
+
+        for path in paths {
+            fs::remove_dir_all(path).unwrap();
+        }
+    }
+
+    // A new device joins the account by QR code, then gets revoked by the primary device
+    #[tokio::test]
+    async fn test_link_and_revoke_device() {
+        let paths = ["test_data/enigma_link_phone", "test_data/enigma_link_tablet", "test_data/enigma_link_forger"];
+        for path in paths {
+            if Path::new(path).exists() {
+                fs::remove_dir_all(path).unwrap();
+            }
+        }
+        let directory = Arc::new(MockDirectory::with_peer("@unused"));
+
+        let phone = EnigmaApp::init_with_directory(paths[0], "@bob", PASSPHRASE, directory.clone()).await.unwrap();
+        let pending = EnigmaApp::begin_link(paths[1], "@bob", PASSPHRASE, "Tablet", directory.clone()).unwrap();
+        assert!(pending.complete().await.is_err(), "Not linked yet");
+
+        let request = pending.request();
+        assert!(request.to_qr_svg().unwrap().contains("<svg"));
+
+        // The new device keeps its keys across a restart
+        drop(pending);
+        let mut pending = EnigmaApp::begin_link(paths[1], "@bob", PASSPHRASE, "Tablet", directory.clone()).unwrap();
+        assert_eq!(pending.request(), request);
+        let scanned = request.to_payload().unwrap();
+        let approval = phone.link_device(&scanned).await.unwrap();
+        let added = approval.device.clone();
+        assert_eq!(added.name, "Tablet");
+        assert!(approval.to_qr_svg().unwrap().contains("<svg"));
+        assert!(phone.link_device(&scanned).await.is_err(), "Already linked");
+
+        // The list is only accepted once the account key is pinned by the approval
+        assert!(pending.complete().await.is_err(), "No approval scanned yet");
+        assert!(pending.approve(&scanned).is_err(), "Not an approval");
+        pending.approve(&approval.to_payload().unwrap()).unwrap();
+
+        // A list of another "@bob" account listing this device is refused
+        let other = Arc::new(MockDirectory::with_peer("@unused"));
+        let forger = EnigmaApp::init_with_directory(paths[2], "@bob", PASSPHRASE, other.clone()).await.unwrap();
+        forger.link_device(&scanned).await.unwrap();
+        let published = directory.devices.lock().unwrap().clone();
+        *directory.devices.lock().unwrap() = other.devices.lock().unwrap().clone();
+        let err = pending.complete().await.err().expect("forged list accepted");
+        assert!(matches!(err.downcast_ref::<DeviceError>(), Some(DeviceError::AccountKeyMismatch(_))));
+        *directory.devices.lock().unwrap() = published;
+
+        // Reopened after the primary device linked it, the new device finishes the link
+        drop(pending);
+        let tablet = EnigmaApp::open(paths[1], PASSPHRASE, directory.clone()).await.unwrap();
+        assert_eq!(tablet.user.device_id, added.id);
+        assert_eq!(tablet.public_identity().await, phone.public_identity().await);
+        assert_eq!(tablet.devices().await, phone.devices().await);
+
+        // Only the primary device edits the list, and it cannot remove itself
+        assert!(tablet.revoke_device(added.id).await.is_err());
+        assert!(phone.revoke_device(PRIMARY_DEVICE).await.is_err());
+        phone.revoke_device(added.id).await.unwrap();
+        let devices = phone.devices().await;
+        assert!(devices.device(added.id).is_none());
+        assert_eq!(*directory.devices.lock().unwrap(), devices, "The new list is published");
+
+        drop((phone, tablet, forger));
+        for path in paths {
+            fs::remove_dir_all(path).unwrap();
+        }
+    }
+
+    // The identity created on first run is loaded back, not regenerated
+    #[tokio::test]
+    async fn test_identity_persists_across_restarts() {
+        let test_path = "test_data/enigma_reopen";
+        if Path::new(test_path).exists() {
+            fs::remove_dir_all(test_path).unwrap();
+        }
+        let directory = Arc::new(MockDirectory::with_peer("@unused"));
+
+        // Opening before the account exists fails
+        assert!(EnigmaApp::open(test_path, PASSPHRASE, directory.clone()).await.is_err());
+
+        let (uuid, public_key) = {
+            let app = EnigmaApp::create(test_path, "@persistent", PASSPHRASE, directory.clone()).await.unwrap();
+            (app.user.uuid, app.signing.public_key_bytes().to_vec())
+        };
+
+        // A second creation in the same storage is refused
+        assert!(EnigmaApp::create(test_path, "@persistent", PASSPHRASE, directory.clone()).await.is_err());
+
+        let reopened = EnigmaApp::open(test_path, PASSPHRASE, directory.clone()).await.unwrap();
+        assert_eq!(reopened.user.username, "@persistent");
+        assert_eq!(reopened.user.uuid, uuid);
+        assert_eq!(reopened.signing.public_key_bytes(), public_key.as_slice());
+        drop(reopened);
+
+        // The storage cannot be unlocked with another passphrase
+        assert!(EnigmaApp::open(test_path, "wrong passphrase", directory.clone()).await.is_err());
+
+        // init on an existing storage opens it, and checks the username
+        let again = EnigmaApp::init_with_directory(test_path, "@persistent", PASSPHRASE, directory.clone()).await.unwrap();
+        assert_eq!(again.user.uuid, uuid);
+        drop(again);
+        assert!(EnigmaApp::init_with_directory(test_path, "@someone_else", PASSPHRASE, directory).await.is_err());
+
+        fs::remove_dir_all(test_path).unwrap();
+    }
+}
@@ -17,16 +17,22 @@ pub extern "system" fn Java_com_enigma_EnigmaBridge_init(
     _class: JClass,
     j_storage_path: JString,
     j_username: JString,
+    j_passphrase: JString,
 ) {
     let storage_path: String = env.get_string(j_storage_path).unwrap().into();
     let username: String = env.get_string(j_username).unwrap().into();
+    let passphrase: String = env.get_string(j_passphrase).unwrap().into();
 
     let rt = Runtime::new().unwrap();
-    let app = rt.block_on(EnigmaApp::init(&storage_path, &username)).unwrap();
+    let app = rt.block_on(EnigmaApp::init(&storage_path, &username, &passphrase)).unwrap();
 
     let arc_app = Arc::new(Mutex::new(app));
     let ui = Arc::new(UI::new(arc_app.clone()));
 
+    // Keep the inbound pipeline running for the lifetime of the process
+    let inbound_ui = ui.clone();
+    std::thread::spawn(move || rt.block_on(inbound_ui.run_inbound()));
+
     unsafe {
         APP_INSTANCE = Some(arc_app);
         UI_INSTANCE = Some(ui);
@@ -1,8 +1,6 @@
-use ring::aead::{
-    Aad, BoundKey, CHACHA20_POLY1305, LessSafeKey, Nonce, NonceSequence, OpeningKey,
-    SealingKey, UnboundKey, AES_256_GCM,
-};
+use ring::aead::{Aad, CHACHA20_POLY1305, LessSafeKey, Nonce, UnboundKey};
 use ring::error::Unspecified;
+use crate::crypto::padding::{pad, unpad, PaddingScheme};
 use ring::rand::{SecureRandom, SystemRandom};
 
 /// Size of the symmetric key in bytes (256 bits)
@@ -56,11 +54,16 @@ impl EncryptionEngine {
         })
     }
 
-    /// Encrypts data and returns a vector containing the ciphertext + tag
-    pub fn encrypt(&mut self, plaintext: &[u8], associated_data: &[u8]) -> Result<Vec<u8>, Unspecified> {
+    /// Encrypts data with the default padding and returns the ciphertext + tag with the nonce used
+    pub fn encrypt(&mut self, plaintext: &[u8], associated_data: &[u8]) -> Result<(Vec<u8>, [u8; NONCE_LEN]), Unspecified> {
+        self.encrypt_padded(plaintext, associated_data, PaddingScheme::default())
+    }
+
+    /// Pads data with `padding`, then encrypts it
+    pub fn encrypt_padded(&mut self, plaintext: &[u8], associated_data: &[u8], padding: PaddingScheme) -> Result<(Vec<u8>, [u8; NONCE_LEN]), Unspecified> {
         let nonce = self.nonce_seq.next()?;
-        let mut in_out = plaintext.to_vec();
-        in_out.resize(plaintext.len() + CHACHA20_POLY1305.tag_len(), 0);
+        let nonce_bytes = *nonce.as_ref();
+        let mut in_out = pad(plaintext, padding);
 
         self.key.seal_in_place_append_tag(
             nonce,
@@ -68,15 +71,15 @@ impl EncryptionEngine {
             &mut in_out,
         )?;
 
-        Ok(in_out)
+        Ok((in_out, nonce_bytes))
     }
 
-    /// Decrypts data and verifies authenticity
+    /// Decrypts data, verifies authenticity and strips the padding
     pub fn decrypt(&self, ciphertext: &[u8], nonce_bytes: &[u8], associated_data: &[u8]) -> Result<Vec<u8>, Unspecified> {
-        let nonce = Nonce::try_assume_unique_for_key(nonce_bytes.try_into().unwrap())?;
+        let nonce = Nonce::try_assume_unique_for_key(nonce_bytes)?;
         let mut in_out = ciphertext.to_vec();
 
         let plaintext = self.key.open_in_place(nonce, Aad::from(associated_data), &mut in_out)?;
-        Ok(plaintext.to_vec())
+        unpad(plaintext).map_err(|_| Unspecified)
     }
 }
@@ -0,0 +1,171 @@
+use crate::models::user::PublicIdentity;
+use anyhow::{Result, anyhow};
+use qrcode::render::{svg, unicode};
+use qrcode::QrCode;
+use ring::digest::{digest, SHA512};
+use thiserror::Error;
+
+/// Version of the fingerprint derivation, bumped if the format ever changes
+pub const FINGERPRINT_VERSION: u16 = 0;
+
+/// Hash iterations slowing down the search for a colliding key
+pub const FINGERPRINT_ITERATIONS: usize = 5200;
+
+/// Bytes of each user's fingerprint (6 groups of 5 digits are taken from the first 30)
+const FINGERPRINT_LEN: usize = 32;
+const DIGIT_GROUPS: usize = 6;
+
+/// Prefix of the payload carried by the QR code
+const SCAN_MAGIC: &[u8] = b"enigma-sn";
+
+/// Errors raised when comparing a scanned fingerprint
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum FingerprintError {
+    #[error("scanned code is not an Enigma safety number")]
+    Malformed,
+    #[error("scanned code uses fingerprint version {0}")]
+    VersionMismatch(u16),
+    #[error("safety numbers do not match")]
+    Mismatch,
+}
+
+/// Safety number of a conversation between two users, derived from both identity keys.
+/// Both sides compute the same number, whoever computes it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SafetyNumber {
+    local_fingerprint: Vec<u8>,
+    remote_fingerprint: Vec<u8>,
+    digits: String,
+}
+
+impl SafetyNumber {
+    /// Derives the safety number from both usernames and signing public keys
+    pub fn new(local_username: &str, local_key: &[u8], remote_username: &str, remote_key: &[u8]) -> Self {
+        let local_fingerprint = fingerprint(local_username, local_key);
+        let remote_fingerprint = fingerprint(remote_username, remote_key);
+
+        // Same order on both sides: the smaller half comes first
+        let mut halves = [digits(&local_fingerprint), digits(&remote_fingerprint)];
+        halves.sort();
+
+        Self {
+            local_fingerprint,
+            remote_fingerprint,
+            digits: halves.concat(),
+        }
+    }
+
+    /// Safety number between our identity and a contact's
+    pub fn from_identities(local: &PublicIdentity, remote: &PublicIdentity) -> Self {
+        Self::new(
+            &local.username,
+            &local.signing_public_key,
+            &remote.username,
+            &remote.signing_public_key,
+        )
+    }
+
+    /// The 60 digits of the safety number
+    pub fn digits(&self) -> &str {
+        &self.digits
+    }
+
+    /// The digits in groups of five, as shown to the user
+    pub fn formatted(&self) -> String {
+        self.digits
+            .as_bytes()
+            .chunks(5)
+            .map(|c| std::str::from_utf8(c).unwrap())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Payload encoded in our QR code: version, our fingerprint, then the peer's
+    pub fn scannable_payload(&self) -> Vec<u8> {
+        let mut payload = SCAN_MAGIC.to_vec();
+        payload.extend_from_slice(&FINGERPRINT_VERSION.to_be_bytes());
+        payload.extend_from_slice(&self.local_fingerprint);
+        payload.extend_from_slice(&self.remote_fingerprint);
+        payload
+    }
+
+    /// Checks the payload scanned from the peer's screen: it must hold the peer's
+    /// fingerprint then ours, i.e. both devices see the same keys
+    pub fn verify_scanned(&self, payload: &[u8]) -> Result<(), FingerprintError> {
+        let rest = payload.strip_prefix(SCAN_MAGIC).ok_or(FingerprintError::Malformed)?;
+        if rest.len() != 2 + 2 * FINGERPRINT_LEN {
+            return Err(FingerprintError::Malformed);
+        }
+
+        let version = u16::from_be_bytes([rest[0], rest[1]]);
+        if version != FINGERPRINT_VERSION {
+            return Err(FingerprintError::VersionMismatch(version));
+        }
+
+        let (theirs, ours) = rest[2..].split_at(FINGERPRINT_LEN);
+        if theirs == self.remote_fingerprint.as_slice() && ours == self.local_fingerprint.as_slice() {
+            Ok(())
+        } else {
+            Err(FingerprintError::Mismatch)
+        }
+    }
+
+    fn qr_code(&self) -> Result<QrCode> {
+        QrCode::new(self.scannable_payload()).map_err(|e| anyhow!("QR encoding failed: {:?}", e))
+    }
+
+    /// QR code as an SVG document
+    pub fn to_qr_svg(&self) -> Result<String> {
+        Ok(self
+            .qr_code()?
+            .render::<svg::Color>()
+            .min_dimensions(256, 256)
+            .build())
+    }
+
+    /// QR code as a PNG image
+    pub fn to_qr_png(&self) -> Result<Vec<u8>> {
+        let image = self.qr_code()?.render::<image::Luma<u8>>().min_dimensions(256, 256).build();
+        let mut png = Vec::new();
+        image::DynamicImage::ImageLuma8(image).write_to(&mut png, image::ImageOutputFormat::Png)?;
+        Ok(png)
+    }
+
+    /// QR code drawn with Unicode blocks, for terminals
+    pub fn to_qr_terminal(&self) -> Result<String> {
+        Ok(self
+            .qr_code()?
+            .render::<unicode::Dense1x2>()
+            .dark_color(unicode::Dense1x2::Light)
+            .light_color(unicode::Dense1x2::Dark)
+            .build())
+    }
+}
+
+/// Iterated SHA-512 over the version, key and username of one user
+fn fingerprint(username: &str, key: &[u8]) -> Vec<u8> {
+    let mut input = FINGERPRINT_VERSION.to_be_bytes().to_vec();
+    input.extend_from_slice(key);
+    input.extend_from_slice(username.as_bytes());
+
+    let mut hash = digest(&SHA512, &input).as_ref().to_vec();
+    for _ in 0..FINGERPRINT_ITERATIONS {
+        let mut round = hash;
+        round.extend_from_slice(key);
+        hash = digest(&SHA512, &round).as_ref().to_vec();
+    }
+    hash.truncate(FINGERPRINT_LEN);
+    hash
+}
+
+/// 30 digits: six 5-byte chunks, each reduced modulo 100000
+fn digits(fingerprint: &[u8]) -> String {
+    fingerprint
+        .chunks(5)
+        .take(DIGIT_GROUPS)
+        .map(|chunk| {
+            let value = chunk.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64);
+            format!("{:05}", value % 100_000)
+        })
+        .collect()
+}
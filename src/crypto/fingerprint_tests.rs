@@ -0,0 +1,55 @@
+#[cfg(test)]
+mod tests {
+    use super::super::fingerprint::{FingerprintError, SafetyNumber};
+
+    const ALICE_KEY: [u8; 32] = [1u8; 32];
+    const BOB_KEY: [u8; 32] = [2u8; 32];
+
+    // Both users compute the same 60-digit number, and it depends on both keys
+    #[test]
+    fn test_safety_number_is_symmetric() {
+        let alice_view = SafetyNumber::new("@alice", &ALICE_KEY, "@bob", &BOB_KEY);
+        let bob_view = SafetyNumber::new("@bob", &BOB_KEY, "@alice", &ALICE_KEY);
+
+        assert_eq!(alice_view.digits(), bob_view.digits());
+        assert_eq!(alice_view.digits().len(), 60);
+        assert!(alice_view.digits().chars().all(|c| c.is_ascii_digit()));
+        assert_eq!(alice_view.formatted().split(' ').count(), 12);
+
+        let mallory = SafetyNumber::new("@alice", &ALICE_KEY, "@bob", &[3u8; 32]);
+        assert_ne!(alice_view.digits(), mallory.digits());
+    }
+
+    // Scanning the peer's QR code succeeds only when both sides see the same keys
+    #[test]
+    fn test_verify_scanned_payload() {
+        let alice_view = SafetyNumber::new("@alice", &ALICE_KEY, "@bob", &BOB_KEY);
+        let bob_view = SafetyNumber::new("@bob", &BOB_KEY, "@alice", &ALICE_KEY);
+
+        assert_eq!(alice_view.verify_scanned(&bob_view.scannable_payload()), Ok(()));
+        assert_eq!(bob_view.verify_scanned(&alice_view.scannable_payload()), Ok(()));
+
+        // Our own code, or a code computed with a substituted key, does not match
+        assert_eq!(
+            alice_view.verify_scanned(&alice_view.scannable_payload()),
+            Err(FingerprintError::Mismatch)
+        );
+        let mitm = SafetyNumber::new("@bob", &[3u8; 32], "@alice", &ALICE_KEY);
+        assert_eq!(alice_view.verify_scanned(&mitm.scannable_payload()), Err(FingerprintError::Mismatch));
+
+        assert_eq!(alice_view.verify_scanned(b"hello"), Err(FingerprintError::Malformed));
+        let mut future = bob_view.scannable_payload();
+        future[9] = 0xff;
+        assert!(matches!(alice_view.verify_scanned(&future), Err(FingerprintError::VersionMismatch(_))));
+    }
+
+    // The QR code renders in all three formats
+    #[test]
+    fn test_qr_rendering() {
+        let number = SafetyNumber::new("@alice", &ALICE_KEY, "@bob", &BOB_KEY);
+
+        assert!(number.to_qr_svg().unwrap().contains("<svg"));
+        assert!(number.to_qr_png().unwrap().starts_with(b"\x89PNG"));
+        assert!(number.to_qr_terminal().unwrap().lines().count() > 10);
+    }
+}
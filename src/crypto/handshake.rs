@@ -1,100 +1,521 @@
-use x25519_dalek::{EphemeralSecret, PublicKey as X25519PublicKey, StaticSecret};
+use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret};
 use ed25519_dalek::{Keypair as EdKeypair, PublicKey as EdPublicKey, Signature, Signer, Verifier};
 use rand_core::OsRng;
 use hkdf::Hkdf;
 use sha2::Sha256;
 use anyhow::{Result, anyhow};
 use serde::{Serialize, Deserialize};
+use crate::crypto::signature::SigningKey;
+use crate::crypto::mls::KeyPackage;
+use crate::models::user::PublicIdentity;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
 
-#[derive(Clone)]
+/// Number of one-time prekeys generated per batch
+pub const ONE_TIME_PREKEY_BATCH: usize = 100;
+
+/// Default lifetime of a signed prekey before it is replaced
+pub const SPK_ROTATION_INTERVAL: Duration = Duration::from_secs(7 * 24 * 3600);
+
+/// Default time a replaced signed prekey is kept to answer in-flight initial messages
+pub const SPK_GRACE_PERIOD: Duration = Duration::from_secs(14 * 24 * 3600);
+
+/// Long-term identity: Ed25519 for signatures, X25519 for the X3DH DH steps
 pub struct IdentityKey {
     pub keypair: EdKeypair,
+    pub dh_secret: StaticSecret,
+    pub dh_public: X25519PublicKey,
 }
 
+/// Medium-term prekey signed by the identity key, replaced on a schedule
 #[derive(Clone)]
 pub struct SignedPreKey {
+    pub id: u32,
     pub secret: StaticSecret,
     pub public: X25519PublicKey,
     pub signature: Signature,
+    pub created_at: i64, // Unix time (seconds) of generation
 }
 
+/// One-time prekey, consumed by a single X3DH handshake
+#[derive(Clone)]
+pub struct OneTimePreKey {
+    pub id: u32,
+    pub secret: StaticSecret,
+    pub public: X25519PublicKey,
+}
+
+/// Public half of a one-time prekey, as published in a bundle
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PublicOneTimePreKey {
+    pub id: u32,
+    pub public: X25519PublicKey,
+}
+
+/// Ephemeral key of the initiator, used for several DH steps then dropped
 #[derive(Clone)]
 pub struct EphemeralKey {
-    pub secret: EphemeralSecret,
+    pub secret: StaticSecret,
     pub public: X25519PublicKey,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct X3DHBundle {
     pub identity_pub: EdPublicKey,
+    pub identity_dh_pub: X25519PublicKey,
+    pub identity_dh_signature: Signature,
+    #[serde(default)]
+    pub spk_id: u32,
     pub spk_pub: X25519PublicKey,
     pub spk_signature: Signature,
+    pub one_time_prekeys: Vec<PublicOneTimePreKey>,
+    #[serde(default)]
+    pub key_packages: Vec<KeyPackage>, // MLS key packages, at most one handed out per fetch
+}
+
+/// Header sent with the first message so the responder can derive the same secret
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct InitialMessageHeader {
+    pub identity_pub: EdPublicKey,
+    pub identity_dh_pub: X25519PublicKey,
+    pub identity_dh_signature: Signature,
+    pub ephemeral_pub: X25519PublicKey,
+    pub spk_id: u32, // Signed prekey of the responder used by the initiator
+    pub one_time_prekey_id: Option<u32>,
 }
 
 pub struct X3DHInitResult {
     pub ephemeral: EphemeralKey,
     pub shared_secret: [u8; 32],
+    pub header: InitialMessageHeader,
 }
 
-/// Generate identity + signed prekey bundle
-pub fn generate_identity_bundle() -> Result<(IdentityKey, SignedPreKey, X3DHBundle)> {
-    let id_key = IdentityKey {
-        keypair: EdKeypair::generate(&mut OsRng),
-    };
+impl Clone for IdentityKey {
+    fn clone(&self) -> Self {
+        Self {
+            keypair: EdKeypair::from_bytes(&self.keypair.to_bytes()).expect("valid keypair bytes"),
+            dh_secret: self.dh_secret.clone(),
+            dh_public: self.dh_public,
+        }
+    }
+}
 
-    let spk_secret = StaticSecret::new(OsRng);
-    let spk_public = X25519PublicKey::from(&spk_secret);
-    let spk_bytes = spk_public.as_bytes();
+impl IdentityKey {
+    /// Generate a fresh identity (signing + DH key pairs)
+    pub fn generate() -> Self {
+        let dh_secret = StaticSecret::new(OsRng);
+        let dh_public = X25519PublicKey::from(&dh_secret);
+        Self {
+            keypair: EdKeypair::generate(&mut OsRng),
+            dh_secret,
+            dh_public,
+        }
+    }
 
-    let spk_signature = id_key.keypair.sign(spk_bytes);
+    /// The signing key in the form used to sign identities, device lists and messages
+    pub fn signing_key(&self) -> Result<SigningKey> {
+        SigningKey::from_seed(self.keypair.secret.as_bytes(), self.keypair.public.as_bytes())
+            .map_err(|e| anyhow!("Invalid identity key: {}", e))
+    }
 
-    let spk = SignedPreKey {
-        secret: spk_secret,
-        public: spk_public,
-        signature: spk_signature,
-    };
+    /// Self-signed public identity of `username` with these keys
+    pub fn public_identity(&self, username: &str) -> Result<PublicIdentity> {
+        let mut identity = PublicIdentity {
+            username: username.to_owned(),
+            signing_public_key: self.keypair.public.as_bytes().to_vec(),
+            encryption_public_key: self.dh_public.as_bytes().to_vec(),
+            signature: vec![],
+        };
+        identity.sign(&self.signing_key()?);
+        Ok(identity)
+    }
+}
 
-    let bundle = X3DHBundle {
-        identity_pub: id_key.keypair.public,
-        spk_pub: spk_public,
-        spk_signature,
-    };
+impl SignedPreKey {
+    /// Generate a signed prekey with the given id, signed by the identity key
+    pub fn generate(identity: &IdentityKey, id: u32, created_at: i64) -> Self {
+        let secret = StaticSecret::new(OsRng);
+        let public = X25519PublicKey::from(&secret);
+        Self {
+            id,
+            signature: identity.keypair.sign(public.as_bytes()),
+            secret,
+            public,
+            created_at,
+        }
+    }
+}
+
+/// When signed prekeys are replaced, and how long replaced ones stay usable
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpkRotationPolicy {
+    pub interval: Duration,
+    pub grace_period: Duration,
+}
+
+impl Default for SpkRotationPolicy {
+    fn default() -> Self {
+        Self {
+            interval: SPK_ROTATION_INTERVAL,
+            grace_period: SPK_GRACE_PERIOD,
+        }
+    }
+}
+
+/// Current signed prekey, plus the replaced ones still inside their grace period
+#[derive(Clone)]
+pub struct SignedPreKeyStore {
+    current: SignedPreKey,
+    retired: Vec<(SignedPreKey, i64)>, // Replaced keys with the time they were replaced
+}
+
+impl SignedPreKeyStore {
+    /// Creates a store around the current signed prekey
+    pub fn new(current: SignedPreKey) -> Self {
+        Self { current, retired: vec![] }
+    }
+
+    /// The signed prekey published in our bundle
+    pub fn current(&self) -> &SignedPreKey {
+        &self.current
+    }
+
+    /// Looks up the signed prekey an initial message refers to, current or retired
+    pub fn get(&self, id: u32) -> Option<&SignedPreKey> {
+        if self.current.id == id {
+            return Some(&self.current);
+        }
+        self.retired.iter().map(|(spk, _)| spk).find(|spk| spk.id == id)
+    }
+
+    /// Whether the current signed prekey is older than the rotation interval
+    pub fn is_due(&self, now: i64, policy: &SpkRotationPolicy) -> bool {
+        now.saturating_sub(self.current.created_at) >= policy.interval.as_secs() as i64
+    }
+
+    /// Replaces the current signed prekey; the previous one is kept for the grace period
+    pub fn rotate(&mut self, identity: &IdentityKey, now: i64) -> &SignedPreKey {
+        let next = SignedPreKey::generate(identity, self.current.id.wrapping_add(1), now);
+        let previous = std::mem::replace(&mut self.current, next);
+        self.retired.push((previous, now));
+        &self.current
+    }
+
+    /// Drops the retired keys whose grace period is over (their secrets are zeroed on drop)
+    /// and returns how many were removed
+    pub fn purge_expired(&mut self, now: i64, policy: &SpkRotationPolicy) -> usize {
+        let grace = policy.grace_period.as_secs() as i64;
+        let before = self.retired.len();
+        self.retired.retain(|(_, retired_at)| now.saturating_sub(*retired_at) < grace);
+        before - self.retired.len()
+    }
+
+    /// Number of replaced keys still accepted
+    pub fn retired_count(&self) -> usize {
+        self.retired.len()
+    }
+}
+
+impl OneTimePreKey {
+    /// Generate a one-time prekey with the given id
+    pub fn generate(id: u32) -> Self {
+        let secret = StaticSecret::new(OsRng);
+        let public = X25519PublicKey::from(&secret);
+        Self { id, secret, public }
+    }
+
+    /// Public part to publish in a bundle
+    pub fn to_public(&self) -> PublicOneTimePreKey {
+        PublicOneTimePreKey {
+            id: self.id,
+            public: self.public,
+        }
+    }
+}
+
+/// Local store of unused one-time prekeys, tracking which ids were consumed
+#[derive(Default, Clone)]
+pub struct OneTimePreKeyStore {
+    keys: HashMap<u32, OneTimePreKey>,
+    consumed: HashSet<u32>,
+    next_id: u32,
+}
+
+impl OneTimePreKeyStore {
+    /// Creates an empty store
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Generates a new batch of prekeys and returns their public parts for publishing
+    pub fn generate_batch(&mut self, count: usize) -> Vec<PublicOneTimePreKey> {
+        let mut published = Vec::with_capacity(count);
+        for _ in 0..count {
+            let opk = OneTimePreKey::generate(self.next_id);
+            self.next_id = self.next_id.wrapping_add(1);
+            published.push(opk.to_public());
+            self.keys.insert(opk.id, opk);
+        }
+        published
+    }
 
-    Ok((id_key, spk, bundle))
+    /// Removes and returns the prekey with the given id; a prekey can only be taken once
+    pub fn take(&mut self, id: u32) -> Result<OneTimePreKey> {
+        if self.consumed.contains(&id) {
+            return Err(anyhow!("One-time prekey {} already consumed", id));
+        }
+        let opk = self
+            .keys
+            .remove(&id)
+            .ok_or_else(|| anyhow!("Unknown one-time prekey {}", id))?;
+        self.consumed.insert(id);
+        Ok(opk)
+    }
+
+    /// Ids of the prekeys consumed so far
+    pub fn consumed_ids(&self) -> &HashSet<u32> {
+        &self.consumed
+    }
+
+    /// Number of prekeys still available
+    pub fn remaining(&self) -> usize {
+        self.keys.len()
+    }
+}
+
+/// Serializable private key material of an account, restored on startup
+#[derive(Serialize, Deserialize, Clone)]
+pub struct KeyMaterial {
+    pub identity_seed: [u8; 32],
+    pub identity_dh_secret: [u8; 32],
+    pub signed_prekey: SignedPreKeyRecord,
+    pub retired_prekeys: Vec<(SignedPreKeyRecord, i64)>,
+    pub one_time_prekeys: Vec<(u32, [u8; 32])>,
+    pub consumed_prekeys: Vec<u32>,
+    pub next_prekey_id: u32,
+}
+
+/// Serializable signed prekey
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SignedPreKeyRecord {
+    pub id: u32,
+    pub secret: [u8; 32],
+    pub signature: Vec<u8>,
+    pub created_at: i64,
 }
 
-/// Verify the signed prekey with the identity public key
+impl SignedPreKeyRecord {
+    fn export(spk: &SignedPreKey) -> Self {
+        Self {
+            id: spk.id,
+            secret: spk.secret.to_bytes(),
+            signature: spk.signature.to_bytes().to_vec(),
+            created_at: spk.created_at,
+        }
+    }
+
+    fn restore(&self) -> Result<SignedPreKey> {
+        let secret = StaticSecret::from(self.secret);
+        Ok(SignedPreKey {
+            id: self.id,
+            public: X25519PublicKey::from(&secret),
+            secret,
+            signature: Signature::from_bytes(&self.signature)
+                .map_err(|_| anyhow!("Invalid signed prekey signature"))?,
+            created_at: self.created_at,
+        })
+    }
+}
+
+impl KeyMaterial {
+    /// Exports the identity, signed prekeys and unused one-time prekeys
+    pub fn export(identity: &IdentityKey, spks: &SignedPreKeyStore, opks: &OneTimePreKeyStore) -> Self {
+        Self {
+            identity_seed: identity.keypair.secret.to_bytes(),
+            identity_dh_secret: identity.dh_secret.to_bytes(),
+            signed_prekey: SignedPreKeyRecord::export(&spks.current),
+            retired_prekeys: spks
+                .retired
+                .iter()
+                .map(|(spk, retired_at)| (SignedPreKeyRecord::export(spk), *retired_at))
+                .collect(),
+            one_time_prekeys: opks
+                .keys
+                .values()
+                .map(|opk| (opk.id, opk.secret.to_bytes()))
+                .collect(),
+            consumed_prekeys: opks.consumed.iter().copied().collect(),
+            next_prekey_id: opks.next_id,
+        }
+    }
+
+    /// Rebuilds the key set exported with `export`
+    pub fn restore(&self) -> Result<(IdentityKey, SignedPreKeyStore, OneTimePreKeyStore)> {
+        let secret = ed25519_dalek::SecretKey::from_bytes(&self.identity_seed)
+            .map_err(|_| anyhow!("Invalid identity key"))?;
+        let public = EdPublicKey::from(&secret);
+        let dh_secret = StaticSecret::from(self.identity_dh_secret);
+        let identity = IdentityKey {
+            keypair: EdKeypair { secret, public },
+            dh_public: X25519PublicKey::from(&dh_secret),
+            dh_secret,
+        };
+
+        let spks = SignedPreKeyStore {
+            current: self.signed_prekey.restore()?,
+            retired: self
+                .retired_prekeys
+                .iter()
+                .map(|(record, retired_at)| Ok((record.restore()?, *retired_at)))
+                .collect::<Result<_>>()?,
+        };
+
+        let opks = OneTimePreKeyStore {
+            keys: self
+                .one_time_prekeys
+                .iter()
+                .map(|(id, secret)| {
+                    let secret = StaticSecret::from(*secret);
+                    let opk = OneTimePreKey {
+                        id: *id,
+                        public: X25519PublicKey::from(&secret),
+                        secret,
+                    };
+                    (*id, opk)
+                })
+                .collect(),
+            consumed: self.consumed_prekeys.iter().copied().collect(),
+            next_id: self.next_prekey_id,
+        };
+
+        Ok((identity, spks, opks))
+    }
+}
+
+/// Generate identity + signed prekey + one-time prekeys bundle
+pub fn generate_identity_bundle() -> Result<(IdentityKey, SignedPreKey, OneTimePreKeyStore, X3DHBundle)> {
+    let id_key = IdentityKey::generate();
+    let spk = SignedPreKey::generate(&id_key, 0, chrono::Utc::now().timestamp());
+
+    let mut opks = OneTimePreKeyStore::new();
+    let one_time_prekeys = opks.generate_batch(ONE_TIME_PREKEY_BATCH);
+
+    let bundle = build_bundle(&id_key, &spk, one_time_prekeys);
+
+    Ok((id_key, spk, opks, bundle))
+}
+
+/// Assemble the publishable bundle for an identity and its prekeys
+pub fn build_bundle(
+    identity: &IdentityKey,
+    spk: &SignedPreKey,
+    one_time_prekeys: Vec<PublicOneTimePreKey>,
+) -> X3DHBundle {
+    X3DHBundle {
+        identity_pub: identity.keypair.public,
+        identity_dh_pub: identity.dh_public,
+        identity_dh_signature: identity.keypair.sign(identity.dh_public.as_bytes()),
+        spk_id: spk.id,
+        spk_pub: spk.public,
+        spk_signature: spk.signature,
+        one_time_prekeys,
+        key_packages: Vec::new(),
+    }
+}
+
+/// Verify the signed prekey and the identity DH key with the identity public key
 pub fn verify_signed_prekey(bundle: &X3DHBundle) -> Result<()> {
     bundle
         .identity_pub
         .verify(bundle.spk_pub.as_bytes(), &bundle.spk_signature)
-        .map_err(|_| anyhow!("Invalid SPK signature"))
+        .map_err(|_| anyhow!("Invalid SPK signature"))?;
+    bundle
+        .identity_pub
+        .verify(bundle.identity_dh_pub.as_bytes(), &bundle.identity_dh_signature)
+        .map_err(|_| anyhow!("Invalid identity DH key signature"))
+}
+
+/// Derive the 32-byte X3DH secret from the concatenated DH outputs
+fn derive_shared_secret(dh_outputs: &[&[u8]]) -> Result<[u8; 32]> {
+    // 32 bytes of 0xFF separate X25519 outputs from other curve encodings (X3DH §2.2)
+    let mut dh_concat = vec![0xFFu8; 32];
+    for dh in dh_outputs {
+        dh_concat.extend_from_slice(dh);
+    }
+
+    let hk = Hkdf::<Sha256>::new(Some(&[0u8; 32]), &dh_concat);
+    let mut okm = [0u8; 32];
+    hk.expand(b"x3dh derived key", &mut okm)
+        .map_err(|_| anyhow!("X3DH key derivation failed"))?;
+    Ok(okm)
 }
 
-/// Execute the X3DH initiator step to generate shared secret
-pub fn x3dh_initiate(bundle: &X3DHBundle) -> Result<X3DHInitResult> {
+/// Execute the X3DH initiator step to generate shared secret.
+/// Uses the first one-time prekey of the bundle when the node handed one out.
+pub fn x3dh_initiate(identity: &IdentityKey, bundle: &X3DHBundle) -> Result<X3DHInitResult> {
     verify_signed_prekey(bundle)?;
 
+    let ek_secret = StaticSecret::new(OsRng);
     let ek = EphemeralKey {
-        secret: EphemeralSecret::new(OsRng),
-        public: X25519PublicKey::from(&EphemeralSecret::new(OsRng)),
+        public: X25519PublicKey::from(&ek_secret),
+        secret: ek_secret,
     };
 
-    let dh1 = ek.secret.diffie_hellman(&bundle.spk_pub);
-    let dh2 = ek.secret.diffie_hellman(&X25519PublicKey::from(bundle.identity_pub.to_bytes()));
-    let dh3 = StaticSecret::new(OsRng).diffie_hellman(&bundle.spk_pub); // simulate IK·SPK (only if known)
+    let dh1 = identity.dh_secret.diffie_hellman(&bundle.spk_pub);
+    let dh2 = ek.secret.diffie_hellman(&bundle.identity_dh_pub);
+    let dh3 = ek.secret.diffie_hellman(&bundle.spk_pub);
 
-    let mut dh_concat = Vec::new();
-    dh_concat.extend_from_slice(dh1.as_bytes());
-    dh_concat.extend_from_slice(dh2.as_bytes());
-    dh_concat.extend_from_slice(dh3.as_bytes());
+    let opk = bundle.one_time_prekeys.first();
+    let shared_secret = match opk {
+        Some(opk) => {
+            let dh4 = ek.secret.diffie_hellman(&opk.public);
+            derive_shared_secret(&[dh1.as_bytes(), dh2.as_bytes(), dh3.as_bytes(), dh4.as_bytes()])?
+        }
+        None => derive_shared_secret(&[dh1.as_bytes(), dh2.as_bytes(), dh3.as_bytes()])?,
+    };
 
-    let hk = Hkdf::<Sha256>::new(None, &dh_concat);
-    let mut okm = [0u8; 32];
-    hk.expand(b"x3dh derived key", &mut okm)?;
+    let header = InitialMessageHeader {
+        identity_pub: identity.keypair.public,
+        identity_dh_pub: identity.dh_public,
+        identity_dh_signature: identity.keypair.sign(identity.dh_public.as_bytes()),
+        ephemeral_pub: ek.public,
+        spk_id: bundle.spk_id,
+        one_time_prekey_id: opk.map(|opk| opk.id),
+    };
 
     Ok(X3DHInitResult {
         ephemeral: ek,
-        shared_secret: okm,
+        shared_secret,
+        header,
     })
 }
+
+/// Execute the X3DH responder step from the initiator's header, with the signed prekey
+/// it refers to. The referenced one-time prekey is removed from the store.
+pub fn x3dh_respond(
+    identity: &IdentityKey,
+    spk: &SignedPreKey,
+    opks: &mut OneTimePreKeyStore,
+    header: &InitialMessageHeader,
+) -> Result<[u8; 32]> {
+    if header.spk_id != spk.id {
+        return Err(anyhow!("Initial message uses signed prekey {}, not {}", header.spk_id, spk.id));
+    }
+    header
+        .identity_pub
+        .verify(header.identity_dh_pub.as_bytes(), &header.identity_dh_signature)
+        .map_err(|_| anyhow!("Invalid initiator identity DH key signature"))?;
+
+    let dh1 = spk.secret.diffie_hellman(&header.identity_dh_pub);
+    let dh2 = identity.dh_secret.diffie_hellman(&header.ephemeral_pub);
+    let dh3 = spk.secret.diffie_hellman(&header.ephemeral_pub);
+
+    match header.one_time_prekey_id {
+        Some(id) => {
+            let opk = opks.take(id)?;
+            let dh4 = opk.secret.diffie_hellman(&header.ephemeral_pub);
+            derive_shared_secret(&[dh1.as_bytes(), dh2.as_bytes(), dh3.as_bytes(), dh4.as_bytes()])
+        }
+        None => derive_shared_secret(&[dh1.as_bytes(), dh2.as_bytes(), dh3.as_bytes()]),
+    }
+}
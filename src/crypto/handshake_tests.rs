@@ -0,0 +1,104 @@
+#[cfg(test)]
+mod tests {
+    use super::super::handshake::{
+        build_bundle, generate_identity_bundle, x3dh_initiate, x3dh_respond, KeyMaterial,
+        SignedPreKeyStore, SpkRotationPolicy,
+    };
+    use ed25519_dalek::Signer;
+
+    // Initiator and responder must derive the same secret from the initial header
+    #[test]
+    fn test_initiate_and_respond_agree() {
+        let (alice_id, _alice_spk, _alice_opks, _alice_bundle) = generate_identity_bundle().unwrap();
+        let (bob_id, bob_spk, mut bob_opks, mut bob_bundle) = generate_identity_bundle().unwrap();
+        bob_bundle.one_time_prekeys.clear();
+
+        let init = x3dh_initiate(&alice_id, &bob_bundle).expect("initiate failed");
+        assert_eq!(init.header.one_time_prekey_id, None);
+        let secret = x3dh_respond(&bob_id, &bob_spk, &mut bob_opks, &init.header).expect("respond failed");
+
+        assert_eq!(secret, init.shared_secret);
+        assert_eq!(init.header.identity_pub, alice_id.keypair.public);
+    }
+
+    // A bundle whose SPK was not signed by the identity key is rejected
+    #[test]
+    fn test_initiate_rejects_forged_spk() {
+        let (alice_id, _, _, _) = generate_identity_bundle().unwrap();
+        let (_, _, _, mut bob_bundle) = generate_identity_bundle().unwrap();
+        let (_, mallory_spk, _, _) = generate_identity_bundle().unwrap();
+
+        bob_bundle.spk_pub = mallory_spk.public;
+        assert!(x3dh_initiate(&alice_id, &bob_bundle).is_err());
+    }
+
+    // A header claiming someone else's DH identity key is rejected by the responder
+    #[test]
+    fn test_respond_rejects_unsigned_identity_dh_key() {
+        let (alice_id, _, _, _) = generate_identity_bundle().unwrap();
+        let (bob_id, bob_spk, mut bob_opks, bob_bundle) = generate_identity_bundle().unwrap();
+        let (mallory_id, _, _, _) = generate_identity_bundle().unwrap();
+
+        let mut init = x3dh_initiate(&alice_id, &bob_bundle).unwrap();
+        init.header.identity_dh_pub = mallory_id.dh_public;
+        init.header.identity_dh_signature = mallory_id.keypair.sign(mallory_id.dh_public.as_bytes());
+
+        assert!(x3dh_respond(&bob_id, &bob_spk, &mut bob_opks, &init.header).is_err());
+    }
+
+    // The one-time prekey is mixed in on both sides, then deleted from the responder's store
+    #[test]
+    fn test_one_time_prekey_consumed_once() {
+        let (alice_id, _, _, _) = generate_identity_bundle().unwrap();
+        let (bob_id, bob_spk, mut bob_opks, bob_bundle) = generate_identity_bundle().unwrap();
+        let before = bob_opks.remaining();
+
+        let init = x3dh_initiate(&alice_id, &bob_bundle).unwrap();
+        let opk_id = init.header.one_time_prekey_id.expect("bundle had one-time prekeys");
+        let secret = x3dh_respond(&bob_id, &bob_spk, &mut bob_opks, &init.header).unwrap();
+
+        assert_eq!(secret, init.shared_secret);
+        assert_eq!(bob_opks.remaining(), before - 1);
+        assert!(bob_opks.consumed_ids().contains(&opk_id));
+
+        // Replaying the same initial message must not reuse the prekey
+        assert!(x3dh_respond(&bob_id, &bob_spk, &mut bob_opks, &init.header).is_err());
+    }
+
+    // Rotation keeps the replaced signed prekey until its grace period ends
+    #[test]
+    fn test_signed_prekey_rotation() {
+        let (alice_id, _, _, _) = generate_identity_bundle().unwrap();
+        let (bob_id, bob_spk, mut bob_opks, _) = generate_identity_bundle().unwrap();
+        let policy = SpkRotationPolicy::default();
+        let day = 24 * 3600;
+        let start = bob_spk.created_at;
+        let mut spks = SignedPreKeyStore::new(bob_spk);
+
+        assert!(!spks.is_due(start + day, &policy));
+        assert!(spks.is_due(start + 7 * day, &policy));
+
+        let old_bundle = build_bundle(&bob_id, spks.current(), vec![]);
+        spks.rotate(&bob_id, start + 7 * day);
+        let new_bundle = build_bundle(&bob_id, spks.current(), vec![]);
+        assert_eq!(new_bundle.spk_id, old_bundle.spk_id + 1);
+        x3dh_initiate(&alice_id, &new_bundle).expect("rotated SPK is signed");
+
+        // The header names the SPK it used: only that key can answer it
+        let init = x3dh_initiate(&alice_id, &old_bundle).unwrap();
+        assert!(x3dh_respond(&bob_id, spks.current(), &mut bob_opks, &init.header).is_err());
+        let old_spk = spks.get(init.header.spk_id).expect("kept during the grace period");
+        let secret = x3dh_respond(&bob_id, old_spk, &mut bob_opks, &init.header).unwrap();
+        assert_eq!(secret, init.shared_secret);
+
+        // The retired keys survive a restart
+        let (_, restored, _) = KeyMaterial::export(&bob_id, &spks, &bob_opks).restore().unwrap();
+        assert_eq!(restored.retired_count(), 1);
+        assert_eq!(restored.current().public, spks.current().public);
+
+        assert_eq!(spks.purge_expired(start + 20 * day, &policy), 0);
+        assert_eq!(spks.purge_expired(start + 21 * day, &policy), 1);
+        assert!(spks.get(old_bundle.spk_id).is_none());
+        assert!(spks.get(new_bundle.spk_id).is_some());
+    }
+}
@@ -0,0 +1,1242 @@
+use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use rand_core::OsRng;
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+use serde::{Serialize, Deserialize};
+use anyhow::{Result, anyhow};
+use uuid::Uuid;
+use std::collections::HashMap;
+use crate::crypto::padding::{pad, unpad, PaddingScheme};
+use crate::crypto::signature::{SigningKey, verify_signature};
+use crate::models::device::DeviceId;
+use crate::models::group::Group;
+use crate::models::message::put_field;
+
+// Group key agreement modelled on MLS (ratchet tree, commits, Welcomes, per-sender hash
+// ratchets), written for Enigma. It is not RFC 9420 and does not interoperate with MLS
+// implementations. It has not been reviewed by cryptographers: it needs that review, or to
+// be replaced by an audited implementation such as openmls, before groups rely on it.
+
+/// Prefix of every label used in key derivations and signatures
+const MLS_LABEL: &[u8] = b"enigma-mls-v1 ";
+
+/// Maximum number of message keys derived ahead within one sender's chain
+pub const MAX_MLS_SKIP: u32 = 1000;
+
+/// Seconds during which messages of the previous epoch are still accepted after a commit
+pub const PREVIOUS_EPOCH_WINDOW: i64 = 5 * 60;
+
+/// Position of a member in the ratchet tree
+pub type LeafIndex = u32;
+
+// ===================== Tree arithmetic =====================
+// Leaves sit at even node indexes, parents at odd ones; the tree always holds a power of
+// two leaves, so growing it keeps every existing index.
+
+fn level(x: u32) -> u32 {
+    (!x).trailing_zeros()
+}
+
+fn left(x: u32) -> u32 {
+    x ^ (1 << (level(x) - 1))
+}
+
+fn right(x: u32) -> u32 {
+    x ^ (3 << (level(x) - 1))
+}
+
+fn parent(x: u32) -> u32 {
+    let k = level(x);
+    let b = (x >> (k + 1)) & 1;
+    (x | (1 << k)) ^ (b << (k + 1))
+}
+
+fn sibling(x: u32) -> u32 {
+    let p = parent(x);
+    if x < p { right(p) } else { left(p) }
+}
+
+/// Whether node `y` is in the subtree rooted at `x`
+fn covers(x: u32, y: u32) -> bool {
+    let span = (1i64 << level(x)) - 1;
+    (y as i64) >= (x as i64) - span && (y as i64) <= (x as i64) + span
+}
+
+fn leaf_node(leaf: LeafIndex) -> u32 {
+    2 * leaf
+}
+
+// ===================== Key derivation and encryption helpers =====================
+
+fn derive(secret: &[u8], label: &[u8]) -> [u8; 32] {
+    let hk = Hkdf::<Sha256>::new(None, secret);
+    let mut info = MLS_LABEL.to_vec();
+    info.extend_from_slice(label);
+    let mut out = [0u8; 32];
+    hk.expand(&info, &mut out).expect("32 bytes is a valid HKDF length");
+    out
+}
+
+fn random_secret() -> Result<[u8; 32]> {
+    let mut secret = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut secret)
+        .map_err(|_| anyhow!("Random generation failed"))?;
+    Ok(secret)
+}
+
+fn public_of(secret: &[u8; 32]) -> [u8; 32] {
+    *X25519PublicKey::from(&StaticSecret::from(*secret)).as_bytes()
+}
+
+/// Private key of a tree node, from its path secret
+fn node_secret(path_secret: &[u8; 32]) -> [u8; 32] {
+    derive(path_secret, b"node")
+}
+
+fn to_secret(bytes: &[u8]) -> Result<[u8; 32]> {
+    bytes.try_into().map_err(|_| anyhow!("Invalid secret length"))
+}
+
+fn aead_key(key: &[u8; 32]) -> Result<LessSafeKey> {
+    let key = UnboundKey::new(&CHACHA20_POLY1305, key).map_err(|_| anyhow!("Invalid key"))?;
+    Ok(LessSafeKey::new(key))
+}
+
+/// Output of a one-shot public key encryption (X25519, HKDF-SHA256, ChaCha20-Poly1305)
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HpkeCiphertext {
+    pub kem_output: [u8; 32],
+    pub ciphertext: Vec<u8>,
+}
+
+/// Single-use key: HKDF over the DH output, bound to both public keys and `info`
+fn hpke_key(dh_output: &[u8], kem_output: &[u8; 32], recipient: &[u8; 32], info: &[u8]) -> Result<LessSafeKey> {
+    let mut salt = kem_output.to_vec();
+    salt.extend_from_slice(recipient);
+    let hk = Hkdf::<Sha256>::new(Some(&salt), dh_output);
+    let mut key = [0u8; 32];
+    hk.expand(info, &mut key).map_err(|_| anyhow!("HPKE key derivation failed"))?;
+    aead_key(&key)
+}
+
+fn hpke_seal(recipient: &[u8; 32], info: &[u8], plaintext: &[u8]) -> Result<HpkeCiphertext> {
+    let ephemeral = StaticSecret::new(OsRng);
+    let kem_output = *X25519PublicKey::from(&ephemeral).as_bytes();
+    let dh = ephemeral.diffie_hellman(&X25519PublicKey::from(*recipient));
+    let key = hpke_key(dh.as_bytes(), &kem_output, recipient, info)?;
+    let mut ciphertext = plaintext.to_vec();
+    // The key is used once, so a fixed nonce is safe
+    key.seal_in_place_append_tag(Nonce::assume_unique_for_key([0u8; NONCE_LEN]), Aad::from(info), &mut ciphertext)
+        .map_err(|_| anyhow!("HPKE encryption failed"))?;
+    Ok(HpkeCiphertext { kem_output, ciphertext })
+}
+
+fn hpke_open(secret: &[u8; 32], sealed: &HpkeCiphertext, info: &[u8]) -> Result<Vec<u8>> {
+    let dh = StaticSecret::from(*secret).diffie_hellman(&X25519PublicKey::from(sealed.kem_output));
+    let key = hpke_key(dh.as_bytes(), &sealed.kem_output, &public_of(secret), info)?;
+    let mut buffer = sealed.ciphertext.clone();
+    let plaintext = key
+        .open_in_place(Nonce::assume_unique_for_key([0u8; NONCE_LEN]), Aad::from(info), &mut buffer)
+        .map_err(|_| anyhow!("HPKE decryption failed"))?;
+    Ok(plaintext.to_vec())
+}
+
+fn labeled(label: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    put_field(&mut out, MLS_LABEL);
+    put_field(&mut out, label);
+    out
+}
+
+// ===================== Members and key packages =====================
+
+/// Leaf of the ratchet tree: one device of a member
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct LeafNode {
+    pub username: String,
+    pub device: DeviceId,
+    pub encryption_key: [u8; 32], // X25519 key of the leaf, replaced on every commit by its owner
+    pub signature_key: Vec<u8>,   // Ed25519 identity key of the device
+    pub signature: Vec<u8>,
+}
+
+impl LeafNode {
+    fn signed_payload(&self) -> Vec<u8> {
+        let mut out = labeled(b"leaf");
+        put_field(&mut out, self.username.as_bytes());
+        out.extend_from_slice(&self.device.to_be_bytes());
+        out.extend_from_slice(&self.encryption_key);
+        put_field(&mut out, &self.signature_key);
+        out
+    }
+
+    fn sign(&mut self, key: &SigningKey) {
+        self.signature = key.sign(&self.signed_payload()).as_ref().to_vec();
+    }
+
+    /// Checks the signature by the device identity key
+    pub fn verify(&self) -> Result<()> {
+        verify_signature(&self.signature_key, &self.signed_payload(), &self.signature)
+            .map_err(|_| anyhow!("Invalid leaf signature for {}", self.username))
+    }
+}
+
+/// Published with the X3DH bundle so that a device can be added to groups while offline:
+/// a signed leaf plus a one-time init key for the Welcome
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct KeyPackage {
+    pub init_key: [u8; 32],
+    pub leaf: LeafNode,
+    pub signature: Vec<u8>,
+}
+
+/// Private half of a key package, kept until a Welcome consumes it
+#[derive(Clone, Serialize, Deserialize)]
+pub struct KeyPackageSecrets {
+    pub init_secret: [u8; 32],
+    pub leaf_secret: [u8; 32],
+}
+
+impl KeyPackage {
+    /// New key package for a device, signed with its identity key
+    pub fn generate(username: &str, device: DeviceId, key: &SigningKey) -> Result<(Self, KeyPackageSecrets)> {
+        let secrets = KeyPackageSecrets { init_secret: random_secret()?, leaf_secret: random_secret()? };
+        let mut leaf = LeafNode {
+            username: username.to_owned(),
+            device,
+            encryption_key: public_of(&node_secret(&secrets.leaf_secret)),
+            signature_key: key.public_key_bytes().to_vec(),
+            signature: Vec::new(),
+        };
+        leaf.sign(key);
+        let mut package = Self { init_key: public_of(&secrets.init_secret), leaf, signature: Vec::new() };
+        package.signature = key.sign(&package.signed_payload()).as_ref().to_vec();
+        Ok((package, secrets))
+    }
+
+    fn signed_payload(&self) -> Vec<u8> {
+        let mut out = labeled(b"key package");
+        out.extend_from_slice(&self.init_key);
+        put_field(&mut out, &self.leaf.signed_payload());
+        put_field(&mut out, &self.leaf.signature);
+        out
+    }
+
+    /// Checks both signatures by the device identity key
+    pub fn verify(&self) -> Result<()> {
+        self.leaf.verify()?;
+        verify_signature(&self.leaf.signature_key, &self.signed_payload(), &self.signature)
+            .map_err(|_| anyhow!("Invalid key package signature for {}", self.leaf.username))
+    }
+}
+
+// ===================== Ratchet tree =====================
+
+/// Inner node: its key is known to the members below it
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ParentNode {
+    pub public_key: [u8; 32],
+    pub unmerged_leaves: Vec<LeafIndex>, // Added since the key was set: they do not know it
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Node {
+    Leaf(LeafNode),
+    Parent(ParentNode),
+}
+
+/// Public ratchet tree shared by all members
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RatchetTree {
+    pub nodes: Vec<Option<Node>>,
+}
+
+impl RatchetTree {
+    fn leaf_count(&self) -> u32 {
+        (self.nodes.len() as u32).div_ceil(2)
+    }
+
+    fn root(&self) -> u32 {
+        self.leaf_count() - 1
+    }
+
+    /// Ancestors of `x`, from its parent up to the root
+    fn direct_path(&self, mut x: u32) -> Vec<u32> {
+        let mut path = Vec::new();
+        while x != self.root() {
+            x = parent(x);
+            path.push(x);
+        }
+        path
+    }
+
+    /// Siblings of `x` and of its ancestors below the root
+    fn copath(&self, x: u32) -> Vec<u32> {
+        let mut nodes = vec![x];
+        nodes.extend(self.direct_path(x));
+        nodes.pop();
+        nodes.into_iter().map(sibling).collect()
+    }
+
+    /// Leaf at `leaf`, if not blank
+    pub fn leaf(&self, leaf: LeafIndex) -> Option<&LeafNode> {
+        match self.nodes.get(leaf_node(leaf) as usize) {
+            Some(Some(Node::Leaf(node))) => Some(node),
+            _ => None,
+        }
+    }
+
+    /// Occupied leaves
+    pub fn leaves(&self) -> Vec<(LeafIndex, &LeafNode)> {
+        (0..self.leaf_count()).filter_map(|i| self.leaf(i).map(|l| (i, l))).collect()
+    }
+
+    fn public_key(&self, x: u32) -> Result<[u8; 32]> {
+        match &self.nodes[x as usize] {
+            Some(Node::Leaf(leaf)) => Ok(leaf.encryption_key),
+            Some(Node::Parent(node)) => Ok(node.public_key),
+            None => Err(anyhow!("Node {} is blank", x)),
+        }
+    }
+
+    /// Smallest set of non-blank nodes covering the subtree of `x`, with the leaves that
+    /// do not know the key of a node yet
+    fn resolution(&self, x: u32) -> Vec<u32> {
+        match &self.nodes[x as usize] {
+            Some(Node::Leaf(_)) => vec![x],
+            Some(Node::Parent(node)) => {
+                let mut nodes = vec![x];
+                nodes.extend(node.unmerged_leaves.iter().map(|l| leaf_node(*l)));
+                nodes
+            }
+            None if level(x) == 0 => Vec::new(),
+            None => {
+                let mut nodes = self.resolution(left(x));
+                nodes.extend(self.resolution(right(x)));
+                nodes
+            }
+        }
+    }
+
+    /// Puts a new member in the leftmost blank leaf, doubling the tree when full
+    fn add_leaf(&mut self, leaf: LeafNode) -> LeafIndex {
+        let index = match (0..self.leaf_count()).find(|i| self.leaf(*i).is_none()) {
+            Some(index) => index,
+            None => {
+                let index = self.leaf_count();
+                self.nodes.resize(self.nodes.len() * 2 + 1, None);
+                index
+            }
+        };
+        self.nodes[leaf_node(index) as usize] = Some(Node::Leaf(leaf));
+        for x in self.direct_path(leaf_node(index)) {
+            if let Some(Node::Parent(node)) = &mut self.nodes[x as usize] {
+                node.unmerged_leaves.push(index);
+            }
+        }
+        index
+    }
+
+    /// Blanks a leaf and its direct path
+    fn remove_leaf(&mut self, leaf: LeafIndex) -> Result<()> {
+        if self.leaf(leaf).is_none() {
+            return Err(anyhow!("No member at leaf {}", leaf));
+        }
+        self.nodes[leaf_node(leaf) as usize] = None;
+        for x in self.direct_path(leaf_node(leaf)) {
+            self.nodes[x as usize] = None;
+        }
+        Ok(())
+    }
+
+    /// Replaces the keys of a leaf and blanks its direct path, whose keys it knew
+    fn update_leaf(&mut self, index: LeafIndex, leaf: LeafNode) -> Result<()> {
+        if self.leaf(index).is_none() {
+            return Err(anyhow!("No member at leaf {}", index));
+        }
+        self.nodes[leaf_node(index) as usize] = Some(Node::Leaf(leaf));
+        for x in self.direct_path(leaf_node(index)) {
+            self.nodes[x as usize] = None;
+        }
+        Ok(())
+    }
+
+    /// Applies removals, updates, then additions; returns the new leaves with their init keys
+    fn apply(&mut self, proposals: &[AuthenticatedProposal], committer: LeafIndex) -> Result<Vec<(LeafIndex, [u8; 32])>> {
+        for proposal in proposals {
+            if let Proposal::Remove(leaf) = proposal.proposal {
+                if leaf == committer {
+                    return Err(anyhow!("A committer cannot remove itself"));
+                }
+                self.remove_leaf(leaf)?;
+            }
+        }
+        for proposal in proposals {
+            if let Proposal::Update(leaf) = &proposal.proposal {
+                if proposal.proposer == committer {
+                    return Err(anyhow!("A committer refreshes its leaf with its update path"));
+                }
+                self.update_leaf(proposal.proposer, leaf.clone())?;
+            }
+        }
+        let mut added = Vec::new();
+        for proposal in proposals {
+            if let Proposal::Add(package) = &proposal.proposal {
+                added.push((self.add_leaf(package.leaf.clone()), package.init_key));
+            }
+        }
+        Ok(added)
+    }
+
+    fn hash(&self) -> Result<Vec<u8>> {
+        Ok(Sha256::digest(&bincode::serialize(self)?).to_vec())
+    }
+}
+
+// ===================== Handshake messages =====================
+
+/// Change requested to the group: a membership change, or new keys for the proposer's leaf
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Proposal {
+    Add(KeyPackage),
+    Remove(LeafIndex),
+    Update(LeafNode),
+}
+
+/// Proposal signed by the member who made it, for one epoch
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuthenticatedProposal {
+    pub group_id: Uuid,
+    pub epoch: u64,
+    pub proposer: LeafIndex,
+    pub proposal: Proposal,
+    pub signature: Vec<u8>,
+}
+
+impl AuthenticatedProposal {
+    fn signed_payload(&self) -> Result<Vec<u8>> {
+        let mut out = labeled(b"proposal");
+        out.extend_from_slice(self.group_id.as_bytes());
+        out.extend_from_slice(&self.epoch.to_be_bytes());
+        out.extend_from_slice(&self.proposer.to_be_bytes());
+        put_field(&mut out, &bincode::serialize(&self.proposal)?);
+        Ok(out)
+    }
+}
+
+/// New key of one node of the committer's direct path, with its path secret encrypted
+/// to each node of the resolution of the copath child
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UpdatePathNode {
+    pub public_key: [u8; 32],
+    pub encrypted_path_secrets: Vec<HpkeCiphertext>,
+}
+
+/// Fresh leaf and direct path of the committer
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UpdatePath {
+    pub leaf: LeafNode,
+    pub nodes: Vec<UpdatePathNode>,
+}
+
+/// Moves the group to the next epoch: applies proposals and refreshes the committer's path
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Commit {
+    pub group_id: Uuid,
+    pub epoch: u64, // Epoch the commit starts from
+    pub committer: LeafIndex,
+    pub proposals: Vec<AuthenticatedProposal>,
+    pub path: UpdatePath,
+    pub signature: Vec<u8>,
+    pub confirmation_tag: [u8; 32], // Proves the committer derived the new epoch secrets
+}
+
+impl Commit {
+    fn signed_payload(&self) -> Result<Vec<u8>> {
+        let mut out = labeled(b"commit");
+        out.extend_from_slice(self.group_id.as_bytes());
+        out.extend_from_slice(&self.epoch.to_be_bytes());
+        out.extend_from_slice(&self.committer.to_be_bytes());
+        put_field(&mut out, &bincode::serialize(&self.proposals)?);
+        put_field(&mut out, &bincode::serialize(&self.path)?);
+        Ok(out)
+    }
+}
+
+/// Secrets a new member needs to enter the epoch its Welcome starts
+#[derive(Serialize, Deserialize)]
+struct GroupSecrets {
+    joiner_secret: [u8; 32],
+    path_secret: [u8; 32], // Of the lowest node shared with the committer
+}
+
+/// Group secrets of one new member, encrypted to the init key of its key package
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EncryptedGroupSecrets {
+    pub init_key: [u8; 32],
+    pub secrets: HpkeCiphertext,
+}
+
+/// Lets the members added by a commit join its epoch
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Welcome {
+    pub group_id: Uuid,
+    pub epoch: u64,
+    pub committer: LeafIndex,
+    pub tree: RatchetTree,
+    pub secrets: Vec<EncryptedGroupSecrets>,
+    pub signature: Vec<u8>,
+}
+
+impl Welcome {
+    fn signed_payload(&self) -> Result<Vec<u8>> {
+        let mut out = labeled(b"welcome");
+        out.extend_from_slice(self.group_id.as_bytes());
+        out.extend_from_slice(&self.epoch.to_be_bytes());
+        out.extend_from_slice(&self.committer.to_be_bytes());
+        put_field(&mut out, &bincode::serialize(&self.tree)?);
+        put_field(&mut out, &bincode::serialize(&self.secrets)?);
+        Ok(out)
+    }
+}
+
+/// Welcome sent to new members over pairwise sessions, with the group metadata
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GroupWelcome {
+    pub group: Group,
+    pub welcome: Welcome,
+}
+
+/// Application message encrypted with the sender's chain of the current epoch
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MlsMessage {
+    pub group_id: Uuid,
+    pub epoch: u64,
+    pub sender: LeafIndex,
+    pub generation: u32,
+    pub ciphertext: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl MlsMessage {
+    fn header(&self) -> Vec<u8> {
+        let mut out = labeled(b"application");
+        out.extend_from_slice(self.group_id.as_bytes());
+        out.extend_from_slice(&self.epoch.to_be_bytes());
+        out.extend_from_slice(&self.sender.to_be_bytes());
+        out.extend_from_slice(&self.generation.to_be_bytes());
+        out
+    }
+
+    fn signed_payload(&self) -> Vec<u8> {
+        let mut out = self.header();
+        put_field(&mut out, &self.ciphertext);
+        out
+    }
+}
+
+/// Membership change carried by a proposal, for permission checks
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    Add { proposer: String, member: String },
+    Remove { proposer: String, member: String },
+}
+
+/// Result of processing a commit from another member
+#[derive(Debug, PartialEq, Eq)]
+pub enum CommitOutcome {
+    /// The group moved to the next epoch
+    Applied,
+    /// We are no longer a member
+    Removed,
+}
+
+// ===================== Group state =====================
+
+/// Secrets of one epoch
+#[derive(Clone, Serialize, Deserialize)]
+struct EpochSecrets {
+    encryption: [u8; 32],   // Root of the senders' message chains
+    confirmation: [u8; 32], // Key of the confirmation tags
+    init: [u8; 32],         // Feeds the next epoch
+}
+
+impl EpochSecrets {
+    fn derive(joiner_secret: &[u8; 32], context: &[u8]) -> Self {
+        let mut input = joiner_secret.to_vec();
+        input.extend_from_slice(context);
+        let epoch_secret = derive(&input, b"epoch");
+        Self {
+            encryption: derive(&epoch_secret, b"encryption"),
+            confirmation: derive(&epoch_secret, b"confirm"),
+            init: derive(&epoch_secret, b"init"),
+        }
+    }
+
+    fn confirmation_tag(&self, commit: &Commit) -> Result<[u8; 32]> {
+        let mut input = self.confirmation.to_vec();
+        input.extend_from_slice(&Sha256::digest(&commit.signed_payload()?));
+        Ok(derive(&input, b"confirmation tag"))
+    }
+}
+
+fn joiner_secret(init_secret: &[u8; 32], commit_secret: &[u8; 32]) -> [u8; 32] {
+    let mut input = init_secret.to_vec();
+    input.extend_from_slice(commit_secret);
+    derive(&input, b"joiner")
+}
+
+/// Binds the epoch secrets to the group, the epoch and the exact tree
+fn group_context(group_id: &Uuid, epoch: u64, tree: &RatchetTree) -> Result<Vec<u8>> {
+    let mut out = group_id.as_bytes().to_vec();
+    out.extend_from_slice(&epoch.to_be_bytes());
+    out.extend_from_slice(&tree.hash()?);
+    Ok(out)
+}
+
+fn path_info(group_id: &Uuid, epoch: u64) -> Vec<u8> {
+    let mut out = labeled(b"path secret");
+    out.extend_from_slice(group_id.as_bytes());
+    out.extend_from_slice(&epoch.to_be_bytes());
+    out
+}
+
+fn welcome_info(group_id: &Uuid) -> Vec<u8> {
+    let mut out = labeled(b"welcome secrets");
+    out.extend_from_slice(group_id.as_bytes());
+    out
+}
+
+/// Hash ratchet of one sender within an epoch
+#[derive(Clone, Serialize, Deserialize)]
+struct SenderChain {
+    generation: u32,
+    chain_key: [u8; 32],
+    skipped: Vec<(u32, [u8; 32])>,
+}
+
+impl SenderChain {
+    fn new(encryption_secret: &[u8; 32], sender: LeafIndex) -> Self {
+        let mut input = encryption_secret.to_vec();
+        input.extend_from_slice(&sender.to_be_bytes());
+        Self { generation: 0, chain_key: derive(&input, b"sender"), skipped: Vec::new() }
+    }
+
+    /// Key of generation `n`; used keys are forgotten
+    fn key_for(&mut self, n: u32) -> Result<[u8; 32]> {
+        if let Some(pos) = self.skipped.iter().position(|(g, _)| *g == n) {
+            return Ok(self.skipped.remove(pos).1);
+        }
+        if n < self.generation {
+            return Err(anyhow!("Duplicate or expired group message {}", n));
+        }
+        if n - self.generation > MAX_MLS_SKIP {
+            return Err(anyhow!("Group message {} is too far ahead", n));
+        }
+        loop {
+            let key = derive(&self.chain_key, b"key");
+            self.chain_key = derive(&self.chain_key, b"next");
+            self.generation += 1;
+            if self.generation - 1 == n {
+                let excess = self.skipped.len().saturating_sub(MAX_MLS_SKIP as usize);
+                self.skipped.drain(..excess);
+                return Ok(key);
+            }
+            self.skipped.push((self.generation - 1, key));
+        }
+    }
+}
+
+fn message_nonce(key: &[u8; 32]) -> Nonce {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce.copy_from_slice(&derive(key, b"nonce")[..NONCE_LEN]);
+    Nonce::assume_unique_for_key(nonce)
+}
+
+/// What is left of the previous epoch: enough to read the messages sent before its
+/// members processed the commit that ended it
+#[derive(Clone, Serialize, Deserialize)]
+struct PreviousEpoch {
+    epoch: u64,
+    tree: RatchetTree,
+    encryption: [u8; 32],
+    sender_chains: HashMap<LeafIndex, SenderChain>,
+    expires: i64, // Unix time
+}
+
+/// State of this device in an MLS group: public tree, private keys of its direct path and
+/// secrets of the current epoch
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MlsGroup {
+    group_id: Uuid,
+    epoch: u64,
+    tree: RatchetTree,
+    own_leaf: LeafIndex,
+    private_keys: HashMap<u32, [u8; 32]>, // By node index
+    secrets: EpochSecrets,
+    sender_chains: HashMap<LeafIndex, SenderChain>,
+    pending_proposals: Vec<AuthenticatedProposal>,
+    previous: Option<PreviousEpoch>,
+    awaiting: Vec<Proposal>,           // Our proposals not committed yet
+    pending_update: Option<[u8; 32]>, // Leaf secret of our Update proposal
+}
+
+impl MlsGroup {
+    /// New group whose only member is the device of `package`, at epoch 0
+    pub fn create(group_id: Uuid, package: &KeyPackage, secrets: &KeyPackageSecrets) -> Result<Self> {
+        let tree = RatchetTree { nodes: vec![Some(Node::Leaf(package.leaf.clone()))] };
+        let epoch_secrets = EpochSecrets::derive(&random_secret()?, &group_context(&group_id, 0, &tree)?);
+        let mut private_keys = HashMap::new();
+        private_keys.insert(leaf_node(0), node_secret(&secrets.leaf_secret));
+        Ok(Self {
+            group_id,
+            epoch: 0,
+            tree,
+            own_leaf: 0,
+            private_keys,
+            secrets: epoch_secrets,
+            sender_chains: HashMap::new(),
+            pending_proposals: Vec::new(),
+            previous: None,
+            awaiting: Vec::new(),
+            pending_update: None,
+        })
+    }
+
+    /// Current epoch
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Our position in the tree
+    pub fn own_leaf(&self) -> LeafIndex {
+        self.own_leaf
+    }
+
+    /// Public ratchet tree
+    pub fn tree(&self) -> &RatchetTree {
+        &self.tree
+    }
+
+    /// Distinct usernames of the members, sorted
+    pub fn usernames(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.tree.leaves().into_iter().map(|(_, l)| l.username.clone()).collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// Leaves of every device of a member
+    pub fn leaves_of(&self, username: &str) -> Vec<LeafIndex> {
+        self.tree
+            .leaves()
+            .into_iter()
+            .filter(|(_, l)| l.username == username)
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// Proposals received during this epoch, to be committed
+    pub fn pending_proposals(&self) -> &[AuthenticatedProposal] {
+        &self.pending_proposals
+    }
+
+    /// Signs a proposal for the current epoch
+    pub fn propose(&self, proposal: Proposal, key: &SigningKey) -> Result<AuthenticatedProposal> {
+        let mut proposal = AuthenticatedProposal {
+            group_id: self.group_id,
+            epoch: self.epoch,
+            proposer: self.own_leaf,
+            proposal,
+            signature: Vec::new(),
+        };
+        proposal.signature = key.sign(&proposal.signed_payload()?).as_ref().to_vec();
+        Ok(proposal)
+    }
+
+    /// Signs a proposal for the committer of the group, and remembers it until a commit
+    /// includes it (see `take_awaiting`)
+    pub fn submit(&mut self, proposal: Proposal, key: &SigningKey) -> Result<AuthenticatedProposal> {
+        let proposal = self.propose(proposal, key)?;
+        self.verify_proposal(&proposal)?;
+        self.awaiting.push(proposal.proposal.clone());
+        Ok(proposal)
+    }
+
+    /// Proposes new keys for our leaf, for the committer to apply: the way members other
+    /// than the committer refresh their keys
+    pub fn propose_update(&mut self, key: &SigningKey) -> Result<AuthenticatedProposal> {
+        let leaf_secret = random_secret()?;
+        let mut leaf = self.tree.leaf(self.own_leaf).ok_or_else(|| anyhow!("Our leaf is blank"))?.clone();
+        leaf.encryption_key = public_of(&node_secret(&leaf_secret));
+        leaf.sign(key);
+        let proposal = self.submit(Proposal::Update(leaf), key)?;
+        self.pending_update = Some(leaf_secret);
+        Ok(proposal)
+    }
+
+    /// Our proposals that the last commit left out and that still apply, to submit again
+    /// for the new epoch. Updates must be made again with `propose_update`.
+    pub fn take_awaiting(&mut self) -> Vec<Proposal> {
+        let awaiting = std::mem::take(&mut self.awaiting);
+        awaiting
+            .into_iter()
+            .filter(|proposal| match proposal {
+                Proposal::Add(package) => !self
+                    .tree
+                    .leaves()
+                    .iter()
+                    .any(|(_, l)| l.username == package.leaf.username && l.device == package.leaf.device),
+                Proposal::Remove(leaf) => self.tree.leaf(*leaf).is_some(),
+                Proposal::Update(_) => true,
+            })
+            .collect()
+    }
+
+    /// Keeps a proposal of another member until someone commits it
+    pub fn store_proposal(&mut self, proposal: AuthenticatedProposal) -> Result<()> {
+        self.verify_proposal(&proposal)?;
+        self.pending_proposals.push(proposal);
+        Ok(())
+    }
+
+    fn verify_proposal(&self, proposal: &AuthenticatedProposal) -> Result<()> {
+        if proposal.group_id != self.group_id || proposal.epoch != self.epoch {
+            return Err(anyhow!("Proposal for epoch {}, group is at {}", proposal.epoch, self.epoch));
+        }
+        let proposer = self
+            .tree
+            .leaf(proposal.proposer)
+            .ok_or_else(|| anyhow!("Proposal from unknown leaf {}", proposal.proposer))?;
+        verify_signature(&proposer.signature_key, &proposal.signed_payload()?, &proposal.signature)
+            .map_err(|_| anyhow!("Invalid proposal signature"))?;
+        match &proposal.proposal {
+            Proposal::Add(package) => {
+                package.verify()?;
+                let present = self
+                    .tree
+                    .leaves()
+                    .iter()
+                    .any(|(_, l)| l.username == package.leaf.username && l.device == package.leaf.device);
+                if present {
+                    return Err(anyhow!("Device {} of {} is already a member", package.leaf.device, package.leaf.username));
+                }
+                Ok(())
+            }
+            Proposal::Remove(leaf) => self
+                .tree
+                .leaf(*leaf)
+                .map(|_| ())
+                .ok_or_else(|| anyhow!("No member at leaf {}", leaf)),
+            Proposal::Update(leaf) => {
+                leaf.verify()?;
+                if leaf.username != proposer.username
+                    || leaf.device != proposer.device
+                    || leaf.signature_key != proposer.signature_key
+                {
+                    return Err(anyhow!("An update cannot change the identity of its proposer"));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Membership changes requested by proposals, with who asked for them. Updates only
+    /// touch the proposer's own keys and change nobody.
+    pub fn changes(&self, proposals: &[AuthenticatedProposal]) -> Result<Vec<Change>> {
+        let mut changes = Vec::new();
+        for p in proposals {
+            let proposer = self
+                .tree
+                .leaf(p.proposer)
+                .ok_or_else(|| anyhow!("Proposal from unknown leaf {}", p.proposer))?
+                .username
+                .clone();
+            match &p.proposal {
+                Proposal::Add(package) => changes.push(Change::Add { proposer, member: package.leaf.username.clone() }),
+                Proposal::Remove(leaf) => changes.push(Change::Remove {
+                    proposer,
+                    member: self
+                        .tree
+                        .leaf(*leaf)
+                        .ok_or_else(|| anyhow!("No member at leaf {}", leaf))?
+                        .username
+                        .clone(),
+                }),
+                Proposal::Update(_) => {}
+            }
+        }
+        Ok(changes)
+    }
+
+    /// Commits `proposals` with a fresh path for our leaf (an empty commit only refreshes
+    /// our keys). The new epoch starts immediately; the Welcome is for the added members.
+    pub fn commit(&mut self, proposals: Vec<AuthenticatedProposal>, key: &SigningKey) -> Result<(Commit, Option<Welcome>)> {
+        for proposal in &proposals {
+            self.verify_proposal(proposal)?;
+        }
+        let mut tree = self.tree.clone();
+        let added = tree.apply(&proposals, self.own_leaf)?;
+
+        // New leaf key, then path secrets up our direct path
+        let own_node = leaf_node(self.own_leaf);
+        let leaf_secret = random_secret()?;
+        let mut leaf = tree.leaf(self.own_leaf).ok_or_else(|| anyhow!("Our leaf is blank"))?.clone();
+        leaf.encryption_key = public_of(&node_secret(&leaf_secret));
+        leaf.sign(key);
+        tree.nodes[own_node as usize] = Some(Node::Leaf(leaf.clone()));
+
+        let info = path_info(&self.group_id, self.epoch);
+        let new_leaves: Vec<u32> = added.iter().map(|(l, _)| leaf_node(*l)).collect();
+        let mut private_keys = HashMap::new();
+        private_keys.insert(own_node, node_secret(&leaf_secret));
+        let mut path_secrets = HashMap::new();
+        let mut path_secret = leaf_secret;
+        let mut nodes = Vec::new();
+        for (x, copath_node) in tree.direct_path(own_node).into_iter().zip(tree.copath(own_node)) {
+            path_secret = derive(&path_secret, b"path");
+            let secret = node_secret(&path_secret);
+            let mut encrypted_path_secrets = Vec::new();
+            for recipient in tree.resolution(copath_node) {
+                if !new_leaves.contains(&recipient) {
+                    encrypted_path_secrets.push(hpke_seal(&tree.public_key(recipient)?, &info, &path_secret)?);
+                }
+            }
+            nodes.push(UpdatePathNode { public_key: public_of(&secret), encrypted_path_secrets });
+            tree.nodes[x as usize] = Some(Node::Parent(ParentNode { public_key: public_of(&secret), unmerged_leaves: Vec::new() }));
+            private_keys.insert(x, secret);
+            path_secrets.insert(x, path_secret);
+        }
+        let commit_secret = derive(&path_secret, b"commit");
+
+        let mut commit = Commit {
+            group_id: self.group_id,
+            epoch: self.epoch,
+            committer: self.own_leaf,
+            proposals,
+            path: UpdatePath { leaf, nodes },
+            signature: Vec::new(),
+            confirmation_tag: [0u8; 32],
+        };
+        commit.signature = key.sign(&commit.signed_payload()?).as_ref().to_vec();
+        let joiner = joiner_secret(&self.secrets.init, &commit_secret);
+        let epoch = self.epoch + 1;
+        let secrets = EpochSecrets::derive(&joiner, &group_context(&self.group_id, epoch, &tree)?);
+        commit.confirmation_tag = secrets.confirmation_tag(&commit)?;
+
+        let welcome = if added.is_empty() {
+            None
+        } else {
+            let winfo = welcome_info(&self.group_id);
+            let mut encrypted = Vec::new();
+            for (leaf, init_key) in &added {
+                let shared = tree
+                    .direct_path(own_node)
+                    .into_iter()
+                    .find(|x| covers(*x, leaf_node(*leaf)))
+                    .ok_or_else(|| anyhow!("No common ancestor with leaf {}", leaf))?;
+                let group_secrets = GroupSecrets { joiner_secret: joiner, path_secret: path_secrets[&shared] };
+                encrypted.push(EncryptedGroupSecrets {
+                    init_key: *init_key,
+                    secrets: hpke_seal(init_key, &winfo, &bincode::serialize(&group_secrets)?)?,
+                });
+            }
+            let mut welcome = Welcome {
+                group_id: self.group_id,
+                epoch,
+                committer: self.own_leaf,
+                tree: tree.clone(),
+                secrets: encrypted,
+                signature: Vec::new(),
+            };
+            welcome.signature = key.sign(&welcome.signed_payload()?).as_ref().to_vec();
+            Some(welcome)
+        };
+
+        self.enter_epoch(epoch, tree, private_keys, secrets);
+        Ok((commit, welcome))
+    }
+
+    /// Applies a commit of another member, checking its signature, our path secret and the
+    /// confirmation tag. The state is left untouched on failure.
+    pub fn process_commit(&mut self, commit: &Commit) -> Result<CommitOutcome> {
+        if commit.group_id != self.group_id || commit.epoch != self.epoch {
+            return Err(anyhow!("Commit for epoch {}, group is at {}", commit.epoch, self.epoch));
+        }
+        if commit.committer == self.own_leaf {
+            return Err(anyhow!("Our own commit is already applied"));
+        }
+        let committer = self
+            .tree
+            .leaf(commit.committer)
+            .ok_or_else(|| anyhow!("Commit from unknown leaf {}", commit.committer))?;
+        verify_signature(&committer.signature_key, &commit.signed_payload()?, &commit.signature)
+            .map_err(|_| anyhow!("Invalid commit signature"))?;
+        let new_leaf = &commit.path.leaf;
+        if new_leaf.username != committer.username
+            || new_leaf.device != committer.device
+            || new_leaf.signature_key != committer.signature_key
+        {
+            return Err(anyhow!("A commit cannot change the identity of its committer"));
+        }
+        new_leaf.verify()?;
+        for proposal in &commit.proposals {
+            self.verify_proposal(proposal)?;
+        }
+
+        let mut tree = self.tree.clone();
+        let added = tree.apply(&commit.proposals, commit.committer)?;
+        if tree.leaf(self.own_leaf).is_none() {
+            return Ok(CommitOutcome::Removed);
+        }
+
+        // Our own Update, if committed, replaced our leaf key with the pending one
+        let own_node = leaf_node(self.own_leaf);
+        let mut private_keys = self.private_keys.clone();
+        let own_update = commit
+            .proposals
+            .iter()
+            .find(|p| p.proposer == self.own_leaf && matches!(p.proposal, Proposal::Update(_)));
+        if own_update.is_some() {
+            let leaf_key = tree.leaf(self.own_leaf).map(|l| l.encryption_key);
+            let secret = self
+                .pending_update
+                .map(|secret| node_secret(&secret))
+                .filter(|secret| Some(public_of(secret)) == leaf_key)
+                .ok_or_else(|| anyhow!("The commit holds an update of our leaf we did not make"))?;
+            private_keys.insert(own_node, secret);
+        }
+
+        let committer_node = leaf_node(commit.committer);
+        tree.nodes[committer_node as usize] = Some(Node::Leaf(new_leaf.clone()));
+        let path = tree.direct_path(committer_node);
+        let copath = tree.copath(committer_node);
+        if path.len() != commit.path.nodes.len() {
+            return Err(anyhow!("Update path of {} nodes for a direct path of {}", commit.path.nodes.len(), path.len()));
+        }
+
+        // The lowest node of the committer's path above us: its secret is encrypted to the
+        // resolution of our side, where we hold one private key
+        let shared = path
+            .iter()
+            .position(|x| covers(*x, own_node))
+            .ok_or_else(|| anyhow!("No common ancestor with the committer"))?;
+        let new_leaves: Vec<u32> = added.iter().map(|(l, _)| leaf_node(*l)).collect();
+        let resolution: Vec<u32> = tree
+            .resolution(copath[shared])
+            .into_iter()
+            .filter(|x| !new_leaves.contains(x))
+            .collect();
+        let (position, holder) = resolution
+            .iter()
+            .enumerate()
+            .find(|(_, x)| private_keys.contains_key(*x))
+            .ok_or_else(|| anyhow!("No key to decrypt the update path"))?;
+        let ciphertext = commit.path.nodes[shared]
+            .encrypted_path_secrets
+            .get(position)
+            .ok_or_else(|| anyhow!("Missing path secret in the commit"))?;
+        let info = path_info(&self.group_id, self.epoch);
+        let mut path_secret = to_secret(&hpke_open(&private_keys[holder], ciphertext, &info)?)?;
+
+        for (i, (x, node)) in path.iter().zip(&commit.path.nodes).enumerate().skip(shared) {
+            if i > shared {
+                path_secret = derive(&path_secret, b"path");
+            }
+            let secret = node_secret(&path_secret);
+            if public_of(&secret) != node.public_key {
+                return Err(anyhow!("Path secret does not match the committed key"));
+            }
+            private_keys.insert(*x, secret);
+        }
+        for (x, node) in path.iter().zip(&commit.path.nodes) {
+            tree.nodes[*x as usize] = Some(Node::Parent(ParentNode { public_key: node.public_key, unmerged_leaves: Vec::new() }));
+        }
+        let commit_secret = derive(&path_secret, b"commit");
+
+        let epoch = self.epoch + 1;
+        let joiner = joiner_secret(&self.secrets.init, &commit_secret);
+        let secrets = EpochSecrets::derive(&joiner, &group_context(&self.group_id, epoch, &tree)?);
+        if secrets.confirmation_tag(commit)? != commit.confirmation_tag {
+            return Err(anyhow!("Confirmation tag mismatch: the commit leads to another group state"));
+        }
+
+        self.awaiting.retain(|proposal| {
+            !commit.proposals.iter().any(|p| p.proposer == self.own_leaf && p.proposal == *proposal)
+        });
+        self.enter_epoch(epoch, tree, private_keys, secrets);
+        Ok(CommitOutcome::Applied)
+    }
+
+    /// Joins the group from a Welcome addressed to one of our key packages
+    pub fn join(welcome: &Welcome, package: &KeyPackage, package_secrets: &KeyPackageSecrets) -> Result<Self> {
+        let tree = welcome.tree.clone();
+        let committer = tree
+            .leaf(welcome.committer)
+            .ok_or_else(|| anyhow!("Welcome from unknown leaf {}", welcome.committer))?;
+        verify_signature(&committer.signature_key, &welcome.signed_payload()?, &welcome.signature)
+            .map_err(|_| anyhow!("Invalid Welcome signature"))?;
+
+        let entry = welcome
+            .secrets
+            .iter()
+            .find(|s| s.init_key == package.init_key)
+            .ok_or_else(|| anyhow!("Welcome is not for this key package"))?;
+        let group_secrets: GroupSecrets = bincode::deserialize(&hpke_open(
+            &package_secrets.init_secret,
+            &entry.secrets,
+            &welcome_info(&welcome.group_id),
+        )?)?;
+        let own_leaf = tree
+            .leaves()
+            .into_iter()
+            .find(|(_, l)| **l == package.leaf)
+            .map(|(i, _)| i)
+            .ok_or_else(|| anyhow!("Our leaf is not in the Welcome tree"))?;
+
+        let own_node = leaf_node(own_leaf);
+        let mut private_keys = HashMap::new();
+        private_keys.insert(own_node, node_secret(&package_secrets.leaf_secret));
+        let path = tree.direct_path(leaf_node(welcome.committer));
+        let shared = path
+            .iter()
+            .position(|x| covers(*x, own_node))
+            .ok_or_else(|| anyhow!("No common ancestor with the committer"))?;
+        let mut path_secret = group_secrets.path_secret;
+        for (i, x) in path.iter().enumerate().skip(shared) {
+            if i > shared {
+                path_secret = derive(&path_secret, b"path");
+            }
+            let secret = node_secret(&path_secret);
+            if public_of(&secret) != tree.public_key(*x)? {
+                return Err(anyhow!("Path secret does not match the Welcome tree"));
+            }
+            private_keys.insert(*x, secret);
+        }
+
+        let secrets = EpochSecrets::derive(
+            &group_secrets.joiner_secret,
+            &group_context(&welcome.group_id, welcome.epoch, &tree)?,
+        );
+        Ok(Self {
+            group_id: welcome.group_id,
+            epoch: welcome.epoch,
+            tree,
+            own_leaf,
+            private_keys,
+            secrets,
+            sender_chains: HashMap::new(),
+            pending_proposals: Vec::new(),
+            previous: None,
+            awaiting: Vec::new(),
+            pending_update: None,
+        })
+    }
+
+    /// Switches to a new epoch. The receiving chains of the previous one are kept for
+    /// PREVIOUS_EPOCH_WINDOW seconds; older secrets are dropped.
+    fn enter_epoch(&mut self, epoch: u64, tree: RatchetTree, private_keys: HashMap<u32, [u8; 32]>, secrets: EpochSecrets) {
+        let mut sender_chains = std::mem::take(&mut self.sender_chains);
+        sender_chains.remove(&self.own_leaf);
+        self.previous = Some(PreviousEpoch {
+            epoch: self.epoch,
+            tree: std::mem::replace(&mut self.tree, tree),
+            encryption: self.secrets.encryption,
+            sender_chains,
+            expires: chrono::Utc::now().timestamp() + PREVIOUS_EPOCH_WINDOW,
+        });
+        self.epoch = epoch;
+        self.private_keys = private_keys
+            .into_iter()
+            .filter(|(x, secret)| self.tree.public_key(*x).is_ok_and(|public| public == public_of(secret)))
+            .collect();
+        self.secrets = secrets;
+        self.pending_proposals.clear();
+        self.pending_update = None;
+    }
+
+    fn sender_chain(&mut self, sender: LeafIndex) -> SenderChain {
+        let encryption = self.secrets.encryption;
+        self.sender_chains
+            .entry(sender)
+            .or_insert_with(|| SenderChain::new(&encryption, sender))
+            .clone()
+    }
+
+    /// Pads, encrypts and signs an application message with the next key of our chain
+    pub fn encrypt(&mut self, plaintext: &[u8], padding: PaddingScheme, key: &SigningKey) -> Result<MlsMessage> {
+        let mut chain = self.sender_chain(self.own_leaf);
+        let generation = chain.generation;
+        let message_key = chain.key_for(generation)?;
+        let mut message = MlsMessage {
+            group_id: self.group_id,
+            epoch: self.epoch,
+            sender: self.own_leaf,
+            generation,
+            ciphertext: pad(plaintext, padding),
+            signature: Vec::new(),
+        };
+        aead_key(&message_key)?
+            .seal_in_place_append_tag(message_nonce(&message_key), Aad::from(message.header()), &mut message.ciphertext)
+            .map_err(|_| anyhow!("Encryption failed"))?;
+        message.signature = key.sign(&message.signed_payload()).as_ref().to_vec();
+        self.sender_chains.insert(self.own_leaf, chain);
+        Ok(message)
+    }
+
+    /// Verifies and decrypts an application message of the current epoch, or of the
+    /// previous one for a short while after a commit
+    pub fn decrypt(&mut self, message: &MlsMessage) -> Result<Vec<u8>> {
+        if message.group_id != self.group_id {
+            return Err(anyhow!("Message for group {}", message.group_id));
+        }
+        if message.sender == self.own_leaf {
+            return Err(anyhow!("Our own message"));
+        }
+        if self.previous.as_ref().is_some_and(|p| p.expires <= chrono::Utc::now().timestamp()) {
+            self.previous = None;
+        }
+        let (tree, encryption, chains) = match &mut self.previous {
+            Some(previous) if message.epoch == previous.epoch => {
+                (&previous.tree, previous.encryption, &mut previous.sender_chains)
+            }
+            _ if message.epoch == self.epoch => (&self.tree, self.secrets.encryption, &mut self.sender_chains),
+            _ => return Err(anyhow!("Message for epoch {}, group is at {}", message.epoch, self.epoch)),
+        };
+        let sender = tree
+            .leaf(message.sender)
+            .ok_or_else(|| anyhow!("Message from unknown leaf {}", message.sender))?;
+        verify_signature(&sender.signature_key, &message.signed_payload(), &message.signature)
+            .map_err(|_| anyhow!("Invalid signature on group message"))?;
+
+        let mut chain = chains
+            .get(&message.sender)
+            .cloned()
+            .unwrap_or_else(|| SenderChain::new(&encryption, message.sender));
+        let message_key = chain.key_for(message.generation)?;
+        let mut buffer = message.ciphertext.clone();
+        let padded = aead_key(&message_key)?
+            .open_in_place(message_nonce(&message_key), Aad::from(message.header()), &mut buffer)
+            .map_err(|_| anyhow!("Group message authentication failed"))?;
+        let plaintext = unpad(padded)?;
+        chains.insert(message.sender, chain);
+        Ok(plaintext)
+    }
+
+    /// Leaf that sent a message of `epoch`: the current one, or the previous one while its
+    /// messages are still accepted
+    pub fn sender_leaf(&self, epoch: u64, leaf: LeafIndex) -> Option<&LeafNode> {
+        match &self.previous {
+            Some(previous) if previous.epoch == epoch => previous.tree.leaf(leaf),
+            _ => self.tree.leaf(leaf),
+        }
+    }
+
+    /// Username of the member at `leaf`
+    pub fn member_at(&self, leaf: LeafIndex) -> Option<&str> {
+        self.tree.leaf(leaf).map(|l| l.username.as_str())
+    }
+}
@@ -0,0 +1,189 @@
+#[cfg(test)]
+mod tests {
+    use super::super::mls::{Change, CommitOutcome, KeyPackage, KeyPackageSecrets, MlsGroup, Proposal};
+    use super::super::padding::PaddingScheme;
+    use super::super::signature::SigningKey;
+    use uuid::Uuid;
+
+    struct Member {
+        key: SigningKey,
+        package: KeyPackage,
+        secrets: KeyPackageSecrets,
+    }
+
+    fn member(username: &str) -> Member {
+        let key = SigningKey::generate().unwrap();
+        let (package, secrets) = KeyPackage::generate(username, 1, &key).unwrap();
+        Member { key, package, secrets }
+    }
+
+    /// `sender` encrypts one message that every receiver decrypts
+    fn exchange(sender: &mut MlsGroup, key: &SigningKey, receivers: &mut [&mut MlsGroup]) {
+        let message = sender.encrypt(b"hello group", PaddingScheme::default(), key).unwrap();
+        for receiver in receivers.iter_mut() {
+            assert_eq!(receiver.decrypt(&message).unwrap(), b"hello group");
+        }
+    }
+
+    // Members join through Welcomes, follow every commit and share each epoch; removed
+    // members are left behind
+    #[test]
+    fn test_mls_add_update_remove() {
+        let (alice, bob, carol, dave) = (member("@alice"), member("@bob"), member("@carol"), member("@dave"));
+        let group_id = Uuid::new_v4();
+        let mut a = MlsGroup::create(group_id, &alice.package, &alice.secrets).unwrap();
+
+        // Add: one commit for Bob and Carol, who join from the Welcome
+        let proposals = vec![
+            a.propose(Proposal::Add(bob.package.clone()), &alice.key).unwrap(),
+            a.propose(Proposal::Add(carol.package.clone()), &alice.key).unwrap(),
+        ];
+        let (_, welcome) = a.commit(proposals, &alice.key).unwrap();
+        let welcome = welcome.unwrap();
+        let mut b = MlsGroup::join(&welcome, &bob.package, &bob.secrets).unwrap();
+        let mut c = MlsGroup::join(&welcome, &carol.package, &carol.secrets).unwrap();
+        assert_eq!((a.epoch(), b.epoch(), c.epoch()), (1, 1, 1));
+        assert_eq!(a.usernames(), vec!["@alice", "@bob", "@carol"]);
+        exchange(&mut a, &alice.key, &mut [&mut b, &mut c]);
+        exchange(&mut c, &carol.key, &mut [&mut a, &mut b]);
+
+        // Any member can commit: Bob adds Dave
+        let proposal = b.propose(Proposal::Add(dave.package.clone()), &bob.key).unwrap();
+        assert_eq!(
+            b.changes(std::slice::from_ref(&proposal)).unwrap(),
+            vec![Change::Add { proposer: "@bob".to_owned(), member: "@dave".to_owned() }]
+        );
+        let (commit, welcome) = b.commit(vec![proposal], &bob.key).unwrap();
+        assert_eq!(a.process_commit(&commit).unwrap(), CommitOutcome::Applied);
+        assert_eq!(c.process_commit(&commit).unwrap(), CommitOutcome::Applied);
+        let mut d = MlsGroup::join(&welcome.unwrap(), &dave.package, &dave.secrets).unwrap();
+        assert!(a.process_commit(&commit).is_err(), "A commit applies once");
+        exchange(&mut d, &dave.key, &mut [&mut a, &mut b, &mut c]);
+        exchange(&mut b, &bob.key, &mut [&mut a, &mut c, &mut d]);
+
+        // Update: Carol refreshes her path; messages of the previous epoch are still read for
+        // a while, with the keys of that epoch
+        let stale = a.encrypt(b"old epoch", PaddingScheme::default(), &alice.key).unwrap();
+        let before = c.tree().leaf(c.own_leaf()).unwrap().encryption_key;
+        let compromised = c.clone();
+        let (commit, welcome) = c.commit(Vec::new(), &carol.key).unwrap();
+        assert!(welcome.is_none());
+        assert_ne!(c.tree().leaf(c.own_leaf()).unwrap().encryption_key, before);
+        for state in [&mut a, &mut b, &mut d] {
+            assert_eq!(state.process_commit(&commit).unwrap(), CommitOutcome::Applied);
+            assert_eq!(state.epoch(), 3);
+        }
+        assert_eq!(b.decrypt(&stale).unwrap(), b"old epoch");
+        assert!(b.decrypt(&stale).is_err(), "Used keys are deleted");
+        let fresh = a.encrypt(b"new epoch", PaddingScheme::default(), &alice.key).unwrap();
+        assert_eq!(c.decrypt(&fresh).unwrap(), b"new epoch");
+        assert!(c.decrypt(&fresh).is_err(), "Used keys are deleted");
+        let mut compromised = compromised;
+        assert!(compromised.decrypt(&fresh).is_err(), "Old secrets do not reach the new epoch");
+
+        // Remove: Alice removes Bob, who cannot follow the group any more
+        let proposals: Vec<_> = a
+            .leaves_of("@bob")
+            .into_iter()
+            .map(|leaf| a.propose(Proposal::Remove(leaf), &alice.key).unwrap())
+            .collect();
+        let (commit, _) = a.commit(proposals, &alice.key).unwrap();
+        assert_eq!(b.process_commit(&commit).unwrap(), CommitOutcome::Removed);
+        assert_eq!(c.process_commit(&commit).unwrap(), CommitOutcome::Applied);
+        assert_eq!(d.process_commit(&commit).unwrap(), CommitOutcome::Applied);
+        assert!(d.decrypt(&stale).is_err(), "Only the previous epoch is kept");
+        let after_removal = d.encrypt(b"without bob", PaddingScheme::default(), &dave.key).unwrap();
+        assert!(b.decrypt(&after_removal).is_err());
+        assert_eq!(a.decrypt(&after_removal).unwrap(), b"without bob");
+        assert_eq!(c.decrypt(&after_removal).unwrap(), b"without bob");
+
+        // Leave: Dave proposes his own removal, Carol commits it
+        let leave = d.propose(Proposal::Remove(d.own_leaf()), &dave.key).unwrap();
+        c.store_proposal(leave.clone()).unwrap();
+        a.store_proposal(leave).unwrap();
+        let (commit, _) = c.commit(c.pending_proposals().to_vec(), &carol.key).unwrap();
+        assert_eq!(a.process_commit(&commit).unwrap(), CommitOutcome::Applied);
+        assert_eq!(d.process_commit(&commit).unwrap(), CommitOutcome::Removed);
+        assert!(a.pending_proposals().is_empty());
+        assert_eq!(a.usernames(), vec!["@alice", "@carol"]);
+        assert_eq!(a.epoch(), 5);
+        exchange(&mut c, &carol.key, &mut [&mut a]);
+    }
+
+    // Members other than the committer refresh their keys with an Update proposal, and
+    // submit again the proposals a commit left out
+    #[test]
+    fn test_mls_update_proposal() {
+        let (alice, bob, carol) = (member("@alice"), member("@bob"), member("@carol"));
+        let mut a = MlsGroup::create(Uuid::new_v4(), &alice.package, &alice.secrets).unwrap();
+        let proposals = vec![
+            a.propose(Proposal::Add(bob.package.clone()), &alice.key).unwrap(),
+            a.propose(Proposal::Add(carol.package.clone()), &alice.key).unwrap(),
+        ];
+        let welcome = a.commit(proposals, &alice.key).unwrap().1.unwrap();
+        let mut b = MlsGroup::join(&welcome, &bob.package, &bob.secrets).unwrap();
+        let mut c = MlsGroup::join(&welcome, &carol.package, &carol.secrets).unwrap();
+        let before = b.tree().leaf(b.own_leaf()).unwrap().encryption_key;
+
+        let update = b.propose_update(&bob.key).unwrap();
+        assert!(b.changes(std::slice::from_ref(&update)).unwrap().is_empty(), "Not a membership change");
+        a.store_proposal(update).unwrap();
+
+        // A commit made meanwhile leaves the update out: Bob makes it again
+        let (commit, _) = a.commit(Vec::new(), &alice.key).unwrap();
+        assert_eq!(b.process_commit(&commit).unwrap(), CommitOutcome::Applied);
+        assert_eq!(c.process_commit(&commit).unwrap(), CommitOutcome::Applied);
+        assert!(matches!(b.take_awaiting()[..], [Proposal::Update(_)]));
+
+        let update = b.propose_update(&bob.key).unwrap();
+        let (commit, _) = a.commit(vec![update], &alice.key).unwrap();
+        assert_eq!(b.process_commit(&commit).unwrap(), CommitOutcome::Applied);
+        assert_eq!(c.process_commit(&commit).unwrap(), CommitOutcome::Applied);
+        assert_ne!(b.tree().leaf(b.own_leaf()).unwrap().encryption_key, before);
+        assert!(b.take_awaiting().is_empty());
+        exchange(&mut b, &bob.key, &mut [&mut a, &mut c]);
+        exchange(&mut a, &alice.key, &mut [&mut b, &mut c]);
+
+        // Only the owner of a leaf updates it
+        let forged = c.propose(Proposal::Update(b.tree().leaf(b.own_leaf()).unwrap().clone()), &carol.key).unwrap();
+        assert!(a.store_proposal(forged).is_err());
+    }
+
+    // Forged or tampered handshake messages are rejected and leave the state untouched
+    #[test]
+    fn test_mls_authentication() {
+        let (alice, bob, eve) = (member("@alice"), member("@bob"), member("@eve"));
+        let mut a = MlsGroup::create(Uuid::new_v4(), &alice.package, &alice.secrets).unwrap();
+        let add = a.propose(Proposal::Add(bob.package.clone()), &alice.key).unwrap();
+        let (_, welcome) = a.commit(vec![add], &alice.key).unwrap();
+        let welcome = welcome.unwrap();
+        assert!(MlsGroup::join(&welcome, &eve.package, &eve.secrets).is_err(), "Not addressed to Eve");
+        let mut b = MlsGroup::join(&welcome, &bob.package, &bob.secrets).unwrap();
+
+        // A key package signed by another key is refused
+        let mut forged = eve.package.clone();
+        forged.leaf.username = "@carol".to_owned();
+        assert!(a.propose(Proposal::Add(forged.clone()), &alice.key).and_then(|p| b.store_proposal(p)).is_err());
+
+        // A proposal signed by Eve on Alice's behalf is refused
+        let impostor = a.propose(Proposal::Remove(b.own_leaf()), &eve.key).unwrap();
+        assert!(b.store_proposal(impostor).is_err());
+
+        let (commit, _) = a.commit(Vec::new(), &alice.key).unwrap();
+        let mut tampered = commit.clone();
+        tampered.path.nodes[0].public_key[0] ^= 1;
+        assert!(b.process_commit(&tampered).is_err());
+        let mut wrong_tag = commit.clone();
+        wrong_tag.confirmation_tag[0] ^= 1;
+        assert!(b.process_commit(&wrong_tag).is_err());
+        assert_eq!(b.epoch(), 1);
+
+        assert_eq!(b.process_commit(&commit).unwrap(), CommitOutcome::Applied);
+        exchange(&mut b, &bob.key, &mut [&mut a]);
+
+        // Messages are signed by the sender's leaf key
+        let mut message = a.encrypt(b"signed", PaddingScheme::default(), &alice.key).unwrap();
+        message.generation += 1;
+        assert!(b.decrypt(&message).is_err());
+    }
+}
@@ -2,6 +2,25 @@ pub mod encryption;
 pub mod signature;
 pub mod ratchet;
 pub mod handshake;
+pub mod session;
+pub mod fingerprint;
+pub mod sealed;
+pub mod padding;
+pub mod sender_key;
+pub mod mls;
 #[cfg(test)]
 mod ratchet_tests;
-mod app_e2e;
+#[cfg(test)]
+mod handshake_tests;
+#[cfg(test)]
+mod session_tests;
+#[cfg(test)]
+mod fingerprint_tests;
+#[cfg(test)]
+mod sealed_tests;
+#[cfg(test)]
+mod padding_tests;
+#[cfg(test)]
+mod sender_key_tests;
+#[cfg(test)]
+mod mls_tests;
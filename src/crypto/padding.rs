@@ -0,0 +1,119 @@
+use serde::{Serialize, Deserialize};
+use anyhow::{Result, anyhow};
+use std::collections::HashMap;
+use crate::models::message::MessageType;
+
+/// Byte marking the end of the content in a padded buffer (ISO/IEC 7816-4 style)
+const PADDING_MARKER: u8 = 0x80;
+
+/// Sizes used by `PaddingScheme::Bucketed`; larger payloads are rounded up to a multiple
+/// of the last one
+pub const BUCKET_SIZES: [usize; 6] = [256, 1024, 4096, 16 * 1024, 64 * 1024, 256 * 1024];
+
+/// How a plaintext is padded before encryption to hide its exact length
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Default)]
+pub enum PaddingScheme {
+    /// End marker only: the length is visible to the byte
+    None,
+    /// Padmé: at most 12% overhead, leaks O(log log n) bits of the length
+    #[default]
+    Padme,
+    /// Next size of `BUCKET_SIZES`: all short messages look alike
+    Bucketed,
+}
+
+
+impl PaddingScheme {
+    /// Size of the padded buffer for `len` bytes of content (marker included)
+    pub fn padded_len(&self, len: usize) -> usize {
+        let min = len + 1;
+        match self {
+            PaddingScheme::None => min,
+            PaddingScheme::Padme => padme(min),
+            PaddingScheme::Bucketed => match BUCKET_SIZES.iter().find(|&&size| size >= min) {
+                Some(&size) => size,
+                None => {
+                    let largest = BUCKET_SIZES[BUCKET_SIZES.len() - 1];
+                    min.div_ceil(largest) * largest
+                }
+            },
+        }
+    }
+}
+
+/// Padmé length: keeps the exponent and the top bits of the mantissa of `len`, rounding
+/// the lower bits up
+fn padme(len: usize) -> usize {
+    if len < 2 {
+        return len;
+    }
+    let exponent = (usize::BITS - 1 - len.leading_zeros()) as usize; // floor(log2 len)
+    let exponent_bits = (usize::BITS - exponent.leading_zeros()) as usize; // floor(log2 exponent) + 1
+    let mask = (1usize << (exponent - exponent_bits)) - 1;
+    (len + mask) & !mask
+}
+
+/// Appends the end marker and zeros up to the size given by `scheme`
+pub fn pad(data: &[u8], scheme: PaddingScheme) -> Vec<u8> {
+    let mut out = Vec::with_capacity(scheme.padded_len(data.len()));
+    out.extend_from_slice(data);
+    out.push(PADDING_MARKER);
+    out.resize(scheme.padded_len(data.len()), 0);
+    out
+}
+
+/// Strips the padding added by `pad`, whatever the scheme
+pub fn unpad(padded: &[u8]) -> Result<Vec<u8>> {
+    let end = padded
+        .iter()
+        .rposition(|&b| b != 0)
+        .ok_or_else(|| anyhow!("Missing padding marker"))?;
+    if padded[end] != PADDING_MARKER {
+        return Err(anyhow!("Invalid padding"));
+    }
+    Ok(padded[..end].to_vec())
+}
+
+/// Padding scheme used for each message type
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PaddingPolicy {
+    pub default: PaddingScheme,
+    pub per_type: HashMap<u8, PaddingScheme>, // By `MessageType::code`
+}
+
+impl Default for PaddingPolicy {
+    /// Buckets for texts and signaling, whose sizes are small and telling; Padmé for media
+    fn default() -> Self {
+        let mut policy = Self {
+            default: PaddingScheme::Padme,
+            per_type: HashMap::new(),
+        };
+        for msg_type in [
+            MessageType::Text,
+            MessageType::CallOffer,
+            MessageType::CallAnswer,
+            MessageType::CallHangup,
+            MessageType::GroupInvite,
+            MessageType::GroupInviteReply,
+            MessageType::SenderKeyDistribution,
+            MessageType::MlsProposal,
+            MessageType::GroupOperation,
+        ] {
+            policy.set(&msg_type, PaddingScheme::Bucketed);
+        }
+        policy
+    }
+}
+
+impl PaddingPolicy {
+    /// Sets the scheme of one message type
+    pub fn set(&mut self, msg_type: &MessageType, scheme: PaddingScheme) {
+        self.per_type.insert(msg_type.code(), scheme);
+    }
+
+    /// Scheme applied to a message type
+    pub fn scheme_for(&self, msg_type: &MessageType) -> PaddingScheme {
+        self.per_type.get(&msg_type.code()).copied().unwrap_or(self.default)
+    }
+}
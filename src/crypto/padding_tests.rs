@@ -0,0 +1,75 @@
+#[cfg(test)]
+mod tests {
+    use super::super::padding::{pad, unpad, PaddingPolicy, PaddingScheme, BUCKET_SIZES};
+    use crate::models::message::MessageType;
+
+    const SCHEMES: [PaddingScheme; 3] = [PaddingScheme::None, PaddingScheme::Padme, PaddingScheme::Bucketed];
+
+    // Any content, including trailing zeros and marker bytes, survives pad then unpad
+    #[test]
+    fn test_round_trip() {
+        for scheme in SCHEMES {
+            for len in [0usize, 1, 2, 3, 15, 255, 256, 1000, 5000, 300_000] {
+                let mut data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+                if len > 2 {
+                    data[len - 2] = 0x80;
+                    data[len - 1] = 0;
+                }
+                let padded = pad(&data, scheme);
+                assert_eq!(padded.len(), scheme.padded_len(len));
+                assert_eq!(unpad(&padded).unwrap(), data, "{:?} with {} bytes", scheme, len);
+            }
+        }
+
+        assert!(unpad(&[]).is_err());
+        assert!(unpad(&[0, 0, 0]).is_err());
+        assert!(unpad(&[1, 2, 0]).is_err());
+    }
+
+    // Buckets hide the length within each size class
+    #[test]
+    fn test_bucket_sizes() {
+        let scheme = PaddingScheme::Bucketed;
+        assert_eq!(scheme.padded_len(0), 256);
+        assert_eq!(pad(b"yes", scheme).len(), pad(&[b'a'; 200], scheme).len());
+        assert_eq!(scheme.padded_len(255), 256);
+        assert_eq!(scheme.padded_len(256), 1024, "The marker needs one more byte");
+        assert_eq!(scheme.padded_len(4000), 4096);
+        for size in BUCKET_SIZES {
+            assert_eq!(scheme.padded_len(size - 1), size);
+        }
+        let largest = BUCKET_SIZES[BUCKET_SIZES.len() - 1];
+        assert_eq!(scheme.padded_len(largest), 2 * largest);
+        assert_eq!(scheme.padded_len(5 * largest - 1), 5 * largest);
+    }
+
+    // Padmé stays within 12% overhead and groups lengths into few sizes
+    #[test]
+    fn test_padme_sizes() {
+        let scheme = PaddingScheme::Padme;
+        assert_eq!(scheme.padded_len(8), 10);
+        assert_eq!(scheme.padded_len(1000), 1024);
+        assert_eq!(scheme.padded_len(1024), 1088);
+        for len in (1..200_000).step_by(97) {
+            let padded = scheme.padded_len(len);
+            assert!(padded > len);
+            assert!(padded as f64 <= (len + 1) as f64 * 1.12, "{} -> {}", len, padded);
+        }
+        let distinct: std::collections::HashSet<usize> = (1000..2000).map(|len| scheme.padded_len(len)).collect();
+        assert!(distinct.len() <= 17);
+        assert_eq!(PaddingScheme::None.padded_len(1000), 1001);
+    }
+
+    // Texts use buckets by default, other types follow the policy default
+    #[test]
+    fn test_policy_per_message_type() {
+        let mut policy = PaddingPolicy::default();
+        assert_eq!(policy.scheme_for(&MessageType::Text), PaddingScheme::Bucketed);
+        assert_eq!(policy.scheme_for(&MessageType::Image), PaddingScheme::Padme);
+
+        policy.set(&MessageType::Image, PaddingScheme::None);
+        policy.default = PaddingScheme::Bucketed;
+        assert_eq!(policy.scheme_for(&MessageType::Image), PaddingScheme::None);
+        assert_eq!(policy.scheme_for(&MessageType::Video), PaddingScheme::Bucketed);
+    }
+}
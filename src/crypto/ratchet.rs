@@ -1,65 +1,258 @@
-use ring::agreement::{EphemeralPrivateKey, PublicKey, UnparsedPublicKey, X25519};
+use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret};
+use rand_core::OsRng;
+use hkdf::Hkdf;
+use sha2::Sha256;
 use ring::rand::{SecureRandom, SystemRandom};
 use ring::hkdf::{Salt, HKDF_SHA256};
-use ring::aead::{LessSafeKey, UnboundKey, CHACHA20_POLY1305, Nonce, Aad, SealingKey, OpeningKey, BoundKey, NONCE_LEN};
-use ring::aead;
+use ring::aead::{LessSafeKey, UnboundKey, CHACHA20_POLY1305, Nonce, Aad, NONCE_LEN};
+use serde::{Serialize, Deserialize};
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashMap;
 use anyhow::{Result, anyhow};
+use crate::crypto::padding::{pad, unpad, PaddingScheme};
+
+/// Limits applied to the skipped message key store.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RatchetConfig {
+    pub max_skip: u32,             // Max keys derived ahead within one chain
+    pub max_skipped_keys: usize,   // Max keys kept across all chains
+    pub skipped_key_ttl_secs: i64, // Lifetime of a stored key
+}
+
+impl Default for RatchetConfig {
+    fn default() -> Self {
+        Self {
+            max_skip: 1000,
+            max_skipped_keys: 2000,
+            skipped_key_ttl_secs: 7 * 24 * 3600,
+        }
+    }
+}
+
+/// Message key kept for a message that has not arrived yet.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SkippedKey {
+    pub message_key: [u8; 32],
+    pub stored_at: DateTime<Utc>,
+}
+
+/// Header sent with every ratchet message, authenticated as associated data.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MessageHeader {
+    pub dh_public: [u8; 32],        // Sender's current ratchet public key
+    pub message_number: u32,        // Index in the current sending chain
+    pub previous_chain_length: u32, // Length of the sender's previous sending chain
+}
+
+impl MessageHeader {
+    /// Fixed-size encoding used as AEAD associated data.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(40);
+        out.extend_from_slice(&self.dh_public);
+        out.extend_from_slice(&self.message_number.to_be_bytes());
+        out.extend_from_slice(&self.previous_chain_length.to_be_bytes());
+        out
+    }
+}
+
+/// Encrypted ratchet message: clear header plus nonce || ciphertext || tag.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RatchetMessage {
+    pub header: MessageHeader,
+    pub ciphertext: Vec<u8>,
+}
+
+/// Serializable snapshot of a ratchet session, used to survive restarts.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SessionState {
+    pub root_key: [u8; 32],
+    pub sending_chain_key: Option<[u8; 32]>,
+    pub receiving_chain_key: Option<[u8; 32]>,
+    pub dh_secret: [u8; 32],
+    pub peer_dh_public_key: Option<[u8; 32]>,
+    pub send_count: u32,
+    pub recv_count: u32,
+    pub previous_send_count: u32,
+    pub skipped_keys: Vec<([u8; 32], u32, SkippedKey)>,
+    pub config: RatchetConfig,
+}
 
 /// Represents the state of the Double Ratchet algorithm.
+#[derive(Clone)]
 pub struct Ratchet {
     root_key: [u8; 32],
-    sending_chain_key: [u8; 32],
-    receiving_chain_key: [u8; 32],
-    dh_private_key: EphemeralPrivateKey,
-    dh_public_key: PublicKey,
-    peer_dh_public_key: Option<Vec<u8>>,
+    sending_chain_key: Option<[u8; 32]>,
+    receiving_chain_key: Option<[u8; 32]>,
+    dh_secret: StaticSecret,
+    dh_public: X25519PublicKey,
+    peer_dh_public_key: Option<X25519PublicKey>,
+    send_count: u32,
+    recv_count: u32,
+    previous_send_count: u32,
+    skipped_keys: HashMap<([u8; 32], u32), SkippedKey>,
+    config: RatchetConfig,
     rng: SystemRandom,
 }
 
 impl Ratchet {
-    /// Initializes a new Ratchet instance with a shared secret.
-    pub fn new(shared_secret: &[u8]) -> Self {
-        let rng = SystemRandom::new();
-        let dh_private_key = EphemeralPrivateKey::generate(&X25519, &rng).unwrap();
-        let dh_public_key = dh_private_key.compute_public_key().unwrap();
+    /// Initializes the initiator side from the X3DH secret and the responder's
+    /// ratchet public key (its signed prekey).
+    pub fn new_initiator(shared_secret: &[u8], peer_public_key: &[u8; 32]) -> Result<Self> {
+        let dh_secret = StaticSecret::new(OsRng);
+        let dh_public = X25519PublicKey::from(&dh_secret);
+        let peer = X25519PublicKey::from(*peer_public_key);
 
-        let salt = Salt::new(HKDF_SHA256, shared_secret);
-        let prk = salt.extract(&[]);
-        let okm = prk.expand(&[], HKDF_SHA256).unwrap();
-        let mut root_key = [0u8; 32];
-        okm.fill(&mut root_key).unwrap();
+        let root_key = Self::initial_root_key(shared_secret)?;
+        let (root_key, sending_chain_key) =
+            Self::kdf_root(&root_key, dh_secret.diffie_hellman(&peer).as_bytes())?;
 
-        Self {
+        Ok(Self {
             root_key,
-            sending_chain_key: [0u8; 32],
-            receiving_chain_key: [0u8; 32],
-            dh_private_key,
-            dh_public_key,
+            sending_chain_key: Some(sending_chain_key),
+            receiving_chain_key: None,
+            dh_secret,
+            dh_public,
+            peer_dh_public_key: Some(peer),
+            send_count: 0,
+            recv_count: 0,
+            previous_send_count: 0,
+            skipped_keys: HashMap::new(),
+            config: RatchetConfig::default(),
+            rng: SystemRandom::new(),
+        })
+    }
+
+    /// Initializes the responder side from the X3DH secret, using the signed
+    /// prekey pair as first ratchet key. It can send once it has received.
+    pub fn new_responder(shared_secret: &[u8], own_secret: StaticSecret) -> Result<Self> {
+        let dh_public = X25519PublicKey::from(&own_secret);
+
+        Ok(Self {
+            root_key: Self::initial_root_key(shared_secret)?,
+            sending_chain_key: None,
+            receiving_chain_key: None,
+            dh_secret: own_secret,
+            dh_public,
             peer_dh_public_key: None,
-            rng,
+            send_count: 0,
+            recv_count: 0,
+            previous_send_count: 0,
+            skipped_keys: HashMap::new(),
+            config: RatchetConfig::default(),
+            rng: SystemRandom::new(),
+        })
+    }
+
+    /// Replaces the skipped message key limits.
+    pub fn with_config(mut self, config: RatchetConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Exports the full session state for persistence.
+    pub fn to_state(&self) -> SessionState {
+        SessionState {
+            root_key: self.root_key,
+            sending_chain_key: self.sending_chain_key,
+            receiving_chain_key: self.receiving_chain_key,
+            dh_secret: self.dh_secret.to_bytes(),
+            peer_dh_public_key: self.peer_dh_public_key.map(|k| *k.as_bytes()),
+            send_count: self.send_count,
+            recv_count: self.recv_count,
+            previous_send_count: self.previous_send_count,
+            skipped_keys: self
+                .skipped_keys
+                .iter()
+                .map(|((dh, n), key)| (*dh, *n, key.clone()))
+                .collect(),
+            config: self.config.clone(),
         }
     }
 
+    /// Restores a session previously exported with `to_state`.
+    pub fn from_state(state: SessionState) -> Self {
+        let dh_secret = StaticSecret::from(state.dh_secret);
+        let dh_public = X25519PublicKey::from(&dh_secret);
+
+        Self {
+            root_key: state.root_key,
+            sending_chain_key: state.sending_chain_key,
+            receiving_chain_key: state.receiving_chain_key,
+            dh_secret,
+            dh_public,
+            peer_dh_public_key: state.peer_dh_public_key.map(X25519PublicKey::from),
+            send_count: state.send_count,
+            recv_count: state.recv_count,
+            previous_send_count: state.previous_send_count,
+            skipped_keys: state
+                .skipped_keys
+                .into_iter()
+                .map(|(dh, n, key)| ((dh, n), key))
+                .collect(),
+            config: state.config,
+            rng: SystemRandom::new(),
+        }
+    }
+
+    /// Number of message keys currently kept for late messages.
+    pub fn skipped_key_count(&self) -> usize {
+        self.skipped_keys.len()
+    }
+
     /// Returns the current public key for transmission to the peer.
     pub fn public_key(&self) -> &[u8] {
-        self.dh_public_key.as_ref()
+        self.dh_public.as_bytes()
+    }
+
+    /// Stretches the shared secret into the first root key.
+    fn initial_root_key(shared_secret: &[u8]) -> Result<[u8; 32]> {
+        let salt = Salt::new(HKDF_SHA256, shared_secret);
+        let prk = salt.extract(&[]);
+        let okm = prk
+            .expand(&[], HKDF_SHA256)
+            .map_err(|_| anyhow!("Root key derivation failed"))?;
+        let mut root_key = [0u8; 32];
+        okm.fill(&mut root_key)
+            .map_err(|_| anyhow!("Root key derivation failed"))?;
+        Ok(root_key)
+    }
+
+    /// Root KDF: mixes a DH output into the root key, yields (root key, chain key).
+    fn kdf_root(root_key: &[u8; 32], dh_output: &[u8]) -> Result<([u8; 32], [u8; 32])> {
+        let hk = Hkdf::<Sha256>::new(Some(root_key), dh_output);
+        let mut okm = [0u8; 64];
+        hk.expand(b"enigma ratchet root", &mut okm)
+            .map_err(|_| anyhow!("Root KDF failed"))?;
+
+        let mut new_root = [0u8; 32];
+        let mut chain_key = [0u8; 32];
+        new_root.copy_from_slice(&okm[..32]);
+        chain_key.copy_from_slice(&okm[32..]);
+        Ok((new_root, chain_key))
     }
 
-    /// Performs a DH ratchet with the peer's public key.
-    pub fn dh_ratchet(&mut self, peer_public_key: &[u8]) -> Result<()> {
-        let peer_key = UnparsedPublicKey::new(&X25519, peer_public_key);
-        let shared_secret = self.dh_private_key.agree(&peer_key)
-            .map_err(|_| anyhow!("DH agreement failed"))?;
+    /// Performs a DH ratchet step on reception of a new peer public key:
+    /// derives the receiving chain, then a fresh key pair and sending chain.
+    fn dh_ratchet(&mut self, peer_public_key: &[u8; 32]) -> Result<()> {
+        let peer = X25519PublicKey::from(*peer_public_key);
+
+        self.previous_send_count = self.send_count;
+        self.send_count = 0;
+        self.recv_count = 0;
+
+        let (root_key, receiving_chain_key) =
+            Self::kdf_root(&self.root_key, self.dh_secret.diffie_hellman(&peer).as_bytes())?;
+        self.root_key = root_key;
+        self.receiving_chain_key = Some(receiving_chain_key);
 
-        let salt = Salt::new(HKDF_SHA256, &self.root_key);
-        let prk = salt.extract(shared_secret.as_ref());
-        let okm = prk.expand(&[], HKDF_SHA256).unwrap();
-        okm.fill(&mut self.root_key).unwrap();
+        self.dh_secret = StaticSecret::new(OsRng);
+        self.dh_public = X25519PublicKey::from(&self.dh_secret);
 
-        // Reset sending/receiving chain keys after DH
-        self.sending_chain_key = [0u8; 32];
-        self.receiving_chain_key = [0u8; 32];
-        self.peer_dh_public_key = Some(peer_public_key.to_vec());
+        let (root_key, sending_chain_key) =
+            Self::kdf_root(&self.root_key, self.dh_secret.diffie_hellman(&peer).as_bytes())?;
+        self.root_key = root_key;
+        self.sending_chain_key = Some(sending_chain_key);
+        self.peer_dh_public_key = Some(peer);
 
         Ok(())
     }
@@ -75,48 +268,165 @@ impl Ratchet {
         (new_chain_key, message_key)
     }
 
-    /// Encrypts a message using the current sending chain.
-    pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<Vec<u8>> {
-        let (new_ck, mk) = Self::kdf_chain(&self.sending_chain_key);
-        self.sending_chain_key = new_ck;
+    /// Encrypts a message using the current sending chain, with the default padding.
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<RatchetMessage> {
+        self.encrypt_padded(plaintext, PaddingScheme::default())
+    }
+
+    /// Pads the plaintext with `padding`, then encrypts it using the current sending chain.
+    pub fn encrypt_padded(&mut self, plaintext: &[u8], padding: PaddingScheme) -> Result<RatchetMessage> {
+        let chain_key = self
+            .sending_chain_key
+            .ok_or_else(|| anyhow!("No sending chain yet: wait for the peer's first message"))?;
+        let (new_ck, mk) = Self::kdf_chain(&chain_key);
 
-        let key = UnboundKey::new(&CHACHA20_POLY1305, &mk)?;
+        let header = MessageHeader {
+            dh_public: *self.dh_public.as_bytes(),
+            message_number: self.send_count,
+            previous_chain_length: self.previous_send_count,
+        };
+
+        let key = UnboundKey::new(&CHACHA20_POLY1305, &mk)
+            .map_err(|_| anyhow!("Invalid message key"))?;
         let sealing_key = LessSafeKey::new(key);
 
         let mut nonce_bytes = [0u8; NONCE_LEN];
-        self.rng.fill(&mut nonce_bytes)?;
+        self.rng.fill(&mut nonce_bytes)
+            .map_err(|_| anyhow!("Nonce generation failed"))?;
         let nonce = Nonce::assume_unique_for_key(nonce_bytes);
 
-        let mut buffer = plaintext.to_vec();
-        buffer.resize(buffer.len() + CHACHA20_POLY1305.tag_len(), 0);
+        let mut buffer = pad(plaintext, padding);
+        sealing_key
+            .seal_in_place_append_tag(nonce, Aad::from(header.to_bytes()), &mut buffer)
+            .map_err(|_| anyhow!("Encryption failed"))?;
+
+        let mut ciphertext = nonce_bytes.to_vec();
+        ciphertext.extend_from_slice(&buffer);
+
+        self.sending_chain_key = Some(new_ck);
+        self.send_count += 1;
+
+        Ok(RatchetMessage { header, ciphertext })
+    }
+
+    /// Decrypts a message, performing a DH ratchet step when the header carries
+    /// a new peer key. Apart from expired skipped keys, the state is left untouched
+    /// if decryption fails.
+    pub fn decrypt(&mut self, message: &RatchetMessage) -> Result<Vec<u8>> {
+        self.expire_skipped_keys(Utc::now());
+        let mut next = self.clone();
+        let plaintext = next.decrypt_in_place(message)?;
+        *self = next;
+        Ok(plaintext)
+    }
+
+    fn decrypt_in_place(&mut self, message: &RatchetMessage) -> Result<Vec<u8>> {
+        let header = &message.header;
 
-        sealing_key.seal_in_place_append_tag(nonce, Aad::from(b"msg"), &mut buffer)?;
+        // Late message whose key was kept when a later one arrived first
+        if let Some(skipped) = self.skipped_keys.remove(&(header.dh_public, header.message_number)) {
+            return Self::open(&skipped.message_key, header, &message.ciphertext);
+        }
+
+        let known_peer = self.peer_dh_public_key.map(|k| *k.as_bytes());
+        if known_peer != Some(header.dh_public) {
+            // Keep the keys of messages still in flight on the previous chain
+            self.skip_message_keys(header.previous_chain_length)?;
+            self.dh_ratchet(&header.dh_public)?;
+        }
+
+        if header.message_number < self.recv_count {
+            return Err(anyhow!(
+                "Duplicate or expired message number {}",
+                header.message_number
+            ));
+        }
+        self.skip_message_keys(header.message_number)?;
 
-        let mut output = nonce_bytes.to_vec();
-        output.extend_from_slice(&buffer);
+        let chain_key = self
+            .receiving_chain_key
+            .ok_or_else(|| anyhow!("No receiving chain"))?;
+        let (new_ck, mk) = Self::kdf_chain(&chain_key);
+        self.receiving_chain_key = Some(new_ck);
+        self.recv_count += 1;
 
-        Ok(output)
+        Self::open(&mk, header, &message.ciphertext)
     }
 
-    /// Decrypts a message using the current receiving chain.
-    pub fn decrypt(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>> {
+    /// Advances the receiving chain up to `until`, storing the intermediate message keys.
+    fn skip_message_keys(&mut self, until: u32) -> Result<()> {
+        let (mut chain_key, peer) = match (self.receiving_chain_key, self.peer_dh_public_key) {
+            (Some(ck), Some(peer)) => (ck, *peer.as_bytes()),
+            _ => return Ok(()),
+        };
+        if until <= self.recv_count {
+            return Ok(());
+        }
+        if until - self.recv_count > self.config.max_skip {
+            return Err(anyhow!(
+                "Too many skipped messages ({} > {})",
+                until - self.recv_count,
+                self.config.max_skip
+            ));
+        }
+
+        let now = Utc::now();
+        while self.recv_count < until {
+            let (next_ck, mk) = Self::kdf_chain(&chain_key);
+            self.skipped_keys.insert(
+                (peer, self.recv_count),
+                SkippedKey {
+                    message_key: mk,
+                    stored_at: now,
+                },
+            );
+            chain_key = next_ck;
+            self.recv_count += 1;
+        }
+        self.receiving_chain_key = Some(chain_key);
+
+        // Evict the oldest keys beyond the global bound
+        while self.skipped_keys.len() > self.config.max_skipped_keys {
+            let oldest = self
+                .skipped_keys
+                .iter()
+                .min_by_key(|(id, key)| (key.stored_at, id.1))
+                .map(|(id, _)| *id);
+            match oldest {
+                Some(id) => {
+                    self.skipped_keys.remove(&id);
+                }
+                None => break,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Drops skipped keys older than the configured lifetime.
+    fn expire_skipped_keys(&mut self, now: DateTime<Utc>) {
+        let ttl = Duration::seconds(self.config.skipped_key_ttl_secs);
+        self.skipped_keys.retain(|_, key| now - key.stored_at < ttl);
+    }
+
+    /// Opens nonce || ciphertext || tag with a message key and strips the padding.
+    fn open(message_key: &[u8; 32], header: &MessageHeader, ciphertext: &[u8]) -> Result<Vec<u8>> {
         if ciphertext.len() < NONCE_LEN + CHACHA20_POLY1305.tag_len() {
             return Err(anyhow!("Invalid ciphertext length"));
         }
 
-        let nonce = Nonce::try_assume_unique_for_key(&ciphertext[..NONCE_LEN])?;
+        let nonce = Nonce::try_assume_unique_for_key(&ciphertext[..NONCE_LEN])
+            .map_err(|_| anyhow!("Invalid nonce"))?;
         let mut buffer = ciphertext[NONCE_LEN..].to_vec();
 
-        let (new_ck, mk) = Self::kdf_chain(&self.receiving_chain_key);
-        self.receiving_chain_key = new_ck;
-
-        let key = UnboundKey::new(&CHACHA20_POLY1305, &mk)?;
+        let key = UnboundKey::new(&CHACHA20_POLY1305, message_key)
+            .map_err(|_| anyhow!("Invalid message key"))?;
         let opening_key = LessSafeKey::new(key);
 
         let plaintext = opening_key
-            .open_in_place(nonce, Aad::from(b"msg"), &mut buffer)?
-            .to_vec();
+            .open_in_place(nonce, Aad::from(header.to_bytes()), &mut buffer)
+            .map_err(|_| anyhow!("Message authentication failed"))?;
 
-        Ok(plaintext)
+        unpad(plaintext)
     }
 }
@@ -1,64 +1,188 @@
 #[cfg(test)]
 mod tests {
-    use super::super::ratchet::Ratchet;
-    use ring::rand::SystemRandom;
+    use super::super::ratchet::{Ratchet, RatchetConfig};
+    use super::super::padding::PaddingScheme;
+    use ring::aead::NONCE_LEN;
+    use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret};
+    use rand_core::OsRng;
 
-    // Ensure that a message can be encrypted and decrypted with the same ratchet
+    // Build an initiator/responder pair as after an X3DH handshake
+    fn pair(shared: &[u8]) -> (Ratchet, Ratchet) {
+        let bob_spk = StaticSecret::new(OsRng);
+        let bob_spk_pub = X25519PublicKey::from(&bob_spk);
+
+        let alice = Ratchet::new_initiator(shared, bob_spk_pub.as_bytes()).expect("initiator");
+        let bob = Ratchet::new_responder(shared, bob_spk).expect("responder");
+        (alice, bob)
+    }
+
+    // Ensure that messages decrypt on the other side in both directions
+    #[test]
+    fn test_encrypt_decrypt_round_trip() {
+        let (mut alice, mut bob) = pair(b"shared_secret_for_test_123456789012");
+
+        for i in 0..3u8 {
+            let ct = alice.encrypt(&[i; 8]).expect("encrypt failed");
+            assert_eq!(ct.header.message_number, i as u32);
+            assert_eq!(bob.decrypt(&ct).expect("decrypt failed"), vec![i; 8]);
+        }
+
+        let reply = bob.encrypt(b"Top secret").expect("reply failed");
+        assert_eq!(alice.decrypt(&reply).expect("decrypt reply failed"), b"Top secret");
+    }
+
+    // Padding hides the plaintext length and is removed on decryption
     #[test]
-    fn test_encrypt_decrypt_same_instance() {
-        let shared = b"shared_secret_for_test_123456789012";
-        let mut r = Ratchet::new(shared);
+    fn test_padded_ciphertext_lengths() {
+        let (mut alice, mut bob) = pair(b"padding_secret_shared_by_both");
 
-        let msg = b"Top secret";
-        let ciphertext = r.encrypt(msg).expect("encrypt failed");
-        let plaintext = r.decrypt(&ciphertext).expect("decrypt failed");
+        let short = alice.encrypt_padded(b"yes", PaddingScheme::Bucketed).expect("encrypt failed");
+        let long = alice.encrypt_padded(&[b'x'; 200], PaddingScheme::Bucketed).expect("encrypt failed");
+        assert_eq!(short.ciphertext.len(), long.ciphertext.len());
+        assert_eq!(short.ciphertext.len(), NONCE_LEN + 256 + 16);
 
-        assert_eq!(plaintext, msg);
+        let exact = alice.encrypt_padded(b"yes", PaddingScheme::None).expect("encrypt failed");
+        assert!(exact.ciphertext.len() < short.ciphertext.len());
+
+        assert_eq!(bob.decrypt(&short).expect("decrypt failed"), b"yes");
+        assert_eq!(bob.decrypt(&long).expect("decrypt failed"), vec![b'x'; 200]);
+        assert_eq!(bob.decrypt(&exact).expect("decrypt failed"), b"yes");
     }
 
-    // Ensure that encryption produces different ciphertexts for same input (nonce-based)
+    // Ensure that encryption produces different ciphertexts for same input
     #[test]
     fn test_nonce_uniqueness() {
-        let shared = b"another_test_key_which_is_shared";
-        let mut r = Ratchet::new(shared);
+        let (mut alice, _bob) = pair(b"another_test_key_which_is_shared");
 
         let msg = b"same content";
-        let c1 = r.encrypt(msg).expect("encrypt failed");
-        let c2 = r.encrypt(msg).expect("encrypt failed");
+        let c1 = alice.encrypt(msg).expect("encrypt failed");
+        let c2 = alice.encrypt(msg).expect("encrypt failed");
 
-        assert_ne!(c1, c2, "Ciphertexts should differ due to random nonces");
+        assert_ne!(c1.ciphertext, c2.ciphertext, "Ciphertexts should differ (new message key)");
     }
 
-    // Check that decrypt fails if ciphertext is tampered
+    // Check that decrypt fails if ciphertext is tampered, without breaking the session
     #[test]
     fn test_decrypt_modified_ciphertext_fails() {
-        let shared = b"some_shared_context_between_peers";
-        let mut r = Ratchet::new(shared);
+        let (mut alice, mut bob) = pair(b"some_shared_context_between_peers");
+
+        let ct = alice.encrypt(b"confidential").expect("encrypt failed");
+        let mut tampered = ct.clone();
+        tampered.ciphertext[10] ^= 0xFF; // corrupt some byte
 
-        let msg = b"confidential";
-        let mut ct = r.encrypt(msg).expect("encrypt failed");
+        assert!(bob.decrypt(&tampered).is_err(), "Modified ciphertext should not decrypt");
+        assert_eq!(bob.decrypt(&ct).expect("original still decrypts"), b"confidential");
+    }
+
+    // The header is authenticated: changing the message number breaks decryption
+    #[test]
+    fn test_decrypt_modified_header_fails() {
+        let (mut alice, mut bob) = pair(b"header_binding_secret");
 
-        ct[10] ^= 0xFF; // corrupt some byte
-        let result = r.decrypt(&ct);
+        let mut ct = alice.encrypt(b"hello").expect("encrypt failed");
+        ct.header.previous_chain_length = 42;
 
-        assert!(result.is_err(), "Modified ciphertext should not decrypt");
+        assert!(bob.decrypt(&ct).is_err());
     }
 
-    // Test that DH ratchet resets keys and changes root
+    // Each round trip performs a DH ratchet step and publishes a new ratchet key
     #[test]
     fn test_dh_ratchet_changes_state() {
-        let shared = b"consistent_key_material";
-        let mut r1 = Ratchet::new(shared);
-        let mut r2 = Ratchet::new(shared);
+        let (mut alice, mut bob) = pair(b"consistent_key_material");
+        let alice_key_before = alice.public_key().to_vec();
+
+        let first = alice.encrypt(b"ping").unwrap();
+        bob.decrypt(&first).unwrap();
+        let reply = bob.encrypt(b"pong").unwrap();
+        assert_ne!(reply.header.dh_public.to_vec(), first.header.dh_public.to_vec());
+
+        alice.decrypt(&reply).unwrap();
+        assert_ne!(alice.public_key(), alice_key_before.as_slice(), "Alice should rotate her ratchet key");
+
+        let next = alice.encrypt(b"ping again").unwrap();
+        assert_eq!(next.header.message_number, 0, "New sending chain starts at zero");
+        assert_eq!(next.header.previous_chain_length, 1);
+        assert_eq!(bob.decrypt(&next).unwrap(), b"ping again");
+    }
+
+    // The responder has no sending chain until it receives the first message
+    #[test]
+    fn test_responder_cannot_send_first() {
+        let (_alice, mut bob) = pair(b"responder_waits_for_initiator");
+        assert!(bob.encrypt(b"too early").is_err());
+    }
 
-        let root_before = r1.root_key;
+    // Messages delivered out of order, including across a DH step, still decrypt
+    #[test]
+    fn test_out_of_order_messages() {
+        let (mut alice, mut bob) = pair(b"out_of_order_shared_secret");
+
+        let m0 = alice.encrypt(b"m0").unwrap();
+        let m1 = alice.encrypt(b"m1").unwrap();
+        let m2 = alice.encrypt(b"m2").unwrap();
+
+        assert_eq!(bob.decrypt(&m2).unwrap(), b"m2");
+        assert_eq!(bob.skipped_key_count(), 2);
+
+        // Bob answers, Alice ratchets and sends on a new chain before m0/m1 arrive
+        let reply = bob.encrypt(b"r0").unwrap();
+        alice.decrypt(&reply).unwrap();
+        let n0 = alice.encrypt(b"n0").unwrap();
+
+        assert_eq!(bob.decrypt(&n0).unwrap(), b"n0");
+        assert_eq!(bob.decrypt(&m0).unwrap(), b"m0");
+        assert_eq!(bob.decrypt(&m1).unwrap(), b"m1");
+        assert_eq!(bob.skipped_key_count(), 0);
+
+        // A replayed message has no key left
+        assert!(bob.decrypt(&m1).is_err());
+    }
+
+    // A lost message does not desynchronize the chain
+    #[test]
+    fn test_lost_message_does_not_break_session() {
+        let (mut alice, mut bob) = pair(b"lost_message_shared_secret");
+
+        let _lost = alice.encrypt(b"never delivered").unwrap();
+        let m1 = alice.encrypt(b"delivered").unwrap();
+
+        assert_eq!(bob.decrypt(&m1).unwrap(), b"delivered");
+        let reply = bob.encrypt(b"ack").unwrap();
+        assert_eq!(alice.decrypt(&reply).unwrap(), b"ack");
+    }
+
+    // Skipping more than max_skip messages is refused and leaves the state intact
+    #[test]
+    fn test_max_skip_enforced() {
+        let (mut alice, bob) = pair(b"max_skip_shared_secret");
+        let mut bob = bob.with_config(RatchetConfig {
+            max_skip: 2,
+            ..RatchetConfig::default()
+        });
+
+        let first = alice.encrypt(b"first").unwrap();
+        let batch: Vec<_> = (0..4).map(|_| alice.encrypt(b"x").unwrap()).collect();
+
+        assert!(bob.decrypt(&batch[3]).is_err(), "4 skipped keys exceed max_skip");
+        assert_eq!(bob.skipped_key_count(), 0);
+        assert_eq!(bob.decrypt(&first).unwrap(), b"first");
+        assert_eq!(bob.decrypt(&batch[2]).unwrap(), b"x");
+    }
+
+    // Skipped keys expire after their lifetime
+    #[test]
+    fn test_skipped_keys_expire() {
+        let (mut alice, bob) = pair(b"expiry_shared_secret");
+        let mut bob = bob.with_config(RatchetConfig {
+            skipped_key_ttl_secs: 0,
+            ..RatchetConfig::default()
+        });
 
-        // Perform a DH ratchet step on r1 using r2's public key
-        let pk = r2.public_key();
-        r1.dh_ratchet(pk).expect("ratchet failed");
+        let m0 = alice.encrypt(b"m0").unwrap();
+        let m1 = alice.encrypt(b"m1").unwrap();
 
-        assert_ne!(r1.root_key, root_before, "Root key should change after DH ratchet");
-        assert_eq!(r1.sending_chain_key, [0u8; 32], "Sending CK should reset");
-        assert_eq!(r1.receiving_chain_key, [0u8; 32], "Receiving CK should reset");
+        bob.decrypt(&m1).unwrap();
+        assert!(bob.decrypt(&m0).is_err(), "Expired key must not decrypt");
+        assert_eq!(bob.skipped_key_count(), 0);
     }
 }
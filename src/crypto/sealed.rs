@@ -0,0 +1,126 @@
+use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use rand_core::OsRng;
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+use serde::{Serialize, Deserialize};
+use anyhow::{Result, anyhow};
+use crate::models::device::DeviceId;
+use crate::models::message::put_field;
+
+/// Prefix distinguishing a sealed envelope from a plain `Message` on the wire
+const SEALED_MAGIC: &[u8] = b"enigma-sealed";
+
+/// Domain separation prefix of routing tokens
+const ROUTING_DOMAIN: &[u8] = b"enigma-route-v1";
+
+/// HKDF info of the sealing key
+const SEALED_KDF_INFO: &[u8] = b"enigma-sealed-sender-v1";
+
+/// Outer envelope of a sealed-sender message: only the recipient can tell who sent it.
+/// The inner bytes are a complete signed `Message`, sender included.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SealedEnvelope {
+    pub routing_token: Vec<u8>,   // Opaque recipient device address
+    pub ephemeral_public: [u8; 32],
+    pub ciphertext: Vec<u8>,      // nonce || sealed inner message || tag
+}
+
+/// Opaque address of one device of a recipient: a hash of its username, id and identity key,
+/// so transports can route without reading usernames.
+pub fn routing_token(username: &str, device: DeviceId, encryption_public_key: &[u8]) -> Vec<u8> {
+    let mut data = Vec::new();
+    put_field(&mut data, ROUTING_DOMAIN);
+    put_field(&mut data, username.as_bytes());
+    data.extend_from_slice(&device.to_be_bytes());
+    put_field(&mut data, encryption_public_key);
+    Sha256::digest(&data).to_vec()
+}
+
+impl SealedEnvelope {
+    /// Encrypts `inner` to the recipient device's X25519 identity key with a one-time
+    /// ephemeral key, so the envelope carries nothing about the sender.
+    pub fn seal(recipient: &X25519PublicKey, routing_token: Vec<u8>, inner: &[u8]) -> Result<Self> {
+        let ephemeral = StaticSecret::new(OsRng);
+        let ephemeral_public = *X25519PublicKey::from(&ephemeral).as_bytes();
+        let key = Self::derive_key(ephemeral.diffie_hellman(recipient).as_bytes(), &ephemeral_public, recipient)?;
+
+        let mut nonce_bytes = [0u8; NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce_bytes)
+            .map_err(|_| anyhow!("Nonce generation failed"))?;
+        let mut buffer = inner.to_vec();
+        key.seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce_bytes),
+            Aad::from(Self::associated_data(&routing_token, &ephemeral_public)),
+            &mut buffer,
+        )
+        .map_err(|_| anyhow!("Sealing failed"))?;
+
+        let mut ciphertext = nonce_bytes.to_vec();
+        ciphertext.extend_from_slice(&buffer);
+        Ok(Self { routing_token, ephemeral_public, ciphertext })
+    }
+
+    /// Decrypts the inner message with the recipient device's X25519 identity secret
+    pub fn open(&self, identity_dh_secret: &StaticSecret) -> Result<Vec<u8>> {
+        if self.ciphertext.len() < NONCE_LEN + CHACHA20_POLY1305.tag_len() {
+            return Err(anyhow!("Invalid sealed envelope length"));
+        }
+        let ephemeral = X25519PublicKey::from(self.ephemeral_public);
+        let own_public = X25519PublicKey::from(identity_dh_secret);
+        let key = Self::derive_key(identity_dh_secret.diffie_hellman(&ephemeral).as_bytes(), &self.ephemeral_public, &own_public)?;
+
+        let nonce = Nonce::try_assume_unique_for_key(&self.ciphertext[..NONCE_LEN])
+            .map_err(|_| anyhow!("Invalid nonce"))?;
+        let mut buffer = self.ciphertext[NONCE_LEN..].to_vec();
+        let inner = key
+            .open_in_place(
+                nonce,
+                Aad::from(Self::associated_data(&self.routing_token, &self.ephemeral_public)),
+                &mut buffer,
+            )
+            .map_err(|_| anyhow!("Sealed envelope authentication failed"))?;
+        Ok(inner.to_vec())
+    }
+
+    /// Wire encoding, recognizable by `is_sealed`
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut out = SEALED_MAGIC.to_vec();
+        out.extend(bincode::serialize(self)?);
+        Ok(out)
+    }
+
+    /// Parses the wire encoding produced by `to_bytes`
+    pub fn from_bytes(raw: &[u8]) -> Result<Self> {
+        let body = raw
+            .strip_prefix(SEALED_MAGIC)
+            .ok_or_else(|| anyhow!("Not a sealed envelope"))?;
+        Ok(bincode::deserialize(body)?)
+    }
+
+    /// Whether raw inbound bytes are a sealed envelope rather than a plain message
+    pub fn is_sealed(raw: &[u8]) -> bool {
+        raw.starts_with(SEALED_MAGIC)
+    }
+
+    /// Sealing key: HKDF over the DH output, bound to both public keys
+    fn derive_key(dh_output: &[u8], ephemeral_public: &[u8; 32], recipient: &X25519PublicKey) -> Result<LessSafeKey> {
+        let mut salt = ephemeral_public.to_vec();
+        salt.extend_from_slice(recipient.as_bytes());
+        let hk = Hkdf::<Sha256>::new(Some(&salt), dh_output);
+        let mut okm = [0u8; 32];
+        hk.expand(SEALED_KDF_INFO, &mut okm)
+            .map_err(|_| anyhow!("Sealing key derivation failed"))?;
+        let key = UnboundKey::new(&CHACHA20_POLY1305, &okm).map_err(|_| anyhow!("Invalid sealing key"))?;
+        Ok(LessSafeKey::new(key))
+    }
+
+    fn associated_data(routing_token: &[u8], ephemeral_public: &[u8; 32]) -> Vec<u8> {
+        let mut aad = Vec::with_capacity(routing_token.len() + 36);
+        put_field(&mut aad, routing_token);
+        aad.extend_from_slice(ephemeral_public);
+        aad
+    }
+}
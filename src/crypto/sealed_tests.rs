@@ -0,0 +1,39 @@
+#[cfg(test)]
+mod tests {
+    use super::super::sealed::{routing_token, SealedEnvelope};
+    use rand_core::OsRng;
+    use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret};
+
+    // Only the recipient's identity secret opens the envelope, which carries no sender data
+    #[test]
+    fn test_seal_and_open() {
+        let bob = StaticSecret::new(OsRng);
+        let bob_public = X25519PublicKey::from(&bob);
+        let token = routing_token("@bob", 1, bob_public.as_bytes());
+        assert_eq!(token.len(), 32);
+        assert_ne!(token, routing_token("@bob", 2, bob_public.as_bytes()));
+
+        let inner = b"signed message from @alice";
+        let sealed = SealedEnvelope::seal(&bob_public, token.clone(), inner).unwrap();
+        let wire = sealed.to_bytes().unwrap();
+        assert!(SealedEnvelope::is_sealed(&wire));
+        assert!(!wire.windows(6).any(|w| w == b"@alice"), "The sender is not visible on the wire");
+
+        let parsed = SealedEnvelope::from_bytes(&wire).unwrap();
+        assert_eq!(parsed.open(&bob).unwrap(), inner);
+        assert!(parsed.open(&StaticSecret::new(OsRng)).is_err());
+
+        // The routing token is authenticated: it cannot be redirected
+        let mut redirected = parsed.clone();
+        redirected.routing_token = routing_token("@carol", 1, bob_public.as_bytes());
+        assert!(redirected.open(&bob).is_err());
+
+        let mut tampered = parsed;
+        let last = tampered.ciphertext.len() - 1;
+        tampered.ciphertext[last] ^= 1;
+        assert!(tampered.open(&bob).is_err());
+
+        assert!(!SealedEnvelope::is_sealed(b"plain"));
+        assert!(SealedEnvelope::from_bytes(b"plain").is_err());
+    }
+}
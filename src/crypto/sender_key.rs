@@ -0,0 +1,242 @@
+use ed25519_dalek::{Keypair as EdKeypair, PublicKey as EdPublicKey, SecretKey as EdSecretKey, Signature, Signer, Verifier};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use rand_core::OsRng;
+use hkdf::Hkdf;
+use sha2::Sha256;
+use serde::{Serialize, Deserialize};
+use anyhow::{Result, anyhow};
+use uuid::Uuid;
+use crate::crypto::padding::{pad, unpad, PaddingScheme};
+use crate::models::device::DeviceId;
+use crate::models::group::{Group, GroupOperation};
+use crate::models::message::put_field;
+
+/// Domain separation prefix of the signed sender key message encoding
+const SENDER_KEY_DOMAIN: &[u8] = b"enigma-sender-key-v1";
+
+/// Maximum number of message keys derived ahead to reach a later iteration
+pub const MAX_SENDER_KEY_SKIP: u32 = 1000;
+
+/// Sending half of a member's sender key: chain key and private signature key
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SenderKey {
+    pub key_id: u32,         // Increases on every rotation
+    pub iteration: u32,      // Index of the next message
+    pub chain_key: [u8; 32],
+    pub signing_secret: [u8; 32],
+}
+
+/// Sender key of a member as distributed to the others over pairwise sessions,
+/// together with the group state known to the sender
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SenderKeyDistribution {
+    pub group: Group,
+    pub operations: Vec<GroupOperation>, // Signed operations behind the roles raised in a channel view
+    pub sender_device: DeviceId,
+    pub key_id: u32,
+    pub iteration: u32,
+    pub chain_key: [u8; 32],
+    pub signing_public: [u8; 32],
+}
+
+/// Receiving state of another member's sender key
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ReceivedSenderKey {
+    pub key_id: u32,
+    pub iteration: u32,                // Next expected iteration
+    pub chain_key: [u8; 32],
+    pub signing_public: [u8; 32],
+    pub skipped: Vec<(u32, [u8; 32])>, // Keys of messages that have not arrived yet
+}
+
+/// Group message encrypted once with the sender's chain and signed with its sender key
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SenderKeyMessage {
+    pub group_id: Uuid,
+    pub sender_device: DeviceId,
+    pub key_id: u32,
+    pub iteration: u32,
+    pub ciphertext: Vec<u8>, // nonce || ciphertext || tag
+    pub signature: Vec<u8>,
+}
+
+/// Chain step: (next chain key, message key)
+fn kdf_chain(chain_key: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let hk = Hkdf::<Sha256>::new(None, chain_key);
+    let mut next = [0u8; 32];
+    let mut message_key = [0u8; 32];
+    hk.expand(b"enigma sender chain", &mut next).expect("32 bytes is a valid HKDF length");
+    hk.expand(b"enigma sender msg", &mut message_key).expect("32 bytes is a valid HKDF length");
+    (next, message_key)
+}
+
+fn aead_key(message_key: &[u8; 32]) -> Result<LessSafeKey> {
+    let key = UnboundKey::new(&CHACHA20_POLY1305, message_key).map_err(|_| anyhow!("Invalid message key"))?;
+    Ok(LessSafeKey::new(key))
+}
+
+impl SenderKey {
+    /// Fresh chain and signature key
+    pub fn generate(key_id: u32) -> Result<Self> {
+        let rng = SystemRandom::new();
+        let mut chain_key = [0u8; 32];
+        rng.fill(&mut chain_key).map_err(|_| anyhow!("Random generation failed"))?;
+        let signing_secret = EdKeypair::generate(&mut OsRng).secret.to_bytes();
+        Ok(Self { key_id, iteration: 0, chain_key, signing_secret })
+    }
+
+    fn keypair(&self) -> Result<EdKeypair> {
+        let secret = EdSecretKey::from_bytes(&self.signing_secret).map_err(|_| anyhow!("Invalid sender signing key"))?;
+        let public = EdPublicKey::from(&secret);
+        Ok(EdKeypair { secret, public })
+    }
+
+    /// What the other members need to decrypt our next messages
+    pub fn distribution(
+        &self,
+        group: &Group,
+        operations: Vec<GroupOperation>,
+        sender_device: DeviceId,
+    ) -> Result<SenderKeyDistribution> {
+        Ok(SenderKeyDistribution {
+            group: group.clone(),
+            operations,
+            sender_device,
+            key_id: self.key_id,
+            iteration: self.iteration,
+            chain_key: self.chain_key,
+            signing_public: self.keypair()?.public.to_bytes(),
+        })
+    }
+
+    /// Pads and encrypts a group message with the next chain key, then signs it
+    pub fn encrypt(
+        &mut self,
+        group_id: Uuid,
+        sender_device: DeviceId,
+        plaintext: &[u8],
+        padding: PaddingScheme,
+    ) -> Result<SenderKeyMessage> {
+        let (next, message_key) = kdf_chain(&self.chain_key);
+        let mut message = SenderKeyMessage {
+            group_id,
+            sender_device,
+            key_id: self.key_id,
+            iteration: self.iteration,
+            ciphertext: Vec::new(),
+            signature: Vec::new(),
+        };
+
+        let mut nonce_bytes = [0u8; NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce_bytes)
+            .map_err(|_| anyhow!("Nonce generation failed"))?;
+        let mut buffer = pad(plaintext, padding);
+        aead_key(&message_key)?
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce_bytes),
+                Aad::from(message.header()),
+                &mut buffer,
+            )
+            .map_err(|_| anyhow!("Encryption failed"))?;
+        message.ciphertext = nonce_bytes.to_vec();
+        message.ciphertext.extend_from_slice(&buffer);
+        message.signature = self.keypair()?.sign(&message.signed_payload()).to_bytes().to_vec();
+
+        self.chain_key = next;
+        self.iteration += 1;
+        Ok(message)
+    }
+}
+
+impl SenderKeyMessage {
+    /// Header authenticated as associated data: group, device, key id and iteration
+    fn header(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(64);
+        put_field(&mut out, SENDER_KEY_DOMAIN);
+        out.extend_from_slice(self.group_id.as_bytes());
+        out.extend_from_slice(&self.sender_device.to_be_bytes());
+        out.extend_from_slice(&self.key_id.to_be_bytes());
+        out.extend_from_slice(&self.iteration.to_be_bytes());
+        out
+    }
+
+    /// Content covered by the sender key signature
+    pub fn signed_payload(&self) -> Vec<u8> {
+        let mut out = self.header();
+        put_field(&mut out, &self.ciphertext);
+        out
+    }
+}
+
+impl From<&SenderKeyDistribution> for ReceivedSenderKey {
+    fn from(distribution: &SenderKeyDistribution) -> Self {
+        Self {
+            key_id: distribution.key_id,
+            iteration: distribution.iteration,
+            chain_key: distribution.chain_key,
+            signing_public: distribution.signing_public,
+            skipped: Vec::new(),
+        }
+    }
+}
+
+impl ReceivedSenderKey {
+    /// Verifies the signature, then decrypts. The state is left untouched on failure.
+    pub fn decrypt(&mut self, message: &SenderKeyMessage) -> Result<Vec<u8>> {
+        if message.key_id != self.key_id {
+            return Err(anyhow!("Unknown sender key {} (current is {})", message.key_id, self.key_id));
+        }
+        let public = EdPublicKey::from_bytes(&self.signing_public).map_err(|_| anyhow!("Invalid sender key"))?;
+        let signature = Signature::from_bytes(&message.signature).map_err(|_| anyhow!("Malformed signature"))?;
+        public
+            .verify(&message.signed_payload(), &signature)
+            .map_err(|_| anyhow!("Invalid sender key signature"))?;
+
+        let mut next = self.clone();
+        let message_key = next.message_key(message.iteration)?;
+        let plaintext = Self::open(&message_key, message)?;
+        *self = next;
+        Ok(plaintext)
+    }
+
+    /// Key of iteration `n`, from the skipped keys or by advancing the chain
+    fn message_key(&mut self, n: u32) -> Result<[u8; 32]> {
+        if let Some(pos) = self.skipped.iter().position(|(i, _)| *i == n) {
+            return Ok(self.skipped.remove(pos).1);
+        }
+        if n < self.iteration {
+            return Err(anyhow!("Duplicate or expired group message {}", n));
+        }
+        if n - self.iteration > MAX_SENDER_KEY_SKIP {
+            return Err(anyhow!("Group message {} is too far ahead", n));
+        }
+        while self.iteration < n {
+            let (next, skipped) = kdf_chain(&self.chain_key);
+            self.skipped.push((self.iteration, skipped));
+            self.chain_key = next;
+            self.iteration += 1;
+        }
+        let excess = self.skipped.len().saturating_sub(MAX_SENDER_KEY_SKIP as usize);
+        self.skipped.drain(..excess);
+
+        let (next, message_key) = kdf_chain(&self.chain_key);
+        self.chain_key = next;
+        self.iteration += 1;
+        Ok(message_key)
+    }
+
+    fn open(message_key: &[u8; 32], message: &SenderKeyMessage) -> Result<Vec<u8>> {
+        if message.ciphertext.len() < NONCE_LEN + CHACHA20_POLY1305.tag_len() {
+            return Err(anyhow!("Invalid ciphertext length"));
+        }
+        let nonce = Nonce::try_assume_unique_for_key(&message.ciphertext[..NONCE_LEN])
+            .map_err(|_| anyhow!("Invalid nonce"))?;
+        let mut buffer = message.ciphertext[NONCE_LEN..].to_vec();
+        let padded = aead_key(message_key)?
+            .open_in_place(nonce, Aad::from(message.header()), &mut buffer)
+            .map_err(|_| anyhow!("Group message authentication failed"))?;
+        unpad(padded)
+    }
+}
@@ -0,0 +1,67 @@
+#[cfg(test)]
+mod tests {
+    use super::super::padding::PaddingScheme;
+    use super::super::sender_key::{ReceivedSenderKey, SenderKey, MAX_SENDER_KEY_SKIP};
+    use crate::models::group::Group;
+
+    // Members who received the distribution decrypt every message, in any order
+    #[test]
+    fn test_sender_key_round_trip() {
+        let group = Group::new("Friends", "@alice", false);
+        let mut alice = SenderKey::generate(0).unwrap();
+        let distribution = alice.distribution(&group, Vec::new(), 1).unwrap();
+        let mut bob = ReceivedSenderKey::from(&distribution);
+
+        let first = alice.encrypt(group.id, 1, b"first", PaddingScheme::Bucketed).unwrap();
+        let second = alice.encrypt(group.id, 1, b"second", PaddingScheme::Bucketed).unwrap();
+        let third = alice.encrypt(group.id, 1, b"third", PaddingScheme::None).unwrap();
+        assert_eq!(first.ciphertext.len(), second.ciphertext.len(), "Padded to the same bucket");
+
+        assert_eq!(bob.decrypt(&third).unwrap(), b"third");
+        assert_eq!(bob.decrypt(&first).unwrap(), b"first");
+        assert_eq!(bob.decrypt(&second).unwrap(), b"second");
+        assert!(bob.decrypt(&second).is_err(), "Replays are rejected");
+        assert!(bob.skipped.is_empty());
+
+        // A member joining later starts at the current iteration
+        let mut carol = ReceivedSenderKey::from(&alice.distribution(&group, Vec::new(), 1).unwrap());
+        let fourth = alice.encrypt(group.id, 1, b"fourth", PaddingScheme::default()).unwrap();
+        assert!(carol.decrypt(&first).is_err());
+        assert_eq!(carol.decrypt(&fourth).unwrap(), b"fourth");
+    }
+
+    // Forged or tampered messages fail without advancing the receiving chain
+    #[test]
+    fn test_sender_key_authentication() {
+        let group = Group::new("Friends", "@alice", false);
+        let mut alice = SenderKey::generate(0).unwrap();
+        let mut bob = ReceivedSenderKey::from(&alice.distribution(&group, Vec::new(), 1).unwrap());
+        let message = alice.encrypt(group.id, 1, b"hello", PaddingScheme::default()).unwrap();
+
+        // Same chain, another signature key: a member cannot impersonate another
+        let mut mallory = alice.clone();
+        mallory.signing_secret = SenderKey::generate(0).unwrap().signing_secret;
+        let forged = mallory.encrypt(group.id, 1, b"evil", PaddingScheme::default()).unwrap();
+        assert!(bob.decrypt(&forged).is_err());
+
+        let mut tampered = message.clone();
+        let last = tampered.ciphertext.len() - 1;
+        tampered.ciphertext[last] ^= 1;
+        assert!(bob.decrypt(&tampered).is_err());
+        let mut moved = message.clone();
+        moved.iteration = 5;
+        assert!(bob.decrypt(&moved).is_err(), "The header is signed");
+
+        let mut rotated = SenderKey::generate(1).unwrap();
+        let stale = rotated.encrypt(group.id, 1, b"new key", PaddingScheme::default()).unwrap();
+        assert!(bob.decrypt(&stale).is_err(), "Unknown key id");
+
+        assert_eq!(bob.iteration, 0);
+        assert_eq!(bob.decrypt(&message).unwrap(), b"hello");
+
+        let mut far = alice.clone();
+        far.iteration += MAX_SENDER_KEY_SKIP + 1;
+        let too_far = far.encrypt(group.id, 1, b"late", PaddingScheme::default()).unwrap();
+        assert!(bob.decrypt(&too_far).is_err());
+    }
+}
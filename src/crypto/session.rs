@@ -0,0 +1,672 @@
+use crate::crypto::handshake::{
+    build_bundle, x3dh_initiate, x3dh_respond, IdentityKey, InitialMessageHeader, KeyMaterial,
+    OneTimePreKeyStore, SignedPreKeyStore, SpkRotationPolicy, X3DHBundle,
+};
+use crate::crypto::mls::KeyPackage;
+use crate::crypto::padding::PaddingScheme;
+use crate::crypto::ratchet::{Ratchet, RatchetMessage, SessionState};
+use crate::crypto::sealed::{routing_token, SealedEnvelope};
+use crate::models::device::{DeviceError, DeviceId, DeviceInfo, DeviceList};
+use crate::models::user::PublicIdentity;
+use crate::network::directory::PreKeyDirectory;
+use crate::storage::account::AccountStore;
+use crate::storage::sessions::SessionStore;
+use ed25519_dalek::{PublicKey as EdPublicKey, Signature};
+use x25519_dalek::PublicKey as X25519PublicKey;
+use serde::{Serialize, Deserialize};
+use anyhow::{Result, anyhow};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Key of the session with one device of a peer
+pub fn device_address(username: &str, device: DeviceId) -> String {
+    format!("{}/{}", username, device)
+}
+
+/// Encrypted payload exchanged between two device sessions
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SessionEnvelope {
+    pub sender_device: DeviceId,
+    pub receiver_device: DeviceId,
+    pub prekey: Option<InitialMessageHeader>, // X3DH header, repeated until the peer replies
+    pub sender_devices: Option<DeviceList>,   // Sender's signed device list, sent with the header
+    pub message: RatchetMessage,
+}
+
+/// Long-term keys of a peer device, as authenticated during X3DH
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PeerIdentity {
+    pub signing: EdPublicKey,
+    pub dh: X25519PublicKey,
+    pub dh_signature: Signature, // Signature of `dh` by `signing`
+}
+
+impl PeerIdentity {
+    /// Identity announced in a device's prekey bundle
+    pub fn from_bundle(bundle: &X3DHBundle) -> Self {
+        Self {
+            signing: bundle.identity_pub,
+            dh: bundle.identity_dh_pub,
+            dh_signature: bundle.identity_dh_signature,
+        }
+    }
+
+    /// Identity announced in the X3DH header of a first message
+    pub fn from_header(header: &InitialMessageHeader) -> Self {
+        Self {
+            signing: header.identity_pub,
+            dh: header.identity_dh_pub,
+            dh_signature: header.identity_dh_signature,
+        }
+    }
+
+    /// Whether these are the keys the account lists for `device`
+    pub fn matches(&self, device: &DeviceInfo) -> bool {
+        self.signing.as_bytes()[..] == device.signing_public_key[..]
+            && self.dh.as_bytes()[..] == device.encryption_public_key[..]
+    }
+}
+
+/// Ratchet session with one peer device
+#[derive(Clone)]
+pub struct PeerSession {
+    pub ratchet: Ratchet,
+    pub pending_prekey: Option<InitialMessageHeader>,
+    pub peer_identity: PeerIdentity,
+    pub origin: Option<X25519PublicKey>, // Ephemeral key of the X3DH header we accepted it from
+}
+
+/// Serializable form of a `PeerSession`
+#[derive(Serialize, Deserialize)]
+pub struct PeerSessionRecord {
+    pub state: SessionState,
+    pub pending_prekey: Option<InitialMessageHeader>,
+    pub peer_identity: PeerIdentity,
+    #[serde(default)]
+    pub origin: Option<X25519PublicKey>,
+}
+
+impl PeerSession {
+    /// Exports the session for persistence
+    pub fn to_record(&self) -> PeerSessionRecord {
+        PeerSessionRecord {
+            state: self.ratchet.to_state(),
+            pending_prekey: self.pending_prekey.clone(),
+            peer_identity: self.peer_identity.clone(),
+            origin: self.origin,
+        }
+    }
+
+    /// Restores a session exported with `to_record`
+    pub fn from_record(record: PeerSessionRecord) -> Self {
+        Self {
+            ratchet: Ratchet::from_state(record.state),
+            pending_prekey: record.pending_prekey,
+            peer_identity: record.peer_identity,
+            origin: record.origin,
+        }
+    }
+}
+
+/// Maps each peer device address (username/device) to its own ratchet session
+pub struct SessionManager {
+    device_id: DeviceId,
+    local_devices: DeviceList,
+    identity: IdentityKey,
+    signed_prekeys: SignedPreKeyStore,
+    spk_policy: SpkRotationPolicy,
+    spk_unpublished: bool, // Rotated signed prekey not yet accepted by the directory
+    one_time_prekeys: OneTimePreKeyStore,
+    sessions: HashMap<String, PeerSession>,
+    peer_devices: HashMap<String, DeviceList>, // Verified device lists of peers
+    pins: HashMap<String, Vec<u8>>,            // Account keys approved by the user
+    store: Arc<SessionStore>,
+    account: Arc<AccountStore>,
+    directory: Arc<dyn PreKeyDirectory>,
+}
+
+impl SessionManager {
+    /// Creates the manager for device `device_id` of the account described by `local_devices`,
+    /// and restores the sessions saved in the store
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        device_id: DeviceId,
+        local_devices: DeviceList,
+        identity: IdentityKey,
+        signed_prekeys: SignedPreKeyStore,
+        one_time_prekeys: OneTimePreKeyStore,
+        store: Arc<SessionStore>,
+        account: Arc<AccountStore>,
+        directory: Arc<dyn PreKeyDirectory>,
+    ) -> Result<Self> {
+        local_devices.verify()?;
+        let listed = local_devices
+            .device(device_id)
+            .is_some_and(|d| d.signing_public_key[..] == identity.keypair.public.as_bytes()[..]);
+        if !listed {
+            return Err(anyhow!("Device {} is not in the device list of {}", device_id, local_devices.username()));
+        }
+
+        let sessions = store.load_all()?.into_iter().collect();
+        let peer_devices = store
+            .load_devices()?
+            .into_iter()
+            .map(|devices| (devices.username().to_owned(), devices))
+            .collect();
+        let pins = store.load_pins()?.into_iter().collect();
+
+        Ok(Self {
+            device_id,
+            local_devices,
+            identity,
+            signed_prekeys,
+            spk_policy: SpkRotationPolicy::default(),
+            spk_unpublished: false,
+            one_time_prekeys,
+            sessions,
+            peer_devices,
+            pins,
+            store,
+            account,
+            directory,
+        })
+    }
+
+    /// Saves the key material, after prekeys were generated or consumed
+    fn persist_keys(&self) -> Result<()> {
+        self.account.save_keys(&KeyMaterial::export(
+            &self.identity,
+            &self.signed_prekeys,
+            &self.one_time_prekeys,
+        ))
+    }
+
+    /// Changes how often the signed prekey is rotated and how long old ones are kept
+    pub fn set_spk_policy(&mut self, policy: SpkRotationPolicy) {
+        self.spk_policy = policy;
+    }
+
+    /// Id of the signed prekey currently published
+    pub fn current_spk_id(&self) -> u32 {
+        self.signed_prekeys.current().id
+    }
+
+    /// Rotates the signed prekey when it is older than the rotation interval and deletes
+    /// replaced keys past their grace period. Returns whether a new key must be published
+    /// (also when a previous publication failed).
+    pub fn rotate_signed_prekey_if_due(&mut self, now: i64) -> Result<bool> {
+        let rotated = self.signed_prekeys.is_due(now, &self.spk_policy);
+        if rotated {
+            self.signed_prekeys.rotate(&self.identity, now);
+            self.spk_unpublished = true;
+        }
+        let purged = self.signed_prekeys.purge_expired(now, &self.spk_policy);
+        if rotated || purged > 0 {
+            self.persist_keys()?;
+        }
+        Ok(self.spk_unpublished)
+    }
+
+    /// Our own self-signed account identity, as published and seen by peers
+    pub fn local_identity(&self) -> &PublicIdentity {
+        &self.local_devices.identity
+    }
+
+    /// This device's id within the account
+    pub fn device_id(&self) -> DeviceId {
+        self.device_id
+    }
+
+    /// The signed device list of our account
+    pub fn local_devices(&self) -> &DeviceList {
+        &self.local_devices
+    }
+
+    /// Replaces our device list after a device was linked or revoked
+    pub fn update_local_devices(&mut self, devices: DeviceList) -> Result<()> {
+        devices.verify()?;
+        if devices.identity != self.local_devices.identity || devices.version <= self.local_devices.version {
+            return Err(anyhow!("Device list is not a newer list of this account"));
+        }
+        self.account.save_devices(&devices)?;
+        self.local_devices = devices;
+        Ok(())
+    }
+
+    /// Whether a session with at least one device of this peer exists
+    pub fn has_session(&self, peer: &str) -> bool {
+        let prefix = format!("{}/", peer);
+        self.sessions.keys().any(|address| address.starts_with(&prefix))
+    }
+
+    /// Whether a session with this device of a peer exists
+    pub fn has_device_session(&self, peer: &str, device: DeviceId) -> bool {
+        self.sessions.contains_key(&device_address(peer, device))
+    }
+
+    /// Number of established device sessions
+    pub fn session_count(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Identity key of a peer device we have a session with
+    pub fn peer_identity(&self, peer: &str, device: DeviceId) -> Option<EdPublicKey> {
+        self.sessions.get(&device_address(peer, device)).map(|s| s.peer_identity.signing)
+    }
+
+    /// Account identity of a peer whose device list we verified
+    pub fn peer_public_identity(&self, peer: &str) -> Option<PublicIdentity> {
+        self.peer_devices.get(peer).map(|devices| devices.identity.clone())
+    }
+
+    /// Verified device list of a peer
+    pub fn peer_devices(&self, peer: &str) -> Option<&DeviceList> {
+        self.peer_devices.get(peer)
+    }
+
+    /// Identity key we know for a peer device: the one its verified device list gives, or
+    /// the one of our session with it
+    pub fn device_key(&self, peer: &str, device: DeviceId) -> Option<EdPublicKey> {
+        self.peer_devices
+            .get(peer)
+            .and_then(|devices| devices.device(device))
+            .and_then(|d| EdPublicKey::from_bytes(&d.signing_public_key).ok())
+            .or_else(|| self.peer_identity(peer, device))
+    }
+
+    /// Trusts a new account key for a peer, as approved by the user, and drops our
+    /// sessions and the device list signed by its former key
+    pub fn approve_account_key(&mut self, peer: &str, account_key: &[u8]) -> Result<()> {
+        self.remove_session(peer)?;
+        self.peer_devices.remove(peer);
+        self.store.delete_devices(peer)?;
+        self.store.save_pin(peer, account_key)?;
+        self.pins.insert(peer.to_owned(), account_key.to_vec());
+        Ok(())
+    }
+
+    /// Account key we trust for a peer: ours for our own account, the one the user
+    /// approved, else the one of the device list we accepted
+    fn pinned_key(&self, peer: &str) -> Option<&[u8]> {
+        if peer == self.local_devices.username() {
+            return Some(&self.local_devices.identity.signing_public_key);
+        }
+        self.pins
+            .get(peer)
+            .map(|key| &key[..])
+            .or_else(|| self.peer_devices.get(peer).map(|d| &d.identity.signing_public_key[..]))
+    }
+
+    /// Account key a device list of a peer must be signed with: the pinned one, or on first
+    /// contact the one registered in the directory
+    async fn expected_account_key(&self, peer: &str) -> Result<Vec<u8>> {
+        if let Some(key) = self.pinned_key(peer) {
+            return Ok(key.to_vec());
+        }
+        let identity = self.directory.fetch_identity(peer).await?;
+        identity.verify()?;
+        if identity.username != peer {
+            return Err(anyhow!("Identity of {} returned for {}", identity.username, peer));
+        }
+        Ok(identity.signing_public_key)
+    }
+
+    /// Checks that a device list is the verified list of `peer`, signed by the expected
+    /// account key. Another key is never accepted silently: the user must approve it.
+    fn check_devices(peer: &str, devices: &DeviceList, account_key: &[u8]) -> Result<()> {
+        devices.verify()?;
+        if devices.username() != peer {
+            return Err(anyhow!("Device list of {} presented for {}", devices.username(), peer));
+        }
+        if devices.identity.signing_public_key != account_key {
+            return Err(DeviceError::AccountKeyMismatch(peer.to_owned()).into());
+        }
+        Ok(())
+    }
+
+    /// Our current publishable bundle, with freshly generated one-time prekeys
+    pub fn refill_bundle(&mut self, one_time_prekeys: usize) -> Result<X3DHBundle> {
+        let opks = self.one_time_prekeys.generate_batch(one_time_prekeys);
+        self.persist_keys()?;
+        Ok(build_bundle(&self.identity, self.signed_prekeys.current(), opks))
+    }
+
+    /// Publishes this device's bundle with new one-time prekeys to the directory
+    pub async fn publish_prekeys(&mut self, username: &str, one_time_prekeys: usize) -> Result<()> {
+        self.publish_prekeys_with(username, one_time_prekeys, Vec::new()).await
+    }
+
+    /// Same as `publish_prekeys`, also publishing MLS key packages of this device
+    pub async fn publish_prekeys_with(
+        &mut self,
+        username: &str,
+        one_time_prekeys: usize,
+        key_packages: Vec<KeyPackage>,
+    ) -> Result<()> {
+        let mut bundle = self.refill_bundle(one_time_prekeys)?;
+        bundle.key_packages = key_packages;
+        self.directory.publish_bundle(username, self.device_id, &bundle).await?;
+        self.spk_unpublished = false;
+        Ok(())
+    }
+
+    /// Registers our self-signed identity in the directory
+    pub async fn publish_identity(&self) -> Result<()> {
+        self.directory.publish_identity(self.local_identity()).await
+    }
+
+    /// Publishes our signed device list in the directory
+    pub async fn publish_devices(&self) -> Result<()> {
+        self.directory.publish_devices(&self.local_devices).await
+    }
+
+    /// Fetches the device list of a peer again, dropping the sessions of revoked devices
+    pub async fn refresh_devices(&mut self, peer: &str) -> Result<()> {
+        let devices = self.directory.fetch_devices(peer).await?;
+        let account_key = self.expected_account_key(peer).await?;
+        Self::check_devices(peer, &devices, &account_key)?;
+        self.accept_devices(peer, devices)
+    }
+
+    /// The most recent of a presented list and the one we know; both are signed by the
+    /// pinned account key
+    fn newest_devices<'a>(&'a self, peer: &str, presented: &'a DeviceList) -> &'a DeviceList {
+        match self.peer_devices.get(peer) {
+            Some(known) if known.version > presented.version => known,
+            _ => presented,
+        }
+    }
+
+    /// Records a device list checked by `check_devices` unless a newer one is known, and
+    /// drops the sessions of the devices it no longer contains
+    fn accept_devices(&mut self, peer: &str, devices: DeviceList) -> Result<()> {
+        if self.pinned_key(peer).is_some_and(|key| key != &devices.identity.signing_public_key[..]) {
+            return Err(DeviceError::AccountKeyMismatch(peer.to_owned()).into());
+        }
+        if self.newest_devices(peer, &devices) != &devices {
+            return Ok(());
+        }
+
+        let revoked: Vec<String> = self
+            .sessions
+            .keys()
+            .filter(|address| {
+                address
+                    .strip_prefix(peer)
+                    .and_then(|rest| rest.strip_prefix('/'))
+                    .and_then(|id| id.parse::<DeviceId>().ok())
+                    .is_some_and(|id| devices.device(id).is_none())
+            })
+            .cloned()
+            .collect();
+        for address in revoked {
+            self.sessions.remove(&address);
+            self.store.delete(&address)?;
+        }
+
+        self.store.save_devices(&devices)?;
+        self.peer_devices.insert(peer.to_owned(), devices);
+        Ok(())
+    }
+
+    /// Fetches one key package for each device of a peer, signed by the key its account
+    /// lists for that device. Devices without a package left are skipped.
+    pub async fn fetch_key_packages(&mut self, peer: &str) -> Result<Vec<KeyPackage>> {
+        self.refresh_devices(peer).await?;
+        let devices = self.peer_devices(peer).cloned().ok_or_else(|| anyhow!("No devices for {}", peer))?;
+        let mut packages = Vec::new();
+        for device in &devices.devices {
+            let bundle = self.directory.fetch_bundle(peer, device.id).await?;
+            if let Some(package) = bundle.key_packages.into_iter().next() {
+                package.verify()?;
+                if package.leaf.username != peer
+                    || package.leaf.device != device.id
+                    || package.leaf.signature_key != device.signing_public_key
+                {
+                    return Err(anyhow!("Key package of {} does not match its device {}", peer, device.id));
+                }
+                packages.push(package);
+            }
+        }
+        Ok(packages)
+    }
+
+    /// Starts a session with a peer device by fetching its bundle and running X3DH
+    async fn open_session(&mut self, peer: &str, device: &DeviceInfo) -> Result<()> {
+        let bundle = self.directory.fetch_bundle(peer, device.id).await?;
+
+        // The bundle must hold the keys the account signed for this device
+        let peer_identity = PeerIdentity::from_bundle(&bundle);
+        if !peer_identity.matches(device) {
+            return Err(anyhow!("Bundle of {} does not match its device {}", peer, device.id));
+        }
+
+        let x3dh = x3dh_initiate(&self.identity, &bundle)?;
+        let ratchet = Ratchet::new_initiator(&x3dh.shared_secret, bundle.spk_pub.as_bytes())?;
+
+        self.sessions.insert(
+            device_address(peer, device.id),
+            PeerSession {
+                ratchet,
+                pending_prekey: Some(x3dh.header),
+                peer_identity,
+                origin: None,
+            },
+        );
+        Ok(())
+    }
+
+    /// Encrypts a message for every device of a peer with the default padding
+    pub async fn encrypt(&mut self, peer: &str, plaintext: &[u8]) -> Result<Vec<SessionEnvelope>> {
+        self.encrypt_padded(peer, plaintext, PaddingScheme::default()).await
+    }
+
+    /// Encrypts a message for every device of a peer, creating sessions on first contact.
+    /// Devices whose bundle cannot be fetched are skipped; at least one must be reachable.
+    pub async fn encrypt_padded(
+        &mut self,
+        peer: &str,
+        plaintext: &[u8],
+        padding: PaddingScheme,
+    ) -> Result<Vec<SessionEnvelope>> {
+        // Messages to our own account go to our other devices
+        let own = peer == self.local_devices.username();
+        if !own && !self.peer_devices.contains_key(peer) {
+            self.refresh_devices(peer).await?;
+        }
+        let devices: Vec<DeviceInfo> = if own {
+            self.local_devices.devices.iter().filter(|d| d.id != self.device_id).cloned().collect()
+        } else {
+            self.peer_devices.get(peer).map(|list| list.devices.clone()).unwrap_or_default()
+        };
+
+        let mut envelopes = Vec::with_capacity(devices.len());
+        let mut last_error = None;
+        for device in devices {
+            let address = device_address(peer, device.id);
+            if !self.sessions.contains_key(&address) {
+                if let Err(e) = self.open_session(peer, &device).await {
+                    last_error = Some(e);
+                    continue;
+                }
+            }
+
+            let session = self
+                .sessions
+                .get_mut(&address)
+                .ok_or_else(|| anyhow!("No session with {}", address))?;
+            let message = session.ratchet.encrypt_padded(plaintext, padding)?;
+            envelopes.push(SessionEnvelope {
+                sender_device: self.device_id,
+                receiver_device: device.id,
+                prekey: session.pending_prekey.clone(),
+                sender_devices: session
+                    .pending_prekey
+                    .as_ref()
+                    .map(|_| self.local_devices.clone()),
+                message,
+            });
+            self.store.save(&address, session)?;
+        }
+
+        if envelopes.is_empty() {
+            return Err(last_error.unwrap_or_else(|| anyhow!("{} has no device", peer)));
+        }
+        Ok(envelopes)
+    }
+
+    /// Decrypts a message from a peer device, accepting the session it initiates on first
+    /// contact. A new X3DH header (the peer lost its session or changed its identity key)
+    /// starts a new session, which replaces ours only if it decrypts the message.
+    /// The device list it carries must be signed by the pinned account key (see
+    /// `check_devices`).
+    pub async fn decrypt(&mut self, peer: &str, envelope: &SessionEnvelope) -> Result<Vec<u8>> {
+        if envelope.receiver_device != self.device_id {
+            return Err(anyhow!(
+                "Message for device {}, this is device {}",
+                envelope.receiver_device,
+                self.device_id
+            ));
+        }
+        let address = device_address(peer, envelope.sender_device);
+
+        // Work on copies so a forged message neither breaks the session nor burns a prekey
+        let mut one_time_prekeys = self.one_time_prekeys.clone();
+        let existing = self.sessions.get(&address).cloned();
+        let (mut session, plaintext, presented_devices) = match (&envelope.prekey, existing) {
+            // No header, or the header of the exchange the session came from, repeated until
+            // we reply
+            (None, Some(mut session)) => {
+                let plaintext = session.ratchet.decrypt(&envelope.message)?;
+                (session, plaintext, None)
+            }
+            (Some(header), Some(mut session)) if session.origin == Some(header.ephemeral_pub) => {
+                let plaintext = session.ratchet.decrypt(&envelope.message)?;
+                (session, plaintext, None)
+            }
+            (Some(header), existing) => {
+                let fresh = self
+                    .respond(peer, envelope, header, &mut one_time_prekeys)
+                    .await
+                    .and_then(|(mut session, devices)| {
+                        let plaintext = session.ratchet.decrypt(&envelope.message)?;
+                        Ok((session, plaintext, Some(devices)))
+                    });
+                match (fresh, existing) {
+                    (Ok(fresh), _) => fresh,
+                    // A list signed by another account key is never worked around
+                    (Err(e), _) if e.downcast_ref::<DeviceError>().is_some() => return Err(e),
+                    (Err(_), Some(mut session)) => {
+                        one_time_prekeys = self.one_time_prekeys.clone();
+                        let plaintext = session.ratchet.decrypt(&envelope.message)?;
+                        (session, plaintext, None)
+                    }
+                    (Err(e), None) => return Err(e),
+                }
+            }
+            (None, None) => return Err(anyhow!("No session with {} and no X3DH header", address)),
+        };
+
+        // The peer answered: it has the session, stop repeating the X3DH header
+        if envelope.prekey.is_none() {
+            session.pending_prekey = None;
+        }
+
+        self.store.save(&address, &session)?;
+        self.sessions.insert(address, session);
+        if let Some(devices) = presented_devices {
+            self.accept_devices(peer, devices)?;
+        }
+        if one_time_prekeys.remaining() != self.one_time_prekeys.remaining() {
+            // A one-time prekey was consumed: delete it from disk as well
+            self.one_time_prekeys = one_time_prekeys;
+            self.persist_keys()?;
+        }
+        Ok(plaintext)
+    }
+
+    /// Builds the responder session of an X3DH header, checking that the sender device is
+    /// listed with these keys in its account's device list
+    async fn respond(
+        &self,
+        peer: &str,
+        envelope: &SessionEnvelope,
+        header: &InitialMessageHeader,
+        one_time_prekeys: &mut OneTimePreKeyStore,
+    ) -> Result<(PeerSession, DeviceList)> {
+        let address = device_address(peer, envelope.sender_device);
+        let devices = envelope
+            .sender_devices
+            .as_ref()
+            .ok_or_else(|| anyhow!("First message from {} carries no device list", address))?;
+        let account_key = self.expected_account_key(peer).await?;
+        Self::check_devices(peer, devices, &account_key)?;
+
+        // The sender device must be listed (in the newest list we know) with these keys
+        let peer_identity = PeerIdentity::from_header(header);
+        let listed = self
+            .newest_devices(peer, devices)
+            .device(envelope.sender_device)
+            .is_some_and(|d| peer_identity.matches(d));
+        if !listed {
+            return Err(anyhow!("{} is not a device of {}", address, peer));
+        }
+
+        // The initiator may have fetched a bundle published before the last rotation
+        let spk = self
+            .signed_prekeys
+            .get(header.spk_id)
+            .ok_or_else(|| anyhow!("Signed prekey {} is unknown or expired", header.spk_id))?;
+        let secret = x3dh_respond(&self.identity, spk, one_time_prekeys, header)?;
+        let session = PeerSession {
+            ratchet: Ratchet::new_responder(&secret, spk.secret.clone())?,
+            pending_prekey: None,
+            peer_identity,
+            origin: Some(header.ephemeral_pub),
+        };
+        Ok((session, devices.clone()))
+    }
+
+    /// Routing token of this device, carried by sealed envelopes addressed to it
+    pub fn routing_token(&self) -> Vec<u8> {
+        routing_token(self.local_devices.username(), self.device_id, self.identity.dh_public.as_bytes())
+    }
+
+    /// Wraps an outgoing wire message for one device of a peer, hiding the sender.
+    /// The peer's device list must be known (it is after `encrypt`); our own devices are
+    /// looked up in the local list.
+    pub fn seal(&self, peer: &str, device: DeviceId, inner: &[u8]) -> Result<SealedEnvelope> {
+        let devices = if peer == self.local_devices.username() {
+            Some(&self.local_devices)
+        } else {
+            self.peer_devices.get(peer)
+        };
+        let info = devices
+            .and_then(|devices| devices.device(device))
+            .ok_or_else(|| anyhow!("Unknown device {} of {}", device, peer))?;
+        let key: [u8; 32] = info.encryption_public_key[..]
+            .try_into()
+            .map_err(|_| anyhow!("Invalid encryption key for device {} of {}", device, peer))?;
+        let token = routing_token(peer, device, &key);
+        SealedEnvelope::seal(&X25519PublicKey::from(key), token, inner)
+    }
+
+    /// Unwraps a sealed envelope addressed to this device
+    pub fn open_sealed(&self, sealed: &SealedEnvelope) -> Result<Vec<u8>> {
+        if sealed.routing_token != self.routing_token() {
+            return Err(anyhow!("Sealed envelope addressed to another device"));
+        }
+        sealed.open(&self.identity.dh_secret)
+    }
+
+    /// Drops the sessions with every device of a peer
+    pub fn remove_session(&mut self, peer: &str) -> Result<()> {
+        let prefix = format!("{}/", peer);
+        let addresses: Vec<String> = self.sessions.keys().filter(|a| a.starts_with(&prefix)).cloned().collect();
+        for address in addresses {
+            self.sessions.remove(&address);
+            self.store.delete(&address)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,351 @@
+#[cfg(test)]
+mod tests {
+    use super::super::handshake::{
+        generate_identity_bundle, IdentityKey, OneTimePreKeyStore, SignedPreKey, SignedPreKeyStore, X3DHBundle,
+    };
+    use super::super::session::{device_address, SessionManager};
+    use crate::models::device::{DeviceError, DeviceId, DeviceList, PRIMARY_DEVICE};
+    use crate::models::user::PublicIdentity;
+    use crate::network::directory::PreKeyDirectory;
+    use crate::storage::account::AccountStore;
+    use crate::storage::persistence::Persistence;
+    use crate::storage::sessions::SessionStore;
+    use anyhow::{anyhow, Result};
+    use async_trait::async_trait;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+
+    type Keys = (IdentityKey, SignedPreKey, OneTimePreKeyStore, X3DHBundle);
+
+    // In-memory directory behaving like the nodes: one one-time prekey per fetch
+    #[derive(Default)]
+    struct MockDirectory {
+        bundles: Mutex<HashMap<String, X3DHBundle>>,
+        identities: Mutex<HashMap<String, PublicIdentity>>,
+        devices: Mutex<HashMap<String, DeviceList>>,
+    }
+
+    #[async_trait]
+    impl PreKeyDirectory for MockDirectory {
+        async fn fetch_bundle(&self, username: &str, device: DeviceId) -> Result<X3DHBundle> {
+            let mut bundles = self.bundles.lock().unwrap();
+            let bundle = bundles
+                .get_mut(&device_address(username, device))
+                .ok_or_else(|| anyhow!("unknown device"))?;
+            let mut response = bundle.clone();
+            response.one_time_prekeys = if bundle.one_time_prekeys.is_empty() {
+                vec![]
+            } else {
+                vec![bundle.one_time_prekeys.remove(0)]
+            };
+            Ok(response)
+        }
+
+        async fn publish_bundle(&self, username: &str, device: DeviceId, bundle: &X3DHBundle) -> Result<()> {
+            self.bundles.lock().unwrap().insert(device_address(username, device), bundle.clone());
+            Ok(())
+        }
+
+        async fn fetch_devices(&self, username: &str) -> Result<DeviceList> {
+            self.devices.lock().unwrap().get(username).cloned().ok_or_else(|| anyhow!("unknown user"))
+        }
+
+        async fn publish_devices(&self, devices: &DeviceList) -> Result<()> {
+            self.devices.lock().unwrap().insert(devices.username().to_owned(), devices.clone());
+            Ok(())
+        }
+
+        async fn fetch_identity(&self, username: &str) -> Result<PublicIdentity> {
+            self.identities.lock().unwrap().get(username).cloned().ok_or_else(|| anyhow!("unknown user"))
+        }
+
+        async fn publish_identity(&self, identity: &PublicIdentity) -> Result<()> {
+            self.identities.lock().unwrap().insert(identity.username.clone(), identity.clone());
+            Ok(())
+        }
+    }
+
+    // Manager of one device of the account described by `devices`, published in the directory
+    fn device(path: &str, id: DeviceId, devices: &DeviceList, keys: Keys, directory: Arc<MockDirectory>) -> SessionManager {
+        if Path::new(path).exists() {
+            fs::remove_dir_all(path).unwrap();
+        }
+        let (identity, spk, opks, bundle) = keys;
+        let username = devices.username();
+        directory.bundles.lock().unwrap().insert(device_address(username, id), bundle);
+        directory.devices.lock().unwrap().insert(username.to_owned(), devices.clone());
+        directory.identities.lock().unwrap().insert(username.to_owned(), devices.identity.clone());
+
+        let persistence = Persistence::open(path).unwrap();
+        persistence.unlock_with_key(&[7u8; 32]).unwrap();
+        let persistence = Arc::new(persistence);
+        let store = Arc::new(SessionStore::new(persistence.clone()));
+        let account = Arc::new(AccountStore::new(persistence));
+        SessionManager::new(
+            id,
+            devices.clone(),
+            identity,
+            SignedPreKeyStore::new(spk),
+            opks,
+            store,
+            account,
+            directory,
+        )
+        .unwrap()
+    }
+
+    // Single-device account
+    fn manager(path: &str, username: &str, directory: Arc<MockDirectory>) -> SessionManager {
+        let keys = generate_identity_bundle().unwrap();
+        let devices = DeviceList::new(keys.0.public_identity(username).unwrap(), "phone", &keys.0.signing_key().unwrap());
+        device(path, PRIMARY_DEVICE, &devices, keys, directory)
+    }
+
+    // First contact fetches the bundle, the responder accepts it, and replies clear the X3DH header
+    #[tokio::test]
+    async fn test_session_established_on_first_contact() {
+        let directory = Arc::new(MockDirectory::default());
+        let mut alice = manager("test_data/session_alice", "@alice", directory.clone());
+        let mut bob = manager("test_data/session_bob", "@bob", directory.clone());
+
+        let first = alice.encrypt("@bob", b"hi bob").await.unwrap().remove(0);
+        assert!(first.prekey.is_some(), "First message carries the X3DH header");
+        assert_eq!(bob.decrypt("@alice", &first).await.unwrap(), b"hi bob");
+        assert!(bob.has_session("@alice"));
+
+        let reply = bob.encrypt("@alice", b"hi alice").await.unwrap().remove(0);
+        assert!(reply.prekey.is_none(), "Responder never sends an X3DH header");
+        assert_eq!(alice.decrypt("@bob", &reply).await.unwrap(), b"hi alice");
+
+        let next = alice.encrypt("@bob", b"how are you?").await.unwrap().remove(0);
+        assert!(next.prekey.is_none(), "Header dropped once the peer replied");
+        assert_eq!(bob.decrypt("@alice", &next).await.unwrap(), b"how are you?");
+
+        fs::remove_dir_all("test_data/session_alice").unwrap();
+        fs::remove_dir_all("test_data/session_bob").unwrap();
+    }
+
+    // A peer that lost its session but kept its identity starts over with a new X3DH
+    // exchange, which replaces the old session once it decrypts
+    #[tokio::test]
+    async fn test_session_restarted_by_peer() {
+        let directory = Arc::new(MockDirectory::default());
+        let mut alice = manager("test_data/restart_alice", "@alice", directory.clone());
+        let mut bob = manager("test_data/restart_bob", "@bob", directory.clone());
+
+        let first = alice.encrypt("@bob", b"hi bob").await.unwrap().remove(0);
+        bob.decrypt("@alice", &first).await.unwrap();
+        let reply = bob.encrypt("@alice", b"hi alice").await.unwrap().remove(0);
+        alice.decrypt("@bob", &reply).await.unwrap();
+
+        // Alice restores a backup without her sessions
+        alice.remove_session("@bob").unwrap();
+        let restart = alice.encrypt("@bob", b"starting over").await.unwrap().remove(0);
+        assert_ne!(restart.prekey.as_ref().unwrap().ephemeral_pub, first.prekey.as_ref().unwrap().ephemeral_pub);
+        assert_eq!(bob.decrypt("@alice", &restart).await.unwrap(), b"starting over");
+        let reply = bob.encrypt("@alice", b"welcome back").await.unwrap().remove(0);
+        assert_eq!(alice.decrypt("@bob", &reply).await.unwrap(), b"welcome back");
+
+        // A replayed header of the old exchange does not replace the new session
+        assert!(bob.decrypt("@alice", &first).await.is_err());
+        let next = alice.encrypt("@bob", b"still here").await.unwrap().remove(0);
+        assert_eq!(bob.decrypt("@alice", &next).await.unwrap(), b"still here");
+
+        fs::remove_dir_all("test_data/restart_alice").unwrap();
+        fs::remove_dir_all("test_data/restart_bob").unwrap();
+    }
+
+    // Each recipient gets its own session: a message for Bob cannot be read by Carol
+    #[tokio::test]
+    async fn test_sessions_are_per_peer() {
+        let directory = Arc::new(MockDirectory::default());
+        let mut alice = manager("test_data/peer_alice", "@alice", directory.clone());
+        let mut bob = manager("test_data/peer_bob", "@bob", directory.clone());
+        let mut carol = manager("test_data/peer_carol", "@carol", directory.clone());
+
+        let to_bob = alice.encrypt("@bob", b"for bob only").await.unwrap().remove(0);
+        let to_carol = alice.encrypt("@carol", b"for carol only").await.unwrap().remove(0);
+        assert_eq!(alice.session_count(), 2);
+
+        assert!(carol.decrypt("@alice", &to_bob).await.is_err());
+        assert_eq!(carol.decrypt("@alice", &to_carol).await.unwrap(), b"for carol only");
+        assert_eq!(bob.decrypt("@alice", &to_bob).await.unwrap(), b"for bob only");
+
+        for path in ["test_data/peer_alice", "test_data/peer_bob", "test_data/peer_carol"] {
+            fs::remove_dir_all(path).unwrap();
+        }
+    }
+
+    // A first message whose identity is not signed for the claimed sender is refused
+    #[tokio::test]
+    async fn test_first_message_requires_signed_identity() {
+        let directory = Arc::new(MockDirectory::default());
+        let mut alice = manager("test_data/identity_alice", "@alice", directory.clone());
+        let mut bob = manager("test_data/identity_bob", "@bob", directory.clone());
+
+        let first = alice.encrypt("@bob", b"hi bob").await.unwrap().remove(0);
+
+        // Alice's genuine device list does not vouch for the name "@mallory"
+        assert!(bob.decrypt("@mallory", &first).await.is_err());
+        let mut unsigned = first.clone();
+        unsigned.sender_devices = None;
+        assert!(bob.decrypt("@alice", &unsigned).await.is_err());
+        let mut forged = first.clone();
+        forged.sender_devices.as_mut().unwrap().identity.signature[0] ^= 1;
+        assert!(bob.decrypt("@alice", &forged).await.is_err());
+        assert!(!bob.has_session("@alice"));
+
+        assert_eq!(bob.decrypt("@alice", &first).await.unwrap(), b"hi bob");
+        assert_eq!(bob.peer_public_identity("@alice").unwrap(), *alice.local_identity());
+
+        // Device lists whose signature is not valid are not used
+        directory.devices.lock().unwrap().get_mut("@bob").unwrap().signature[0] ^= 1;
+        assert!(alice.refresh_devices("@bob").await.is_err());
+        let mut carol = manager("test_data/identity_carol", "@carol", directory.clone());
+        assert!(carol.encrypt("@bob", b"hi").await.is_err());
+
+        for path in ["test_data/identity_alice", "test_data/identity_bob", "test_data/identity_carol"] {
+            fs::remove_dir_all(path).unwrap();
+        }
+    }
+
+    // A device list signed by another account key than the pinned one (or, on first contact,
+    // the one registered in the directory) is refused until the user approves that key
+    #[tokio::test]
+    async fn test_account_key_is_pinned() {
+        let directory = Arc::new(MockDirectory::default());
+        let mut alice = manager("test_data/pin_alice", "@alice", directory.clone());
+        let mut bob = manager("test_data/pin_bob", "@bob", directory.clone());
+        let mut carol = manager("test_data/pin_carol", "@carol", directory.clone());
+        let first = alice.encrypt("@bob", b"hi bob").await.unwrap().remove(0);
+        bob.decrypt("@alice", &first).await.unwrap();
+
+        // Mallory signs her own list under Alice's name, through a directory she controls
+        let evil = Arc::new(MockDirectory::default());
+        for peer in ["@bob", "@carol"] {
+            let address = device_address(peer, PRIMARY_DEVICE);
+            let bundle = directory.bundles.lock().unwrap()[&address].clone();
+            evil.bundles.lock().unwrap().insert(address, bundle);
+            let devices = directory.devices.lock().unwrap()[peer].clone();
+            evil.devices.lock().unwrap().insert(peer.to_owned(), devices);
+            let identity = directory.identities.lock().unwrap()[peer].clone();
+            evil.identities.lock().unwrap().insert(peer.to_owned(), identity);
+        }
+        let mut mallory = manager("test_data/pin_mallory", "@alice", evil);
+        let forged = mallory.encrypt("@bob", b"it's me").await.unwrap().remove(0);
+        let err = bob.decrypt("@alice", &forged).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<DeviceError>(), Some(DeviceError::AccountKeyMismatch(_))));
+        let to_carol = mallory.encrypt("@carol", b"it's me").await.unwrap().remove(0);
+        let err = carol.decrypt("@alice", &to_carol).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<DeviceError>(), Some(DeviceError::AccountKeyMismatch(_))));
+
+        // Alice's real session is untouched
+        let next = alice.encrypt("@bob", b"still me").await.unwrap().remove(0);
+        assert_eq!(bob.decrypt("@alice", &next).await.unwrap(), b"still me");
+
+        // Once the user approves the new key, lists signed by it are accepted
+        let key = mallory.local_identity().signing_public_key.clone();
+        bob.approve_account_key("@alice", &key).unwrap();
+        assert_eq!(bob.decrypt("@alice", &forged).await.unwrap(), b"it's me");
+
+        for path in ["test_data/pin_alice", "test_data/pin_bob", "test_data/pin_carol", "test_data/pin_mallory"] {
+            fs::remove_dir_all(path).unwrap();
+        }
+    }
+
+    // Messages started on the previous signed prekey still decrypt during the grace period only
+    #[tokio::test]
+    async fn test_signed_prekey_rotation_grace_period() {
+        let directory = Arc::new(MockDirectory::default());
+        let mut alice = manager("test_data/rotation_alice", "@alice", directory.clone());
+        let mut bob = manager("test_data/rotation_bob", "@bob", directory.clone());
+        let mut carol = manager("test_data/rotation_carol", "@carol", directory.clone());
+        let mut dave = manager("test_data/rotation_dave", "@dave", directory.clone());
+        let now = chrono::Utc::now().timestamp();
+        let day = 24 * 3600;
+
+        // Alice and Dave fetch Bob's bundle before he rotates
+        let from_alice = alice.encrypt("@bob", b"sent before rotation").await.unwrap().remove(0);
+        let from_dave = dave.encrypt("@bob", b"delivered too late").await.unwrap().remove(0);
+        assert_eq!(from_alice.prekey.as_ref().unwrap().spk_id, 0);
+
+        assert!(!bob.rotate_signed_prekey_if_due(now).unwrap());
+        assert!(bob.rotate_signed_prekey_if_due(now + 8 * day).unwrap());
+        bob.publish_prekeys("@bob", 1).await.unwrap();
+        assert_eq!(bob.current_spk_id(), 1);
+        assert!(!bob.rotate_signed_prekey_if_due(now + 8 * day).unwrap(), "Published: nothing pending");
+
+        // In-flight initial message on the old key, and a new one on the fresh key
+        assert_eq!(bob.decrypt("@alice", &from_alice).await.unwrap(), b"sent before rotation");
+        let from_carol = carol.encrypt("@bob", b"sent after rotation").await.unwrap().remove(0);
+        assert_eq!(from_carol.prekey.as_ref().unwrap().spk_id, 1);
+        assert_eq!(bob.decrypt("@carol", &from_carol).await.unwrap(), b"sent after rotation");
+
+        // Once the grace period is over the old private key is gone
+        bob.rotate_signed_prekey_if_due(now + 23 * day).unwrap();
+        assert!(bob.decrypt("@dave", &from_dave).await.is_err());
+
+        for path in ["test_data/rotation_alice", "test_data/rotation_bob", "test_data/rotation_carol", "test_data/rotation_dave"] {
+            fs::remove_dir_all(path).unwrap();
+        }
+    }
+
+    // Messages fan out to every device of the account; revoked devices are dropped
+    #[tokio::test]
+    async fn test_multi_device_fan_out_and_revocation() {
+        let directory = Arc::new(MockDirectory::default());
+        let mut alice = manager("test_data/devices_alice", "@alice", directory.clone());
+
+        let phone_keys = generate_identity_bundle().unwrap();
+        let tablet_keys = generate_identity_bundle().unwrap();
+        let account_key = phone_keys.0.signing_key().unwrap();
+        let mut devices = DeviceList::new(phone_keys.0.public_identity("@bob").unwrap(), "phone", &account_key);
+        let tablet_id = devices
+            .add(
+                "tablet",
+                tablet_keys.0.keypair.public.as_bytes().to_vec(),
+                tablet_keys.0.dh_public.as_bytes().to_vec(),
+                &account_key,
+            )
+            .id;
+        let mut phone = device("test_data/devices_phone", PRIMARY_DEVICE, &devices, phone_keys, directory.clone());
+        let mut tablet = device("test_data/devices_tablet", tablet_id, &devices, tablet_keys, directory.clone());
+
+        let envelopes = alice.encrypt("@bob", b"to both devices").await.unwrap();
+        assert_eq!(envelopes.len(), 2);
+        let (for_phone, for_tablet) = if envelopes[0].receiver_device == PRIMARY_DEVICE {
+            (&envelopes[0], &envelopes[1])
+        } else {
+            (&envelopes[1], &envelopes[0])
+        };
+        assert!(phone.decrypt("@alice", for_tablet).await.is_err(), "Each copy is for one device");
+        assert_eq!(phone.decrypt("@alice", for_phone).await.unwrap(), b"to both devices");
+        assert_eq!(tablet.decrypt("@alice", for_tablet).await.unwrap(), b"to both devices");
+
+        // The tablet answers on the session Alice opened with it
+        let reply = tablet.encrypt("@alice", b"from the tablet").await.unwrap().remove(0);
+        assert_eq!(alice.decrypt("@bob", &reply).await.unwrap(), b"from the tablet");
+        assert!(alice.has_device_session("@bob", tablet_id));
+
+        // Bob revokes the tablet: Alice drops its session once she sees the new list
+        devices.revoke(tablet_id, &account_key).unwrap();
+        phone.update_local_devices(devices.clone()).unwrap();
+        phone.publish_devices().await.unwrap();
+        alice.refresh_devices("@bob").await.unwrap();
+        assert!(!alice.has_device_session("@bob", tablet_id));
+        let envelopes = alice.encrypt("@bob", b"phone only").await.unwrap();
+        assert_eq!(envelopes.len(), 1);
+        assert_eq!(envelopes[0].receiver_device, PRIMARY_DEVICE);
+
+        // The tablet cannot come back with the list it still holds
+        tablet.remove_session("@alice").unwrap();
+        let retry = tablet.encrypt("@alice", b"still here").await.unwrap().remove(0);
+        assert!(alice.decrypt("@bob", &retry).await.is_err());
+
+        for path in ["test_data/devices_alice", "test_data/devices_phone", "test_data/devices_tablet"] {
+            fs::remove_dir_all(path).unwrap();
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use ring::rand::{SecureRandom, SystemRandom};
+use ring::rand::SystemRandom;
 use ring::signature::{
     Ed25519KeyPair, KeyPair, Signature, UnparsedPublicKey, ED25519,
 };
@@ -20,6 +20,14 @@ impl SigningKey {
         })
     }
 
+    /// Rebuilds a key pair from its 32-byte seed and public key.
+    pub fn from_seed(seed: &[u8], public_key: &[u8]) -> Result<Self, ring::error::KeyRejected> {
+        let key_pair = Ed25519KeyPair::from_seed_and_public_key(seed, public_key)?;
+        Ok(Self {
+            key_pair: Arc::new(key_pair),
+        })
+    }
+
     /// Signs a message and returns the signature.
     pub fn sign(&self, message: &[u8]) -> Signature {
         self.key_pair.sign(message)
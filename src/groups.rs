@@ -0,0 +1,1065 @@
+use crate::app::{AppEvent, EnigmaApp};
+use crate::crypto::mls::{
+    AuthenticatedProposal, Change, Commit, CommitOutcome, GroupWelcome, KeyPackage, LeafIndex, MlsGroup, MlsMessage,
+    Proposal,
+};
+use crate::crypto::sender_key::{ReceivedSenderKey, SenderKey, SenderKeyDistribution, SenderKeyMessage};
+use crate::crypto::session::device_address;
+use crate::storage::groups::{OwnKeyPackage, OwnSenderKey};
+use crate::storage::messages::StoredMessage;
+use crate::models::message::{Message, MessageError, MessageType};
+use crate::models::conversation::Conversation;
+use crate::models::group::{ChannelBacklog, ChannelPost, Group, GroupAction, GroupError, GroupOperation, GroupProtocol, GroupRole};
+use crate::models::device::DeviceId;
+use crate::models::invite::{GroupInvite, InviteError, InviteReply};
+
+use anyhow::{Result, anyhow};
+use ed25519_dalek::PublicKey as EdPublicKey;
+use ring::aead::NONCE_LEN;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Most posts a new channel subscriber receives from the backlog
+pub const MAX_CHANNEL_BACKLOG: usize = 100;
+
+/// Groups, channels, MLS groups and invites of the client. It keeps them in the group store
+/// and reaches the members through the pairwise sessions and transport of the app.
+pub struct GroupService<'a> {
+    app: &'a EnigmaApp,
+}
+
+impl<'a> GroupService<'a> {
+    /// Creates the group service of `app`
+    pub fn new(app: &'a EnigmaApp) -> Self {
+        Self { app }
+    }
+
+    /// Generates key packages for this device, keeping their private keys until a Welcome
+    /// consumes them
+    pub fn new_key_packages(&self, count: usize) -> Result<Vec<KeyPackage>> {
+        let mut packages = Vec::with_capacity(count);
+        let user = &self.app.user;
+        for _ in 0..count {
+            let (package, secrets) = KeyPackage::generate(&user.username, user.device_id, &self.app.signing)?;
+            self.app.groups.save_key_package(&OwnKeyPackage { package: package.clone(), secrets })?;
+            packages.push(package);
+        }
+        Ok(packages)
+    }
+
+    /// Groups we belong to
+    pub fn list(&self) -> Result<Vec<Group>> {
+        self.app.groups.list()
+    }
+
+    /// Loads one of our groups
+    pub(crate) fn group(&self, group_id: &Uuid) -> Result<Group> {
+        self.app.groups.get(group_id)?.ok_or_else(|| anyhow!("Unknown group {}", group_id))
+    }
+
+    /// Creates a group with `members` and sends them our sender key
+    pub async fn create_group(&self, name: &str, members: &[&str]) -> Result<Group> {
+        self.create_sender_key_group(name, members, false).await
+    }
+
+    /// Creates a channel: only its owners and admins post, and each subscriber only sees
+    /// them. Subscribers receive the sender key of every owner and admin.
+    pub async fn create_channel(&self, name: &str, subscribers: &[&str]) -> Result<Group> {
+        self.create_sender_key_group(name, subscribers, true).await
+    }
+
+    async fn create_sender_key_group(&self, name: &str, members: &[&str], is_channel: bool) -> Result<Group> {
+        let mut group = Group::new(name, &self.app.user.username, is_channel);
+        for member in members {
+            group.add_member(member, GroupRole::Member);
+        }
+        self.start_group(&group)?;
+        self.share_sender_key(&group, &Self::key_holders(&group)).await?;
+        Ok(group)
+    }
+
+    /// Creates an MLS group: one commit adds every device of `members` that published a
+    /// key package, and each of them receives the Welcome
+    pub async fn create_mls_group(&self, name: &str, members: &[&str]) -> Result<Group> {
+        let mut group = Group::new(name, &self.app.user.username, false);
+        group.protocol = GroupProtocol::Mls;
+        let (package, secrets) = KeyPackage::generate(&self.app.user.username, self.app.user.device_id, &self.app.signing)?;
+        let mut state = MlsGroup::create(group.id, &package, &secrets)?;
+        self.app.groups.save_mls_state(&group.id, &state)?;
+        self.start_group(&group)?;
+        if !members.is_empty() {
+            let members: Vec<String> = members.iter().map(|m| m.to_string()).collect();
+            self.mls_add(&mut group, &mut state, &members).await?;
+        }
+        Ok(group)
+    }
+
+    /// Adds a member (owners and admins only): an invite operation after which every member
+    /// sends its sender key to the newcomer, or a commit of its key packages in an MLS group
+    pub async fn add_group_member(&self, group_id: &Uuid, username: &str) -> Result<Group> {
+        let action = GroupAction::Invite { username: username.to_owned() };
+        let mut group = self.group(group_id)?;
+        if group.protocol != GroupProtocol::Mls {
+            return self.administer(group_id, action).await;
+        }
+        group.authorize(&self.app.user.username, &action)?;
+        let mut state = self.mls_state(&group)?;
+        self.mls_add(&mut group, &mut state, &[username.to_owned()]).await?;
+        Ok(group)
+    }
+
+    /// Adds a subscriber to a channel (owners and admins only). Besides the sender keys of the
+    /// owners and admins, it receives from us up to `backlog` of the latest posts (at most
+    /// MAX_CHANNEL_BACKLOG, none if zero).
+    pub async fn add_channel_subscriber(&self, channel_id: &Uuid, username: &str, backlog: usize) -> Result<Group> {
+        if !self.group(channel_id)?.is_channel {
+            return Err(anyhow!("{} is not a channel", channel_id));
+        }
+        let channel = self.add_group_member(channel_id, username).await?;
+        if backlog > 0 {
+            self.send_channel_backlog(&channel, username, backlog.min(MAX_CHANNEL_BACKLOG)).await?;
+        }
+        Ok(channel)
+    }
+
+    /// Removes a member ranked below us (owners and admins only): every member rotates its
+    /// sender key, or the removal is committed in an MLS group, so the removed member
+    /// cannot read what follows
+    pub async fn remove_group_member(&self, group_id: &Uuid, username: &str) -> Result<Group> {
+        if username == self.app.user.username {
+            return Err(anyhow!("Use leave_group to leave a group"));
+        }
+        let action = GroupAction::Remove { username: username.to_owned() };
+        let mut group = self.group(group_id)?;
+        if group.protocol != GroupProtocol::Mls {
+            return self.administer(group_id, action).await;
+        }
+        group.authorize(&self.app.user.username, &action)?;
+        let mut state = self.mls_state(&group)?;
+        let proposals: Vec<Proposal> = state.leaves_of(username).into_iter().map(Proposal::Remove).collect();
+        if proposals.is_empty() {
+            return Err(anyhow!("{} is not a member", username));
+        }
+        self.mls_change(&mut group, &mut state, proposals).await?;
+        Ok(group)
+    }
+
+    /// Leaves a group or unsubscribes from a channel (the owner transfers the ownership
+    /// first): the other members drop our sender key and rotate theirs. In an MLS group, we
+    /// propose our removal and an owner or admin commits it.
+    pub async fn leave_group(&self, group_id: &Uuid) -> Result<()> {
+        let action = GroupAction::Remove { username: self.app.user.username.clone() };
+        let group = self.group(group_id)?;
+        if group.protocol != GroupProtocol::Mls {
+            self.administer(group_id, action).await?;
+            return Ok(());
+        }
+        group.authorize(&self.app.user.username, &action)?;
+        let state = self.mls_state(&group)?;
+        let proposal = state.propose(Proposal::Remove(state.own_leaf()), &self.app.signing)?;
+        self.broadcast(&group, MessageType::MlsProposal, Vec::new(), bincode::serialize(&proposal)?).await?;
+        self.app.groups.delete(group_id)?;
+        let _ = self.app.events.send(AppEvent::GroupRemoved { group_id: *group_id });
+        Ok(())
+    }
+
+    /// Moves a member one role up: read-only to member, or member to admin (owner only)
+    pub async fn promote_group_member(&self, group_id: &Uuid, username: &str) -> Result<Group> {
+        self.administer(group_id, GroupAction::Promote { username: username.to_owned() }).await
+    }
+
+    /// Moves a member one role down: admin to member (owner only), or member to read-only
+    pub async fn demote_group_member(&self, group_id: &Uuid, username: &str) -> Result<Group> {
+        self.administer(group_id, GroupAction::Demote { username: username.to_owned() }).await
+    }
+
+    /// Renames a group (owners and admins only)
+    pub async fn rename_group(&self, group_id: &Uuid, name: &str) -> Result<Group> {
+        self.administer(group_id, GroupAction::Rename { name: name.to_owned() }).await
+    }
+
+    /// Makes another member the owner of a group (owner only); we become an admin
+    pub async fn transfer_group_ownership(&self, group_id: &Uuid, username: &str) -> Result<Group> {
+        self.administer(group_id, GroupAction::TransferOwnership { username: username.to_owned() }).await
+    }
+
+    /// Dissolves a group (owner only): every member deletes it
+    pub async fn dissolve_group(&self, group_id: &Uuid) -> Result<()> {
+        self.administer(group_id, GroupAction::Dissolve).await?;
+        Ok(())
+    }
+
+    /// Signs an invite to a group (owners and admins only), valid for `valid_for`. An invite
+    /// naming `invitee` is sent to it; one naming nobody can be used by whoever holds it,
+    /// shared with `to_link` or `to_qr_svg`, until it is revoked. A new channel subscriber
+    /// also receives up to `backlog` of the latest posts.
+    pub async fn create_group_invite(
+        &self,
+        group_id: &Uuid,
+        invitee: Option<&str>,
+        valid_for: Duration,
+        backlog: usize,
+    ) -> Result<GroupInvite> {
+        let group = self.group(group_id)?;
+        match invitee {
+            Some(invitee) => group.authorize(&self.app.user.username, &GroupAction::Invite { username: invitee.to_owned() })?,
+            None if !matches!(group.role_of(&self.app.user.username), Some(GroupRole::Owner | GroupRole::Admin)) => {
+                return Err(anyhow!("Only owners and admins can invite to {}", group.name));
+            }
+            None => {}
+        }
+        let invite = GroupInvite::new(
+            &group,
+            &self.app.user.username,
+            self.app.user.device_id,
+            invitee,
+            chrono::Duration::from_std(valid_for)?,
+            backlog.min(MAX_CHANNEL_BACKLOG) as u32,
+            &self.app.signing,
+        )?;
+        if let Some(invitee) = invitee {
+            let payload = bincode::serialize(&invite)?;
+            let msg = self.app.send_direct(invitee, MessageType::GroupInvite, &payload).await?;
+            self.app.record_message(invitee, StoredMessage { message: msg, plaintext: payload, outgoing: true })?;
+        }
+        Ok(invite)
+    }
+
+    /// Invites we received and did not answer yet; expired ones are dropped
+    pub fn pending_group_invites(&self) -> Result<Vec<GroupInvite>> {
+        let now = chrono::Utc::now();
+        let mut pending = Vec::new();
+        for invite in self.app.groups.invites()? {
+            if invite.is_expired(now) {
+                self.app.groups.delete_invite(&invite.token)?;
+            } else {
+                pending.push(invite);
+            }
+        }
+        Ok(pending)
+    }
+
+    /// Accepts an invite, received or read from a link or QR code. The acceptance is sent to
+    /// the inviter: the device that signed the invite adds us once online, and the group
+    /// state and keys follow.
+    pub async fn accept_group_invite(&self, invite: &GroupInvite) -> Result<()> {
+        invite.check(&self.app.user.username, chrono::Utc::now())?;
+        if self.app.groups.get(&invite.group_id)?.is_some() {
+            return Err(anyhow!("Already a member of {}", invite.group_name));
+        }
+        let payload = bincode::serialize(&InviteReply::Accept(invite.clone()))?;
+        self.app.send_direct(&invite.inviter, MessageType::GroupInviteReply, &payload).await?;
+        self.app.groups.delete_invite(&invite.token)
+    }
+
+    /// Declines an invite we received; the inviter is told
+    pub async fn decline_group_invite(&self, invite: &GroupInvite) -> Result<()> {
+        let payload = bincode::serialize(&InviteReply::Decline(invite.clone()))?;
+        self.app.send_direct(&invite.inviter, MessageType::GroupInviteReply, &payload).await?;
+        self.app.groups.delete_invite(&invite.token)
+    }
+
+    /// Revokes an invite to a group (owners and admins only): acceptances of it are then
+    /// rejected
+    pub async fn revoke_group_invite(&self, group_id: &Uuid, token: &[u8]) -> Result<Group> {
+        self.administer(group_id, GroupAction::RevokeInvite { token: token.to_vec() }).await
+    }
+
+    /// Replaces our keys in a group for post-compromise security: a new sender key, or in
+    /// an MLS group new keys for our leaf, applied by the next commit
+    pub async fn refresh_group_keys(&self, group_id: &Uuid) -> Result<()> {
+        let mut group = self.group(group_id)?;
+        if group.protocol == GroupProtocol::Mls {
+            let mut state = self.mls_state(&group)?;
+            let leaf = state.tree().leaf(state.own_leaf()).cloned().ok_or_else(|| anyhow!("Our leaf is blank"))?;
+            return self.mls_change(&mut group, &mut state, vec![Proposal::Update(leaf)]).await;
+        }
+        self.rotate_sender_key(&group).await
+    }
+
+    /// Encrypts a message once with our sender key, or the MLS keys of the current epoch,
+    /// and sends it to the group (read-only members cannot post)
+    pub async fn send_group_message(&self, group_id: &Uuid, plaintext: &[u8]) -> Result<Message> {
+        let group = self.group(group_id)?;
+        if !group.can_post(&self.app.user.username) {
+            return Err(anyhow!("{} cannot post in {}", self.app.user.username, group.name));
+        }
+        let padding = self.app.padding_scheme(&MessageType::Text);
+        if group.protocol == GroupProtocol::Mls {
+            let mut state = self.mls_state(&group)?;
+            let encrypted = state.encrypt(plaintext, padding, &self.app.signing)?;
+            self.app.groups.save_mls_state(&group.id, &state)?;
+            let nonce = encrypted.ciphertext[..NONCE_LEN].to_vec();
+            let msg = self.broadcast(&group, MessageType::Text, nonce, bincode::serialize(&encrypted)?).await?;
+            self.app.record_message(
+                &msg.receiver,
+                StoredMessage { message: msg.clone(), plaintext: plaintext.to_vec(), outgoing: true },
+            )?;
+            return Ok(msg);
+        }
+
+        let shared_with = self.own_sender_key(&group)?.shared_with;
+        let mut missing = Vec::new();
+        let mut current = Vec::new();
+        for member in Self::key_holders(&group) {
+            let addresses: Vec<String> = self
+                .key_recipients(&member)
+                .await?
+                .into_iter()
+                .map(|device| device_address(&member, device))
+                .collect();
+            if addresses.iter().any(|address| !shared_with.contains(address)) {
+                missing.push(member);
+            }
+            current.extend(addresses);
+        }
+        // A device dropped from a device list still holds our key: replace it. Devices
+        // that never got our current key receive it first.
+        if shared_with.iter().any(|address| !current.contains(address)) {
+            self.rotate_sender_key(&group).await?;
+        } else if !missing.is_empty() {
+            self.share_sender_key(&group, &missing).await?;
+        }
+
+        let mut own = self.own_sender_key(&group)?;
+        let encrypted = own.key.encrypt(group.id, self.app.user.device_id, plaintext, padding)?;
+        self.app.groups.save_own_key(&group.id, &own)?;
+
+        let nonce = encrypted.ciphertext[..NONCE_LEN].to_vec();
+        let msg = self.broadcast(&group, MessageType::Text, nonce, bincode::serialize(&encrypted)?).await?;
+        self.app.record_message(
+            &msg.receiver,
+            StoredMessage { message: msg.clone(), plaintext: plaintext.to_vec(), outgoing: true },
+        )?;
+        Ok(msg)
+    }
+
+    /// Signs a payload addressed to the group and sends it once to every member
+    pub(crate) async fn broadcast(
+        &self,
+        group: &Group,
+        msg_type: MessageType,
+        nonce: Vec<u8>,
+        payload: Vec<u8>,
+    ) -> Result<Message> {
+        let mut msg = Message {
+            id: Uuid::new_v4(),
+            sender: self.app.user.username.clone(),
+            receiver: group.id.to_string(),
+            timestamp: chrono::Utc::now(),
+            msg_type,
+            nonce,
+            encrypted_payload: payload,
+            signature: None,
+        };
+        msg.sign(&self.app.signing);
+        self.app.webrtc.send_message(&bincode::serialize(&msg)?).await?;
+        Ok(msg)
+    }
+
+    /// Saves a group, adds it to the conversation list and notifies the UI
+    fn save_group(&self, group: &Group) -> Result<()> {
+        self.app.groups.save(group)?;
+        let id = group.id.to_string();
+        if self.app.conversations.get(&id)?.is_none() {
+            self.app.conversations.save(&Conversation::group(group.id))?;
+        }
+        let _ = self.app.events.send(AppEvent::GroupUpdated { group: group.clone() });
+        Ok(())
+    }
+
+    /// Saves a group we create or join, and the state its operations are replayed from
+    fn start_group(&self, group: &Group) -> Result<()> {
+        self.app.groups.save_base(group)?;
+        self.save_group(group)
+    }
+
+    /// Signs an operation on a group, applies it if our role allows it and sends it to the
+    /// other members over pairwise sessions, including a member it removes
+    async fn administer(&self, group_id: &Uuid, action: GroupAction) -> Result<Group> {
+        let group = self.group(group_id)?;
+        let op = GroupOperation::new(&group, &self.app.user.username, self.app.user.device_id, action, &self.app.signing)?;
+        group.clone().apply(&op)?;
+        let updated = self.record_operation(&group, &op).await?;
+        let payload = bincode::serialize(&op)?;
+        for member in Self::operation_recipients(&group, &op) {
+            self.app.send_direct(&member, MessageType::GroupOperation, &payload).await?;
+        }
+        Ok(updated)
+    }
+
+    /// Members an operation is sent to: every other member of a group. Subscribers of a
+    /// channel only receive the operation removing them or dissolving the channel, the
+    /// owners and admins keep their view up to date.
+    fn operation_recipients(group: &Group, op: &GroupOperation) -> Vec<String> {
+        group
+            .others(&op.actor)
+            .into_iter()
+            .filter(|member| {
+                !group.is_channel
+                    || group.can_post(member)
+                    || match &op.action {
+                        GroupAction::Remove { username } => username == member,
+                        GroupAction::Dissolve => true,
+                        _ => false,
+                    }
+            })
+            .collect()
+    }
+
+    /// Checks that an operation comes from the device that signed it, follows the clock of
+    /// the operation it builds on and is not dated before a change of its actor's role we
+    /// already applied, then records it
+    async fn receive_group_operation(&self, sender: &str, sender_device: DeviceId, op: GroupOperation) -> Result<()> {
+        if op.actor != sender || op.actor_device != sender_device {
+            return Err(anyhow!("Operation of {} sent by {}", op.actor, sender));
+        }
+        let group = self.group(&op.group_id)?;
+        let key = self
+            .app
+            .sessions
+            .lock()
+            .await
+            .peer_identity(sender, sender_device)
+            .ok_or_else(|| MessageError::UnknownSender(sender.to_owned()))?;
+        op.verify(key.as_bytes())?;
+        let mut log = self.app.groups.operations(&op.group_id)?;
+        let base = self.app.groups.base_for(&op.group_id)?.unwrap_or_else(|| group.clone());
+        base.check_clock(&op, &log)?;
+        log.push(op.clone());
+        if op.is_backdated(&log) {
+            return Err(GroupError::Backdated(op.actor).into());
+        }
+        self.record_operation(&group, &op).await?;
+        Ok(())
+    }
+
+    /// Adds an operation to the log of its group and replays the group, then follows the
+    /// membership changes: new members receive our sender key, and it is rotated when a
+    /// member is gone. Members who cannot post have no sender key to share.
+    async fn record_operation(&self, previous: &Group, op: &GroupOperation) -> Result<Group> {
+        if previous.protocol == GroupProtocol::Mls
+            && matches!(op.action, GroupAction::Invite { .. } | GroupAction::Remove { .. })
+        {
+            return Err(anyhow!("Members of MLS groups change through commits"));
+        }
+        self.app.groups.save_operation(op)?;
+        let group = self.replay_group(previous)?;
+        if !group.is_member(&self.app.user.username) {
+            self.app.groups.delete(&group.id)?;
+            let _ = self.app.events.send(AppEvent::GroupRemoved { group_id: group.id });
+            return Ok(group);
+        }
+        self.save_group(&group)?;
+        if group.protocol == GroupProtocol::Mls {
+            return Ok(group);
+        }
+
+        let removed: Vec<&str> = previous
+            .members
+            .iter()
+            .filter(|m| !group.is_member(&m.username))
+            .map(|m| m.username.as_str())
+            .collect();
+        for member in &removed {
+            self.app.groups.delete_peer_keys(&group.id, &format!("{}/", member))?;
+        }
+        if !group.can_post(&self.app.user.username) {
+            return Ok(group);
+        }
+        let added: Vec<String> = group
+            .members
+            .iter()
+            .filter(|m| !previous.is_member(&m.username))
+            .map(|m| m.username.clone())
+            .collect();
+        if !removed.is_empty() {
+            self.rotate_sender_key(&group).await?;
+        } else if !added.is_empty() {
+            self.share_sender_key(&group, &added).await?;
+        } else if group.is_channel && op.actor == self.app.user.username {
+            // Subscribers learn the new state of the channel with our key
+            self.share_sender_key(&group, &Self::key_holders(&group)).await?;
+        }
+        Ok(group)
+    }
+
+    /// Sends the latest `count` posts of a channel to a new subscriber
+    async fn send_channel_backlog(&self, channel: &Group, subscriber: &str, count: usize) -> Result<()> {
+        let mut posts: Vec<ChannelPost> = self
+            .app
+            .messages
+            .latest(&channel.id.to_string(), count)?
+            .into_iter()
+            .map(|stored| ChannelPost { message: stored.message, plaintext: stored.plaintext })
+            .collect();
+        posts.reverse();
+        let backlog = ChannelBacklog { channel_id: channel.id, posts };
+        self.app.send_direct(subscriber, MessageType::ChannelBacklog, &bincode::serialize(&backlog)?).await?;
+        Ok(())
+    }
+
+    /// Stores the backlog of a channel sent by one of its owners or admins. Each post keeps
+    /// the signature of the device of its author, who must still be able to post; posts of
+    /// former owners and admins are left out. The signature covers the encrypted post, so
+    /// the sender only vouches for the plaintext.
+    async fn apply_channel_backlog(&self, sender: &str, backlog: ChannelBacklog) -> Result<()> {
+        let channel = self.group(&backlog.channel_id)?;
+        if !channel.is_channel || !channel.can_post(sender) {
+            return Err(anyhow!("{} cannot send the backlog of {}", sender, channel.name));
+        }
+        let conversation_id = channel.id.to_string();
+        for post in backlog.posts.into_iter().take(MAX_CHANNEL_BACKLOG) {
+            if post.message.receiver != conversation_id || post.message.msg_type.is_control() {
+                return Err(anyhow!("Backlog of {} carries a foreign message", channel.name));
+            }
+            if !channel.can_post(&post.message.sender) {
+                continue;
+            }
+            let encrypted: SenderKeyMessage = bincode::deserialize(&post.message.encrypted_payload)?;
+            if encrypted.group_id != channel.id {
+                return Err(anyhow!("Backlog of {} carries a foreign message", channel.name));
+            }
+            let key = self.device_signing_key(&post.message.sender, encrypted.sender_device).await?;
+            post.message.verify(&key)?;
+            self.app.record_message(
+                &conversation_id,
+                StoredMessage { message: post.message, plaintext: post.plaintext, outgoing: false },
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Current state of a group: the state we created or joined it in, followed by its
+    /// operations in their deterministic order. MLS groups take their members from the tree.
+    fn replay_group(&self, group: &Group) -> Result<Group> {
+        let mut base = self.app.groups.base_for(&group.id)?.unwrap_or_else(|| group.clone());
+        if group.protocol == GroupProtocol::Mls {
+            Self::sync_mls_members(&mut base, &self.mls_state(group)?);
+        }
+        Ok(base.replay(&self.app.groups.operations(&group.id)?))
+    }
+
+    /// Our sender key in a group, generated on first use
+    fn own_sender_key(&self, group: &Group) -> Result<OwnSenderKey> {
+        if let Some(own) = self.app.groups.own_key_for(&group.id)? {
+            return Ok(own);
+        }
+        let own = OwnSenderKey { key: SenderKey::generate(0)?, shared_with: Vec::new() };
+        self.app.groups.save_own_key(&group.id, &own)?;
+        Ok(own)
+    }
+
+    /// Members our sender key goes to: all of them, ourselves included for our other devices
+    fn key_holders(group: &Group) -> Vec<String> {
+        group.members.iter().map(|m| m.username.clone()).collect()
+    }
+
+    /// Devices of a member that must hold our sender key: all its listed devices, or our
+    /// other devices
+    async fn key_recipients(&self, member: &str) -> Result<Vec<DeviceId>> {
+        let mut sessions = self.app.sessions.lock().await;
+        let devices = if member == self.app.user.username {
+            sessions.local_devices().clone()
+        } else {
+            if sessions.peer_devices(member).is_none() {
+                sessions.refresh_devices(member).await?;
+            }
+            sessions
+                .peer_devices(member)
+                .cloned()
+                .ok_or_else(|| anyhow!("No devices for {}", member))?
+        };
+        Ok(devices
+            .devices
+            .iter()
+            .map(|d| d.id)
+            .filter(|id| member != self.app.user.username || *id != self.app.user.device_id)
+            .collect())
+    }
+
+    /// Sends the group state, as shown to each of `members`, and our sender key to their
+    /// devices over pairwise sessions, and records the devices that got it
+    async fn share_sender_key(&self, group: &Group, members: &[String]) -> Result<()> {
+        let mut own = self.own_sender_key(group)?;
+        for member in members {
+            let devices = self.key_recipients(member).await?;
+            if devices.is_empty() {
+                continue;
+            }
+            let view = group.view_for(member);
+            let operations = self.role_operations(group, member)?;
+            let distribution = own.key.distribution(&view, operations, self.app.user.device_id)?;
+            let payload = bincode::serialize(&distribution)?;
+            match self.app.send_direct(member, MessageType::SenderKeyDistribution, &payload).await {
+                // Our other devices are not required to post: they get it once reachable
+                Err(_) if *member == self.app.user.username => continue,
+                result => result?,
+            };
+
+            // Devices whose bundle could not be fetched have no session and get it next time
+            let sessions = self.app.sessions.lock().await;
+            for device in devices.into_iter().filter(|id| sessions.has_device_session(member, *id)) {
+                let address = device_address(member, device);
+                if !own.shared_with.contains(&address) {
+                    own.shared_with.push(address);
+                }
+            }
+        }
+        self.app.groups.save_own_key(&group.id, &own)
+    }
+
+    /// Signed operations giving the owners and admins of a channel, or `member`, their roles,
+    /// which `member` checks before taking a view raising them
+    fn role_operations(&self, group: &Group, member: &str) -> Result<Vec<GroupOperation>> {
+        if !group.is_channel {
+            return Ok(Vec::new());
+        }
+        let operations = self.app.groups.operations(&group.id)?;
+        Ok(operations
+            .into_iter()
+            .filter(|op| match &op.action {
+                GroupAction::Promote { username } | GroupAction::TransferOwnership { username } => {
+                    username == member || group.can_post(username)
+                }
+                _ => false,
+            })
+            .collect())
+    }
+
+    /// Replaces our sender key and sends the new one to the current members
+    async fn rotate_sender_key(&self, group: &Group) -> Result<()> {
+        let key_id = self.app.groups.own_key_for(&group.id)?.map_or(0, |own| own.key.key_id + 1);
+        let own = OwnSenderKey { key: SenderKey::generate(key_id)?, shared_with: Vec::new() };
+        self.app.groups.save_own_key(&group.id, &own)?;
+        self.share_sender_key(group, &Self::key_holders(group)).await
+    }
+
+    /// Protocol messages received over a pairwise session
+    pub async fn process_control(&self, msg: &Message, sender_device: DeviceId, plaintext: &[u8]) -> Result<()> {
+        match msg.msg_type {
+            MessageType::SenderKeyDistribution => {
+                self.apply_sender_key(&msg.sender, sender_device, bincode::deserialize(plaintext)?).await
+            }
+            MessageType::MlsWelcome => self.join_mls_group(&msg.sender, bincode::deserialize(plaintext)?),
+            MessageType::GroupOperation => {
+                self.receive_group_operation(&msg.sender, sender_device, bincode::deserialize(plaintext)?).await
+            }
+            MessageType::ChannelBacklog => {
+                self.apply_channel_backlog(&msg.sender, bincode::deserialize(plaintext)?).await
+            }
+            MessageType::GroupInviteReply => {
+                self.receive_invite_reply(&msg.sender, bincode::deserialize(plaintext)?).await
+            }
+            _ => Err(anyhow!("Unexpected control message {:?}", msg.msg_type)),
+        }
+    }
+
+    /// Stores a member's sender key. The group state it carries is only taken for a group we
+    /// do not know yet, or as the newer view of a channel from one of its owners and admins,
+    /// any role it raises coming with the signed operation giving it: members then change
+    /// through group operations.
+    async fn apply_sender_key(&self, sender: &str, sender_device: DeviceId, distribution: SenderKeyDistribution) -> Result<()> {
+        let me = self.app.user.username.as_str();
+        let proposed = &distribution.group;
+        let group = match self.app.groups.get(&proposed.id)? {
+            Some(current)
+                if current.is_channel
+                    && proposed.is_channel
+                    && current.can_post(sender)
+                    && proposed.is_member(me)
+                    && proposed.version >= current.version =>
+            {
+                self.check_raised_roles(&current, proposed, &distribution.operations).await?;
+                for member in current.members.iter().filter(|m| !proposed.is_member(&m.username)) {
+                    self.app.groups.delete_peer_keys(&current.id, &format!("{}/", member.username))?;
+                }
+                self.start_group(proposed)?;
+                proposed.clone()
+            }
+            Some(current) => current,
+            // New group: we learn it from a member who lists us
+            None if proposed.is_member(sender) && proposed.is_member(me) => {
+                self.start_group(proposed)?;
+                proposed.clone()
+            }
+            None => return Err(anyhow!("Group {} does not list both {} and us", proposed.id, sender)),
+        };
+        if !group.is_member(sender) {
+            return Err(anyhow!("{} is not a member of {}", sender, group.name));
+        }
+
+        let address = device_address(sender, sender_device);
+        let newer = self
+            .app
+            .groups
+            .peer_key_for(&group.id, &address)?
+            .is_none_or(|known| distribution.key_id > known.key_id);
+        if newer {
+            self.app.groups.save_peer_key(&group.id, &address, &ReceivedSenderKey::from(&distribution))?;
+        }
+
+        if group.can_post(me) && !self.own_sender_key(&group)?.shared_with.contains(&address) {
+            self.share_sender_key(&group, &[sender.to_owned()]).await
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Checks that each role a channel view raises above our state comes with a signed
+    /// operation giving it
+    async fn check_raised_roles(&self, current: &Group, proposed: &Group, operations: &[GroupOperation]) -> Result<()> {
+        for member in current.raised_roles(proposed) {
+            let op = operations
+                .iter()
+                .find(|op| current.grants(op, member))
+                .ok_or_else(|| anyhow!("View of {} raises the role of {} without an operation", current.name, member.username))?;
+            let key = self.device_signing_key(&op.actor, op.actor_device).await?;
+            op.verify(&key)?;
+        }
+        Ok(())
+    }
+
+    /// Group path of the inbound pipeline: the sender key of the member device decrypts
+    pub async fn process_group_message(&self, group_id: Uuid, msg: Message) -> Result<(Message, Vec<u8>)> {
+        let group = self.group(&group_id)?;
+        if !group.is_member(&msg.sender) {
+            return Err(anyhow!("{} is not a member of {}", msg.sender, group.name));
+        }
+        if !msg.msg_type.is_control() && !group.can_post(&msg.sender) {
+            return Err(anyhow!("{} cannot post in {}", msg.sender, group.name));
+        }
+        if group.protocol == GroupProtocol::Mls {
+            return self.process_mls_message(group, msg).await;
+        }
+        let encrypted: SenderKeyMessage = bincode::deserialize(&msg.encrypted_payload)?;
+        if encrypted.group_id != group_id {
+            return Err(anyhow!("Group message for {} sent to {}", encrypted.group_id, group_id));
+        }
+        // Our other devices post with their own sender keys; this device never receives its own
+        if msg.sender == self.app.user.username && encrypted.sender_device == self.app.user.device_id {
+            return Err(anyhow!("Group message sent by this device"));
+        }
+
+        let sender_key = self.app.sessions.lock().await.peer_identity(&msg.sender, encrypted.sender_device);
+        self.app.verify_sender(&msg, sender_key)?;
+
+        let address = device_address(&msg.sender, encrypted.sender_device);
+        let mut key = self
+            .app
+            .groups
+            .peer_key_for(&group_id, &address)?
+            .ok_or_else(|| anyhow!("No sender key from {} in {} yet", address, group.name))?;
+        let plaintext = key.decrypt(&encrypted)?;
+        self.app.groups.save_peer_key(&group_id, &address, &key)?;
+
+        self.app.record_message(
+            &msg.receiver,
+            StoredMessage { message: msg.clone(), plaintext: plaintext.clone(), outgoing: false },
+        )?;
+        Ok((msg, plaintext))
+    }
+
+    /// Our MLS state in a group
+    pub(crate) fn mls_state(&self, group: &Group) -> Result<MlsGroup> {
+        self.app.groups
+            .mls_state_for(&group.id)?
+            .ok_or_else(|| anyhow!("No MLS state for {}", group.name))
+    }
+
+    /// Fetches a key package for every device of `members` and requests their addition
+    async fn mls_add(&self, group: &mut Group, state: &mut MlsGroup, members: &[String]) -> Result<()> {
+        let mut proposals = Vec::new();
+        for member in members {
+            let packages = self.app.sessions.lock().await.fetch_key_packages(member).await?;
+            if packages.is_empty() {
+                return Err(anyhow!("{} has no key package left", member));
+            }
+            proposals.extend(packages.into_iter().map(Proposal::Add));
+        }
+        self.mls_change(group, state, proposals).await
+    }
+
+    /// Makes changes to an MLS group: committed right away when this device is the
+    /// committer, else proposed to it. An Update gets fresh keys for our leaf.
+    async fn mls_change(&self, group: &mut Group, state: &mut MlsGroup, proposals: Vec<Proposal>) -> Result<()> {
+        if Self::mls_committer(group, state) == Some(state.own_leaf()) {
+            // Our commit refreshes our own keys: updates are left out
+            let proposals = proposals
+                .into_iter()
+                .filter(|p| !matches!(p, Proposal::Update(_)))
+                .map(|p| state.propose(p, &self.app.signing))
+                .collect::<Result<Vec<_>>>()?;
+            return self.mls_commit(group, state, proposals).await;
+        }
+        for proposal in proposals {
+            let proposal = match proposal {
+                Proposal::Update(_) => state.propose_update(&self.app.signing)?,
+                proposal => state.submit(proposal, &self.app.signing)?,
+            };
+            self.app.groups.save_mls_state(&group.id, state)?;
+            self.broadcast(group, MessageType::MlsProposal, Vec::new(), bincode::serialize(&proposal)?).await?;
+        }
+        Ok(())
+    }
+
+    /// Commits `proposals` with the pending ones, sends the commit to the group and the
+    /// Welcome to the added members
+    async fn mls_commit(&self, group: &mut Group, state: &mut MlsGroup, proposals: Vec<AuthenticatedProposal>) -> Result<()> {
+        let mut all = state.pending_proposals().to_vec();
+        all.extend(proposals);
+        let mut added: Vec<String> = all
+            .iter()
+            .filter_map(|p| match &p.proposal {
+                Proposal::Add(package) => Some(package.leaf.username.clone()),
+                Proposal::Update(_) | Proposal::Remove(_) => None,
+            })
+            .collect();
+        added.dedup();
+
+        let (commit, welcome) = state.commit(all, &self.app.signing)?;
+        self.app.groups.save_mls_state(&group.id, state)?;
+        Self::sync_mls_members(group, state);
+        self.save_group(group)?;
+
+        self.broadcast(group, MessageType::MlsCommit, Vec::new(), bincode::serialize(&commit)?).await?;
+        if let Some(welcome) = welcome {
+            let payload = bincode::serialize(&GroupWelcome { group: group.clone(), welcome })?;
+            for member in &added {
+                self.app.send_direct(member, MessageType::MlsWelcome, &payload).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Aligns the member list of an MLS group with its ratchet tree
+    fn sync_mls_members(group: &mut Group, state: &MlsGroup) {
+        let members = state.usernames();
+        group.members.retain(|m| members.contains(&m.username));
+        for member in &members {
+            group.add_member(member, GroupRole::Member);
+        }
+    }
+
+    /// Checks the membership changes of MLS proposals against the roles of their proposers,
+    /// as for the invite and remove operations of other groups
+    fn authorize_changes(group: &Group, changes: &[Change]) -> Result<()> {
+        for change in changes {
+            let (proposer, action) = match change {
+                Change::Add { proposer, member } => (proposer, GroupAction::Invite { username: member.clone() }),
+                Change::Remove { proposer, member } => (proposer, GroupAction::Remove { username: member.clone() }),
+            };
+            group.authorize(proposer, &action)?;
+        }
+        Ok(())
+    }
+
+    /// The only device that commits in an MLS group: the first device of the owner (of the
+    /// first admin without owner). The others send it proposals, so a single commit is made
+    /// for each epoch and concurrent changes cannot fork the group.
+    fn mls_committer(group: &Group, state: &MlsGroup) -> Option<LeafIndex> {
+        let mut ranked: Vec<_> = group
+            .members
+            .iter()
+            .filter(|m| matches!(m.role, GroupRole::Owner | GroupRole::Admin))
+            .collect();
+        ranked.sort_by_key(|m| m.role != GroupRole::Owner);
+        ranked.first().and_then(|m| state.leaves_of(&m.username).into_iter().min())
+    }
+
+    /// Checks that a group message of `epoch` comes from the device at `leaf`, whose key
+    /// signs it
+    fn verify_mls_sender(&self, state: &MlsGroup, msg: &Message, epoch: u64, leaf: LeafIndex) -> Result<()> {
+        if leaf == state.own_leaf() {
+            return Err(anyhow!("Group message sent by this device"));
+        }
+        let node = state
+            .sender_leaf(epoch, leaf)
+            .filter(|l| l.username == msg.sender)
+            .ok_or_else(|| anyhow!("{} is not at leaf {} of the group", msg.sender, leaf))?;
+        Ok(self.app.verify_sender(msg, EdPublicKey::from_bytes(&node.signature_key).ok())?)
+    }
+
+    /// MLS path of the inbound pipeline: commits move to the next epoch, proposals wait for
+    /// a commit, other messages are decrypted with the keys of the current epoch
+    async fn process_mls_message(&self, mut group: Group, msg: Message) -> Result<(Message, Vec<u8>)> {
+        let mut state = self.mls_state(&group)?;
+        match msg.msg_type {
+            MessageType::MlsCommit => {
+                let commit: Commit = bincode::deserialize(&msg.encrypted_payload)?;
+                self.verify_mls_sender(&state, &msg, commit.epoch, commit.committer)?;
+                if Self::mls_committer(&group, &state) != Some(commit.committer) {
+                    return Err(anyhow!("{} is not the committer of {}", msg.sender, group.name));
+                }
+                Self::authorize_changes(&group, &state.changes(&commit.proposals)?)?;
+                match state.process_commit(&commit)? {
+                    CommitOutcome::Removed => {
+                        self.app.groups.delete(&group.id)?;
+                        let _ = self.app.events.send(AppEvent::GroupRemoved { group_id: group.id });
+                    }
+                    CommitOutcome::Applied => {
+                        let awaiting = state.take_awaiting();
+                        self.app.groups.save_mls_state(&group.id, &state)?;
+                        Self::sync_mls_members(&mut group, &state);
+                        self.save_group(&group)?;
+                        // Our proposals the commit left out are made again for the new epoch
+                        if !awaiting.is_empty() {
+                            self.mls_change(&mut group, &mut state, awaiting).await?;
+                        }
+                    }
+                }
+                Ok((msg, Vec::new()))
+            }
+            MessageType::MlsProposal => {
+                let proposal: AuthenticatedProposal = bincode::deserialize(&msg.encrypted_payload)?;
+                self.verify_mls_sender(&state, &msg, proposal.epoch, proposal.proposer)?;
+                Self::authorize_changes(&group, &state.changes(std::slice::from_ref(&proposal))?)?;
+                state.store_proposal(proposal)?;
+                self.app.groups.save_mls_state(&group.id, &state)?;
+                if Self::mls_committer(&group, &state) == Some(state.own_leaf()) {
+                    self.mls_commit(&mut group, &mut state, Vec::new()).await?;
+                }
+                Ok((msg, Vec::new()))
+            }
+            _ => {
+                let encrypted: MlsMessage = bincode::deserialize(&msg.encrypted_payload)?;
+                if encrypted.group_id != group.id {
+                    return Err(anyhow!("Group message for {} sent to {}", encrypted.group_id, group.id));
+                }
+                self.verify_mls_sender(&state, &msg, encrypted.epoch, encrypted.sender)?;
+                let plaintext = state.decrypt(&encrypted)?;
+                self.app.groups.save_mls_state(&group.id, &state)?;
+                self.app.record_message(
+                    &msg.receiver,
+                    StoredMessage { message: msg.clone(), plaintext: plaintext.clone(), outgoing: false },
+                )?;
+                Ok((msg, plaintext))
+            }
+        }
+    }
+
+    /// Joins an MLS group from a Welcome sent by its committer, with the key package it
+    /// was addressed to
+    fn join_mls_group(&self, sender: &str, welcome: GroupWelcome) -> Result<()> {
+        let GroupWelcome { mut group, welcome } = welcome;
+        if group.id != welcome.group_id {
+            return Err(anyhow!("Welcome for {} carries group {}", welcome.group_id, group.id));
+        }
+        if self.app.groups.mls_state_for(&group.id)?.is_some() {
+            return Err(anyhow!("Already a member of {}", group.name));
+        }
+        let own = self
+            .app
+            .groups
+            .key_packages()?
+            .into_iter()
+            .find(|own| welcome.secrets.iter().any(|s| s.init_key == own.package.init_key))
+            .ok_or_else(|| anyhow!("Welcome to {} matches none of our key packages", group.name))?;
+        let state = MlsGroup::join(&welcome, &own.package, &own.secrets)?;
+        if state.member_at(welcome.committer) != Some(sender) {
+            return Err(anyhow!("Welcome to {} not sent by its committer", group.name));
+        }
+        self.app.groups.take_key_package(&own.package.init_key)?;
+
+        group.protocol = GroupProtocol::Mls;
+        Self::sync_mls_members(&mut group, &state);
+        self.app.groups.save_mls_state(&group.id, &state)?;
+        self.start_group(&group)
+    }
+
+    /// Identity key of a device of `username`, from our own device list or the verified
+    /// list of a peer (fetched again if the device is not in it)
+    async fn device_signing_key(&self, username: &str, device: DeviceId) -> Result<Vec<u8>> {
+        let mut sessions = self.app.sessions.lock().await;
+        if username == self.app.user.username {
+            return sessions
+                .local_devices()
+                .device(device)
+                .map(|d| d.signing_public_key.clone())
+                .ok_or_else(|| anyhow!("We have no device {}", device));
+        }
+        if let Some(key) = sessions.peer_identity(username, device) {
+            return Ok(key.as_bytes().to_vec());
+        }
+        if sessions.peer_devices(username).and_then(|list| list.device(device)).is_none() {
+            sessions.refresh_devices(username).await?;
+        }
+        sessions
+            .peer_devices(username)
+            .and_then(|list| list.device(device))
+            .map(|d| d.signing_public_key.clone())
+            .ok_or_else(|| anyhow!("{} has no device {}", username, device))
+    }
+
+    /// Keeps an invite sent to us by its inviter until we answer it
+    pub async fn receive_invite(&self, sender: &str, sender_device: DeviceId, invite: GroupInvite) -> Result<()> {
+        if invite.inviter != sender || invite.inviter_device != sender_device {
+            return Err(anyhow!("Invite of {} sent by {}", invite.inviter, sender));
+        }
+        invite.verify(&self.device_signing_key(sender, sender_device).await?)?;
+        invite.check(&self.app.user.username, chrono::Utc::now())?;
+        self.app.groups.save_invite(&invite)?;
+        let _ = self.app.events.send(AppEvent::GroupInviteReceived { invite });
+        Ok(())
+    }
+
+    /// Answers to invites: acceptances to let in, refusals to report
+    async fn receive_invite_reply(&self, sender: &str, reply: InviteReply) -> Result<()> {
+        match reply {
+            InviteReply::Accept(invite) => self.admit_invitee(sender, invite).await,
+            InviteReply::Decline(invite) => {
+                if invite.inviter == self.app.user.username && invite.invitee.as_deref() == Some(sender) {
+                    let _ = self.app.events.send(AppEvent::GroupInviteDeclined {
+                        group_id: invite.group_id,
+                        username: sender.to_owned(),
+                    });
+                }
+                Ok(())
+            }
+            InviteReply::Rejected { invite, reason } => {
+                if sender != invite.inviter {
+                    return Err(anyhow!("Invite to {} rejected by {}", invite.group_name, sender));
+                }
+                self.app.groups.delete_invite(&invite.token)?;
+                let _ = self.app.events.send(AppEvent::GroupInviteRejected { group_id: invite.group_id, reason });
+                Ok(())
+            }
+        }
+    }
+
+    /// Adds the sender of an acceptance of an invite signed by this device, so that a single
+    /// device lets the joiner in (in an MLS group, it proposes the addition to the
+    /// committer). An invite that is forged, expired, revoked, meant for someone else, or
+    /// whose inviter may no longer invite is rejected, and the sender is told why.
+    async fn admit_invitee(&self, joiner: &str, invite: GroupInvite) -> Result<()> {
+        if invite.inviter != self.app.user.username || invite.inviter_device != self.app.user.device_id {
+            return Ok(());
+        }
+        let group = self.group(&invite.group_id)?;
+        if group.is_member(joiner) {
+            return Ok(());
+        }
+        let checked = self.check_invite(&group, joiner, &invite).await;
+        if let Err(e) = checked {
+            let payload = bincode::serialize(&InviteReply::Rejected { invite, reason: e.to_string() })?;
+            self.app.send_direct(joiner, MessageType::GroupInviteReply, &payload).await?;
+            return Ok(());
+        }
+        if group.is_channel {
+            self.add_channel_subscriber(&group.id, joiner, invite.backlog as usize).await?;
+        } else {
+            self.add_group_member(&group.id, joiner).await?;
+        }
+        Ok(())
+    }
+
+    async fn check_invite(&self, group: &Group, joiner: &str, invite: &GroupInvite) -> Result<()> {
+        invite.verify(&self.device_signing_key(&invite.inviter, invite.inviter_device).await?)?;
+        invite.check(joiner, chrono::Utc::now())?;
+        if group.revoked_invites.contains(&invite.token) {
+            return Err(InviteError::Revoked(invite.group_name.clone()).into());
+        }
+        group.authorize(&invite.inviter, &GroupAction::Invite { username: joiner.to_owned() })?;
+        Ok(())
+    }
+}
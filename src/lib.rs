@@ -6,10 +6,12 @@ extern crate uuid;
 // ========== Module declarations ==========
 pub mod app;
 pub mod crypto;
+pub mod groups;
 pub mod models;
 pub mod network;
 pub mod storage;
 pub mod ui;
+#[cfg(feature = "android")]
 pub mod bindings;
 
 // ========== Re-exports for high-level usage ==========
@@ -19,7 +21,10 @@ pub use models::{user, message, group};
 pub use network::{signaling, webrtc_client, discovery};
 pub use storage::{db, persistence};
 pub use ui::UI;
+#[cfg(feature = "android")]
 pub use bindings::android;
 
 #[cfg(test)]
 mod app_e2e;
+#[cfg(test)]
+mod app_tests;
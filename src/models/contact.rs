@@ -0,0 +1,46 @@
+use serde::{Serialize, Deserialize};
+use chrono::{DateTime, Utc};
+use crate::models::user::PublicIdentity;
+
+/// How much we trust the identity key of a contact
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum TrustState {
+    Unverified, // Key accepted on first use (TOFU)
+    Verified,   // Key checked out of band (safety number, QR code)
+    Changed,    // Key differs from the one seen before: warn the user
+}
+
+/// A person we talk to, with the identity we have seen for them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Contact {
+    pub username: String,                      // @user
+    pub display_name: Option<String>,          // Local nickname
+    pub identity: PublicIdentity,              // Current identity keys
+    pub first_seen: DateTime<Utc>,             // When the contact was first seen
+    pub trust: TrustState,                     // Trust in `identity`
+    pub previous_keys: Vec<Vec<u8>>,           // Signing keys seen before a change
+    pub key_changed_at: Option<DateTime<Utc>>, // Time of the last key change
+    #[serde(default)]
+    pub pending_identity: Option<PublicIdentity>, // Different identity presented, awaiting approval
+}
+
+impl Contact {
+    /// New contact trusted on first use
+    pub fn new(identity: PublicIdentity) -> Self {
+        Self {
+            username: identity.username.clone(),
+            display_name: None,
+            identity,
+            first_seen: Utc::now(),
+            trust: TrustState::Unverified,
+            previous_keys: vec![],
+            key_changed_at: None,
+            pending_identity: None,
+        }
+    }
+
+    /// Name to show in the UI
+    pub fn name(&self) -> &str {
+        self.display_name.as_deref().unwrap_or(&self.username)
+    }
+}
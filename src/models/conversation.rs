@@ -0,0 +1,83 @@
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+use chrono::{DateTime, Utc};
+use crate::models::message::{Message, MessageType};
+
+/// Maximum number of characters kept in a conversation preview
+pub const PREVIEW_LEN: usize = 80;
+
+/// Who the conversation is with
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ConversationKind {
+    Direct,      // 1:1, id is the peer @user
+    Group(Uuid), // Group or channel, id is the group id
+}
+
+/// Entry of the conversation list (home screen)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Conversation {
+    pub id: String,                           // Peer @user or group id
+    pub kind: ConversationKind,               // 1:1 or group
+    pub last_message_id: Option<Uuid>,        // Most recent message
+    pub last_message_preview: Option<String>, // Short text shown in the list
+    pub last_activity: DateTime<Utc>,         // Timestamp of the most recent message
+    pub unread: u64,                          // Incoming messages not read yet
+    pub muted: bool,                          // No notifications
+    pub pinned: bool,                         // Kept on top of the list
+    pub archived: bool,                       // Hidden from the main list
+}
+
+impl Conversation {
+    /// New 1:1 conversation with a peer
+    pub fn direct(peer: &str) -> Self {
+        Self::new(peer.to_owned(), ConversationKind::Direct)
+    }
+
+    /// New group conversation
+    pub fn group(id: Uuid) -> Self {
+        Self::new(id.to_string(), ConversationKind::Group(id))
+    }
+
+    fn new(id: String, kind: ConversationKind) -> Self {
+        Self {
+            id,
+            kind,
+            last_message_id: None,
+            last_message_preview: None,
+            last_activity: Utc::now(),
+            unread: 0,
+            muted: false,
+            pinned: false,
+            archived: false,
+        }
+    }
+
+    /// Updates the last message if `message` is more recent than the current one
+    pub fn record(&mut self, message: &Message, plaintext: &[u8]) {
+        if self.last_message_id.is_some() && message.timestamp < self.last_activity {
+            return;
+        }
+        self.last_message_id = Some(message.id);
+        self.last_message_preview = Some(preview(&message.msg_type, plaintext));
+        self.last_activity = message.timestamp;
+    }
+}
+
+/// Short text describing a message in the conversation list
+pub fn preview(msg_type: &MessageType, plaintext: &[u8]) -> String {
+    match msg_type {
+        MessageType::Text => String::from_utf8_lossy(plaintext).chars().take(PREVIEW_LEN).collect(),
+        MessageType::File => "[File]".to_owned(),
+        MessageType::Image => "[Image]".to_owned(),
+        MessageType::Voice => "[Voice message]".to_owned(),
+        MessageType::Video => "[Video]".to_owned(),
+        MessageType::CallOffer | MessageType::CallAnswer | MessageType::CallHangup => "[Call]".to_owned(),
+        MessageType::GroupInvite | MessageType::GroupInviteReply => "[Group invite]".to_owned(),
+        MessageType::SenderKeyDistribution => "[Group key]".to_owned(),
+        MessageType::MlsWelcome
+        | MessageType::MlsCommit
+        | MessageType::MlsProposal
+        | MessageType::GroupOperation => "[Group update]".to_owned(),
+        MessageType::ChannelBacklog => "[Channel history]".to_owned(),
+    }
+}
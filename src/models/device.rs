@@ -0,0 +1,212 @@
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+use anyhow::{Result, anyhow};
+use qrcode::render::svg;
+use qrcode::QrCode;
+use crate::crypto::signature::{SigningKey, verify_signature};
+use crate::models::message::put_field;
+use crate::models::user::{IdentityError, PublicIdentity};
+
+/// Number identifying a device within an account
+pub type DeviceId = u32;
+
+/// The device that created the account and holds the account identity key
+pub const PRIMARY_DEVICE: DeviceId = 1;
+
+/// Domain separation prefix of the signed device list encoding
+const DEVICE_LIST_DOMAIN: &[u8] = b"enigma-devices-v1";
+
+/// Prefix of the payload carried by a device link QR code
+const LINK_MAGIC: &[u8] = b"enigma-link";
+
+/// Prefix of the payload carried by a device link approval QR code
+const APPROVAL_MAGIC: &[u8] = b"enigma-approval";
+
+/// Errors raised when checking or editing a device list
+#[derive(Debug, Error)]
+pub enum DeviceError {
+    #[error(transparent)]
+    Identity(#[from] IdentityError),
+    #[error("invalid signature on the device list of {0}")]
+    InvalidSignature(String),
+    #[error("{0} has no device {1}")]
+    UnknownDevice(String, DeviceId),
+    #[error("the primary device cannot be revoked")]
+    PrimaryDevice,
+    #[error("the device list of {0} is signed by an account key that was not approved")]
+    AccountKeyMismatch(String),
+}
+
+/// One device of an account, with its own X3DH identity keys
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceInfo {
+    pub id: DeviceId,
+    pub name: String,                   // "Phone", "Tablet"...
+    pub signing_public_key: Vec<u8>,    // Ed25519 key of the device
+    pub encryption_public_key: Vec<u8>, // X25519 key of the device
+}
+
+/// Devices of an account, signed by the account identity key
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceList {
+    pub identity: PublicIdentity, // Self-signed account identity
+    pub version: u64,             // Increases on every change: older lists are ignored
+    pub devices: Vec<DeviceInfo>,
+    pub signature: Vec<u8>,       // Signature by `identity.signing_public_key`
+}
+
+impl DeviceList {
+    /// List of a new account: only the primary device, whose keys are the account keys
+    pub fn new(identity: PublicIdentity, primary_name: &str, key: &SigningKey) -> Self {
+        let primary = DeviceInfo {
+            id: PRIMARY_DEVICE,
+            name: primary_name.to_owned(),
+            signing_public_key: identity.signing_public_key.clone(),
+            encryption_public_key: identity.encryption_public_key.clone(),
+        };
+        let mut list = Self {
+            identity,
+            version: 1,
+            devices: vec![primary],
+            signature: vec![],
+        };
+        list.sign(key);
+        list
+    }
+
+    /// Returns the signed content: length-prefixed username, account key, version and devices.
+    pub fn signed_payload(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        put_field(&mut data, DEVICE_LIST_DOMAIN);
+        put_field(&mut data, self.identity.username.as_bytes());
+        put_field(&mut data, &self.identity.signing_public_key);
+        data.extend_from_slice(&self.version.to_be_bytes());
+        data.extend_from_slice(&(self.devices.len() as u32).to_be_bytes());
+        for device in &self.devices {
+            data.extend_from_slice(&device.id.to_be_bytes());
+            put_field(&mut data, device.name.as_bytes());
+            put_field(&mut data, &device.signing_public_key);
+            put_field(&mut data, &device.encryption_public_key);
+        }
+        data
+    }
+
+    /// Signs the list with the account identity key.
+    pub fn sign(&mut self, key: &SigningKey) {
+        self.signature = key.sign(&self.signed_payload()).as_ref().to_vec();
+    }
+
+    /// Checks the account identity, then the signature of the list by the account key.
+    pub fn verify(&self) -> Result<(), DeviceError> {
+        self.identity.verify()?;
+        verify_signature(&self.identity.signing_public_key, &self.signed_payload(), &self.signature)
+            .map_err(|_| DeviceError::InvalidSignature(self.identity.username.clone()))
+    }
+
+    /// Username of the account
+    pub fn username(&self) -> &str {
+        &self.identity.username
+    }
+
+    /// Looks up a device by id.
+    pub fn device(&self, id: DeviceId) -> Option<&DeviceInfo> {
+        self.devices.iter().find(|d| d.id == id)
+    }
+
+    /// Adds a device under the next free id and re-signs the list.
+    pub fn add(&mut self, name: &str, signing_public_key: Vec<u8>, encryption_public_key: Vec<u8>, key: &SigningKey) -> DeviceInfo {
+        let id = self.devices.iter().map(|d| d.id).max().unwrap_or(PRIMARY_DEVICE) + 1;
+        let device = DeviceInfo {
+            id,
+            name: name.to_owned(),
+            signing_public_key,
+            encryption_public_key,
+        };
+        self.devices.push(device.clone());
+        self.version += 1;
+        self.sign(key);
+        device
+    }
+
+    /// Removes a secondary device and re-signs the list.
+    pub fn revoke(&mut self, id: DeviceId, key: &SigningKey) -> Result<(), DeviceError> {
+        if id == PRIMARY_DEVICE {
+            return Err(DeviceError::PrimaryDevice);
+        }
+        if self.device(id).is_none() {
+            return Err(DeviceError::UnknownDevice(self.identity.username.clone(), id));
+        }
+        self.devices.retain(|d| d.id != id);
+        self.version += 1;
+        self.sign(key);
+        Ok(())
+    }
+}
+
+/// Public keys of a new device, shown as a QR code and scanned by the primary device
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceLinkRequest {
+    pub username: String,
+    pub name: String,
+    pub signing_public_key: Vec<u8>,
+    pub encryption_public_key: Vec<u8>,
+}
+
+impl DeviceLinkRequest {
+    /// Bytes encoded in the QR code
+    pub fn to_payload(&self) -> Result<Vec<u8>> {
+        let mut payload = LINK_MAGIC.to_vec();
+        payload.extend(bincode::serialize(self)?);
+        Ok(payload)
+    }
+
+    /// Parses a scanned QR code
+    pub fn from_payload(payload: &[u8]) -> Result<Self> {
+        let body = payload
+            .strip_prefix(LINK_MAGIC)
+            .ok_or_else(|| anyhow!("Not an Enigma device link code"))?;
+        Ok(bincode::deserialize(body)?)
+    }
+
+    /// QR code as an SVG document
+    pub fn to_qr_svg(&self) -> Result<String> {
+        qr_svg(&self.to_payload()?)
+    }
+}
+
+/// Answer of the primary device to a link request, shown as a QR code and scanned by the
+/// new device: it pins the account key, so the new device only accepts device lists
+/// signed by it, whatever the nodes return
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceLinkApproval {
+    pub username: String,
+    pub device: DeviceInfo,   // Entry added for the new device
+    pub account_key: Vec<u8>, // Ed25519 key signing the device list
+}
+
+impl DeviceLinkApproval {
+    /// Bytes encoded in the QR code
+    pub fn to_payload(&self) -> Result<Vec<u8>> {
+        let mut payload = APPROVAL_MAGIC.to_vec();
+        payload.extend(bincode::serialize(self)?);
+        Ok(payload)
+    }
+
+    /// Parses a scanned QR code
+    pub fn from_payload(payload: &[u8]) -> Result<Self> {
+        let body = payload
+            .strip_prefix(APPROVAL_MAGIC)
+            .ok_or_else(|| anyhow!("Not an Enigma device link approval code"))?;
+        Ok(bincode::deserialize(body)?)
+    }
+
+    /// QR code as an SVG document
+    pub fn to_qr_svg(&self) -> Result<String> {
+        qr_svg(&self.to_payload()?)
+    }
+}
+
+fn qr_svg(payload: &[u8]) -> Result<String> {
+    let code = QrCode::new(payload).map_err(|e| anyhow!("QR encoding failed: {:?}", e))?;
+    Ok(code.render::<svg::Color>().min_dimensions(256, 256).build())
+}
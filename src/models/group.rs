@@ -1,6 +1,39 @@
 use serde::{Serialize, Deserialize};
 use uuid::Uuid;
 use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::fmt;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use crate::crypto::signature::{SigningKey, verify_signature};
+use crate::models::device::DeviceId;
+use crate::models::message::{put_field, Message};
+
+/// Domain separation prefix of the signed group operation encoding
+const GROUP_OPERATION_DOMAIN: &[u8] = b"enigma-group-op-v1";
+
+/// Errors raised when checking or applying a group operation
+#[derive(Debug, Error)]
+pub enum GroupError {
+    #[error("operation for group {0} applied to another group")]
+    WrongGroup(Uuid),
+    #[error("{0} is not a member")]
+    NotMember(String),
+    #[error("{0} is already a member")]
+    AlreadyMember(String),
+    #[error("{actor} is not allowed to {action}")]
+    Forbidden { actor: String, action: String },
+    #[error("a group name cannot be empty")]
+    EmptyName,
+    #[error("invalid signature on a group operation of {0}")]
+    InvalidSignature(String),
+    #[error("operation of {0} is dated before a change of its role")]
+    Backdated(String),
+    #[error("operation of {0} does not follow the operation it builds on")]
+    Clock(String),
+    #[error("the clock of the group cannot advance")]
+    ClockOverflow,
+}
 
 /// Role of a member in a group
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -11,6 +44,44 @@ pub enum GroupRole {
     ReadOnly,
 }
 
+impl GroupRole {
+    /// Rank of the role: members only manage those ranked below them
+    fn rank(&self) -> u8 {
+        match self {
+            GroupRole::Owner => 3,
+            GroupRole::Admin => 2,
+            GroupRole::Member => 1,
+            GroupRole::ReadOnly => 0,
+        }
+    }
+
+    /// Role one step up; there is a single owner, set by a transfer
+    fn promoted(&self) -> Option<GroupRole> {
+        match self {
+            GroupRole::ReadOnly => Some(GroupRole::Member),
+            GroupRole::Member => Some(GroupRole::Admin),
+            GroupRole::Admin | GroupRole::Owner => None,
+        }
+    }
+
+    /// Role one step down
+    fn demoted(&self) -> Option<GroupRole> {
+        match self {
+            GroupRole::Admin => Some(GroupRole::Member),
+            GroupRole::Member => Some(GroupRole::ReadOnly),
+            GroupRole::Owner | GroupRole::ReadOnly => None,
+        }
+    }
+}
+
+/// How the messages of a group are encrypted
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum GroupProtocol {
+    #[default]
+    SenderKeys, // One chain per member, distributed over pairwise sessions
+    Mls,        // Ratchet tree shared by all members, for larger groups
+}
+
 /// Member of a group (user with rights)
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GroupMember {
@@ -29,4 +100,424 @@ pub struct Group {
     pub creator: String,             // @user of group creator
     pub members: Vec<GroupMember>,   // Member list
     pub encrypted_key: Vec<u8>,      // Shared group key encrypted for this client
+    #[serde(default)]
+    pub protocol: GroupProtocol,     // Encryption of group messages
+    #[serde(default)]
+    pub version: u64,                // Highest Lamport clock of the operations applied
+    #[serde(default)]
+    pub head: [u8; 32],              // Hash of the last operation applied
+    #[serde(default)]
+    pub revoked_invites: Vec<Vec<u8>>, // Tokens of the invites that can no longer be used
+}
+
+/// Administrative change to a group
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum GroupAction {
+    Invite { username: String },            // Joins as a member
+    Remove { username: String },            // Removing oneself is leaving
+    Promote { username: String },           // One role up, at most admin
+    Demote { username: String },            // One role down, at least read-only
+    Rename { name: String },
+    TransferOwnership { username: String }, // The former owner becomes an admin
+    Dissolve,
+    RevokeInvite { token: Vec<u8> },        // The invite can no longer be used
+}
+
+impl GroupAction {
+    /// Stable encoding used in the signed payload
+    fn encode(&self, out: &mut Vec<u8>) {
+        let (tag, field) = match self {
+            GroupAction::Invite { username } => (0, username.as_bytes()),
+            GroupAction::Remove { username } => (1, username.as_bytes()),
+            GroupAction::Promote { username } => (2, username.as_bytes()),
+            GroupAction::Demote { username } => (3, username.as_bytes()),
+            GroupAction::Rename { name } => (4, name.as_bytes()),
+            GroupAction::TransferOwnership { username } => (5, username.as_bytes()),
+            GroupAction::Dissolve => (6, &[][..]),
+            GroupAction::RevokeInvite { token } => (7, token.as_slice()),
+        };
+        out.push(tag);
+        put_field(out, field);
+    }
+}
+
+impl fmt::Display for GroupAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GroupAction::Invite { username } => write!(f, "invite {}", username),
+            GroupAction::Remove { username } => write!(f, "remove {}", username),
+            GroupAction::Promote { username } => write!(f, "promote {}", username),
+            GroupAction::Demote { username } => write!(f, "demote {}", username),
+            GroupAction::Rename { name } => write!(f, "rename the group to {}", name),
+            GroupAction::TransferOwnership { username } => write!(f, "transfer the ownership to {}", username),
+            GroupAction::Dissolve => write!(f, "dissolve the group"),
+            GroupAction::RevokeInvite { .. } => write!(f, "revoke an invite"),
+        }
+    }
+}
+
+/// Post of a channel with its decrypted content
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelPost {
+    pub message: Message,
+    pub plaintext: Vec<u8>,
+}
+
+/// Latest posts of a channel, sent by an admin device to a new subscriber
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelBacklog {
+    pub channel_id: Uuid,
+    pub posts: Vec<ChannelPost>, // Oldest first
+}
+
+/// Group action signed by the device of its actor. It names the last operation of the state
+/// it was made on, and its Lamport clock is one more than that operation's; concurrent
+/// operations are ordered by clock, then actor, then signature.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupOperation {
+    pub group_id: Uuid,
+    pub lamport: u64,
+    pub parent: [u8; 32],          // Hash of the operation it builds on
+    pub actor: String,             // @user making the change
+    pub actor_device: DeviceId,    // Device whose identity key signs it
+    pub action: GroupAction,
+    pub timestamp: DateTime<Utc>,
+    pub signature: Vec<u8>,
+}
+
+impl GroupOperation {
+    /// Signs `action` by `actor` on the current state of `group`
+    pub fn new(
+        group: &Group,
+        actor: &str,
+        actor_device: DeviceId,
+        action: GroupAction,
+        key: &SigningKey,
+    ) -> Result<Self, GroupError> {
+        let mut op = Self {
+            group_id: group.id,
+            lamport: group.version.checked_add(1).ok_or(GroupError::ClockOverflow)?,
+            parent: group.head,
+            actor: actor.to_owned(),
+            actor_device,
+            action,
+            timestamp: Utc::now(),
+            signature: Vec::new(),
+        };
+        op.signature = key.sign(&op.signed_payload()).as_ref().to_vec();
+        Ok(op)
+    }
+
+    /// Returns the signed content: group id, clock, parent, actor, device, action and
+    /// timestamp.
+    pub fn signed_payload(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        put_field(&mut data, GROUP_OPERATION_DOMAIN);
+        put_field(&mut data, self.group_id.as_bytes());
+        data.extend_from_slice(&self.lamport.to_be_bytes());
+        data.extend_from_slice(&self.parent);
+        put_field(&mut data, self.actor.as_bytes());
+        data.extend_from_slice(&self.actor_device.to_be_bytes());
+        self.action.encode(&mut data);
+        data.extend_from_slice(&self.timestamp.timestamp().to_be_bytes());
+        data.extend_from_slice(&self.timestamp.timestamp_subsec_nanos().to_be_bytes());
+        data
+    }
+
+    /// Checks the signature against the identity key of the actor's device
+    pub fn verify(&self, signing_public_key: &[u8]) -> Result<(), GroupError> {
+        verify_signature(signing_public_key, &self.signed_payload(), &self.signature)
+            .map_err(|_| GroupError::InvalidSignature(self.actor.clone()))
+    }
+
+    /// Hash naming the operation in those built on it
+    pub fn hash(&self) -> [u8; 32] {
+        Sha256::digest(&self.signed_payload()).into()
+    }
+
+    /// Position of the operation in the order every member applies
+    fn order(&self) -> (u64, &str, &[u8]) {
+        (self.lamport, &self.actor, &self.signature)
+    }
+
+    /// Member whose role the operation lowers: a removed or demoted member, or the owner
+    /// handing over the group
+    fn lowers(&self) -> Option<&str> {
+        match &self.action {
+            GroupAction::Remove { username } | GroupAction::Demote { username } => Some(username),
+            GroupAction::TransferOwnership { .. } => Some(&self.actor),
+            _ => None,
+        }
+    }
+
+    /// Whether an operation of `operations` lowers the role of the actor at the same or a
+    /// later clock without building on this one: a removed or demoted member cannot date an
+    /// operation before the change to keep its former rights
+    pub fn is_backdated(&self, operations: &[GroupOperation]) -> bool {
+        let by_hash = operations.iter().map(|op| (op.hash(), op)).collect();
+        self.backdated(&by_hash)
+    }
+
+    /// Whether the clock is one more than `parent_lamport`, the clock of the parent
+    fn follows(&self, parent_lamport: u64) -> bool {
+        parent_lamport.checked_add(1) == Some(self.lamport)
+    }
+
+    fn backdated(&self, by_hash: &HashMap<[u8; 32], &GroupOperation>) -> bool {
+        let hash = self.hash();
+        by_hash
+            .values()
+            .filter(|change| change.lamport >= self.lamport && change.lowers() == Some(self.actor.as_str()))
+            .any(|change| {
+                let mut ancestor = *change;
+                while ancestor.lamport > self.lamport {
+                    match by_hash.get(&ancestor.parent) {
+                        Some(parent) => ancestor = parent,
+                        None => return true,
+                    }
+                }
+                ancestor.hash() != hash
+            })
+    }
+}
+
+impl Group {
+    /// New group (or channel) whose creator is its owner
+    pub fn new(name: &str, creator: &str, is_channel: bool) -> Self {
+        let now = Utc::now();
+        Self {
+            id: Uuid::new_v4(),
+            name: name.to_owned(),
+            is_channel,
+            created_at: now,
+            creator: creator.to_owned(),
+            members: vec![GroupMember {
+                username: creator.to_owned(),
+                role: GroupRole::Owner,
+                joined_at: now,
+            }],
+            encrypted_key: Vec::new(),
+            protocol: GroupProtocol::default(),
+            version: 0,
+            head: [0; 32],
+            revoked_invites: Vec::new(),
+        }
+    }
+
+    /// Looks up a member
+    pub fn member(&self, username: &str) -> Option<&GroupMember> {
+        self.members.iter().find(|m| m.username == username)
+    }
+
+    /// Whether `username` belongs to the group
+    pub fn is_member(&self, username: &str) -> bool {
+        self.member(username).is_some()
+    }
+
+    /// Role of a member, if any
+    pub fn role_of(&self, username: &str) -> Option<GroupRole> {
+        self.member(username).map(|m| m.role.clone())
+    }
+
+    /// Adds a member; returns false if already present
+    pub fn add_member(&mut self, username: &str, role: GroupRole) -> bool {
+        if self.is_member(username) {
+            return false;
+        }
+        self.members.push(GroupMember {
+            username: username.to_owned(),
+            role,
+            joined_at: Utc::now(),
+        });
+        true
+    }
+
+    /// Removes a member; returns false if absent
+    pub fn remove_member(&mut self, username: &str) -> bool {
+        let before = self.members.len();
+        self.members.retain(|m| m.username != username);
+        self.members.len() != before
+    }
+
+    /// Usernames of the members other than `username`
+    pub fn others(&self, username: &str) -> Vec<String> {
+        self.members
+            .iter()
+            .filter(|m| m.username != username)
+            .map(|m| m.username.clone())
+            .collect()
+    }
+
+    /// Whether `username` may send messages: owners and admins of a channel, members other
+    /// than read-only ones in a group
+    pub fn can_post(&self, username: &str) -> bool {
+        match self.role_of(username) {
+            Some(GroupRole::Owner) | Some(GroupRole::Admin) => true,
+            Some(GroupRole::Member) => !self.is_channel,
+            Some(GroupRole::ReadOnly) | None => false,
+        }
+    }
+
+    /// The group as shown to `username`: the subscribers of a channel only see its owners
+    /// and admins besides themselves
+    pub fn view_for(&self, username: &str) -> Group {
+        let mut view = self.clone();
+        if self.is_channel && !self.can_post(username) {
+            view.members.retain(|m| m.username == username || self.can_post(&m.username));
+        }
+        view
+    }
+
+    /// Members of `proposed` holding a role above their role here; a member we do not know
+    /// may only have been invited
+    pub fn raised_roles<'a>(&self, proposed: &'a Group) -> Vec<&'a GroupMember> {
+        let invited = GroupRole::Member.rank();
+        proposed
+            .members
+            .iter()
+            .filter(|m| m.role.rank() > self.role_of(&m.username).map_or(invited, |role| role.rank()))
+            .collect()
+    }
+
+    /// Whether `op`, newer than this state, gives `member` its role: a promotion by a member
+    /// ranked above that role, or a transfer by the owner
+    pub fn grants(&self, op: &GroupOperation, member: &GroupMember) -> bool {
+        let actor = self.role_of(&op.actor);
+        op.group_id == self.id
+            && op.lamport > self.version
+            && match &op.action {
+                GroupAction::Promote { username } => {
+                    *username == member.username
+                        && member.role != GroupRole::Owner
+                        && actor.is_some_and(|role| role.rank() > member.role.rank())
+                }
+                GroupAction::TransferOwnership { username } => {
+                    *username == member.username && member.role == GroupRole::Owner && actor == Some(GroupRole::Owner)
+                }
+                _ => false,
+            }
+    }
+
+    /// Checks that `actor` may perform `action` with the current roles: owners and admins
+    /// manage members ranked below them and revoke invites, any member but the owner may
+    /// leave, and only the owner transfers the ownership or dissolves the group
+    pub fn authorize(&self, actor: &str, action: &GroupAction) -> Result<(), GroupError> {
+        let role = self.role_of(actor).ok_or_else(|| GroupError::NotMember(actor.to_owned()))?;
+        let target = |username: &str| self.role_of(username).ok_or_else(|| GroupError::NotMember(username.to_owned()));
+        let manager = role.rank() >= GroupRole::Admin.rank();
+        let allowed = match action {
+            GroupAction::Invite { username } => {
+                if self.is_member(username) {
+                    return Err(GroupError::AlreadyMember(username.clone()));
+                }
+                manager
+            }
+            GroupAction::Remove { username } if username == actor => role != GroupRole::Owner,
+            GroupAction::Remove { username } => manager && role.rank() > target(username)?.rank(),
+            GroupAction::Promote { username } => target(username)?.promoted().is_some_and(|r| role.rank() > r.rank()),
+            GroupAction::Demote { username } => {
+                let current = target(username)?;
+                current.demoted().is_some() && role.rank() > current.rank()
+            }
+            GroupAction::Rename { name } => {
+                if name.trim().is_empty() {
+                    return Err(GroupError::EmptyName);
+                }
+                manager
+            }
+            GroupAction::TransferOwnership { username } => {
+                target(username)?;
+                role == GroupRole::Owner && username != actor
+            }
+            GroupAction::Dissolve => role == GroupRole::Owner,
+            GroupAction::RevokeInvite { .. } => manager,
+        };
+        if !allowed {
+            return Err(GroupError::Forbidden { actor: actor.to_owned(), action: action.to_string() });
+        }
+        Ok(())
+    }
+
+    /// Validates an operation against the current roles, then applies it. A dissolved
+    /// group has no members left.
+    pub fn apply(&mut self, op: &GroupOperation) -> Result<(), GroupError> {
+        if op.group_id != self.id {
+            return Err(GroupError::WrongGroup(op.group_id));
+        }
+        if op.parent == self.head && !op.follows(self.version) {
+            return Err(GroupError::Clock(op.actor.clone()));
+        }
+        self.authorize(&op.actor, &op.action)?;
+        match &op.action {
+            GroupAction::Invite { username } => self.members.push(GroupMember {
+                username: username.clone(),
+                role: GroupRole::Member,
+                joined_at: op.timestamp,
+            }),
+            GroupAction::Remove { username } => {
+                self.remove_member(username);
+            }
+            GroupAction::Promote { username } => self.change_role(username, GroupRole::promoted),
+            GroupAction::Demote { username } => self.change_role(username, GroupRole::demoted),
+            GroupAction::Rename { name } => self.name = name.clone(),
+            GroupAction::TransferOwnership { username } => {
+                self.change_role(&op.actor, |_| Some(GroupRole::Admin));
+                self.change_role(username, |_| Some(GroupRole::Owner));
+            }
+            GroupAction::Dissolve => self.members.clear(),
+            GroupAction::RevokeInvite { token } => self.revoked_invites.push(token.clone()),
+        }
+        self.version = self.version.max(op.lamport);
+        self.head = op.hash();
+        Ok(())
+    }
+
+    fn change_role(&mut self, username: &str, change: impl Fn(&GroupRole) -> Option<GroupRole>) {
+        if let Some(member) = self.members.iter_mut().find(|m| m.username == username) {
+            if let Some(role) = change(&member.role) {
+                member.role = role;
+            }
+        }
+    }
+
+    /// Applies `operations` to this state in their deterministic order, skipping those it
+    /// already includes and those not allowed at their turn, so that members holding the
+    /// same operations reach the same state whatever order they received them in. A
+    /// backdated operation is dropped.
+    pub fn replay(&self, operations: &[GroupOperation]) -> Group {
+        let known: HashMap<[u8; 32], &GroupOperation> = operations.iter().map(|op| (op.hash(), op)).collect();
+        let mut pending: Vec<&GroupOperation> = operations
+            .iter()
+            .filter(|op| op.lamport > self.version && self.clock_follows(op, &known))
+            .collect();
+        pending.sort_by(|a, b| a.order().cmp(&b.order()));
+        let by_hash: HashMap<[u8; 32], &GroupOperation> = pending.iter().map(|op| (op.hash(), *op)).collect();
+        let mut group = self.clone();
+        for op in pending.into_iter().filter(|op| !op.backdated(&by_hash)) {
+            let _ = group.apply(op);
+        }
+        group
+    }
+
+    /// Checks that the clock of `op` is one more than that of the operation it builds on,
+    /// which is the last operation of this state or one of `operations`
+    pub fn check_clock(&self, op: &GroupOperation, operations: &[GroupOperation]) -> Result<(), GroupError> {
+        let known = operations.iter().map(|op| (op.hash(), op)).collect();
+        if !self.clock_follows(op, &known) {
+            return Err(GroupError::Clock(op.actor.clone()));
+        }
+        Ok(())
+    }
+
+    /// Whether `op` builds on the creation of the group, the last operation of this state or
+    /// one of `known`, with a clock one more than that operation's
+    fn clock_follows(&self, op: &GroupOperation, known: &HashMap<[u8; 32], &GroupOperation>) -> bool {
+        let parent_lamport = match known.get(&op.parent) {
+            Some(parent) => parent.lamport,
+            None if op.parent == self.head => self.version,
+            None if op.parent == [0; 32] => 0,
+            None => return false,
+        };
+        op.follows(parent_lamport)
+    }
 }
@@ -0,0 +1,205 @@
+#[cfg(test)]
+mod tests {
+    use super::super::group::{Group, GroupAction, GroupError, GroupOperation, GroupRole};
+    use crate::crypto::signature::SigningKey;
+
+    fn op(group: &Group, actor: &str, key: &SigningKey, action: GroupAction) -> GroupOperation {
+        GroupOperation::new(group, actor, 1, action, key).unwrap()
+    }
+
+    fn roles(group: &Group) -> Vec<(String, GroupRole)> {
+        group.members.iter().map(|m| (m.username.clone(), m.role.clone())).collect()
+    }
+
+    // Each operation is checked against the current role of its actor
+    #[test]
+    fn test_roles_are_enforced() {
+        let key = SigningKey::generate().unwrap();
+        let mut group = Group::new("Team", "@alice", false);
+        group.add_member("@bob", GroupRole::Member);
+        let invite = GroupAction::Invite { username: "@carol".to_owned() };
+        assert!(matches!(group.apply(&op(&group, "@bob", &key, invite.clone())), Err(GroupError::Forbidden { .. })));
+        group.apply(&op(&group, "@alice", &key, invite.clone())).unwrap();
+        assert!(matches!(group.apply(&op(&group, "@alice", &key, invite)), Err(GroupError::AlreadyMember(_))));
+
+        // Admins manage the members ranked below them
+        let promote = |username: &str| GroupAction::Promote { username: username.to_owned() };
+        group.apply(&op(&group, "@alice", &key, promote("@bob"))).unwrap();
+        assert_eq!(group.role_of("@bob"), Some(GroupRole::Admin));
+        let demote = |username: &str| GroupAction::Demote { username: username.to_owned() };
+        group.apply(&op(&group, "@alice", &key, demote("@carol"))).unwrap();
+        group.apply(&op(&group, "@bob", &key, promote("@carol"))).unwrap();
+        assert!(group.apply(&op(&group, "@bob", &key, promote("@carol"))).is_err(), "Only the owner makes admins");
+        group.apply(&op(&group, "@bob", &key, demote("@carol"))).unwrap();
+        assert_eq!(group.role_of("@carol"), Some(GroupRole::ReadOnly));
+        assert!(!group.can_post("@carol"));
+        assert!(group.apply(&op(&group, "@bob", &key, GroupAction::Remove { username: "@alice".to_owned() })).is_err());
+        assert!(group.apply(&op(&group, "@carol", &key, GroupAction::Rename { name: "Mine".to_owned() })).is_err());
+        group.apply(&op(&group, "@bob", &key, GroupAction::Rename { name: "Crew".to_owned() })).unwrap();
+        assert_eq!(group.name, "Crew");
+
+        // The owner hands over the group before leaving; only the owner dissolves it
+        let leave = GroupAction::Remove { username: "@alice".to_owned() };
+        assert!(group.apply(&op(&group, "@alice", &key, leave.clone())).is_err());
+        assert!(group.apply(&op(&group, "@bob", &key, GroupAction::Dissolve)).is_err());
+        group.apply(&op(&group, "@alice", &key, GroupAction::TransferOwnership { username: "@bob".to_owned() })).unwrap();
+        assert_eq!(group.role_of("@alice"), Some(GroupRole::Admin));
+        assert_eq!(group.role_of("@bob"), Some(GroupRole::Owner));
+        group.apply(&op(&group, "@alice", &key, leave)).unwrap();
+        assert!(matches!(
+            group.apply(&op(&group, "@alice", &key, GroupAction::Dissolve)),
+            Err(GroupError::NotMember(_))
+        ));
+        group.apply(&op(&group, "@bob", &key, GroupAction::Dissolve)).unwrap();
+        assert!(group.members.is_empty());
+        assert_eq!(group.version, 9);
+
+        let other = Group::new("Other", "@bob", false);
+        assert!(matches!(
+            group.apply(&op(&other, "@bob", &key, GroupAction::Dissolve)),
+            Err(GroupError::WrongGroup(_))
+        ));
+    }
+
+    // Concurrent operations are applied in the same order by every member, whatever order
+    // they arrive in; an operation no longer allowed at its turn is skipped
+    #[test]
+    fn test_concurrent_operations_converge() {
+        let (alice, bob) = (SigningKey::generate().unwrap(), SigningKey::generate().unwrap());
+        let mut base = Group::new("Team", "@alice", false);
+        base.add_member("@bob", GroupRole::Admin);
+        base.add_member("@carol", GroupRole::Member);
+
+        // Alice demotes Bob while Bob removes Carol: the demotion comes first
+        let demote = op(&base, "@alice", &alice, GroupAction::Demote { username: "@bob".to_owned() });
+        let remove = op(&base, "@bob", &bob, GroupAction::Remove { username: "@carol".to_owned() });
+        let mut eager = base.clone();
+        eager.apply(&remove).unwrap();
+        assert!(!eager.is_member("@carol"));
+
+        let one = base.replay(&[demote.clone(), remove.clone()]);
+        let other = base.replay(&[remove.clone(), demote.clone()]);
+        assert_eq!(roles(&one), roles(&other));
+        assert!(one.is_member("@carol"));
+        assert_eq!(one.role_of("@bob"), Some(GroupRole::Member));
+        assert_eq!((one.version, other.version), (1, 1));
+
+        // Operations already included in the state are not replayed
+        let renamed = op(&one, "@alice", &alice, GroupAction::Rename { name: "Crew".to_owned() });
+        let next = one.replay(&[demote, remove, renamed.clone()]);
+        assert_eq!((next.name.as_str(), next.version), ("Crew", 2));
+        assert_eq!(roles(&next), roles(&one));
+
+        // Operations are signed by the actor's device
+        assert!(renamed.verify(alice.public_key_bytes()).is_ok());
+        assert!(matches!(renamed.verify(bob.public_key_bytes()), Err(GroupError::InvalidSignature(_))));
+        let mut forged = renamed;
+        forged.action = GroupAction::Dissolve;
+        assert!(forged.verify(alice.public_key_bytes()).is_err());
+    }
+
+    // Each operation names the one it builds on: a removed admin cannot date an operation
+    // before its removal, while the operations the removal builds on stay
+    #[test]
+    fn test_backdated_operations_are_dropped() {
+        let (alice, bob) = (SigningKey::generate().unwrap(), SigningKey::generate().unwrap());
+        let mut base = Group::new("Team", "@alice", false);
+        base.add_member("@bob", GroupRole::Admin);
+        let invite = |username: &str| GroupAction::Invite { username: username.to_owned() };
+
+        let carol = op(&base, "@bob", &bob, invite("@carol"));
+        let mut seen = base.clone();
+        seen.apply(&carol).unwrap();
+        assert_eq!(seen.head, carol.hash());
+        let removal = op(&seen, "@alice", &alice, GroupAction::Remove { username: "@bob".to_owned() });
+        assert_eq!(removal.parent, carol.hash());
+
+        // Bob signs an operation on the state before the removal once removed
+        let backdated = op(&base, "@bob", &bob, invite("@mallory"));
+        let log = [carol.clone(), removal.clone(), backdated.clone()];
+        assert!(backdated.is_backdated(&log));
+        assert!(!carol.is_backdated(&log));
+        let group = base.replay(&log);
+        assert!(group.is_member("@carol"));
+        assert!(!group.is_member("@bob"));
+        assert!(!group.is_member("@mallory"));
+        assert_eq!(group.version, 2);
+
+        // Whatever clock Bob picks, his operation is dropped or comes after his removal
+        for lamport in [1, 2, 3] {
+            let mut forged = op(&base, "@bob", &bob, invite("@mallory"));
+            forged.lamport = lamport;
+            forged.signature = bob.sign(&forged.signed_payload()).as_ref().to_vec();
+            let group = base.replay(&[carol.clone(), removal.clone(), forged]);
+            assert!(!group.is_member("@mallory"));
+        }
+    }
+
+    // An operation's clock is one more than that of the operation it builds on, so a member
+    // cannot push the clock of the group past what later operations can follow
+    #[test]
+    fn test_operation_clock_follows_parent() {
+        let alice = SigningKey::generate().unwrap();
+        let base = Group::new("Team", "@alice", false);
+        let rename = |name: &str| GroupAction::Rename { name: name.to_owned() };
+
+        let first = op(&base, "@alice", &alice, rename("One"));
+        let mut jumped = op(&base, "@alice", &alice, rename("Two"));
+        jumped.lamport = u64::MAX;
+        jumped.signature = alice.sign(&jumped.signed_payload()).as_ref().to_vec();
+        assert!(base.check_clock(&first, &[]).is_ok());
+        assert!(matches!(base.check_clock(&jumped, std::slice::from_ref(&first)), Err(GroupError::Clock(_))));
+        assert!(matches!(base.clone().apply(&jumped), Err(GroupError::Clock(_))));
+
+        let group = base.replay(&[first.clone(), jumped]);
+        assert_eq!((group.name.as_str(), group.version), ("One", 1));
+        let second = op(&group, "@alice", &alice, rename("Two"));
+        assert!(base.check_clock(&second, &[first]).is_ok());
+
+        // An operation naming an unknown parent is dropped
+        let mut orphan = op(&group, "@alice", &alice, rename("Three"));
+        orphan.parent = [9; 32];
+        assert!(base.check_clock(&orphan, &[]).is_err());
+
+        let mut full = base.clone();
+        full.version = u64::MAX;
+        assert!(matches!(
+            GroupOperation::new(&full, "@alice", 1, rename("Four"), &alice),
+            Err(GroupError::ClockOverflow)
+        ));
+    }
+
+    // Owners and admins revoke invites; every member then knows the token
+    #[test]
+    fn test_revoke_invite() {
+        let key = SigningKey::generate().unwrap();
+        let mut group = Group::new("Team", "@alice", false);
+        group.add_member("@bob", GroupRole::Admin);
+        group.add_member("@carol", GroupRole::Member);
+        let revoke = GroupAction::RevokeInvite { token: vec![7; 16] };
+        assert!(matches!(group.apply(&op(&group, "@carol", &key, revoke.clone())), Err(GroupError::Forbidden { .. })));
+        group.apply(&op(&group, "@bob", &key, revoke)).unwrap();
+        assert_eq!(group.revoked_invites, vec![vec![7; 16]]);
+    }
+
+    // Only owners and admins post in a channel, and subscribers do not see each other
+    #[test]
+    fn test_channel_views() {
+        let mut channel = Group::new("News", "@alice", true);
+        channel.add_member("@bob", GroupRole::Admin);
+        channel.add_member("@carol", GroupRole::Member);
+        channel.add_member("@dave", GroupRole::Member);
+        assert!(channel.can_post("@bob"));
+        assert!(!channel.can_post("@carol"));
+
+        let view = channel.view_for("@carol");
+        let names: Vec<&str> = view.members.iter().map(|m| m.username.as_str()).collect();
+        assert_eq!(names, vec!["@alice", "@bob", "@carol"]);
+        assert_eq!(channel.view_for("@bob").members.len(), 4);
+
+        let mut group = Group::new("Team", "@alice", false);
+        group.add_member("@carol", GroupRole::Member);
+        assert!(group.can_post("@carol"));
+        assert_eq!(group.view_for("@carol").members.len(), 2);
+    }
+}
@@ -0,0 +1,168 @@
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+use chrono::{DateTime, Duration, Utc};
+use thiserror::Error;
+use anyhow::{Result, anyhow};
+use ring::rand::{SecureRandom, SystemRandom};
+use qrcode::render::svg;
+use qrcode::QrCode;
+use crate::crypto::signature::{SigningKey, verify_signature};
+use crate::models::device::DeviceId;
+use crate::models::group::Group;
+use crate::models::message::put_field;
+
+/// Domain separation prefix of the signed invite encoding
+const INVITE_DOMAIN: &[u8] = b"enigma-invite-v1";
+
+/// Prefix of the payload carried by an invite QR code
+const INVITE_MAGIC: &[u8] = b"enigma-invite";
+
+/// Start of an invite link, followed by the hex encoded payload
+const INVITE_LINK_PREFIX: &str = "enigma://join/";
+
+/// Errors raised when checking an invite
+#[derive(Debug, Error)]
+pub enum InviteError {
+    #[error("invalid signature on the invite of {0}")]
+    InvalidSignature(String),
+    #[error("the invite to {0} has expired")]
+    Expired(String),
+    #[error("the invite to {group} is meant for {invitee}")]
+    WrongInvitee { group: String, invitee: String },
+    #[error("the invite to {0} was revoked")]
+    Revoked(String),
+}
+
+/// Invitation to a group, signed by the device of an owner or admin, which lets the invitee
+/// in. It is sent to a contact, or shared as a link or QR code usable by anyone when it
+/// names no invitee, until it expires or is revoked (GroupAction::RevokeInvite). It names
+/// no other member: the invitee learns them once let in.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GroupInvite {
+    pub token: Vec<u8>,            // Random identifier
+    pub group_id: Uuid,
+    pub group_name: String,
+    pub is_channel: bool,
+    pub inviter: String,           // @user who signed the invite
+    pub inviter_device: DeviceId,
+    pub invitee: Option<String>,   // None: whoever holds the invite
+    pub backlog: u32,              // Latest posts a channel subscriber receives
+    pub expires_at: DateTime<Utc>,
+    pub signature: Vec<u8>,
+}
+
+/// Content of a GroupInviteReply message (a GroupInvite message carries the invite itself)
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum InviteReply {
+    Accept(GroupInvite),                               // From the invitee to the inviter
+    Decline(GroupInvite),                              // From the invitee to the inviter
+    Rejected { invite: GroupInvite, reason: String },  // From the inviter refusing an acceptance
+}
+
+impl GroupInvite {
+    /// Signs an invite to `group` valid for `valid_for`
+    pub fn new(
+        group: &Group,
+        inviter: &str,
+        inviter_device: DeviceId,
+        invitee: Option<&str>,
+        valid_for: Duration,
+        backlog: u32,
+        key: &SigningKey,
+    ) -> Result<Self> {
+        let mut token = vec![0u8; 16];
+        SystemRandom::new()
+            .fill(&mut token)
+            .map_err(|_| anyhow!("Random generation failed"))?;
+        let mut invite = Self {
+            token,
+            group_id: group.id,
+            group_name: group.name.clone(),
+            is_channel: group.is_channel,
+            inviter: inviter.to_owned(),
+            inviter_device,
+            invitee: invitee.map(str::to_owned),
+            backlog,
+            expires_at: Utc::now() + valid_for,
+            signature: Vec::new(),
+        };
+        invite.signature = key.sign(&invite.signed_payload()).as_ref().to_vec();
+        Ok(invite)
+    }
+
+    /// Returns the signed content: every field but the signature.
+    pub fn signed_payload(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        put_field(&mut data, INVITE_DOMAIN);
+        put_field(&mut data, &self.token);
+        put_field(&mut data, self.group_id.as_bytes());
+        put_field(&mut data, self.group_name.as_bytes());
+        data.push(self.is_channel as u8);
+        put_field(&mut data, self.inviter.as_bytes());
+        data.extend_from_slice(&self.inviter_device.to_be_bytes());
+        put_field(&mut data, self.invitee.as_deref().unwrap_or("").as_bytes());
+        data.extend_from_slice(&self.backlog.to_be_bytes());
+        data.extend_from_slice(&self.expires_at.timestamp().to_be_bytes());
+        data
+    }
+
+    /// Checks the signature against the identity key of the inviter's device
+    pub fn verify(&self, signing_public_key: &[u8]) -> Result<(), InviteError> {
+        verify_signature(signing_public_key, &self.signed_payload(), &self.signature)
+            .map_err(|_| InviteError::InvalidSignature(self.inviter.clone()))
+    }
+
+    /// Whether the invite can no longer be used at `now`
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        now > self.expires_at
+    }
+
+    /// Checks that `username` may use the invite at `now`
+    pub fn check(&self, username: &str, now: DateTime<Utc>) -> Result<(), InviteError> {
+        if self.is_expired(now) {
+            return Err(InviteError::Expired(self.group_name.clone()));
+        }
+        match &self.invitee {
+            Some(invitee) if invitee != username => Err(InviteError::WrongInvitee {
+                group: self.group_name.clone(),
+                invitee: invitee.clone(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Bytes encoded in the QR code or the link
+    pub fn to_payload(&self) -> Result<Vec<u8>> {
+        let mut payload = INVITE_MAGIC.to_vec();
+        payload.extend(bincode::serialize(self)?);
+        Ok(payload)
+    }
+
+    /// Parses a scanned QR code
+    pub fn from_payload(payload: &[u8]) -> Result<Self> {
+        let body = payload
+            .strip_prefix(INVITE_MAGIC)
+            .ok_or_else(|| anyhow!("Not an Enigma group invite"))?;
+        Ok(bincode::deserialize(body)?)
+    }
+
+    /// Invite as a link (enigma://join/...)
+    pub fn to_link(&self) -> Result<String> {
+        Ok(format!("{}{}", INVITE_LINK_PREFIX, hex::encode(self.to_payload()?)))
+    }
+
+    /// Parses an invite link
+    pub fn from_link(link: &str) -> Result<Self> {
+        let body = link
+            .trim()
+            .strip_prefix(INVITE_LINK_PREFIX)
+            .ok_or_else(|| anyhow!("Not an Enigma invite link"))?;
+        Self::from_payload(&hex::decode(body)?)
+    }
+
+    /// QR code as an SVG document
+    pub fn to_qr_svg(&self) -> Result<String> {
+        let code = QrCode::new(self.to_payload()?).map_err(|e| anyhow!("QR encoding failed: {:?}", e))?;
+        Ok(code.render::<svg::Color>().min_dimensions(256, 256).build())
+    }
+}
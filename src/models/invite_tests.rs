@@ -0,0 +1,64 @@
+#[cfg(test)]
+mod tests {
+    use super::super::group::{Group, GroupRole};
+    use super::super::invite::{GroupInvite, InviteError};
+    use crate::crypto::signature::SigningKey;
+    use chrono::{Duration, Utc};
+
+    fn group() -> Group {
+        let mut group = Group::new("Team", "@alice", false);
+        group.add_member("@bob", GroupRole::Admin);
+        group.add_member("@carol", GroupRole::Member);
+        group
+    }
+
+    // Invites are signed by the inviter's device
+    #[test]
+    fn test_invite_signature() {
+        let key = SigningKey::generate().unwrap();
+        let invite = GroupInvite::new(&group(), "@alice", 1, Some("@dave"), Duration::hours(1), 0, &key).unwrap();
+        invite.verify(key.public_key_bytes()).unwrap();
+
+        let other = SigningKey::generate().unwrap();
+        assert!(matches!(invite.verify(other.public_key_bytes()), Err(InviteError::InvalidSignature(_))));
+        let mut tampered = invite.clone();
+        tampered.invitee = None;
+        assert!(tampered.verify(key.public_key_bytes()).is_err());
+        let mut tampered = invite;
+        tampered.inviter = "@bob".to_owned();
+        assert!(tampered.verify(key.public_key_bytes()).is_err());
+    }
+
+    // Invites expire, and an invite naming its invitee is only valid for it
+    #[test]
+    fn test_invite_validity() {
+        let key = SigningKey::generate().unwrap();
+        let now = Utc::now();
+        let invite = GroupInvite::new(&group(), "@alice", 1, Some("@dave"), Duration::hours(1), 0, &key).unwrap();
+        invite.check("@dave", now).unwrap();
+        assert!(matches!(invite.check("@eve", now), Err(InviteError::WrongInvitee { .. })));
+        assert!(matches!(invite.check("@dave", now + Duration::hours(2)), Err(InviteError::Expired(_))));
+
+        let link = GroupInvite::new(&group(), "@bob", 1, None, Duration::minutes(5), 10, &key).unwrap();
+        link.check("@eve", now).unwrap();
+        assert!(link.is_expired(now + Duration::minutes(6)));
+    }
+
+    // Links and QR codes carry the whole signed invite, which names no other member
+    #[test]
+    fn test_invite_link_round_trip() {
+        let key = SigningKey::generate().unwrap();
+        let invite = GroupInvite::new(&group(), "@alice", 1, None, Duration::days(7), 20, &key).unwrap();
+        let link = invite.to_link().unwrap();
+        assert!(link.starts_with("enigma://join/"));
+        assert!(!link.contains(&hex::encode("@bob")));
+        let parsed = GroupInvite::from_link(&link).unwrap();
+        assert_eq!(parsed, invite);
+        parsed.verify(key.public_key_bytes()).unwrap();
+        assert_eq!(GroupInvite::from_payload(&invite.to_payload().unwrap()).unwrap(), invite);
+        assert!(invite.to_qr_svg().unwrap().contains("<svg"));
+
+        assert!(GroupInvite::from_link("https://example.com/join").is_err());
+        assert!(GroupInvite::from_payload(b"not an invite").is_err());
+    }
+}
@@ -1,6 +1,24 @@
 use serde::{Serialize, Deserialize};
 use uuid::Uuid;
 use chrono::{DateTime, Utc};
+use thiserror::Error;
+use crate::crypto::signature::{SigningKey, verify_signature};
+
+/// Domain separation prefix of the signed envelope encoding
+const SIGNING_DOMAIN: &[u8] = b"enigma-message-v1";
+
+/// Errors raised when checking the authenticity of a message
+#[derive(Debug, Error)]
+pub enum MessageError {
+    #[error("message {0} is not signed")]
+    Unsigned(Uuid),
+    #[error("invalid signature on message {id} from {sender}")]
+    InvalidSignature { id: Uuid, sender: String },
+    #[error("no known identity for sender {0}")]
+    UnknownSender(String),
+    #[error("{0} presented an identity key that differs from the known one")]
+    IdentityMismatch(String),
+}
 
 /// Type of message being sent
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -14,6 +32,13 @@ pub enum MessageType {
     CallAnswer,
     CallHangup,
     GroupInvite,
+    SenderKeyDistribution, // Group sender key, sent over pairwise sessions
+    MlsWelcome,            // MLS group secrets for new members, sent over pairwise sessions
+    MlsCommit,             // MLS epoch change, sent to the group
+    MlsProposal,           // MLS membership change awaiting a commit, sent to the group
+    GroupOperation,        // Signed group administration change, sent over pairwise sessions
+    ChannelBacklog,        // Latest posts of a channel for a new subscriber, sent over pairwise sessions
+    GroupInviteReply,      // Acceptance, refusal or rejection of a group invite, sent over pairwise sessions
 }
 
 /// Represents a payload transmitted between users
@@ -28,3 +53,83 @@ pub struct Message {
     pub nonce: Vec<u8>,             // Nonce used during encryption
     pub signature: Option<Vec<u8>>, // Optional signature (if applicable)
 }
+
+impl MessageType {
+    /// Stable numeric tag used in the signed encoding
+    pub fn code(&self) -> u8 {
+        match self {
+            MessageType::Text => 0,
+            MessageType::File => 1,
+            MessageType::Image => 2,
+            MessageType::Voice => 3,
+            MessageType::Video => 4,
+            MessageType::CallOffer => 5,
+            MessageType::CallAnswer => 6,
+            MessageType::CallHangup => 7,
+            MessageType::GroupInvite => 8,
+            MessageType::SenderKeyDistribution => 9,
+            MessageType::MlsWelcome => 10,
+            MessageType::MlsCommit => 11,
+            MessageType::MlsProposal => 12,
+            MessageType::GroupOperation => 13,
+            MessageType::ChannelBacklog => 14,
+            MessageType::GroupInviteReply => 15,
+        }
+    }
+
+    /// Protocol messages handled by the core, never shown in a conversation
+    pub fn is_control(&self) -> bool {
+        matches!(
+            self,
+            MessageType::SenderKeyDistribution
+                | MessageType::MlsWelcome
+                | MessageType::MlsCommit
+                | MessageType::MlsProposal
+                | MessageType::GroupOperation
+                | MessageType::ChannelBacklog
+                | MessageType::GroupInviteReply
+        )
+    }
+}
+
+/// Appends a field prefixed with its length, so that field boundaries are unambiguous
+pub(crate) fn put_field(out: &mut Vec<u8>, field: &[u8]) {
+    out.extend_from_slice(&(field.len() as u32).to_be_bytes());
+    out.extend_from_slice(field);
+}
+
+impl Message {
+    /// Canonical byte encoding of the envelope covered by the signature
+    /// (id, sender, receiver, timestamp, type, payload)
+    pub fn signing_payload(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(64 + self.encrypted_payload.len());
+        put_field(&mut out, SIGNING_DOMAIN);
+        put_field(&mut out, self.id.as_bytes());
+        put_field(&mut out, self.sender.as_bytes());
+        put_field(&mut out, self.receiver.as_bytes());
+        out.extend_from_slice(&self.timestamp.timestamp().to_be_bytes());
+        out.extend_from_slice(&self.timestamp.timestamp_subsec_nanos().to_be_bytes());
+        out.push(self.msg_type.code());
+        put_field(&mut out, &self.encrypted_payload);
+        out
+    }
+
+    /// Signs the envelope with the sender's identity key
+    pub fn sign(&mut self, key: &SigningKey) {
+        self.signature = Some(key.sign(&self.signing_payload()).as_ref().to_vec());
+    }
+
+    /// Verifies the envelope signature against the sender's Ed25519 public key
+    pub fn verify(&self, signing_public_key: &[u8]) -> Result<(), MessageError> {
+        let signature = self
+            .signature
+            .as_ref()
+            .ok_or(MessageError::Unsigned(self.id))?;
+        verify_signature(signing_public_key, &self.signing_payload(), signature).map_err(|_| {
+            MessageError::InvalidSignature {
+                id: self.id,
+                sender: self.sender.clone(),
+            }
+        })
+    }
+}
@@ -0,0 +1,84 @@
+#[cfg(test)]
+mod tests {
+    use super::super::message::{Message, MessageError, MessageType};
+    use crate::crypto::signature::SigningKey;
+    use uuid::Uuid;
+
+    fn sample() -> Message {
+        Message {
+            id: Uuid::new_v4(),
+            sender: "@alice".to_string(),
+            receiver: "@bob".to_string(),
+            timestamp: chrono::Utc::now(),
+            msg_type: MessageType::Text,
+            encrypted_payload: vec![1, 2, 3, 4],
+            nonce: vec![0; 12],
+            signature: None,
+        }
+    }
+
+    // A signed message verifies with the sender's key and not with another one
+    #[test]
+    fn test_sign_and_verify() {
+        let key = SigningKey::generate().unwrap();
+        let other = SigningKey::generate().unwrap();
+
+        let mut msg = sample();
+        msg.sign(&key);
+
+        assert!(msg.verify(key.public_key_bytes()).is_ok());
+        assert!(matches!(
+            msg.verify(other.public_key_bytes()),
+            Err(MessageError::InvalidSignature { .. })
+        ));
+    }
+
+    // Every envelope field is covered by the signature
+    #[test]
+    fn test_tampered_fields_are_rejected() {
+        let key = SigningKey::generate().unwrap();
+        let mut msg = sample();
+        msg.sign(&key);
+
+        let mut forged = msg.clone();
+        forged.sender = "@mallory".to_string();
+        assert!(forged.verify(key.public_key_bytes()).is_err());
+
+        let mut forged = msg.clone();
+        forged.receiver = "@carol".to_string();
+        assert!(forged.verify(key.public_key_bytes()).is_err());
+
+        let mut forged = msg.clone();
+        forged.timestamp += chrono::Duration::seconds(1);
+        assert!(forged.verify(key.public_key_bytes()).is_err());
+
+        let mut forged = msg.clone();
+        forged.msg_type = MessageType::File;
+        assert!(forged.verify(key.public_key_bytes()).is_err());
+
+        let mut forged = msg.clone();
+        forged.encrypted_payload.push(5);
+        assert!(forged.verify(key.public_key_bytes()).is_err());
+    }
+
+    // Moving bytes between sender and receiver changes the encoding
+    #[test]
+    fn test_encoding_is_unambiguous() {
+        let mut a = sample();
+        a.sender = "@ab".to_string();
+        a.receiver = "c".to_string();
+
+        let mut b = a.clone();
+        b.sender = "@a".to_string();
+        b.receiver = "bc".to_string();
+
+        assert_ne!(a.signing_payload(), b.signing_payload());
+    }
+
+    // Unsigned messages are rejected
+    #[test]
+    fn test_unsigned_rejected() {
+        let key = SigningKey::generate().unwrap();
+        assert!(matches!(sample().verify(key.public_key_bytes()), Err(MessageError::Unsigned(_))));
+    }
+}
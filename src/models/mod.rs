@@ -0,0 +1,13 @@
+pub mod user;
+pub mod message;
+pub mod group;
+pub mod conversation;
+pub mod contact;
+pub mod device;
+pub mod invite;
+#[cfg(test)]
+mod message_tests;
+#[cfg(test)]
+mod group_tests;
+#[cfg(test)]
+mod invite_tests;
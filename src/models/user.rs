@@ -1,8 +1,24 @@
 use serde::{Serialize, Deserialize};
 use uuid::Uuid;
+use thiserror::Error;
+use crate::crypto::signature::{SigningKey, verify_signature};
+use crate::models::device::DeviceId;
+use crate::models::message::put_field;
+
+/// Domain separation prefix of the signed identity encoding
+const IDENTITY_DOMAIN: &[u8] = b"enigma-identity-v1";
+
+/// Errors raised when checking a public identity
+#[derive(Debug, Error)]
+pub enum IdentityError {
+    #[error("identity of {0} is not signed")]
+    Unsigned(String),
+    #[error("invalid self-signature on the identity of {0}")]
+    InvalidSignature(String),
+}
 
 /// Public representation of a user in the system.
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct PublicIdentity {
     pub username: String,                // Unique @user identifier
     pub signing_public_key: Vec<u8>,    // Ed25519 public key (for signatures)
@@ -11,22 +27,37 @@ pub struct PublicIdentity {
 }
 
 /// Local representation of a user (includes keys and private data).
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct LocalUser {
     pub uuid: Uuid,                         // Local stable ID
     pub username: String,                   // Chosen @user
+    pub device_id: DeviceId,                // This device within the account
+    pub device_name: String,                // Name shown in the device list
     pub signing_private_key: Vec<u8>,       // Ed25519 private key (PKCS#8 format)
-    pub encryption_private_key: Vec<u8>,    // X25519 private key
-    pub encryption_public_key: Vec<u8>,     // X25519 public key
 }
 
 impl PublicIdentity {
-    /// Returns the signed message content (username + keys).
+    /// Returns the signed message content: length-prefixed (username, signing key, encryption key).
     pub fn signed_payload(&self) -> Vec<u8> {
-        let mut data = vec![];
-        data.extend(self.username.as_bytes());
-        data.extend(&self.signing_public_key);
-        data.extend(&self.encryption_public_key);
+        let mut data = Vec::with_capacity(32 + self.username.len() + 64);
+        put_field(&mut data, IDENTITY_DOMAIN);
+        put_field(&mut data, self.username.as_bytes());
+        put_field(&mut data, &self.signing_public_key);
+        put_field(&mut data, &self.encryption_public_key);
         data
     }
+
+    /// Self-signs the identity with the key matching `signing_public_key`.
+    pub fn sign(&mut self, key: &SigningKey) {
+        self.signature = key.sign(&self.signed_payload()).as_ref().to_vec();
+    }
+
+    /// Checks the self-signature against `signing_public_key`.
+    pub fn verify(&self) -> Result<(), IdentityError> {
+        if self.signature.is_empty() {
+            return Err(IdentityError::Unsigned(self.username.clone()));
+        }
+        verify_signature(&self.signing_public_key, &self.signed_payload(), &self.signature)
+            .map_err(|_| IdentityError::InvalidSignature(self.username.clone()))
+    }
 }
@@ -0,0 +1,196 @@
+use crate::crypto::handshake::X3DHBundle;
+use crate::models::device::{DeviceId, DeviceList};
+use crate::models::user::PublicIdentity;
+use async_trait::async_trait;
+use reqwest::Client;
+use serde::{Serialize, Deserialize};
+use anyhow::{Result, anyhow};
+use std::time::Duration;
+
+/// Seed nodes used when no node list is configured
+pub const DEFAULT_NODES: &[&str] = &[
+    "https://node1.enigma.net:1488",
+    "https://node2.enigma.org:1488",
+];
+
+/// Source of X3DH prekey bundles, identities and device lists (the signaling nodes, or a mock in tests)
+#[async_trait]
+pub trait PreKeyDirectory: Send + Sync {
+    /// Fetches the bundle of one of a peer's devices; nodes hand out at most one one-time
+    /// prekey per fetch
+    async fn fetch_bundle(&self, username: &str, device: DeviceId) -> Result<X3DHBundle>;
+
+    /// Publishes (or replenishes) the bundle of one of our devices
+    async fn publish_bundle(&self, username: &str, device: DeviceId, bundle: &X3DHBundle) -> Result<()>;
+
+    /// Fetches a peer's device list (to be verified by the caller)
+    async fn fetch_devices(&self, username: &str) -> Result<DeviceList>;
+
+    /// Publishes our signed device list
+    async fn publish_devices(&self, devices: &DeviceList) -> Result<()>;
+
+    /// Fetches a peer's self-signed identity
+    async fn fetch_identity(&self, username: &str) -> Result<PublicIdentity>;
+
+    /// Registers our self-signed identity
+    async fn publish_identity(&self, identity: &PublicIdentity) -> Result<()>;
+}
+
+/// Identity as exchanged with the nodes (keys and signature hex-encoded)
+#[derive(Serialize, Deserialize)]
+struct NodeIdentity {
+    username: String,
+    public_key: String,
+    encryption_public_key: String,
+    signature: String,
+    timestamp: u64,
+}
+
+impl NodeIdentity {
+    fn from_identity(identity: &PublicIdentity) -> Self {
+        Self {
+            username: identity.username.clone(),
+            public_key: hex::encode(&identity.signing_public_key),
+            encryption_public_key: hex::encode(&identity.encryption_public_key),
+            signature: hex::encode(&identity.signature),
+            timestamp: chrono::Utc::now().timestamp() as u64,
+        }
+    }
+
+    fn into_identity(self) -> Result<PublicIdentity> {
+        Ok(PublicIdentity {
+            username: self.username,
+            signing_public_key: hex::decode(self.public_key)?,
+            encryption_public_key: hex::decode(self.encryption_public_key)?,
+            signature: hex::decode(self.signature)?,
+        })
+    }
+}
+
+/// Prekey directory backed by the `/bundle` and `/prekeys` endpoints of the nodes
+pub struct NodeDirectory {
+    client: Client,
+    nodes: Vec<String>,
+}
+
+impl NodeDirectory {
+    /// Creates a directory querying the given nodes in order
+    pub fn new(nodes: Vec<String>) -> Result<Self> {
+        let client = Client::builder()
+            .timeout(Duration::from_secs(5))
+            .build()?;
+        Ok(Self { client, nodes })
+    }
+
+    /// Creates a directory on the default seed nodes
+    pub fn with_default_nodes() -> Result<Self> {
+        Self::new(DEFAULT_NODES.iter().map(|n| n.to_string()).collect())
+    }
+}
+
+#[async_trait]
+impl PreKeyDirectory for NodeDirectory {
+    async fn fetch_bundle(&self, username: &str, device: DeviceId) -> Result<X3DHBundle> {
+        for node in &self.nodes {
+            let url = format!("{}/bundle/{}/{}", node.trim_end_matches('/'), username, device);
+            match self.client.get(&url).send().await {
+                Ok(resp) if resp.status().is_success() => {
+                    if let Ok(bundle) = resp.json::<X3DHBundle>().await {
+                        return Ok(bundle);
+                    }
+                }
+                _ => continue,
+            }
+        }
+        Err(anyhow!("No node could provide a bundle for {}", username))
+    }
+
+    async fn publish_bundle(&self, username: &str, device: DeviceId, bundle: &X3DHBundle) -> Result<()> {
+        let mut published = false;
+        for node in &self.nodes {
+            let url = format!("{}/prekeys/{}/{}", node.trim_end_matches('/'), username, device);
+            if let Ok(resp) = self.client.post(&url).json(bundle).send().await {
+                published |= resp.status().is_success();
+            }
+        }
+        if published {
+            Ok(())
+        } else {
+            Err(anyhow!("No node accepted the prekey bundle"))
+        }
+    }
+
+    async fn fetch_identity(&self, username: &str) -> Result<PublicIdentity> {
+        for node in &self.nodes {
+            let url = format!("{}/resolve/{}", node.trim_end_matches('/'), username);
+            let identity = match self.client.get(&url).send().await {
+                Ok(resp) if resp.status().is_success() => match resp.json::<NodeIdentity>().await {
+                    Ok(raw) => raw.into_identity(),
+                    Err(_) => continue,
+                },
+                _ => continue,
+            };
+            // A node could be malicious: only accept an identity signed for this username
+            match identity {
+                Ok(identity) if identity.username == username && identity.verify().is_ok() => {
+                    return Ok(identity)
+                }
+                _ => continue,
+            }
+        }
+        Err(anyhow!("No node could provide a valid identity for {}", username))
+    }
+
+    async fn publish_identity(&self, identity: &PublicIdentity) -> Result<()> {
+        let body = NodeIdentity::from_identity(identity);
+        let mut published = false;
+        for node in &self.nodes {
+            let url = format!("{}/register", node.trim_end_matches('/'));
+            if let Ok(resp) = self.client.post(&url).json(&body).send().await {
+                published |= resp.status().is_success();
+            }
+        }
+        if published {
+            Ok(())
+        } else {
+            Err(anyhow!("No node accepted the identity"))
+        }
+    }
+
+    async fn fetch_devices(&self, username: &str) -> Result<DeviceList> {
+        // Nodes only store the lists: keep the newest one that is signed for this account
+        let mut newest: Option<DeviceList> = None;
+        for node in &self.nodes {
+            let url = format!("{}/devices/{}", node.trim_end_matches('/'), username);
+            let devices = match self.client.get(&url).send().await {
+                Ok(resp) if resp.status().is_success() => match resp.json::<DeviceList>().await {
+                    Ok(devices) => devices,
+                    Err(_) => continue,
+                },
+                _ => continue,
+            };
+            if devices.username() == username
+                && devices.verify().is_ok()
+                && newest.as_ref().is_none_or(|n| devices.version > n.version)
+            {
+                newest = Some(devices);
+            }
+        }
+        newest.ok_or_else(|| anyhow!("No node could provide a valid device list for {}", username))
+    }
+
+    async fn publish_devices(&self, devices: &DeviceList) -> Result<()> {
+        let mut published = false;
+        for node in &self.nodes {
+            let url = format!("{}/devices/{}", node.trim_end_matches('/'), devices.username());
+            if let Ok(resp) = self.client.post(&url).json(devices).send().await {
+                published |= resp.status().is_success();
+            }
+        }
+        if published {
+            Ok(())
+        } else {
+            Err(anyhow!("No node accepted the device list"))
+        }
+    }
+}
@@ -1,5 +1,5 @@
 use reqwest::Client;
-use anyhow::{Result, Context};
+use anyhow::Result;
 use std::collections::HashSet;
 use std::time::Duration;
 
@@ -1,3 +1,4 @@
 pub mod webrtc_client;
 pub mod signaling;
 pub mod discovery;
+pub mod directory;
@@ -4,17 +4,15 @@ use webrtc::peer_connection::RTCPeerConnection;
 use webrtc::data_channel::RTCDataChannel;
 use webrtc::data_channel::data_channel_message::DataChannelMessage;
 use webrtc::data_channel::data_channel_init::RTCDataChannelInit;
-use webrtc::ice_transport::ice_candidate::RTCIceCandidate;
+use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
 use webrtc::ice_transport::ice_server::RTCIceServer;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
-use webrtc::peer_connection::sdp::session_description::RTCSessionDescriptionSerde;
 use webrtc::api::media_engine::MediaEngine;
 use webrtc::api::interceptor_registry::register_default_interceptors;
-use webrtc::api::interceptor_registry::Registry;
-use webrtc::api::API;
+use webrtc::interceptor::registry::Registry;
 
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::sync::mpsc::UnboundedSender;
 use anyhow::Result;
 use async_trait::async_trait;
 
@@ -22,6 +20,9 @@ use async_trait::async_trait;
 #[async_trait]
 pub trait WebRTC: Send + Sync {
     async fn send_message(&self, data: &[u8]) -> Result<()>;
+
+    /// Forwards every raw message received on the data channels to `inbound`
+    fn on_message(&self, inbound: UnboundedSender<Vec<u8>>);
 }
 
 /// Represents a WebRTC client capable of establishing peer-to-peer connections.
@@ -85,8 +86,16 @@ impl WebRTCClient {
         Ok(())
     }
 
+    /// Forwards messages of one data channel to the inbound queue.
+    fn forward_messages(channel: &Arc<RTCDataChannel>, inbound: UnboundedSender<Vec<u8>>) {
+        channel.on_message(Box::new(move |msg: DataChannelMessage| {
+            let _ = inbound.send(msg.data.to_vec());
+            Box::pin(async {})
+        }));
+    }
+
     /// Adds a remote ICE candidate.
-    pub async fn add_ice_candidate(&self, candidate: RTCIceCandidate) -> Result<()> {
+    pub async fn add_ice_candidate(&self, candidate: RTCIceCandidateInit) -> Result<()> {
         self.peer_connection.add_ice_candidate(candidate).await?;
         Ok(())
     }
@@ -96,12 +105,18 @@ impl WebRTCClient {
 #[async_trait]
 impl WebRTC for WebRTCClient {
     async fn send_message(&self, data: &[u8]) -> Result<()> {
-        self.data_channel.send(&DataChannelMessage {
-            is_binary: true,
-            data: data.to_vec(),
-        }).await?;
+        self.data_channel.send(&data.to_vec().into()).await?;
         Ok(())
     }
+
+    fn on_message(&self, inbound: UnboundedSender<Vec<u8>>) {
+        // Our own channel, plus any channel opened by the remote peer
+        Self::forward_messages(&self.data_channel, inbound.clone());
+        self.peer_connection.on_data_channel(Box::new(move |channel: Arc<RTCDataChannel>| {
+            Self::forward_messages(&channel, inbound.clone());
+            Box::pin(async {})
+        }));
+    }
 }
 
  
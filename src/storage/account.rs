@@ -0,0 +1,97 @@
+use crate::crypto::handshake::KeyMaterial;
+use crate::models::device::DeviceList;
+use crate::models::user::LocalUser;
+use crate::storage::persistence::Persistence;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+const USER_KEY: &[u8] = b"account/user";
+const KEYS_KEY: &[u8] = b"account/keys";
+const DEVICES_KEY: &[u8] = b"account/devices";
+const PENDING_LINK_KEY: &[u8] = b"account/pending_link";
+
+/// Keys of a new device waiting to be linked to its account, kept across restarts.
+#[derive(Serialize, Deserialize)]
+pub struct PendingDevice {
+    pub username: String,
+    pub device_name: String,
+    pub keys: KeyMaterial,
+    pub account_key: Option<Vec<u8>>, // Pinned from the approval of the primary device
+}
+
+/// Persists the local account: user profile and private key material.
+pub struct AccountStore {
+    persistence: Arc<Persistence>,
+}
+
+impl AccountStore {
+    /// Creates an account store on top of the given persistence layer.
+    pub fn new(persistence: Arc<Persistence>) -> Self {
+        Self { persistence }
+    }
+
+    /// Whether an account was already created in this database.
+    pub fn exists(&self) -> Result<bool> {
+        Ok(self.load_user()?.is_some())
+    }
+
+    /// Saves the local user profile.
+    pub fn save_user(&self, user: &LocalUser) -> Result<()> {
+        self.persistence.put(USER_KEY, user)?;
+        self.persistence.flush()
+    }
+
+    /// Loads the local user profile, if the account exists.
+    pub fn load_user(&self) -> Result<Option<LocalUser>> {
+        let Some(raw) = self.persistence.get_raw(USER_KEY)? else {
+            return Ok(None);
+        };
+        let user: LocalUser = bincode::deserialize(&raw)?;
+        // Profiles of older versions also held a copy of the signed prekey: rewrite them
+        // without it
+        if bincode::serialized_size(&user)? != raw.len() as u64 {
+            self.save_user(&user)?;
+        }
+        Ok(Some(user))
+    }
+
+    /// Saves the identity key, signed prekey and one-time prekeys.
+    pub fn save_keys(&self, keys: &KeyMaterial) -> Result<()> {
+        self.persistence.put(KEYS_KEY, keys)?;
+        self.persistence.flush()
+    }
+
+    /// Loads the key material saved with `save_keys`.
+    pub fn load_keys(&self) -> Result<Option<KeyMaterial>> {
+        self.persistence.get(KEYS_KEY)
+    }
+
+    /// Saves the signed device list of the account.
+    pub fn save_devices(&self, devices: &DeviceList) -> Result<()> {
+        self.persistence.put(DEVICES_KEY, devices)?;
+        self.persistence.flush()
+    }
+
+    /// Loads the device list saved with `save_devices`.
+    pub fn load_devices(&self) -> Result<Option<DeviceList>> {
+        self.persistence.get(DEVICES_KEY)
+    }
+
+    /// Saves the keys of a device being linked.
+    pub fn save_pending_link(&self, pending: &PendingDevice) -> Result<()> {
+        self.persistence.put(PENDING_LINK_KEY, pending)?;
+        self.persistence.flush()
+    }
+
+    /// Loads the keys saved with `save_pending_link`, if a link is in progress.
+    pub fn load_pending_link(&self) -> Result<Option<PendingDevice>> {
+        self.persistence.get(PENDING_LINK_KEY)
+    }
+
+    /// Forgets the pending link once the account is saved.
+    pub fn delete_pending_link(&self) -> Result<()> {
+        self.persistence.delete(PENDING_LINK_KEY)?;
+        self.persistence.flush()
+    }
+}
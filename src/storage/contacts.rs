@@ -0,0 +1,143 @@
+use crate::models::contact::{Contact, TrustState};
+use crate::models::user::PublicIdentity;
+use crate::storage::persistence::Persistence;
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use std::sync::Arc;
+
+const CONTACT_PREFIX: &str = "contact/";
+
+/// Outcome of seeing a peer's identity
+#[derive(Debug, Clone)]
+pub enum IdentityObservation {
+    /// First time we see this peer: trusted on first use
+    New(Contact),
+    /// Same signing key as before
+    Known(Contact),
+    /// The signing key differs from the stored one
+    KeyChanged { contact: Contact, previous_key: Vec<u8> },
+}
+
+/// Persists the contact book: peers' identities and how much we trust them.
+pub struct ContactStore {
+    persistence: Arc<Persistence>,
+}
+
+impl ContactStore {
+    /// Creates a contact store on top of the given persistence layer.
+    pub fn new(persistence: Arc<Persistence>) -> Self {
+        Self { persistence }
+    }
+
+    fn key(username: &str) -> Vec<u8> {
+        format!("{}{}", CONTACT_PREFIX, username).into_bytes()
+    }
+
+    /// Saves a contact.
+    pub fn save(&self, contact: &Contact) -> Result<()> {
+        self.persistence.put(&Self::key(&contact.username), contact)
+    }
+
+    /// Loads a contact, if any.
+    pub fn get(&self, username: &str) -> Result<Option<Contact>> {
+        self.persistence.get(&Self::key(username))
+    }
+
+    /// All contacts, sorted by username.
+    pub fn list(&self) -> Result<Vec<Contact>> {
+        let mut contacts: Vec<Contact> = self
+            .persistence
+            .scan_prefix::<Contact>(CONTACT_PREFIX.as_bytes())?
+            .into_iter()
+            .map(|(_, c)| c)
+            .collect();
+        contacts.sort_by(|a, b| a.username.cmp(&b.username));
+        Ok(contacts)
+    }
+
+    /// Records the identity a peer presented (TOFU): new peers are added as unverified,
+    /// and a different signing key marks the contact as changed.
+    /// Identities whose self-signature does not validate are rejected.
+    pub fn observe(&self, identity: &PublicIdentity) -> Result<IdentityObservation> {
+        identity.verify()?;
+
+        let mut contact = match self.get(&identity.username)? {
+            Some(contact) => contact,
+            None => {
+                let contact = Contact::new(identity.clone());
+                self.save(&contact)?;
+                return Ok(IdentityObservation::New(contact));
+            }
+        };
+
+        if contact.identity.signing_public_key == identity.signing_public_key {
+            return Ok(IdentityObservation::Known(contact));
+        }
+
+        let previous_key = contact.identity.signing_public_key.clone();
+        contact.previous_keys.push(previous_key.clone());
+        contact.identity = identity.clone();
+        contact.trust = TrustState::Changed;
+        contact.key_changed_at = Some(Utc::now());
+        self.save(&contact)?;
+        Ok(IdentityObservation::KeyChanged { contact, previous_key })
+    }
+
+    /// Holds a different identity presented by a known contact without trusting it: the
+    /// current identity stays in use until the user approves the new one.
+    pub fn hold_changed(&self, identity: &PublicIdentity) -> Result<Contact> {
+        identity.verify()?;
+        self.update(&identity.username, |c| {
+            c.pending_identity = Some(identity.clone());
+            c.trust = TrustState::Changed;
+        })
+    }
+
+    /// Replaces the identity of a contact by the one held by `hold_changed`, as approved by
+    /// the user; the contact is unverified until checked again.
+    pub fn approve_pending(&self, username: &str) -> Result<Contact> {
+        let mut contact = self.get(username)?.ok_or_else(|| anyhow!("Unknown contact {}", username))?;
+        let identity = contact
+            .pending_identity
+            .take()
+            .ok_or_else(|| anyhow!("No identity change to approve for {}", username))?;
+        contact.previous_keys.push(contact.identity.signing_public_key.clone());
+        contact.identity = identity;
+        contact.trust = TrustState::Unverified;
+        contact.key_changed_at = Some(Utc::now());
+        self.save(&contact)?;
+        Ok(contact)
+    }
+
+    /// Applies a change to an existing contact and saves it.
+    pub fn update(&self, username: &str, change: impl FnOnce(&mut Contact)) -> Result<Contact> {
+        let mut contact = self.get(username)?.ok_or_else(|| anyhow!("Unknown contact {}", username))?;
+        change(&mut contact);
+        self.save(&contact)?;
+        Ok(contact)
+    }
+
+    /// Sets the local nickname of a contact.
+    pub fn set_display_name(&self, username: &str, name: Option<String>) -> Result<Contact> {
+        self.update(username, |c| c.display_name = name)
+    }
+
+    /// Marks the current identity key of a contact as verified out of band.
+    pub fn mark_verified(&self, username: &str) -> Result<Contact> {
+        self.update(username, |c| c.trust = TrustState::Verified)
+    }
+
+    /// Accepts a changed key without verifying it: the contact goes back to unverified.
+    pub fn acknowledge_change(&self, username: &str) -> Result<Contact> {
+        self.update(username, |c| {
+            if c.trust == TrustState::Changed {
+                c.trust = TrustState::Unverified;
+            }
+        })
+    }
+
+    /// Removes a contact.
+    pub fn delete(&self, username: &str) -> Result<()> {
+        self.persistence.delete(&Self::key(username))
+    }
+}
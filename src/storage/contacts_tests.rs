@@ -0,0 +1,102 @@
+#[cfg(test)]
+mod tests {
+    use super::super::contacts::{ContactStore, IdentityObservation};
+    use super::super::persistence::Persistence;
+    use crate::models::contact::TrustState;
+    use crate::crypto::signature::SigningKey;
+    use crate::models::user::PublicIdentity;
+    use std::fs;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    fn identity(username: &str, key: &SigningKey) -> PublicIdentity {
+        let mut identity = PublicIdentity {
+            username: username.to_owned(),
+            signing_public_key: key.public_key_bytes().to_vec(),
+            encryption_public_key: vec![9u8; 32],
+            signature: vec![],
+        };
+        identity.sign(key);
+        identity
+    }
+
+    // Trust on first use, then key change detection, verification and acknowledgement
+    #[test]
+    fn test_trust_states() {
+        let test_path = "test_data/contacts_trust";
+        if Path::new(test_path).exists() {
+            fs::remove_dir_all(test_path).unwrap();
+        }
+        let persistence = Persistence::open(test_path).unwrap();
+        persistence.unlock_with_key(&[8u8; 32]).unwrap();
+        let store = ContactStore::new(Arc::new(persistence));
+        let old_key = SigningKey::generate().unwrap();
+        let new_key = SigningKey::generate().unwrap();
+
+        assert!(matches!(store.observe(&identity("@bob", &old_key)).unwrap(), IdentityObservation::New(_)));
+        assert!(matches!(store.observe(&identity("@bob", &old_key)).unwrap(), IdentityObservation::Known(_)));
+        let first_seen = store.get("@bob").unwrap().unwrap().first_seen;
+
+        store.mark_verified("@bob").unwrap();
+        store.set_display_name("@bob", Some("Bobby".to_owned())).unwrap();
+
+        match store.observe(&identity("@bob", &new_key)).unwrap() {
+            IdentityObservation::KeyChanged { contact, previous_key } => {
+                assert_eq!(previous_key, old_key.public_key_bytes());
+                assert_eq!(contact.trust, TrustState::Changed);
+                assert_eq!(contact.identity.signing_public_key, new_key.public_key_bytes());
+                assert_eq!(contact.first_seen, first_seen);
+                assert_eq!(contact.name(), "Bobby");
+            }
+            other => panic!("Key change not detected: {:?}", other),
+        }
+
+        assert_eq!(store.acknowledge_change("@bob").unwrap().trust, TrustState::Unverified);
+        assert!(store.mark_verified("@nobody").is_err());
+
+        // Another identity is only held until the user approves it
+        let third_key = SigningKey::generate().unwrap();
+        let held = store.hold_changed(&identity("@bob", &third_key)).unwrap();
+        assert_eq!(held.identity.signing_public_key, new_key.public_key_bytes());
+        assert_eq!(held.trust, TrustState::Changed);
+        let approved = store.approve_pending("@bob").unwrap();
+        assert_eq!(approved.identity.signing_public_key, third_key.public_key_bytes());
+        assert_eq!(approved.previous_keys.len(), 2);
+        assert!(store.approve_pending("@bob").is_err(), "Nothing left to approve");
+        assert_eq!(store.list().unwrap().len(), 1);
+
+        drop(store);
+        fs::remove_dir_all(test_path).unwrap();
+    }
+
+    // Unsigned or forged identities never reach the contact book
+    #[test]
+    fn test_rejects_invalid_identities() {
+        let test_path = "test_data/contacts_forged";
+        if Path::new(test_path).exists() {
+            fs::remove_dir_all(test_path).unwrap();
+        }
+        let persistence = Persistence::open(test_path).unwrap();
+        persistence.unlock_with_key(&[8u8; 32]).unwrap();
+        let store = ContactStore::new(Arc::new(persistence));
+        let key = SigningKey::generate().unwrap();
+
+        let mut unsigned = identity("@bob", &key);
+        unsigned.signature.clear();
+        assert!(store.observe(&unsigned).is_err());
+
+        // A valid signature does not carry over to another username
+        let mut renamed = identity("@bob", &key);
+        renamed.username = "@mallory".to_owned();
+        assert!(store.observe(&renamed).is_err());
+
+        let mut swapped = identity("@bob", &key);
+        swapped.encryption_public_key = vec![6u8; 32];
+        assert!(store.observe(&swapped).is_err());
+
+        assert!(store.list().unwrap().is_empty());
+
+        drop(store);
+        fs::remove_dir_all(test_path).unwrap();
+    }
+}
@@ -0,0 +1,68 @@
+use crate::models::conversation::Conversation;
+use crate::storage::persistence::Persistence;
+use anyhow::{anyhow, Result};
+use std::sync::Arc;
+
+const CONVERSATION_PREFIX: &str = "conversation_list/";
+
+/// Persists the conversation list shown on the home screen.
+pub struct ConversationStore {
+    persistence: Arc<Persistence>,
+}
+
+impl ConversationStore {
+    /// Creates a conversation store on top of the given persistence layer.
+    pub fn new(persistence: Arc<Persistence>) -> Self {
+        Self { persistence }
+    }
+
+    fn key(id: &str) -> Vec<u8> {
+        format!("{}{}", CONVERSATION_PREFIX, id).into_bytes()
+    }
+
+    /// Saves a conversation.
+    pub fn save(&self, conversation: &Conversation) -> Result<()> {
+        self.persistence.put(&Self::key(&conversation.id), conversation)
+    }
+
+    /// Loads a conversation, if any.
+    pub fn get(&self, id: &str) -> Result<Option<Conversation>> {
+        self.persistence.get(&Self::key(id))
+    }
+
+    /// Loads a conversation, or creates it with `create` (not saved yet).
+    pub fn get_or(&self, id: &str, create: impl FnOnce() -> Conversation) -> Result<Conversation> {
+        Ok(self.get(id)?.unwrap_or_else(create))
+    }
+
+    /// Conversations sorted by activity: pinned first, then most recent first.
+    /// Archived conversations are only listed when `include_archived` is set.
+    pub fn list(&self, include_archived: bool) -> Result<Vec<Conversation>> {
+        let mut conversations: Vec<Conversation> = self
+            .persistence
+            .scan_prefix::<Conversation>(CONVERSATION_PREFIX.as_bytes())?
+            .into_iter()
+            .map(|(_, c)| c)
+            .filter(|c| include_archived || !c.archived)
+            .collect();
+        conversations.sort_by(|a, b| {
+            b.pinned
+                .cmp(&a.pinned)
+                .then(b.last_activity.cmp(&a.last_activity))
+        });
+        Ok(conversations)
+    }
+
+    /// Applies a change to an existing conversation and saves it.
+    pub fn update(&self, id: &str, change: impl FnOnce(&mut Conversation)) -> Result<Conversation> {
+        let mut conversation = self.get(id)?.ok_or_else(|| anyhow!("Unknown conversation {}", id))?;
+        change(&mut conversation);
+        self.save(&conversation)?;
+        Ok(conversation)
+    }
+
+    /// Removes a conversation from the list.
+    pub fn delete(&self, id: &str) -> Result<()> {
+        self.persistence.delete(&Self::key(id))
+    }
+}
@@ -0,0 +1,69 @@
+#[cfg(test)]
+mod tests {
+    use super::super::conversations::ConversationStore;
+    use super::super::persistence::Persistence;
+    use crate::models::conversation::{Conversation, PREVIEW_LEN};
+    use crate::models::message::{Message, MessageType};
+    use chrono::{Duration, Utc};
+    use std::fs;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    fn message(text_type: MessageType, seconds_ago: i64) -> Message {
+        Message {
+            id: uuid::Uuid::new_v4(),
+            sender: "@bob".to_owned(),
+            receiver: "@me".to_owned(),
+            timestamp: Utc::now() - Duration::seconds(seconds_ago),
+            msg_type: text_type,
+            encrypted_payload: vec![],
+            nonce: vec![],
+            signature: None,
+        }
+    }
+
+    // The list is sorted by activity, pinned conversations first, archived ones hidden
+    #[test]
+    fn test_list_sorted_by_activity() {
+        let test_path = "test_data/conversations_list";
+        if Path::new(test_path).exists() {
+            fs::remove_dir_all(test_path).unwrap();
+        }
+        let persistence = Persistence::open(test_path).unwrap();
+        persistence.unlock_with_key(&[6u8; 32]).unwrap();
+        let store = ConversationStore::new(Arc::new(persistence));
+
+        for (peer, seconds_ago) in [("@old", 300), ("@recent", 10), ("@middle", 100), ("@hidden", 1)] {
+            let mut conversation = Conversation::direct(peer);
+            conversation.record(&message(MessageType::Text, seconds_ago), b"hello");
+            store.save(&conversation).unwrap();
+        }
+        store.update("@old", |c| c.pinned = true).unwrap();
+        store.update("@hidden", |c| c.archived = true).unwrap();
+
+        let ids: Vec<String> = store.list(false).unwrap().into_iter().map(|c| c.id).collect();
+        assert_eq!(ids, ["@old", "@recent", "@middle"]);
+        assert_eq!(store.list(true).unwrap().len(), 4);
+        assert!(store.update("@nobody", |c| c.muted = true).is_err());
+
+        drop(store);
+        fs::remove_dir_all(test_path).unwrap();
+    }
+
+    // Previews are truncated text, or a label for non-text messages; older messages don't replace newer ones
+    #[test]
+    fn test_record_preview() {
+        let mut conversation = Conversation::direct("@bob");
+
+        let long = "x".repeat(PREVIEW_LEN * 2);
+        conversation.record(&message(MessageType::Text, 20), long.as_bytes());
+        assert_eq!(conversation.last_message_preview.as_ref().unwrap().len(), PREVIEW_LEN);
+
+        let image = message(MessageType::Image, 10);
+        conversation.record(&image, b"\x89PNG");
+        assert_eq!(conversation.last_message_preview.as_deref(), Some("[Image]"));
+
+        conversation.record(&message(MessageType::Text, 60), b"late delivery");
+        assert_eq!(conversation.last_message_id, Some(image.id));
+    }
+}
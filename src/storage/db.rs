@@ -1,35 +1,98 @@
 use sled::{Db, IVec};
 use std::path::Path;
 use anyhow::{Result, Context};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use crate::storage::persistence::Persistence;
+use crate::storage::vault::{RotationProgress, Vault, VAULT_KEY_LEN};
+
+/// Attempts made to open a database whose lock is still held
+const OPEN_ATTEMPTS: u32 = 20;
+
+/// Opens the sled database at `path`. A database dropped just before is released by its
+/// background flusher only after a moment, so a held lock is retried for a short while.
+pub fn open_db<P: AsRef<Path>>(path: P) -> sled::Result<Db> {
+    let mut attempt = 1;
+    loop {
+        match sled::open(path.as_ref()) {
+            Err(sled::Error::Io(e)) if e.to_string().contains("could not acquire lock") && attempt < OPEN_ATTEMPTS => {
+                attempt += 1;
+                thread::sleep(Duration::from_millis(50));
+            }
+            result => return result,
+        }
+    }
+}
 
 /// Represents the local encrypted storage engine.
+/// The database opens locked: call `unlock` or `unlock_with_key` before any read or write.
 pub struct Storage {
     db: Db,
+    vault: Arc<Vault>,
 }
 
 impl Storage {
     /// Opens or creates a new encrypted database at the specified path.
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let db = sled::open(path).context("Failed to open sled database")?;
-        Ok(Self { db })
+        let db = open_db(path).context("Failed to open sled database")?;
+        let vault = Arc::new(Vault::open(&db)?);
+        Ok(Self { db, vault })
+    }
+
+    /// Unlocks the database with the user passphrase.
+    pub fn unlock(&self, passphrase: &str) -> Result<()> {
+        self.vault.unlock(passphrase)
+    }
+
+    /// Unlocks the database with a platform-provided key.
+    pub fn unlock_with_key(&self, key: &[u8; VAULT_KEY_LEN]) -> Result<()> {
+        self.vault.unlock_with_key(key)
+    }
+
+    /// Locks the database again, forgetting the key.
+    pub fn lock(&self) {
+        self.vault.lock()
+    }
+
+    /// Whether the database can currently be read.
+    pub fn is_unlocked(&self) -> bool {
+        self.vault.is_unlocked()
+    }
+
+    /// Changes the unlock passphrase; only the data key is re-wrapped.
+    pub fn change_passphrase(&self, old: &str, new: &str) -> Result<()> {
+        self.vault.change_passphrase(old, new)
+    }
+
+    /// Replaces the platform-provided key wrapping the data key.
+    pub fn change_key(&self, new_key: &[u8; VAULT_KEY_LEN]) -> Result<()> {
+        self.vault.change_key(new_key)
+    }
+
+    /// Re-encrypts every record with a fresh data key; the storage stays usable meanwhile.
+    pub fn rotate_data_key<F: FnMut(RotationProgress)>(&self, progress: F) -> Result<()> {
+        self.vault.rotate_data_key(progress)
+    }
+
+    /// Typed view over the same database, sharing its sled handle and its key.
+    pub fn persistence(&self) -> Persistence {
+        Persistence::from_db(self.db.clone(), self.vault.clone())
     }
 
     /// Stores a value under the given key.
     pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
-        self.db.insert(key, value)?;
-        Ok(())
+        self.persistence().put_raw(key, value)
     }
 
     /// Retrieves a value for the given key.
     pub fn get(&self, key: &[u8]) -> Result<Option<IVec>> {
-        let result = self.db.get(key)?;
-        Ok(result)
+        Ok(self.persistence().get_raw(key)?.map(IVec::from))
     }
 
     /// Deletes a value for the given key.
     pub fn delete(&self, key: &[u8]) -> Result<()> {
-        self.db.remove(key)?;
-        Ok(())
+        self.persistence().delete(key)
     }
 
     /// Flushes the database to ensure all operations are persisted.
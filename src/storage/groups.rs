@@ -0,0 +1,225 @@
+use crate::crypto::mls::{KeyPackage, KeyPackageSecrets, MlsGroup};
+use crate::crypto::sender_key::{ReceivedSenderKey, SenderKey};
+use crate::models::group::{Group, GroupOperation};
+use crate::models::invite::GroupInvite;
+use crate::storage::persistence::Persistence;
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+use std::sync::Arc;
+use uuid::Uuid;
+
+const GROUP_PREFIX: &str = "group/";
+const BASE_PREFIX: &str = "group_base/";
+const OPERATION_PREFIX: &str = "group_op/";
+const OWN_KEY_PREFIX: &str = "sender_key/own/";
+const PEER_KEY_PREFIX: &str = "sender_key/peer/";
+const MLS_STATE_PREFIX: &str = "mls/group/";
+const KEY_PACKAGE_PREFIX: &str = "mls/key_package/";
+const INVITE_PREFIX: &str = "group_invite/";
+
+/// Our sender key in a group, with the member devices who already received it
+#[derive(Clone, Serialize, Deserialize)]
+pub struct OwnSenderKey {
+    pub key: SenderKey,
+    pub shared_with: Vec<String>, // Device addresses (username/device)
+}
+
+/// One of our published key packages with its private keys
+#[derive(Clone, Serialize, Deserialize)]
+pub struct OwnKeyPackage {
+    pub package: KeyPackage,
+    pub secrets: KeyPackageSecrets,
+}
+
+/// Persists groups with the state we joined them in and the operations applied since, our
+/// sender key in each of them and the sender keys of other members, or our MLS state for
+/// MLS groups, and the invites we received and did not answer yet.
+pub struct GroupStore {
+    persistence: Arc<Persistence>,
+}
+
+impl GroupStore {
+    /// Creates a group store on top of the given persistence layer.
+    pub fn new(persistence: Arc<Persistence>) -> Self {
+        Self { persistence }
+    }
+
+    fn group_key(id: &Uuid) -> Vec<u8> {
+        format!("{}{}", GROUP_PREFIX, id).into_bytes()
+    }
+
+    fn base_key(id: &Uuid) -> Vec<u8> {
+        format!("{}{}", BASE_PREFIX, id).into_bytes()
+    }
+
+    fn operation_prefix(id: &Uuid) -> String {
+        format!("{}{}/", OPERATION_PREFIX, id)
+    }
+
+    fn own_key(id: &Uuid) -> Vec<u8> {
+        format!("{}{}", OWN_KEY_PREFIX, id).into_bytes()
+    }
+
+    fn peer_prefix(id: &Uuid) -> String {
+        format!("{}{}/", PEER_KEY_PREFIX, id)
+    }
+
+    fn peer_key(id: &Uuid, address: &str) -> Vec<u8> {
+        format!("{}{}", Self::peer_prefix(id), address).into_bytes()
+    }
+
+    fn mls_key(id: &Uuid) -> Vec<u8> {
+        format!("{}{}", MLS_STATE_PREFIX, id).into_bytes()
+    }
+
+    fn key_package_key(init_key: &[u8]) -> Vec<u8> {
+        format!("{}{}", KEY_PACKAGE_PREFIX, hex::encode(init_key)).into_bytes()
+    }
+
+    fn invite_key(token: &[u8]) -> Vec<u8> {
+        format!("{}{}", INVITE_PREFIX, hex::encode(token)).into_bytes()
+    }
+
+    /// Saves a group.
+    pub fn save(&self, group: &Group) -> Result<()> {
+        self.persistence.put(&Self::group_key(&group.id), group)?;
+        self.persistence.flush()
+    }
+
+    /// Loads a group, if any.
+    pub fn get(&self, id: &Uuid) -> Result<Option<Group>> {
+        self.persistence.get(&Self::group_key(id))
+    }
+
+    /// All groups we belong to.
+    pub fn list(&self) -> Result<Vec<Group>> {
+        let groups: Vec<(_, Group)> = self.persistence.scan_prefix(GROUP_PREFIX.as_bytes())?;
+        Ok(groups.into_iter().map(|(_, g)| g).collect())
+    }
+
+    /// Deletes a group, its operations and every key attached to it.
+    pub fn delete(&self, id: &Uuid) -> Result<()> {
+        self.persistence.delete(&Self::group_key(id))?;
+        self.persistence.delete(&Self::base_key(id))?;
+        let operations: Vec<(_, GroupOperation)> = self.persistence.scan_prefix(Self::operation_prefix(id).as_bytes())?;
+        for (key, _) in operations {
+            self.persistence.delete(&key)?;
+        }
+        self.persistence.delete(&Self::own_key(id))?;
+        self.persistence.delete(&Self::mls_key(id))?;
+        self.delete_peer_keys(id, "")
+    }
+
+    /// Saves the state a group had when we created or joined it.
+    pub fn save_base(&self, group: &Group) -> Result<()> {
+        self.persistence.put(&Self::base_key(&group.id), group)?;
+        self.persistence.flush()
+    }
+
+    /// Loads the state a group had when we created or joined it, if any.
+    pub fn base_for(&self, id: &Uuid) -> Result<Option<Group>> {
+        self.persistence.get(&Self::base_key(id))
+    }
+
+    /// Adds an operation to the log of its group; an operation is stored once.
+    pub fn save_operation(&self, op: &GroupOperation) -> Result<()> {
+        let key = format!(
+            "{}{:020}/{}",
+            Self::operation_prefix(&op.group_id),
+            op.lamport,
+            hex::encode(&op.signature)
+        );
+        self.persistence.put(key.as_bytes(), op)?;
+        self.persistence.flush()
+    }
+
+    /// Operations applied to a group since we created or joined it.
+    pub fn operations(&self, id: &Uuid) -> Result<Vec<GroupOperation>> {
+        let operations: Vec<(_, GroupOperation)> = self.persistence.scan_prefix(Self::operation_prefix(id).as_bytes())?;
+        Ok(operations.into_iter().map(|(_, op)| op).collect())
+    }
+
+    /// Saves our sender key in a group.
+    pub fn save_own_key(&self, id: &Uuid, key: &OwnSenderKey) -> Result<()> {
+        self.persistence.put(&Self::own_key(id), key)?;
+        self.persistence.flush()
+    }
+
+    /// Loads our sender key in a group, if any.
+    pub fn own_key_for(&self, id: &Uuid) -> Result<Option<OwnSenderKey>> {
+        self.persistence.get(&Self::own_key(id))
+    }
+
+    /// Saves the sender key of a member device (address username/device).
+    pub fn save_peer_key(&self, id: &Uuid, address: &str, key: &ReceivedSenderKey) -> Result<()> {
+        self.persistence.put(&Self::peer_key(id, address), key)?;
+        self.persistence.flush()
+    }
+
+    /// Loads the sender key of a member device, if any.
+    pub fn peer_key_for(&self, id: &Uuid, address: &str) -> Result<Option<ReceivedSenderKey>> {
+        self.persistence.get(&Self::peer_key(id, address))
+    }
+
+    /// Deletes the sender keys of a group whose address starts with `address_prefix`
+    /// (e.g. "@bob/" for every device of a member, "" for all).
+    pub fn delete_peer_keys(&self, id: &Uuid, address_prefix: &str) -> Result<()> {
+        let prefix = format!("{}{}", Self::peer_prefix(id), address_prefix);
+        let keys: Vec<(_, ReceivedSenderKey)> = self.persistence.scan_prefix(prefix.as_bytes())?;
+        for (key, _) in keys {
+            self.persistence.delete(&key)?;
+        }
+        Ok(())
+    }
+
+    /// Saves our MLS state in a group.
+    pub fn save_mls_state(&self, id: &Uuid, state: &MlsGroup) -> Result<()> {
+        self.persistence.put(&Self::mls_key(id), state)?;
+        self.persistence.flush()
+    }
+
+    /// Loads our MLS state in a group, if any.
+    pub fn mls_state_for(&self, id: &Uuid) -> Result<Option<MlsGroup>> {
+        self.persistence.get(&Self::mls_key(id))
+    }
+
+    /// Saves a key package we are about to publish.
+    pub fn save_key_package(&self, package: &OwnKeyPackage) -> Result<()> {
+        self.persistence.put(&Self::key_package_key(&package.package.init_key), package)?;
+        self.persistence.flush()
+    }
+
+    /// Removes and returns the key package with this init key: each one is used once.
+    pub fn take_key_package(&self, init_key: &[u8]) -> Result<Option<OwnKeyPackage>> {
+        let key = Self::key_package_key(init_key);
+        let package = self.persistence.get(&key)?;
+        if package.is_some() {
+            self.persistence.delete(&key)?;
+        }
+        Ok(package)
+    }
+
+    /// Our key packages not consumed yet.
+    pub fn key_packages(&self) -> Result<Vec<OwnKeyPackage>> {
+        let packages: Vec<(_, OwnKeyPackage)> = self.persistence.scan_prefix(KEY_PACKAGE_PREFIX.as_bytes())?;
+        Ok(packages.into_iter().map(|(_, p)| p).collect())
+    }
+
+    /// Saves an invite we received.
+    pub fn save_invite(&self, invite: &GroupInvite) -> Result<()> {
+        self.persistence.put(&Self::invite_key(&invite.token), invite)?;
+        self.persistence.flush()
+    }
+
+    /// Invites we received and did not answer yet.
+    pub fn invites(&self) -> Result<Vec<GroupInvite>> {
+        let invites: Vec<(_, GroupInvite)> = self.persistence.scan_prefix(INVITE_PREFIX.as_bytes())?;
+        Ok(invites.into_iter().map(|(_, i)| i).collect())
+    }
+
+    /// Deletes a received invite.
+    pub fn delete_invite(&self, token: &[u8]) -> Result<()> {
+        self.persistence.delete(&Self::invite_key(token))?;
+        self.persistence.flush()
+    }
+}
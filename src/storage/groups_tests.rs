@@ -0,0 +1,55 @@
+#[cfg(test)]
+mod tests {
+    use super::super::groups::{GroupStore, OwnSenderKey};
+    use super::super::persistence::Persistence;
+    use crate::crypto::sender_key::{ReceivedSenderKey, SenderKey};
+    use crate::models::group::{Group, GroupRole};
+    use std::fs;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    // Groups and sender keys survive a reopen; deleting a member's keys keeps the others
+    #[test]
+    fn test_groups_and_sender_keys() {
+        let test_path = "test_data/groups_store";
+        if Path::new(test_path).exists() {
+            fs::remove_dir_all(test_path).unwrap();
+        }
+        let persistence = Persistence::open(test_path).unwrap();
+        persistence.unlock_with_key(&[8u8; 32]).unwrap();
+        let store = GroupStore::new(Arc::new(persistence));
+
+        let mut group = Group::new("Friends", "@alice", false);
+        assert!(group.add_member("@bob", GroupRole::Member));
+        assert!(!group.add_member("@bob", GroupRole::Admin));
+        store.save(&group).unwrap();
+
+        let own = SenderKey::generate(3).unwrap();
+        store
+            .save_own_key(&group.id, &OwnSenderKey { key: own.clone(), shared_with: vec!["@bob/1".to_owned()] })
+            .unwrap();
+        let received = ReceivedSenderKey::from(&own.distribution(&group, Vec::new(), 1).unwrap());
+        store.save_peer_key(&group.id, "@bob/1", &received).unwrap();
+        store.save_peer_key(&group.id, "@bob/2", &received).unwrap();
+        store.save_peer_key(&group.id, "@bobby/1", &received).unwrap();
+
+        let loaded = store.get(&group.id).unwrap().unwrap();
+        assert_eq!(loaded.members.len(), 2);
+        assert_eq!(loaded.role_of("@alice"), Some(GroupRole::Owner));
+        assert_eq!(store.own_key_for(&group.id).unwrap().unwrap().key.key_id, 3);
+        assert_eq!(store.list().unwrap().len(), 1);
+
+        store.delete_peer_keys(&group.id, "@bob/").unwrap();
+        assert!(store.peer_key_for(&group.id, "@bob/1").unwrap().is_none());
+        assert!(store.peer_key_for(&group.id, "@bob/2").unwrap().is_none());
+        assert!(store.peer_key_for(&group.id, "@bobby/1").unwrap().is_some());
+
+        store.delete(&group.id).unwrap();
+        assert!(store.get(&group.id).unwrap().is_none());
+        assert!(store.own_key_for(&group.id).unwrap().is_none());
+        assert!(store.peer_key_for(&group.id, "@bobby/1").unwrap().is_none());
+
+        drop(store);
+        fs::remove_dir_all(test_path).unwrap();
+    }
+}
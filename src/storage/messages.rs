@@ -0,0 +1,197 @@
+use crate::models::message::Message;
+use crate::storage::persistence::Persistence;
+use crate::storage::vault::ORDERED_TREE_PREFIX;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+use std::sync::Arc;
+use uuid::Uuid;
+
+// Conversation trees are paged by key: the vault keeps their record keys sortable
+const CONVERSATION_TREE_PREFIX: &str = ORDERED_TREE_PREFIX;
+const INDEX_PREFIX: &str = "message_index/";
+const META_PREFIX: &str = "conversation_meta/";
+
+/// A message as kept in the local history, with its decrypted content
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredMessage {
+    pub message: Message,
+    pub plaintext: Vec<u8>,
+    pub outgoing: bool,
+}
+
+/// Position in a conversation, used to page through its history
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageCursor {
+    pub timestamp: DateTime<Utc>,
+    pub id: Uuid,
+}
+
+impl MessageCursor {
+    /// Sortable record key: big-endian timestamp (sign bit flipped) followed by the id.
+    fn to_key(self) -> Vec<u8> {
+        let millis = (self.timestamp.timestamp_millis() as u64) ^ (1 << 63);
+        let mut key = millis.to_be_bytes().to_vec();
+        key.extend_from_slice(self.id.as_bytes());
+        key
+    }
+}
+
+impl From<&Message> for MessageCursor {
+    fn from(message: &Message) -> Self {
+        Self { timestamp: message.timestamp, id: message.id }
+    }
+}
+
+impl From<&StoredMessage> for MessageCursor {
+    fn from(stored: &StoredMessage) -> Self {
+        Self::from(&stored.message)
+    }
+}
+
+/// Where a message lives, to delete it by id
+#[derive(Serialize, Deserialize)]
+struct IndexEntry {
+    conversation: String,
+    key: Vec<u8>,
+}
+
+/// Per-conversation read state
+#[derive(Serialize, Deserialize, Default)]
+struct ConversationMeta {
+    unread: u64,
+    read_up_to: Option<Vec<u8>>,
+}
+
+/// Message history: one sled tree per conversation, ordered by timestamp then id
+pub struct MessageStore {
+    persistence: Arc<Persistence>,
+}
+
+impl MessageStore {
+    /// Creates a message store on top of the given persistence layer.
+    pub fn new(persistence: Arc<Persistence>) -> Self {
+        Self { persistence }
+    }
+
+    fn conversation_tree(&self, conversation: &str) -> Result<Persistence> {
+        self.persistence.tree(&format!("{}{}", CONVERSATION_TREE_PREFIX, conversation))
+    }
+
+    fn index_key(id: &Uuid) -> Vec<u8> {
+        format!("{}{}", INDEX_PREFIX, id).into_bytes()
+    }
+
+    fn meta_key(conversation: &str) -> Vec<u8> {
+        format!("{}{}", META_PREFIX, conversation).into_bytes()
+    }
+
+    fn load_meta(&self, conversation: &str) -> Result<ConversationMeta> {
+        Ok(self.persistence.get(&Self::meta_key(conversation))?.unwrap_or_default())
+    }
+
+    fn save_meta(&self, conversation: &str, meta: &ConversationMeta) -> Result<()> {
+        self.persistence.put(&Self::meta_key(conversation), meta)
+    }
+
+    /// Adds a message to a conversation; incoming messages count as unread.
+    pub fn insert(&self, conversation: &str, stored: &StoredMessage) -> Result<()> {
+        // A message delivered twice is stored (and counted) once
+        if self.persistence.get::<IndexEntry>(&Self::index_key(&stored.message.id))?.is_some() {
+            return Ok(());
+        }
+
+        let key = MessageCursor::from(stored).to_key();
+        let tree = self.conversation_tree(conversation)?;
+        let mut records = tree.batch();
+        let mut index = self.persistence.batch();
+        records.put(&key, stored)?;
+        index.put(
+            &Self::index_key(&stored.message.id),
+            &IndexEntry { conversation: conversation.to_owned(), key: key.clone() },
+        )?;
+
+        if !stored.outgoing {
+            let mut meta = self.load_meta(conversation)?;
+            if meta.read_up_to.as_ref().is_none_or(|read| key > *read) {
+                meta.unread += 1;
+                index.put(&Self::meta_key(conversation), &meta)?;
+            }
+        }
+        // The record, its index entry and the unread count are stored together
+        Persistence::apply(&[records, index])
+    }
+
+    /// Retrieves a message by id.
+    pub fn get(&self, id: &Uuid) -> Result<Option<StoredMessage>> {
+        match self.persistence.get::<IndexEntry>(&Self::index_key(id))? {
+            Some(entry) => self.conversation_tree(&entry.conversation)?.get(&entry.key),
+            None => Ok(None),
+        }
+    }
+
+    /// Up to `limit` messages older than `cursor` (the newest ones when `None`),
+    /// newest first.
+    pub fn page_before(
+        &self,
+        conversation: &str,
+        cursor: Option<MessageCursor>,
+        limit: usize,
+    ) -> Result<Vec<StoredMessage>> {
+        let before = cursor.map(MessageCursor::to_key);
+        let page = self
+            .conversation_tree(conversation)?
+            .scan_before::<StoredMessage>(before.as_deref(), limit)?;
+        Ok(page.into_iter().map(|(_, stored)| stored).collect())
+    }
+
+    /// The `n` most recent messages of a conversation, newest first.
+    pub fn latest(&self, conversation: &str, n: usize) -> Result<Vec<StoredMessage>> {
+        self.page_before(conversation, None, n)
+    }
+
+    /// Number of messages in a conversation.
+    pub fn count(&self, conversation: &str) -> Result<usize> {
+        Ok(self.conversation_tree(conversation)?.len())
+    }
+
+    /// Number of incoming messages not yet read.
+    pub fn unread_count(&self, conversation: &str) -> Result<u64> {
+        Ok(self.load_meta(conversation)?.unread)
+    }
+
+    /// Marks the whole conversation as read.
+    pub fn mark_read(&self, conversation: &str) -> Result<()> {
+        let mut meta = self.load_meta(conversation)?;
+        let newest = self.conversation_tree(conversation)?.scan_before::<StoredMessage>(None, 1)?;
+        meta.read_up_to = newest.into_iter().next().map(|(key, _)| key.to_vec()).or(meta.read_up_to);
+        meta.unread = 0;
+        self.save_meta(conversation, &meta)
+    }
+
+    /// Deletes a message by id; returns whether it existed.
+    pub fn delete(&self, id: &Uuid) -> Result<bool> {
+        let index_key = Self::index_key(id);
+        let entry = match self.persistence.get::<IndexEntry>(&index_key)? {
+            Some(entry) => entry,
+            None => return Ok(false),
+        };
+
+        let tree = self.conversation_tree(&entry.conversation)?;
+        let mut records = tree.batch();
+        let mut index = self.persistence.batch();
+        if let Some(stored) = tree.get::<StoredMessage>(&entry.key)? {
+            let mut meta = self.load_meta(&entry.conversation)?;
+            let unread = meta.read_up_to.as_ref().is_none_or(|read| entry.key > *read);
+            if !stored.outgoing && unread && meta.unread > 0 {
+                meta.unread -= 1;
+                index.put(&Self::meta_key(&entry.conversation), &meta)?;
+            }
+        }
+
+        records.delete(&entry.key)?;
+        index.delete(&index_key)?;
+        Persistence::apply(&[records, index])?;
+        Ok(true)
+    }
+}
@@ -0,0 +1,104 @@
+#[cfg(test)]
+mod tests {
+    use super::super::messages::{MessageCursor, MessageStore, StoredMessage};
+    use super::super::persistence::Persistence;
+    use crate::models::message::{Message, MessageType};
+    use chrono::{Duration, TimeZone, Utc};
+    use std::fs;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    fn store(path: &str) -> MessageStore {
+        if Path::new(path).exists() {
+            fs::remove_dir_all(path).unwrap();
+        }
+        let persistence = Persistence::open(path).unwrap();
+        persistence.unlock_with_key(&[5u8; 32]).unwrap();
+        MessageStore::new(Arc::new(persistence))
+    }
+
+    fn message(from: &str, to: &str, second: i64, outgoing: bool) -> StoredMessage {
+        StoredMessage {
+            message: Message {
+                id: uuid::Uuid::new_v4(),
+                sender: from.to_owned(),
+                receiver: to.to_owned(),
+                timestamp: Utc.timestamp_opt(1_700_000_000, 0).unwrap() + Duration::seconds(second),
+                msg_type: MessageType::Text,
+                encrypted_payload: vec![],
+                nonce: vec![],
+                signature: None,
+            },
+            plaintext: format!("message {}", second).into_bytes(),
+            outgoing,
+        }
+    }
+
+    // Pages walk the history backwards in timestamp order, whatever the insertion order
+    #[test]
+    fn test_pagination_in_timestamp_order() {
+        let test_path = "test_data/messages_pages";
+        let store = store(test_path);
+
+        for second in [3, 0, 4, 1, 2, 6, 5] {
+            store.insert("@bob", &message("@bob", "@me", second, false)).unwrap();
+        }
+        store.insert("@carol", &message("@carol", "@me", 10, false)).unwrap();
+
+        let texts = |page: &[StoredMessage]| -> Vec<String> {
+            page.iter().map(|m| String::from_utf8(m.plaintext.clone()).unwrap()).collect()
+        };
+
+        let latest = store.latest("@bob", 3).unwrap();
+        assert_eq!(texts(&latest), ["message 6", "message 5", "message 4"]);
+
+        let cursor = MessageCursor::from(latest.last().unwrap());
+        let older = store.page_before("@bob", Some(cursor), 3).unwrap();
+        assert_eq!(texts(&older), ["message 3", "message 2", "message 1"]);
+
+        let cursor = MessageCursor::from(older.last().unwrap());
+        let oldest = store.page_before("@bob", Some(cursor), 3).unwrap();
+        assert_eq!(texts(&oldest), ["message 0"]);
+
+        assert_eq!(store.count("@bob").unwrap(), 7);
+        assert_eq!(store.count("@carol").unwrap(), 1);
+
+        drop(store);
+        fs::remove_dir_all(test_path).unwrap();
+    }
+
+    // Incoming messages are unread until the conversation is read; deletion keeps counters right
+    #[test]
+    fn test_unread_counters_and_delete() {
+        let test_path = "test_data/messages_unread";
+        let store = store(test_path);
+
+        let first = message("@bob", "@me", 0, false);
+        let reply = message("@me", "@bob", 1, true);
+        let second = message("@bob", "@me", 2, false);
+        for m in [&first, &reply, &second] {
+            store.insert("@bob", m).unwrap();
+        }
+        // Duplicate delivery is not counted twice
+        store.insert("@bob", &second).unwrap();
+        assert_eq!(store.unread_count("@bob").unwrap(), 2);
+
+        assert!(store.delete(&second.message.id).unwrap());
+        assert!(!store.delete(&second.message.id).unwrap());
+        assert!(store.get(&second.message.id).unwrap().is_none());
+        assert_eq!(store.unread_count("@bob").unwrap(), 1);
+
+        store.mark_read("@bob").unwrap();
+        assert_eq!(store.unread_count("@bob").unwrap(), 0);
+        store.insert("@bob", &message("@bob", "@me", 3, false)).unwrap();
+        assert_eq!(store.unread_count("@bob").unwrap(), 1);
+
+        // Deleting a message that was already read leaves the counter alone
+        assert!(store.delete(&first.message.id).unwrap());
+        assert_eq!(store.unread_count("@bob").unwrap(), 1);
+        assert_eq!(store.count("@bob").unwrap(), 2);
+
+        drop(store);
+        fs::remove_dir_all(test_path).unwrap();
+    }
+}
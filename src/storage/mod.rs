@@ -0,0 +1,21 @@
+pub mod db;
+pub mod persistence;
+pub mod sessions;
+pub mod account;
+pub mod vault;
+pub mod messages;
+pub mod conversations;
+pub mod contacts;
+pub mod groups;
+#[cfg(test)]
+mod sessions_tests;
+#[cfg(test)]
+mod vault_tests;
+#[cfg(test)]
+mod messages_tests;
+#[cfg(test)]
+mod conversations_tests;
+#[cfg(test)]
+mod contacts_tests;
+#[cfg(test)]
+mod groups_tests;
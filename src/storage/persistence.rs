@@ -1,40 +1,176 @@
-use sled::{Db, IVec};
+use sled::{Db, IVec, Tree};
+use sled::transaction::{ConflictableTransactionError, Transactional};
 use std::path::Path;
 use anyhow::{Result, Context};
 use serde::{Serialize, de::DeserializeOwned};
+use std::sync::{Arc, OnceLock};
+use crate::storage::db::open_db;
+use crate::storage::vault::{Vault, VaultError, VAULT_KEY_LEN};
 
 /// Represents the local encrypted storage engine.
+/// A `Persistence` reads and writes one sled tree (the default one unless built with `tree`).
+/// Tree names and keys are hashed by the vault, so the tree is only resolved once unlocked.
 pub struct Persistence {
     db: Db,
+    tree: OnceLock<(Vec<u8>, Tree)>, // Hashed name and handle of the tree
+    scope: Vec<u8>,
+    vault: Arc<Vault>,
 }
 
 impl Persistence {
-    /// Opens or creates a new encrypted database at the specified path.
+    /// Opens or creates a new encrypted database at the specified path (locked).
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let db = sled::open(path).context("Failed to open sled database")?;
-        Ok(Self { db })
+        let db = open_db(path).context("Failed to open sled database")?;
+        let vault = Arc::new(Vault::open(&db)?);
+        Ok(Self::from_db(db, vault))
+    }
+
+    /// Wraps an already opened database and its vault (e.g. the ones held by `Storage`).
+    pub fn from_db(db: Db, vault: Arc<Vault>) -> Self {
+        Self { db, tree: OnceLock::new(), scope: Vec::new(), vault }
+    }
+
+    /// View over a named tree of the same database, sharing its key.
+    pub fn tree(&self, name: &str) -> Result<Self> {
+        Ok(Self {
+            db: self.db.clone(),
+            tree: OnceLock::new(),
+            scope: name.as_bytes().to_vec(),
+            vault: self.vault.clone(),
+        })
+    }
+
+    /// Deletes a named tree and all its records.
+    pub fn drop_tree(&self, name: &str) -> Result<()> {
+        let (hashed_name, _) = self.vault.tree(name.as_bytes())?;
+        self.db.drop_tree(hashed_name)?;
+        Ok(())
+    }
+
+    /// Hashed name and handle of this tree
+    fn physical(&self) -> Result<&(Vec<u8>, Tree)> {
+        if let Some(tree) = self.tree.get() {
+            return Ok(tree);
+        }
+        let tree = self.vault.tree(&self.scope)?;
+        Ok(self.tree.get_or_init(|| tree))
+    }
+
+    /// Unlocks the database with the user passphrase.
+    pub fn unlock(&self, passphrase: &str) -> Result<()> {
+        self.vault.unlock(passphrase)
+    }
+
+    /// Unlocks the database with a platform-provided key.
+    pub fn unlock_with_key(&self, key: &[u8; VAULT_KEY_LEN]) -> Result<()> {
+        self.vault.unlock_with_key(key)
     }
 
     /// Stores a serializable value under the given key.
     pub fn put<T: Serialize>(&self, key: &[u8], value: &T) -> Result<()> {
-        let serialized = bincode::serialize(value)?;
-        self.db.insert(key, serialized)?;
-        Ok(())
+        self.put_raw(key, &bincode::serialize(value)?)
     }
 
     /// Retrieves a deserializable value for the given key.
     pub fn get<T: DeserializeOwned>(&self, key: &[u8]) -> Result<Option<T>> {
-        if let Some(ivec) = self.db.get(key)? {
-            let deserialized = bincode::deserialize(&ivec)?;
-            Ok(Some(deserialized))
-        } else {
-            Ok(None)
+        match self.get_raw(key)? {
+            Some(value) => Ok(Some(bincode::deserialize(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Stores raw bytes under the given key.
+    pub fn put_raw(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let (name, tree) = self.physical()?;
+        let record_key = self.vault.record_key(&self.scope, key)?;
+        tree.insert(record_key.as_slice(), self.vault.seal_entry(name, &record_key, key, value)?)?;
+        Ok(())
+    }
+
+    /// Retrieves the raw bytes stored under the given key.
+    pub fn get_raw(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let (name, tree) = self.physical()?;
+        let record_key = self.vault.record_key(&self.scope, key)?;
+        let Some(sealed) = tree.get(&record_key)? else {
+            return Ok(None);
+        };
+        let (stored_key, value) = self.vault.open_entry(name, &record_key, &sealed)?;
+        if stored_key != key {
+            return Err(VaultError::Corrupted(String::from_utf8_lossy(key).into_owned()).into());
+        }
+        Ok(Some(value))
+    }
+
+    /// Loads all values whose key starts with the given prefix. Keys are hashed segment by
+    /// segment: the prefix must end with '/' (or be empty).
+    pub fn scan_prefix<T: DeserializeOwned>(&self, prefix: &[u8]) -> Result<Vec<(IVec, T)>> {
+        let (name, tree) = self.physical()?;
+        let mut results = Vec::new();
+        for item in tree.scan_prefix(self.vault.record_key(&self.scope, prefix)?) {
+            let (record_key, sealed) = item?;
+            results.push(self.decode(name, &record_key, &sealed)?);
         }
+        Ok(results)
+    }
+
+    /// Loads up to `limit` values with a key strictly below `before` (or the last ones
+    /// when `before` is `None`), in descending key order. Only meaningful for ordered trees
+    /// (see `vault::ORDERED_TREE_PREFIX`), whose keys are not hashed.
+    pub fn scan_before<T: DeserializeOwned>(&self, before: Option<&[u8]>, limit: usize) -> Result<Vec<(IVec, T)>> {
+        let (name, tree) = self.physical()?;
+        let range = match before {
+            Some(end) => tree.range(..self.vault.record_key(&self.scope, end)?),
+            None => tree.range::<&[u8], _>(..),
+        };
+        let mut results = Vec::new();
+        for item in range.rev().take(limit) {
+            let (record_key, sealed) = item?;
+            results.push(self.decode(name, &record_key, &sealed)?);
+        }
+        Ok(results)
+    }
+
+    /// Number of records in this tree (0 while locked).
+    pub fn len(&self) -> usize {
+        self.physical().map_or(0, |(_, tree)| tree.len())
+    }
+
+    /// Whether this tree holds no record.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Original key and value of a sealed record
+    fn decode<T: DeserializeOwned>(&self, name: &[u8], record_key: &[u8], sealed: &[u8]) -> Result<(IVec, T)> {
+        let (key, value) = self.vault.open_entry(name, record_key, sealed)?;
+        Ok((key.into(), bincode::deserialize(&value)?))
     }
 
     /// Deletes a value for the given key.
     pub fn delete(&self, key: &[u8]) -> Result<()> {
-        self.db.remove(key)?;
+        let (_, tree) = self.physical()?;
+        tree.remove(self.vault.record_key(&self.scope, key)?)?;
+        Ok(())
+    }
+
+    /// Starts a batch of writes to this tree, applied by `Persistence::apply`.
+    pub fn batch(&self) -> Batch<'_> {
+        Batch { persistence: self, writes: sled::Batch::default() }
+    }
+
+    /// Applies batches of several trees of the same database in one transaction:
+    /// either all their writes are stored or none is.
+    pub fn apply(batches: &[Batch<'_>]) -> Result<()> {
+        let trees = batches
+            .iter()
+            .map(|batch| batch.persistence.physical().map(|(_, tree)| tree))
+            .collect::<Result<Vec<&Tree>>>()?;
+        trees[..].transaction(|views| {
+            for (view, batch) in views.iter().zip(batches) {
+                view.apply_batch(&batch.writes)?;
+            }
+            Ok::<_, ConflictableTransactionError>(())
+        })?;
         Ok(())
     }
 
@@ -44,3 +180,29 @@ impl Persistence {
         Ok(())
     }
 }
+
+/// Writes to one tree, sealed as `Persistence::put` would, and stored together with
+/// the batches of other trees by `Persistence::apply`.
+pub struct Batch<'a> {
+    persistence: &'a Persistence,
+    writes: sled::Batch,
+}
+
+impl Batch<'_> {
+    /// Stages a serializable value under the given key.
+    pub fn put<T: Serialize>(&mut self, key: &[u8], value: &T) -> Result<()> {
+        let (name, _) = self.persistence.physical()?;
+        let vault = &self.persistence.vault;
+        let record_key = vault.record_key(&self.persistence.scope, key)?;
+        let sealed = vault.seal_entry(name, &record_key, key, &bincode::serialize(value)?)?;
+        self.writes.insert(record_key, sealed);
+        Ok(())
+    }
+
+    /// Stages the deletion of the given key.
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        let record_key = self.persistence.vault.record_key(&self.persistence.scope, key)?;
+        self.writes.remove(record_key);
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+use crate::crypto::session::{PeerSession, PeerSessionRecord};
+use crate::models::device::DeviceList;
+use crate::storage::persistence::Persistence;
+use anyhow::Result;
+use std::sync::Arc;
+
+const SESSION_PREFIX: &str = "session/";
+const DEVICES_PREFIX: &str = "peer_devices/";
+const PIN_PREFIX: &str = "peer_pin/";
+
+/// Persists ratchet sessions keyed per peer device, and the device lists of peers,
+/// so conversations survive restarts.
+pub struct SessionStore {
+    persistence: Arc<Persistence>,
+}
+
+impl SessionStore {
+    /// Creates a session store on top of the given persistence layer.
+    pub fn new(persistence: Arc<Persistence>) -> Self {
+        Self { persistence }
+    }
+
+    fn key(peer: &str) -> Vec<u8> {
+        format!("{}{}", SESSION_PREFIX, peer).into_bytes()
+    }
+
+    /// Saves the current state of a peer's session.
+    pub fn save(&self, peer: &str, session: &PeerSession) -> Result<()> {
+        self.persistence.put(&Self::key(peer), &session.to_record())?;
+        self.persistence.flush()
+    }
+
+    /// Restores the session of a peer, if any.
+    pub fn load(&self, peer: &str) -> Result<Option<PeerSession>> {
+        let record: Option<PeerSessionRecord> = self.persistence.get(&Self::key(peer))?;
+        Ok(record.map(PeerSession::from_record))
+    }
+
+    /// Restores every stored session, keyed by peer.
+    pub fn load_all(&self) -> Result<Vec<(String, PeerSession)>> {
+        let records: Vec<(_, PeerSessionRecord)> = self.persistence.scan_prefix(SESSION_PREFIX.as_bytes())?;
+        Ok(records
+            .into_iter()
+            .map(|(key, record)| {
+                let peer = String::from_utf8_lossy(&key[SESSION_PREFIX.len()..]).into_owned();
+                (peer, PeerSession::from_record(record))
+            })
+            .collect())
+    }
+
+    /// Deletes the session of a peer.
+    pub fn delete(&self, peer: &str) -> Result<()> {
+        self.persistence.delete(&Self::key(peer))
+    }
+
+    /// Saves the verified device list of a peer.
+    pub fn save_devices(&self, devices: &DeviceList) -> Result<()> {
+        let key = format!("{}{}", DEVICES_PREFIX, devices.username()).into_bytes();
+        self.persistence.put(&key, devices)?;
+        self.persistence.flush()
+    }
+
+    /// Deletes the device list of a peer.
+    pub fn delete_devices(&self, username: &str) -> Result<()> {
+        self.persistence.delete(format!("{}{}", DEVICES_PREFIX, username).as_bytes())
+    }
+
+    /// Saves the account key the user approved for a peer.
+    pub fn save_pin(&self, username: &str, account_key: &[u8]) -> Result<()> {
+        let key = format!("{}{}", PIN_PREFIX, username).into_bytes();
+        self.persistence.put(&key, &account_key.to_vec())?;
+        self.persistence.flush()
+    }
+
+    /// Restores the approved account keys, keyed by peer.
+    pub fn load_pins(&self) -> Result<Vec<(String, Vec<u8>)>> {
+        let pins: Vec<(_, Vec<u8>)> = self.persistence.scan_prefix(PIN_PREFIX.as_bytes())?;
+        Ok(pins
+            .into_iter()
+            .map(|(key, pin)| (String::from_utf8_lossy(&key[PIN_PREFIX.len()..]).into_owned(), pin))
+            .collect())
+    }
+
+    /// Restores the device lists of all peers.
+    pub fn load_devices(&self) -> Result<Vec<DeviceList>> {
+        let lists: Vec<(_, DeviceList)> = self.persistence.scan_prefix(DEVICES_PREFIX.as_bytes())?;
+        Ok(lists.into_iter().map(|(_, devices)| devices).collect())
+    }
+}
@@ -0,0 +1,69 @@
+#[cfg(test)]
+mod tests {
+    use super::super::persistence::Persistence;
+    use super::super::sessions::SessionStore;
+    use crate::crypto::ratchet::Ratchet;
+    use crate::crypto::handshake::generate_identity_bundle;
+    use crate::crypto::session::{PeerIdentity, PeerSession};
+    use rand_core::OsRng;
+    use std::fs;
+    use std::path::Path;
+    use std::sync::Arc;
+    use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret};
+
+    fn open_unlocked(path: &str) -> Persistence {
+        let persistence = Persistence::open(path).unwrap();
+        persistence.unlock_with_key(&[7u8; 32]).unwrap();
+        persistence
+    }
+
+    // A session saved mid-conversation keeps working after being restored
+    #[test]
+    fn test_session_survives_restart() {
+        let test_path = "test_data/sessions_restore";
+        if Path::new(test_path).exists() {
+            fs::remove_dir_all(test_path).unwrap();
+        }
+
+        let bob_spk = StaticSecret::new(OsRng);
+        let bob_spk_pub = X25519PublicKey::from(&bob_spk);
+        let mut alice = Ratchet::new_initiator(b"restore_secret", bob_spk_pub.as_bytes()).unwrap();
+        let mut bob = Ratchet::new_responder(b"restore_secret", bob_spk).unwrap();
+
+        // Bob receives one message and misses another before the app is killed
+        let m0 = alice.encrypt(b"m0").unwrap();
+        let m1 = alice.encrypt(b"m1").unwrap();
+        let m2 = alice.encrypt(b"m2").unwrap();
+        bob.decrypt(&m0).unwrap();
+        bob.decrypt(&m2).unwrap();
+
+        let (_, _, _, alice_bundle) = generate_identity_bundle().unwrap();
+        let alice_identity = PeerIdentity::from_bundle(&alice_bundle);
+        {
+            let store = SessionStore::new(Arc::new(open_unlocked(test_path)));
+            let session = PeerSession {
+                ratchet: bob,
+                pending_prekey: None,
+                peer_identity: alice_identity.clone(),
+                origin: None,
+            };
+            store.save("@alice", &session).unwrap();
+        }
+
+        let store = SessionStore::new(Arc::new(open_unlocked(test_path)));
+        let session = store.load("@alice").unwrap().expect("session should be stored");
+        assert_eq!(session.peer_identity, alice_identity);
+        assert_eq!(store.load_all().unwrap().len(), 1);
+
+        let mut restored = session.ratchet;
+
+        assert_eq!(restored.decrypt(&m1).unwrap(), b"m1", "Skipped keys are restored");
+        let reply = restored.encrypt(b"back online").unwrap();
+        assert_eq!(alice.decrypt(&reply).unwrap(), b"back online");
+
+        store.delete("@alice").unwrap();
+        assert!(store.load("@alice").unwrap().is_none());
+
+        fs::remove_dir_all(test_path).unwrap();
+    }
+}
@@ -0,0 +1,449 @@
+use argon2::{Algorithm, Argon2, Params, Version};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use sled::{Batch, Db, Tree};
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+use anyhow::{Result, Context};
+use thiserror::Error;
+
+/// Argon2id memory cost (KiB), passes and lanes used to stretch the passphrase
+pub const ARGON2_MEMORY_KIB: u32 = 19 * 1024;
+pub const ARGON2_ITERATIONS: u32 = 2;
+pub const ARGON2_PARALLELISM: u32 = 1;
+
+/// Size of the wrapping and data keys in bytes (256 bits)
+pub const VAULT_KEY_LEN: usize = 32;
+
+/// Trees paged in key order (message history, keyed by timestamp and random id): only their
+/// name is hashed, their record keys stay sortable
+pub const ORDERED_TREE_PREFIX: &str = "conversation/";
+
+const META_TREE: &str = "__vault";
+const SALT_KEY: &[u8] = b"salt";
+const CURRENT_KEY: &[u8] = b"current";
+const DATA_KEY_PREFIX: &str = "key/";
+const INDEX_KEY: &[u8] = b"index_key";
+const TREE_NAME_PREFIX: &str = "v2/";
+const HASH_LEN: usize = 16;
+const GENERATION_LEN: usize = 4;
+/// Scope of the records of the default tree
+const DEFAULT_SCOPE: &[u8] = b"";
+
+/// Errors raised by the encrypted storage layer
+#[derive(Debug, Error)]
+pub enum VaultError {
+    #[error("storage is locked: unlock it first")]
+    Locked,
+    #[error("wrong passphrase or key")]
+    WrongKey,
+    #[error("record {0:?} failed authentication")]
+    Corrupted(String),
+    #[error("no data key for generation {0}")]
+    UnknownGeneration(u32),
+    #[error("database holds unencrypted records written before the vault existed: export them with that version or remove the database")]
+    Unencrypted,
+}
+
+/// Progress of a data key rotation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RotationProgress {
+    pub done: usize,
+    pub total: usize,
+}
+
+/// Keys held while the vault is unlocked
+struct UnlockedKeys {
+    kek: [u8; VAULT_KEY_LEN],
+    data_keys: BTreeMap<u32, [u8; VAULT_KEY_LEN]>,
+    current: u32,
+    index_key: [u8; VAULT_KEY_LEN], // Hashes tree names and record keys
+}
+
+impl UnlockedKeys {
+    fn wipe(&mut self) {
+        self.kek.iter_mut().for_each(|b| *b = 0);
+        self.index_key.iter_mut().for_each(|b| *b = 0);
+        for key in self.data_keys.values_mut() {
+            key.iter_mut().for_each(|b| *b = 0);
+        }
+        self.data_keys.clear();
+    }
+}
+
+/// Transparent record encryption for the sled databases, with envelope keys.
+/// A random data key encrypts the records (ChaCha20-Poly1305, random nonce per record,
+/// tree name and record key as associated data); the passphrase or platform key only wraps that data key.
+/// Each record starts with the generation of the data key that sealed it, so the data key
+/// can be rotated while the application keeps running.
+/// Tree names and record keys never reach the disk either: they are replaced by a keyed
+/// hash (HMAC-SHA256 with a random index key, wrapped like the data keys), and each record
+/// carries its original key inside the ciphertext.
+pub struct Vault {
+    db: Db,
+    meta: Tree,
+    keys: RwLock<Option<UnlockedKeys>>,
+    rng: SystemRandom,
+}
+
+impl Vault {
+    /// Opens the vault metadata of a database; the vault starts locked.
+    /// Databases holding unencrypted records (plain sled values, from before the vault)
+    /// are refused with `VaultError::Unencrypted`.
+    pub fn open(db: &Db) -> Result<Self> {
+        let foreign_tree = db.tree_names().into_iter().any(|name| {
+            name != db.name() && name.as_ref() != META_TREE.as_bytes() && !name.starts_with(TREE_NAME_PREFIX.as_bytes())
+        });
+        if !db.is_empty() || foreign_tree {
+            return Err(VaultError::Unencrypted.into());
+        }
+        let meta = db.open_tree(META_TREE).context("Failed to open vault metadata")?;
+        Ok(Self {
+            db: db.clone(),
+            meta,
+            keys: RwLock::new(None),
+            rng: SystemRandom::new(),
+        })
+    }
+
+    /// Unlocks with a key derived from the user passphrase with Argon2id (salt created on
+    /// first use).
+    pub fn unlock(&self, passphrase: &str) -> Result<()> {
+        let salt = match self.meta.get(SALT_KEY)? {
+            Some(salt) => salt.to_vec(),
+            None => {
+                let salt = self.random_salt()?;
+                self.meta.insert(SALT_KEY, salt.clone())?;
+                salt
+            }
+        };
+        self.unlock_with_key(&derive_kek(passphrase, &salt)?)
+    }
+
+    /// Unlocks with a raw 256-bit key (e.g. provided by the platform keystore).
+    /// On first use a random data key is created and wrapped with this key.
+    pub fn unlock_with_key(&self, kek: &[u8; VAULT_KEY_LEN]) -> Result<()> {
+        let (data_keys, current) = match self.meta.get(CURRENT_KEY)? {
+            Some(current) => (self.unwrap_data_keys(kek)?, decode_generation(&current)?),
+            None => {
+                let data_key = self.random_key()?;
+                let mut batch = Batch::default();
+                batch.insert(data_key_id(1).as_bytes(), self.wrap(kek, 1, &data_key)?);
+                batch.insert(CURRENT_KEY, 1u32.to_be_bytes().to_vec());
+                self.meta.apply_batch(batch)?;
+                self.meta.flush()?;
+                (BTreeMap::from([(1, data_key)]), 1)
+            }
+        };
+        let index_key = match self.meta.get(INDEX_KEY)? {
+            Some(wrapped) => open_with(kek, INDEX_KEY, &wrapped)
+                .ok()
+                .and_then(|key| key.try_into().ok())
+                .ok_or(VaultError::WrongKey)?,
+            None => {
+                let index_key = self.random_key()?;
+                self.meta.insert(INDEX_KEY, self.seal_with(kek, INDEX_KEY, &index_key)?)?;
+                self.meta.flush()?;
+                index_key
+            }
+        };
+
+        *self.keys.write().unwrap() = Some(UnlockedKeys { kek: *kek, data_keys, current, index_key });
+        Ok(())
+    }
+
+    /// Forgets the keys; every read or write fails until the next unlock.
+    pub fn lock(&self) {
+        if let Some(mut keys) = self.keys.write().unwrap().take() {
+            keys.wipe();
+        }
+    }
+
+    /// Whether the vault currently holds its keys.
+    pub fn is_unlocked(&self) -> bool {
+        self.keys.read().unwrap().is_some()
+    }
+
+    /// Replaces the passphrase: only the data keys are re-wrapped, records are untouched.
+    pub fn change_passphrase(&self, old: &str, new: &str) -> Result<()> {
+        let salt = self.meta.get(SALT_KEY)?.ok_or(VaultError::WrongKey)?;
+        self.unwrap_data_keys(&derive_kek(old, &salt)?)?;
+
+        let new_salt = self.random_salt()?;
+        self.rewrap(&derive_kek(new, &new_salt)?, Some(new_salt))
+    }
+
+    /// Replaces the wrapping key (e.g. after the platform keystore rotated it).
+    /// Requires the vault to be unlocked.
+    pub fn change_key(&self, new_kek: &[u8; VAULT_KEY_LEN]) -> Result<()> {
+        self.rewrap(new_kek, None)
+    }
+
+    /// Re-encrypts every record with a fresh data key, reporting progress after each record.
+    /// New writes use the new key immediately; the old key is destroyed once all records moved.
+    pub fn rotate_data_key<F: FnMut(RotationProgress)>(&self, mut progress: F) -> Result<()> {
+        let generation = {
+            let mut guard = self.keys.write().unwrap();
+            let keys = guard.as_mut().ok_or(VaultError::Locked)?;
+            let generation = keys.current + 1;
+            let data_key = self.random_key()?;
+
+            let mut batch = Batch::default();
+            batch.insert(data_key_id(generation).as_bytes(), self.wrap(&keys.kek, generation, &data_key)?);
+            batch.insert(CURRENT_KEY, generation.to_be_bytes().to_vec());
+            self.meta.apply_batch(batch)?;
+            self.meta.flush()?;
+
+            keys.data_keys.insert(generation, data_key);
+            keys.current = generation;
+            generation
+        };
+
+        let trees = self.record_trees()?;
+        let total = trees.iter().map(|(_, t)| t.len()).sum();
+        let mut done = 0;
+        progress(RotationProgress { done, total });
+
+        for (scope, tree) in trees {
+            for item in tree.iter() {
+                let (key, sealed) = item?;
+                if decode_generation(&sealed)? != generation {
+                    let plaintext = self.open_in(&scope, &key, &sealed)?;
+                    let resealed = self.seal_in(&scope, &key, &plaintext)?;
+                    // A concurrent write already used the new key: keep it
+                    let _ = tree.compare_and_swap(&key, Some(sealed), Some(resealed))?;
+                }
+                done += 1;
+                progress(RotationProgress { done, total: total.max(done) });
+            }
+            tree.flush()?;
+        }
+
+        // Every record now uses the new key: destroy the older ones
+        let mut guard = self.keys.write().unwrap();
+        let keys = guard.as_mut().ok_or(VaultError::Locked)?;
+        let old: Vec<u32> = keys.data_keys.keys().copied().filter(|g| *g < generation).collect();
+        let mut batch = Batch::default();
+        for g in old {
+            batch.remove(data_key_id(g).as_bytes());
+            if let Some(mut key) = keys.data_keys.remove(&g) {
+                key.iter_mut().for_each(|b| *b = 0);
+            }
+        }
+        self.meta.apply_batch(batch)?;
+        self.meta.flush()?;
+        Ok(())
+    }
+
+    /// Opens the tree holding the records of the tree named `scope` ("" for the default
+    /// one), under a hashed name. Returns that name with the tree.
+    pub fn tree(&self, scope: &[u8]) -> Result<(Vec<u8>, Tree)> {
+        let mut input = b"tree/".to_vec();
+        input.extend_from_slice(scope);
+        let name = format!("{}{}", TREE_NAME_PREFIX, hex::encode(self.keyed_hash(&input)?)).into_bytes();
+        let tree = self.db.open_tree(&name)?;
+        Ok((name, tree))
+    }
+
+    /// Key under which `key` is stored in the tree named `scope`. Each segment ending with
+    /// '/' is hashed on its own, so a prefix made of whole segments (e.g. "session/") maps
+    /// to a prefix of the hashed keys. Keys of ordered trees are kept as they are.
+    pub fn record_key(&self, scope: &[u8], key: &[u8]) -> Result<Vec<u8>> {
+        if scope.starts_with(ORDERED_TREE_PREFIX.as_bytes()) {
+            return Ok(key.to_vec());
+        }
+        let mut hashed = Vec::new();
+        for segment in key.split_inclusive(|b| *b == b'/') {
+            hashed.extend_from_slice(&self.keyed_hash(segment)?);
+        }
+        Ok(hashed)
+    }
+
+    /// Seals a record of the tree `tree_name` stored under `record_key`, keeping its
+    /// original `key` with the value.
+    pub fn seal_entry(&self, tree_name: &[u8], record_key: &[u8], key: &[u8], value: &[u8]) -> Result<Vec<u8>> {
+        let mut entry = (key.len() as u32).to_be_bytes().to_vec();
+        entry.extend_from_slice(key);
+        entry.extend_from_slice(value);
+        self.seal_in(tree_name, record_key, &entry)
+    }
+
+    /// Opens a record sealed by `seal_entry`: returns its original key and its value.
+    pub fn open_entry(&self, tree_name: &[u8], record_key: &[u8], sealed: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+        let entry = self.open_in(tree_name, record_key, sealed)?;
+        let (len, rest) = entry.split_at(entry.len().min(4));
+        let len = <[u8; 4]>::try_from(len).map(u32::from_be_bytes).unwrap_or(u32::MAX) as usize;
+        if rest.len() < len {
+            return Err(VaultError::Corrupted(hex::encode(record_key)).into());
+        }
+        let (key, value) = rest.split_at(len);
+        Ok((key.to_vec(), value.to_vec()))
+    }
+
+    /// Encrypts a value stored under `record_key` in the tree named `scope`,
+    /// with the current data key.
+    pub fn seal_in(&self, scope: &[u8], record_key: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+        let guard = self.keys.read().unwrap();
+        let keys = guard.as_ref().ok_or(VaultError::Locked)?;
+        let data_key = keys.data_keys.get(&keys.current).ok_or(VaultError::UnknownGeneration(keys.current))?;
+
+        let mut sealed = keys.current.to_be_bytes().to_vec();
+        sealed.extend(self.seal_with(data_key, &record_aad(keys.current, scope, record_key), plaintext)?);
+        Ok(sealed)
+    }
+
+    /// Decrypts a value read from `record_key` in the tree named `scope`,
+    /// whatever data key generation sealed it.
+    pub fn open_in(&self, scope: &[u8], record_key: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+        let guard = self.keys.read().unwrap();
+        let keys = guard.as_ref().ok_or(VaultError::Locked)?;
+        let generation = decode_generation(sealed)
+            .map_err(|_| VaultError::Corrupted(String::from_utf8_lossy(record_key).into_owned()))?;
+        let data_key = keys.data_keys.get(&generation).ok_or(VaultError::UnknownGeneration(generation))?;
+
+        open_with(data_key, &record_aad(generation, scope, record_key), &sealed[GENERATION_LEN..])
+            .map_err(|_| VaultError::Corrupted(String::from_utf8_lossy(record_key).into_owned()).into())
+    }
+
+    /// Rewraps every data key with a new wrapping key in a single atomic batch.
+    fn rewrap(&self, new_kek: &[u8; VAULT_KEY_LEN], new_salt: Option<Vec<u8>>) -> Result<()> {
+        let mut guard = self.keys.write().unwrap();
+        let keys = guard.as_mut().ok_or(VaultError::Locked)?;
+
+        let mut batch = Batch::default();
+        for (generation, data_key) in &keys.data_keys {
+            batch.insert(data_key_id(*generation).as_bytes(), self.wrap(new_kek, *generation, data_key)?);
+        }
+        batch.insert(INDEX_KEY, self.seal_with(new_kek, INDEX_KEY, &keys.index_key)?);
+        if let Some(salt) = new_salt {
+            batch.insert(SALT_KEY, salt);
+        }
+        self.meta.apply_batch(batch)?;
+        self.meta.flush()?;
+
+        keys.kek.iter_mut().for_each(|b| *b = 0);
+        keys.kek = *new_kek;
+        Ok(())
+    }
+
+    fn unwrap_data_keys(&self, kek: &[u8; VAULT_KEY_LEN]) -> Result<BTreeMap<u32, [u8; VAULT_KEY_LEN]>> {
+        let mut data_keys = BTreeMap::new();
+        for item in self.meta.scan_prefix(DATA_KEY_PREFIX) {
+            let (id, wrapped) = item?;
+            let generation: u32 = std::str::from_utf8(&id[DATA_KEY_PREFIX.len()..])?.parse()?;
+            let key = open_with(kek, id.as_ref(), &wrapped).map_err(|_| VaultError::WrongKey)?;
+            let key: [u8; VAULT_KEY_LEN] = key.try_into().map_err(|_| VaultError::WrongKey)?;
+            data_keys.insert(generation, key);
+        }
+        if data_keys.is_empty() {
+            return Err(VaultError::WrongKey.into());
+        }
+        Ok(data_keys)
+    }
+
+    /// Truncated HMAC-SHA256 of `data` with the index key
+    fn keyed_hash(&self, data: &[u8]) -> Result<[u8; HASH_LEN]> {
+        let guard = self.keys.read().unwrap();
+        let keys = guard.as_ref().ok_or(VaultError::Locked)?;
+        let tag = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, &keys.index_key), data);
+        let mut hash = [0u8; HASH_LEN];
+        hash.copy_from_slice(&tag.as_ref()[..HASH_LEN]);
+        Ok(hash)
+    }
+
+    fn wrap(&self, kek: &[u8; VAULT_KEY_LEN], generation: u32, data_key: &[u8; VAULT_KEY_LEN]) -> Result<Vec<u8>> {
+        self.seal_with(kek, data_key_id(generation).as_bytes(), data_key)
+    }
+
+    /// Trees holding encrypted records (everything but the vault metadata), with their scope
+    fn record_trees(&self) -> Result<Vec<(Vec<u8>, Tree)>> {
+        let mut trees = Vec::new();
+        for name in self.db.tree_names() {
+            if name == self.db.name() {
+                trees.push((DEFAULT_SCOPE.to_vec(), (*self.db).clone()));
+            } else if name.as_ref() != META_TREE.as_bytes() {
+                trees.push((name.to_vec(), self.db.open_tree(name)?));
+            }
+        }
+        Ok(trees)
+    }
+
+    fn random_key(&self) -> Result<[u8; VAULT_KEY_LEN]> {
+        let mut key = [0u8; VAULT_KEY_LEN];
+        self.rng.fill(&mut key).map_err(|_| anyhow::anyhow!("RNG failure"))?;
+        Ok(key)
+    }
+
+    fn random_salt(&self) -> Result<Vec<u8>> {
+        let mut salt = vec![0u8; 16];
+        self.rng.fill(&mut salt).map_err(|_| anyhow::anyhow!("RNG failure"))?;
+        Ok(salt)
+    }
+
+    fn seal_with(&self, key: &[u8; VAULT_KEY_LEN], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+        let key = LessSafeKey::new(
+            UnboundKey::new(&CHACHA20_POLY1305, key).map_err(|_| anyhow::anyhow!("Invalid vault key"))?,
+        );
+
+        let mut nonce_bytes = [0u8; NONCE_LEN];
+        self.rng.fill(&mut nonce_bytes).map_err(|_| anyhow::anyhow!("RNG failure"))?;
+
+        let mut buffer = plaintext.to_vec();
+        key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce_bytes), Aad::from(aad), &mut buffer)
+            .map_err(|_| anyhow::anyhow!("Record encryption failed"))?;
+
+        let mut sealed = nonce_bytes.to_vec();
+        sealed.extend_from_slice(&buffer);
+        Ok(sealed)
+    }
+}
+
+fn derive_kek(passphrase: &str, salt: &[u8]) -> Result<[u8; VAULT_KEY_LEN]> {
+    let params = Params::new(ARGON2_MEMORY_KIB, ARGON2_ITERATIONS, ARGON2_PARALLELISM, Some(VAULT_KEY_LEN))
+        .map_err(|e| anyhow::anyhow!("Invalid Argon2 parameters: {}", e))?;
+    let mut kek = [0u8; VAULT_KEY_LEN];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut kek)
+        .map_err(|e| anyhow::anyhow!("Key derivation failed: {}", e))?;
+    Ok(kek)
+}
+
+fn data_key_id(generation: u32) -> String {
+    format!("{}{}", DATA_KEY_PREFIX, generation)
+}
+
+/// Associated data of a record: the key generation, its tree and the record key
+fn record_aad(generation: u32, scope: &[u8], record_key: &[u8]) -> Vec<u8> {
+    let mut aad = generation.to_be_bytes().to_vec();
+    aad.extend_from_slice(&(scope.len() as u32).to_be_bytes());
+    aad.extend_from_slice(scope);
+    aad.extend_from_slice(record_key);
+    aad
+}
+
+fn decode_generation(bytes: &[u8]) -> Result<u32> {
+    let raw: [u8; GENERATION_LEN] = bytes
+        .get(..GENERATION_LEN)
+        .and_then(|b| b.try_into().ok())
+        .ok_or_else(|| anyhow::anyhow!("Truncated record"))?;
+    Ok(u32::from_be_bytes(raw))
+}
+
+fn open_with(key: &[u8; VAULT_KEY_LEN], aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+    if sealed.len() < NONCE_LEN + CHACHA20_POLY1305.tag_len() {
+        return Err(anyhow::anyhow!("Truncated record"));
+    }
+
+    let key = LessSafeKey::new(
+        UnboundKey::new(&CHACHA20_POLY1305, key).map_err(|_| anyhow::anyhow!("Invalid vault key"))?,
+    );
+    let nonce = Nonce::try_assume_unique_for_key(&sealed[..NONCE_LEN])
+        .map_err(|_| anyhow::anyhow!("Invalid nonce"))?;
+    let mut buffer = sealed[NONCE_LEN..].to_vec();
+
+    let plaintext = key
+        .open_in_place(nonce, Aad::from(aad), &mut buffer)
+        .map_err(|_| anyhow::anyhow!("Authentication failed"))?;
+    Ok(plaintext.to_vec())
+}
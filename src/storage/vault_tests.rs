@@ -0,0 +1,225 @@
+#[cfg(test)]
+mod tests {
+    use super::super::db::{open_db, Storage};
+    use super::super::vault::{RotationProgress, VaultError};
+    use sled::IVec;
+    use std::fs;
+    use std::path::Path;
+
+    fn fresh(path: &str) {
+        if Path::new(path).exists() {
+            fs::remove_dir_all(path).unwrap();
+        }
+    }
+
+    // Every (tree name, key, value) on disk, outside the vault metadata
+    fn raw_records(raw: &sled::Db) -> Vec<(IVec, IVec, IVec)> {
+        let mut records = Vec::new();
+        for name in raw.tree_names() {
+            if name.as_ref() != b"__vault" {
+                for item in raw.open_tree(&name).unwrap().iter() {
+                    let (key, value) = item.unwrap();
+                    records.push((name.clone(), key, value));
+                }
+            }
+        }
+        records
+    }
+
+    // Nothing can be read or written before the unlock step
+    #[test]
+    fn test_locked_storage_refuses_access() {
+        let test_path = "test_data/vault_locked";
+        fresh(test_path);
+
+        let storage = Storage::open(test_path).unwrap();
+        let err = storage.put(b"key", b"value").unwrap_err();
+        assert!(matches!(err.downcast_ref::<VaultError>(), Some(VaultError::Locked)));
+
+        storage.unlock("passphrase").unwrap();
+        storage.put(b"key", b"value").unwrap();
+        assert_eq!(storage.get(b"key").unwrap().unwrap().as_ref(), b"value");
+
+        storage.lock();
+        assert!(storage.get(b"key").is_err());
+
+        drop(storage);
+        fs::remove_dir_all(test_path).unwrap();
+    }
+
+    // Values never reach the disk in clear, and only the right passphrase opens them
+    #[test]
+    fn test_values_encrypted_on_disk() {
+        let test_path = "test_data/vault_disk";
+        fresh(test_path);
+
+        {
+            let storage = Storage::open(test_path).unwrap();
+            storage.unlock("passphrase").unwrap();
+            storage.put(b"message/1", b"meet me at noon").unwrap();
+            storage.flush().unwrap();
+        }
+
+        // Neither the value nor its key can be found on disk
+        {
+            let raw = open_db(test_path).unwrap();
+            let records = raw_records(&raw);
+            assert_eq!(records.len(), 1);
+            for (name, key, value) in records {
+                assert!(!value.windows(4).any(|w| w == b"noon"), "Plaintext found on disk");
+                for bytes in [name, key] {
+                    assert!(!bytes.windows(7).any(|w| w == b"message"), "Record key found on disk");
+                }
+            }
+        }
+
+        let storage = Storage::open(test_path).unwrap();
+        let err = storage.unlock("not the passphrase").unwrap_err();
+        assert!(matches!(err.downcast_ref::<VaultError>(), Some(VaultError::WrongKey)));
+        assert!(!storage.is_unlocked());
+
+        storage.unlock("passphrase").unwrap();
+        assert_eq!(storage.get(b"message/1").unwrap().unwrap().as_ref(), b"meet me at noon");
+
+        drop(storage);
+        fs::remove_dir_all(test_path).unwrap();
+    }
+
+    // Records are bound to their key: swapping two values on disk is detected
+    #[test]
+    fn test_swapped_records_rejected() {
+        let test_path = "test_data/vault_swap";
+        fresh(test_path);
+
+        let key = [3u8; 32];
+        {
+            let storage = Storage::open(test_path).unwrap();
+            storage.unlock_with_key(&key).unwrap();
+            storage.put(b"a", b"first").unwrap();
+            storage.put(b"b", b"second").unwrap();
+            storage.flush().unwrap();
+        }
+
+        {
+            let raw = open_db(test_path).unwrap();
+            let records = raw_records(&raw);
+            let tree = raw.open_tree(&records[0].0).unwrap();
+            tree.insert(&records[0].1, records[1].2.clone()).unwrap();
+            tree.insert(&records[1].1, records[0].2.clone()).unwrap();
+            raw.flush().unwrap();
+        }
+
+        let storage = Storage::open(test_path).unwrap();
+        storage.unlock_with_key(&key).unwrap();
+        for key in [b"a", b"b"] {
+            let err = storage.get(key).unwrap_err();
+            assert!(matches!(err.downcast_ref::<VaultError>(), Some(VaultError::Corrupted(_))));
+        }
+
+        drop(storage);
+        fs::remove_dir_all(test_path).unwrap();
+    }
+
+    // Changing the passphrase re-wraps the data key without touching the records
+    #[test]
+    fn test_change_passphrase() {
+        let test_path = "test_data/vault_passphrase";
+        fresh(test_path);
+
+        {
+            let storage = Storage::open(test_path).unwrap();
+            storage.unlock("old passphrase").unwrap();
+            storage.put(b"key", b"value").unwrap();
+            assert!(storage.change_passphrase("wrong", "new passphrase").is_err());
+            storage.change_passphrase("old passphrase", "new passphrase").unwrap();
+            assert_eq!(storage.get(b"key").unwrap().unwrap().as_ref(), b"value");
+        }
+
+        let storage = Storage::open(test_path).unwrap();
+        assert!(storage.unlock("old passphrase").is_err());
+        storage.unlock("new passphrase").unwrap();
+        assert_eq!(storage.get(b"key").unwrap().unwrap().as_ref(), b"value");
+
+        drop(storage);
+        fs::remove_dir_all(test_path).unwrap();
+    }
+
+    // Rotating the data key re-encrypts every record and reports its progress
+    #[test]
+    fn test_rotate_data_key() {
+        let test_path = "test_data/vault_rotate";
+        fresh(test_path);
+
+        let key = [9u8; 32];
+        {
+            let storage = Storage::open(test_path).unwrap();
+            storage.unlock_with_key(&key).unwrap();
+            for i in 0..10u8 {
+                storage.put(&[b'r', i], &[i; 16]).unwrap();
+            }
+            storage.flush().unwrap();
+        }
+        let (tree, record_key, before) = raw_records(&open_db(test_path).unwrap()).remove(0);
+
+        {
+            let storage = Storage::open(test_path).unwrap();
+            storage.unlock_with_key(&key).unwrap();
+
+            let mut reports = Vec::new();
+            storage.rotate_data_key(|p| reports.push(p)).unwrap();
+            assert_eq!(reports.first(), Some(&RotationProgress { done: 0, total: 10 }));
+            assert_eq!(reports.last(), Some(&RotationProgress { done: 10, total: 10 }));
+
+            for i in 0..10u8 {
+                assert_eq!(storage.get(&[b'r', i]).unwrap().unwrap().as_ref(), &[i; 16]);
+            }
+            storage.flush().unwrap();
+        }
+
+        // The old data key is destroyed: a record sealed with it can no longer be opened
+        {
+            let raw = open_db(test_path).unwrap();
+            let tree = raw.open_tree(&tree).unwrap();
+            assert_ne!(tree.get(&record_key).unwrap().unwrap(), before);
+            tree.insert(&record_key, before).unwrap();
+            raw.flush().unwrap();
+        }
+
+        let storage = Storage::open(test_path).unwrap();
+        storage.unlock_with_key(&key).unwrap();
+        let results: Vec<_> = (0..10u8).map(|i| storage.get(&[b'r', i])).collect();
+        assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 9);
+        let err = results.into_iter().find_map(Result::err).unwrap();
+        assert!(matches!(err.downcast_ref::<VaultError>(), Some(VaultError::UnknownGeneration(1))));
+
+        drop(storage);
+        fs::remove_dir_all(test_path).unwrap();
+    }
+
+    // A database written before the vault (plain sled values) is refused, not read as sealed
+    #[test]
+    fn test_unencrypted_database_refused() {
+        let test_path = "test_data/vault_plaintext";
+        fresh(test_path);
+
+        for tree in [&b""[..], b"contacts"] {
+            {
+                let raw = open_db(test_path).unwrap();
+                let target = if tree.is_empty() { (*raw).clone() } else { raw.open_tree(tree).unwrap() };
+                target.insert(b"contact/@bob", &b"bob"[..]).unwrap();
+                raw.flush().unwrap();
+            }
+
+            let err = Storage::open(test_path).err().expect("plaintext database opened");
+            assert!(matches!(err.downcast_ref::<VaultError>(), Some(VaultError::Unencrypted)));
+
+            // The records are left untouched for an export
+            let raw = open_db(test_path).unwrap();
+            let target = if tree.is_empty() { (*raw).clone() } else { raw.open_tree(tree).unwrap() };
+            assert_eq!(target.get(b"contact/@bob").unwrap().unwrap().as_ref(), b"bob");
+            drop(target);
+            drop(raw);
+            fs::remove_dir_all(test_path).unwrap();
+        }
+    }
+}
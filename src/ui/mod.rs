@@ -1,4 +1,4 @@
-use crate::app::EnigmaApp;
+use crate::app::{AppEvent, EnigmaApp};
 use anyhow::Result;
 use std::sync::Arc;
 use tokio::sync::Mutex;
@@ -16,11 +16,59 @@ impl UI {
 
     /// Handle a text message being composed and sent to a recipient
     pub async fn send_text_message(&self, to: &str, content: &str) -> Result<()> {
-        let mut app = self.app.lock().await;
+        let app = self.app.lock().await;
         let _ = app.send_message(to, content.as_bytes()).await?;
         Ok(())
     }
 
+    /// Drives the inbound pipeline: feeds received data to the core and
+    /// dispatches the resulting events until the data channels are closed
+    pub async fn run_inbound(&self) {
+        let (mut inbound, mut events) = {
+            let app = self.app.lock().await;
+            (app.inbound_channel(), app.subscribe())
+        };
+
+        loop {
+            tokio::select! {
+                raw = inbound.recv() => match raw {
+                    Some(raw) => {
+                        // Rejections are reported through the event channel
+                        let app = self.app.lock().await;
+                        let _ = app.handle_incoming(&raw).await;
+                    }
+                    None => break,
+                },
+                Ok(event) = events.recv() => self.dispatch(event).await,
+            }
+        }
+    }
+
+    /// Routes a core event to the matching UI callback
+    pub async fn dispatch(&self, event: AppEvent) {
+        match event {
+            AppEvent::MessageReceived { message, plaintext } => {
+                self.on_incoming_message(&message.sender, &String::from_utf8_lossy(&plaintext))
+                    .await
+            }
+            AppEvent::InboundRejected { reason } => self.notify_error(&reason),
+            AppEvent::ConversationUpdated { conversation } => {
+                self.on_conversation_updated(&conversation.id, conversation.unread)
+            }
+            AppEvent::IdentityKeyChanged { contact, .. } => self.on_identity_key_changed(contact.name()),
+            AppEvent::DataKeyRotation { progress } => {
+                self.on_rotation_progress(progress.done, progress.total)
+            }
+            AppEvent::GroupUpdated { group } => self.on_group_updated(&group.name, group.members.len()),
+            AppEvent::GroupRemoved { group_id } => self.on_group_removed(&group_id.to_string()),
+            AppEvent::GroupInviteReceived { invite } => self.on_group_invite(&invite.inviter, &invite.group_name),
+            AppEvent::GroupInviteDeclined { group_id, username } => {
+                self.on_group_invite_declined(&group_id.to_string(), &username)
+            }
+            AppEvent::GroupInviteRejected { reason, .. } => self.notify_error(&reason),
+        }
+    }
+
     /// Placeholder for future UI trigger: display incoming message
     pub async fn on_incoming_message(&self, from: &str, content: &str) {
         println!("[{}] says: {}", from, content);
@@ -32,6 +80,41 @@ impl UI {
         println!("Connected with peer: {}", peer);
     }
 
+    /// Placeholder for refreshing an entry of the conversation list
+    pub fn on_conversation_updated(&self, conversation: &str, unread: u64) {
+        println!("Conversation {} updated ({} unread)", conversation, unread);
+    }
+
+    /// Placeholder for the safety warning shown when a contact's key changed
+    pub fn on_identity_key_changed(&self, contact: &str) {
+        eprintln!("[Warning] The security key of {} changed", contact);
+    }
+
+    /// Placeholder for storage re-encryption progress
+    pub fn on_rotation_progress(&self, done: usize, total: usize) {
+        println!("Re-encrypting storage: {}/{}", done, total);
+    }
+
+    /// Placeholder for refreshing a group's details
+    pub fn on_group_updated(&self, name: &str, members: usize) {
+        println!("Group {} updated ({} members)", name, members);
+    }
+
+    /// Placeholder for removing a group we no longer belong to
+    pub fn on_group_removed(&self, group: &str) {
+        println!("Left group {}", group);
+    }
+
+    /// Placeholder for asking the user to accept or decline an invite
+    pub fn on_group_invite(&self, inviter: &str, group: &str) {
+        println!("{} invites you to {}", inviter, group);
+    }
+
+    /// Placeholder for telling the inviter an invite was declined
+    pub fn on_group_invite_declined(&self, group: &str, username: &str) {
+        println!("{} declined the invite to {}", username, group);
+    }
+
     /// Placeholder for errors or alerts
     pub fn notify_error(&self, msg: &str) {
         eprintln!("[Error] {}", msg);